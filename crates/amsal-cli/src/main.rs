@@ -9,20 +9,33 @@
 //!   amsal pause                Pause playback
 //!   amsal resume               Resume playback
 //!   amsal stop                 Stop playback
+//!   amsal fadeout [seconds]    Fade out (default 3s), then stop
 //!   amsal next                 Next track
 //!   amsal prev                 Previous track
-//!   amsal seek <seconds>       Seek to position
+//!   amsal seek <seconds>       Seek to position (+N / -N seeks relative)
 //!   amsal volume <0-100>       Set volume
+//!   amsal speed <0.5-3.0>      Set playback speed (pitch preserved)
+//!   amsal loop <start> <end>   Loop between two positions in seconds ("off" clears)
+//!   amsal trim <id> <start> [end]
+//!                              Always play an item from/to these seconds ("off" clears)
 //!   amsal queue <id> [id...]   Set queue from library IDs
 //!   amsal shuffle <on|off>     Toggle shuffle
 //!   amsal repeat <off|all|one> Set repeat mode
 //!   amsal history [limit]      Recent play history
 //!   amsal stats <id>           Track statistics
 //!   amsal eq '<json>'          Set DSP EQ chain
+//!   amsal eq --import <file.txt> [name]
+//!                              Import an Equalizer APO / AutoEQ preset and activate it
+//!   amsal eq --preset <name>   Activate a stored preset (--presets lists them)
+//!   amsal devices [name]       List output devices / prefer one ("default" clears)
+//!   amsal render <file...> -o <out.wav> [--rate N] [--channels N] [--format s16|s24|f32]
+//!                              Render files through the EQ chain into a WAV file
 //!   amsal daemon               Run as background daemon
 
 use amsal_core::playback::{PlaybackCommand, RepeatMode};
-use amsal_core::Engine;
+use amsal_core::effects::dsp;
+use amsal_core::effects::resample::ResampleQuality;
+use amsal_core::{Engine, RenderBackend, RenderOptions, WavFormat};
 use nine_s_shell::Shell;
 
 fn main() {
@@ -54,16 +67,22 @@ fn main() {
         "pause" => { engine.command(PlaybackCommand::Pause).ok(); }
         "resume" => { engine.command(PlaybackCommand::Resume).ok(); }
         "stop" => { engine.command(PlaybackCommand::Stop).ok(); }
+        "fadeout" => cmd_fadeout(&engine, &args[1..]),
         "next" => { engine.command(PlaybackCommand::Next).ok(); }
         "prev" => { engine.command(PlaybackCommand::Previous).ok(); }
         "seek" => cmd_seek(&engine, &args[1..]),
         "volume" => cmd_volume(&engine, &args[1..]),
+        "speed" => cmd_speed(&engine, &args[1..]),
+        "loop" => cmd_loop(&engine, &args[1..]),
+        "trim" => cmd_trim(&engine, &args[1..]),
         "queue" => cmd_queue(&engine, &args[1..]),
         "shuffle" => cmd_shuffle(&engine, &args[1..]),
         "repeat" => cmd_repeat(&engine, &args[1..]),
         "history" => cmd_history(&engine, &args[1..]),
         "stats" => cmd_stats(&engine, &args[1..]),
         "eq" => cmd_eq(&engine, &args[1..]),
+        "devices" => cmd_devices(&engine, &args[1..]),
+        "render" => cmd_render(&engine, &args[1..]),
         "daemon" => cmd_daemon(&engine),
         other => {
             eprintln!("unknown command: {}", other);
@@ -216,20 +235,40 @@ fn cmd_now(engine: &Engine) {
         let status = if playing { "playing" } else { "paused" };
         println!("{} — {}  [{}]", title, artist, status);
         println!("  {} / {}  vol: {}%", fmt_time(pos), fmt_time(dur), (vol * 100.0) as u32);
+        if let (Some(a), Some(b)) = (state["loop"]["start_ms"].as_u64(), state["loop"]["end_ms"].as_u64()) {
+            println!("  loop: {} - {}", fmt_time(a), fmt_time(b));
+        }
     } else {
         println!("stopped");
     }
 }
 
+fn cmd_fadeout(engine: &Engine, args: &[String]) {
+    let secs = match args.first().map(|a| a.parse::<f64>()) {
+        None => 3.0,
+        Some(Ok(secs)) if secs >= 0.0 => secs,
+        Some(_) => {
+            eprintln!("invalid seconds: {}", args[0]);
+            return;
+        }
+    };
+    engine.command(PlaybackCommand::FadeOut { duration_ms: (secs * 1000.0) as u64 }).ok();
+}
+
 fn cmd_seek(engine: &Engine, args: &[String]) {
     if args.is_empty() {
-        eprintln!("usage: amsal seek <seconds>");
+        eprintln!("usage: amsal seek <seconds> | +<seconds> | -<seconds>");
         return;
     }
-    if let Ok(secs) = args[0].parse::<u64>() {
-        engine.command(PlaybackCommand::Seek { position_ms: secs * 1000 }).ok();
-    } else {
-        eprintln!("invalid seconds: {}", args[0]);
+    let relative = args[0].starts_with('+') || args[0].starts_with('-');
+    match args[0].parse::<f64>() {
+        Ok(secs) if secs.is_finite() && relative => {
+            engine.command(PlaybackCommand::SeekRelative { delta_ms: (secs * 1000.0) as i64 }).ok();
+        }
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => {
+            engine.command(PlaybackCommand::Seek { position_ms: (secs * 1000.0) as u64 }).ok();
+        }
+        _ => eprintln!("invalid seconds: {}", args[0]),
     }
 }
 
@@ -246,6 +285,62 @@ fn cmd_volume(engine: &Engine, args: &[String]) {
     }
 }
 
+fn cmd_speed(engine: &Engine, args: &[String]) {
+    if args.is_empty() {
+        eprintln!("usage: amsal speed <0.5-3.0>");
+        return;
+    }
+    match args[0].trim_end_matches('x').parse::<f32>() {
+        Ok(rate) if rate.is_finite() => { engine.command(PlaybackCommand::SetSpeed { rate }).ok(); }
+        _ => eprintln!("invalid speed: {}", args[0]),
+    }
+}
+
+fn cmd_loop(engine: &Engine, args: &[String]) {
+    if args.first().map(String::as_str) == Some("off") {
+        engine.command(PlaybackCommand::ClearLoop).ok();
+        return;
+    }
+    let (Some(start), Some(end)) = (args.first(), args.get(1)) else {
+        eprintln!("usage: amsal loop <start> <end> | off");
+        return;
+    };
+    match (parse_secs_ms(start), parse_secs_ms(end)) {
+        (Some(start_ms), Some(end_ms)) if end_ms > start_ms => {
+            engine.command(PlaybackCommand::SetLoop { start_ms, end_ms }).ok();
+        }
+        _ => eprintln!("invalid loop: {} {} (end must be after start)", start, end),
+    }
+}
+
+fn cmd_trim(engine: &Engine, args: &[String]) {
+    let (Some(id), Some(start)) = (args.first(), args.get(1)) else {
+        eprintln!("usage: amsal trim <id> <start> [end] | <id> off");
+        return;
+    };
+    let range = if start == "off" {
+        Some((0, None))
+    } else {
+        match (parse_secs_ms(start), args.get(2).map(|a| parse_secs_ms(a))) {
+            (Some(start_ms), None) => Some((start_ms, None)),
+            (Some(start_ms), Some(Some(end_ms))) if end_ms > start_ms => Some((start_ms, Some(end_ms))),
+            _ => None,
+        }
+    };
+    let Some((start_ms, end_ms)) = range else {
+        eprintln!("invalid trim points (end must be after start)");
+        return;
+    };
+    if let Err(e) = engine.set_track_range(id, start_ms, end_ms) {
+        eprintln!("trim failed: {}", e);
+    }
+}
+
+/// Seconds (fractions allowed) as milliseconds.
+fn parse_secs_ms(arg: &str) -> Option<u64> {
+    arg.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0).map(|s| (s * 1000.0) as u64)
+}
+
 fn cmd_queue(engine: &Engine, args: &[String]) {
     if args.is_empty() {
         eprintln!("usage: amsal queue <id> [id...]");
@@ -320,6 +415,27 @@ fn cmd_daemon(engine: &Engine) {
 }
 
 fn cmd_eq(engine: &Engine, args: &[String]) {
+    match args.first().map(String::as_str) {
+        Some("--import") => return cmd_eq_import(engine, &args[1..]),
+        Some("--preset") => {
+            let Some(name) = args.get(1) else {
+                eprintln!("usage: amsal eq --preset <name>");
+                return;
+            };
+            match engine.activate_eq_preset(name) {
+                Ok(_) => println!("eq preset: {}", name),
+                Err(e) => eprintln!("error: {}", e),
+            }
+            return;
+        }
+        Some("--presets") => {
+            for name in engine.list_eq_presets() {
+                println!("{}", name);
+            }
+            return;
+        }
+        _ => {}
+    }
     if args.is_empty() {
         eprintln!("usage: amsal eq '<json>'");
         eprintln!("  example: amsal eq '{{\"filters\":[{{\"type\":\"eq\",\"freq_hz\":80,\"gain_db\":6.0,\"q\":0.7}}]}}'");
@@ -327,14 +443,160 @@ fn cmd_eq(engine: &Engine, args: &[String]) {
     }
     let json_str = args.join(" ");
     match serde_json::from_str::<serde_json::Value>(&json_str) {
-        Ok(value) => {
-            engine.shell().put("/amsal/playback/eq", value).ok();
-            println!("eq updated");
-        }
+        Ok(value) => match dsp::parse_filters(&value) {
+            Ok(specs) => {
+                engine.shell().put("/amsal/playback/eq", value).ok();
+                println!("eq updated ({} filters)", specs.len());
+            }
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("invalid eq: {}", e);
+                }
+            }
+        },
         Err(e) => eprintln!("invalid JSON: {}", e),
     }
 }
 
+/// Import a ParametricEQ.txt as a preset (named after the file unless given) and activate it.
+fn cmd_eq_import(engine: &Engine, args: &[String]) {
+    let Some(file) = args.first() else {
+        eprintln!("usage: amsal eq --import <file.txt> [name]");
+        return;
+    };
+    let text = match std::fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("error: {}: {}", file, e);
+            return;
+        }
+    };
+    let name = args.get(1).cloned().unwrap_or_else(|| {
+        std::path::Path::new(file)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "imported".into())
+    });
+    match engine.import_eq_preset(&name, &text).and_then(|_| engine.activate_eq_preset(&name)) {
+        Ok(scroll) => {
+            let count = scroll.data["filters"].as_array().map(|f| f.len()).unwrap_or(0);
+            println!("imported eq preset {} ({} filters), active", name, count);
+        }
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+fn cmd_devices(engine: &Engine, args: &[String]) {
+    if let Some(name) = args.first() {
+        let mut settings = engine
+            .shell()
+            .get("/amsal/settings/audio")
+            .ok()
+            .flatten()
+            .map(|s| s.data)
+            .filter(|v| v.is_object())
+            .unwrap_or_else(|| serde_json::json!({}));
+        settings["output_device"] = if name == "default" { serde_json::Value::Null } else { name.as_str().into() };
+        engine.shell().put("/amsal/settings/audio", settings).ok();
+        println!("output device: {}", name);
+        return;
+    }
+
+    let devices = engine.list_devices();
+    let preferred = devices["preferred"].as_str();
+    for host in devices["hosts"].as_array().into_iter().flatten() {
+        let marker = if host["default"].as_bool().unwrap_or(false) { " (default)" } else { "" };
+        println!("{}{}", host["name"].as_str().unwrap_or("?"), marker);
+        for device in host["devices"].as_array().into_iter().flatten() {
+            let name = device["name"].as_str().unwrap_or("?");
+            let mut tags = Vec::new();
+            if device["default"].as_bool().unwrap_or(false) {
+                tags.push("default");
+            }
+            if Some(name) == preferred {
+                tags.push("preferred");
+            }
+            let tags = if tags.is_empty() { String::new() } else { format!("  [{}]", tags.join(", ")) };
+            println!("  {}{}", name, tags);
+        }
+    }
+}
+
+fn cmd_render(engine: &Engine, args: &[String]) {
+    const USAGE: &str = "usage: amsal render <file...> -o <out.wav> [--rate N] [--channels N] [--format s16|s24|f32]";
+    let mut files = Vec::new();
+    let mut out = None;
+    let mut options = RenderOptions::default();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let ok = match arg.as_str() {
+            "-o" => it.next().map(|v| out = Some(v.clone())).is_some(),
+            "--rate" => it
+                .next()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .map(|v| options.sample_rate = Some(v))
+                .is_some(),
+            "--channels" => it
+                .next()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .map(|v| options.channels = Some(v))
+                .is_some(),
+            "--format" => it.next().and_then(|v| WavFormat::from_name(v)).map(|v| options.format = v).is_some(),
+            _ => {
+                files.push(arg.clone());
+                true
+            }
+        };
+        if !ok {
+            eprintln!("invalid value for {}", arg);
+            eprintln!("{}", USAGE);
+            return;
+        }
+    }
+    let Some(out) = out.filter(|_| !files.is_empty()) else {
+        eprintln!("{}", USAGE);
+        return;
+    };
+
+    let render = RenderBackend::to_file(&out, options);
+    if let Ok(Some(eq)) = engine.shell().get("/amsal/playback/eq") {
+        let checked = dsp::parse_filters(&eq.data).and_then(|specs| dsp::check_files(&specs));
+        for e in checked.err().unwrap_or_default() {
+            eprintln!("warning: eq {} (skipped)", e);
+        }
+        render.set_eq(&eq.data);
+    }
+    if let Ok(Some(settings)) = engine.shell().get("/amsal/settings/audio") {
+        if let Some(quality) = settings.data["resampler"].as_str().and_then(ResampleQuality::from_name) {
+            render.set_resample_quality(quality);
+        }
+        render.set_dither(settings.data["dither"].as_bool().unwrap_or(true));
+    }
+
+    // One file at a time, appended to the same output
+    for file in &files {
+        render.play(file);
+        while !render.is_finished() {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        if render.is_error() {
+            eprintln!("render failed: {}", file);
+            return;
+        }
+        println!("rendered {}", file);
+    }
+    let format = render.output_format();
+    if let Err(e) = render.finish() {
+        eprintln!("could not finalize {}: {}", out, e);
+        return;
+    }
+    if let Some((rate, _)) = format {
+        println!("wrote {} ({})", out, fmt_time(render.frames_rendered() * 1000 / rate as u64));
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Helpers
 // ---------------------------------------------------------------------------
@@ -424,15 +686,26 @@ fn print_usage() {
     println!("  pause                  Pause playback");
     println!("  resume                 Resume playback");
     println!("  stop                   Stop playback");
+    println!("  fadeout [seconds]      Fade out (default 3s), then stop");
     println!("  next                   Next track");
     println!("  prev                   Previous track");
-    println!("  seek <seconds>         Seek to position");
+    println!("  seek <seconds>         Seek to position (+N / -N seeks relative)");
     println!("  volume <0-100>         Set volume");
+    println!("  speed <0.5-3.0>        Set playback speed (pitch preserved)");
+    println!("  loop <start> <end>     Loop between two positions in seconds (\"off\" clears)");
+    println!("  trim <id> <start> [end]");
+    println!("                         Always play an item from/to these seconds (\"off\" clears)");
     println!("  queue <id> [id...]     Set queue from library IDs");
     println!("  shuffle <on|off>       Toggle shuffle");
     println!("  repeat <off|all|one>   Set repeat mode");
     println!("  history [limit]        Recent play history");
     println!("  stats <id>             Track statistics");
     println!("  eq '<json>'            Set DSP EQ chain");
+    println!("  eq --import <file.txt> [name]");
+    println!("                         Import an Equalizer APO / AutoEQ preset and activate it");
+    println!("  eq --preset <name>     Activate a stored preset (--presets lists them)");
+    println!("  devices [name]         List output devices / prefer one (\"default\" clears)");
+    println!("  render <file...> -o <out.wav> [--rate N] [--channels N] [--format s16|s24|f32]");
+    println!("                         Render files through the EQ chain into a WAV file");
     println!("  daemon                 Run as background daemon");
 }
@@ -0,0 +1,678 @@
+//! Analysis effect — EBU R128 loudness of library items.
+//!
+//! Decodes tracks with symphonia and measures integrated loudness (BS.1770
+//! K-weighting + gating), loudness range (EBU Tech 3342) and 4x-oversampled
+//! true peak, then writes `/amsal/analysis/items/{id}`. Gating blocks are kept as
+//! 0.1 LU histograms in the result, so album values come from pooling the
+//! tracks' histograms without decoding again.
+//!
+//! Jobs are requested via `/amsal/analysis/request` and report progress in
+//! `/amsal/analysis/status`. Every finished track is its own checkpoint: an
+//! interrupted job is resumed from the status scroll and skips the tracks
+//! it already analysed.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nine_s_shell::Shell;
+use serde_json::Value;
+
+use super::decode::TrackDecoder;
+use crate::paths;
+use crate::time::TimeSource;
+
+/// Histogram range: 0.1 LU bins from the -70 LUFS absolute gate up to +5 LUFS.
+const HIST_MIN_LUFS: f64 = -70.0;
+const HIST_STEP_LU: f64 = 0.1;
+const HIST_BINS: usize = 750;
+
+/// ReplayGain 2.0 reference loudness, used to turn results into gains.
+const REFERENCE_LUFS: f64 = -18.0;
+
+/// 100 ms sub-blocks: momentary blocks are 4 of them, short-term blocks 30.
+const MOMENTARY_SUB_BLOCKS: usize = 4;
+const SHORT_TERM_SUB_BLOCKS: usize = 30;
+
+/// Progress writes while skipping items (skipped or finished by this job
+/// before a resume): every this many items, or this often.
+const STATUS_EVERY_ITEMS: usize = 500;
+const STATUS_EVERY_MS: i64 = 500;
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn lufs_to_energy(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+fn round2(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+// ---------------------------------------------------------------------------
+// Measurement
+// ---------------------------------------------------------------------------
+
+/// Loudness block counts in 0.1 LU bins above the absolute gate.
+#[derive(Debug, Clone, PartialEq)]
+struct BlockHistogram {
+    counts: Vec<u64>,
+}
+
+impl BlockHistogram {
+    fn new() -> Self {
+        Self { counts: vec![0; HIST_BINS] }
+    }
+
+    fn bin_lufs(bin: usize) -> f64 {
+        HIST_MIN_LUFS + (bin as f64 + 0.5) * HIST_STEP_LU
+    }
+
+    /// Count a block by its mean weighted energy (absolute gate applied).
+    fn add(&mut self, energy: f64) {
+        if energy <= 0.0 {
+            return;
+        }
+        let lufs = energy_to_lufs(energy);
+        if lufs < HIST_MIN_LUFS {
+            return;
+        }
+        let bin = (((lufs - HIST_MIN_LUFS) / HIST_STEP_LU) as usize).min(HIST_BINS - 1);
+        self.counts[bin] += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+    }
+
+    /// Bins at or above `gate` LUFS as (lufs, count).
+    fn gated(&self, gate: f64) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bin, &count)| (Self::bin_lufs(bin), count))
+            .filter(move |(lufs, _)| *lufs >= gate)
+    }
+
+    /// Mean-energy loudness of the blocks at or above `gate`.
+    fn gated_mean(&self, gate: f64) -> Option<f64> {
+        let (energy, blocks) = self
+            .gated(gate)
+            .fold((0.0, 0u64), |(e, n), (lufs, count)| (e + count as f64 * lufs_to_energy(lufs), n + count));
+        (blocks > 0).then(|| energy_to_lufs(energy / blocks as f64))
+    }
+
+    /// BS.1770 integrated loudness: absolute gate, then a -10 LU relative gate.
+    fn integrated(&self) -> Option<f64> {
+        let ungated = self.gated_mean(HIST_MIN_LUFS)?;
+        self.gated_mean(ungated - 10.0)
+    }
+
+    /// EBU Tech 3342 loudness range: -20 LU relative gate, then the spread
+    /// between the 10th and 95th percentiles of short-term loudness.
+    fn loudness_range(&self) -> f64 {
+        let Some(ungated) = self.gated_mean(HIST_MIN_LUFS) else {
+            return 0.0;
+        };
+        let gate = ungated - 20.0;
+        let total: u64 = self.gated(gate).map(|(_, count)| count).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let percentile = |p: f64| {
+            let rank = (p * (total - 1) as f64).round() as u64;
+            let mut seen = 0;
+            for (lufs, count) in self.gated(gate) {
+                seen += count;
+                if seen > rank {
+                    return lufs;
+                }
+            }
+            gate
+        };
+        percentile(0.95) - percentile(0.10)
+    }
+
+    /// Sparse form for the result scroll: the non-empty span of bins.
+    fn to_value(&self) -> Value {
+        let first = self.counts.iter().position(|&c| c > 0);
+        let last = self.counts.iter().rposition(|&c| c > 0);
+        match (first, last) {
+            (Some(first), Some(last)) => serde_json::json!({
+                "start": first,
+                "counts": &self.counts[first..=last],
+            }),
+            _ => serde_json::json!({"start": 0, "counts": []}),
+        }
+    }
+
+    fn from_value(v: &Value) -> Option<Self> {
+        let start = v["start"].as_u64()? as usize;
+        let mut hist = Self::new();
+        for (i, count) in v["counts"].as_array()?.iter().enumerate() {
+            if let Some(slot) = hist.counts.get_mut(start + i) {
+                *slot = count.as_u64()?;
+            }
+        }
+        Some(hist)
+    }
+}
+
+/// Direct Form I biquad in f64 (the K-weighting stages need the precision
+/// at low cutoff frequencies).
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad64 {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad64 {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// BS.1770 K-weighting (high-shelf pre-filter + RLB high-pass) at any rate.
+fn k_weighting(sample_rate: u32) -> [Biquad64; 2] {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad64 {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad64 {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    [shelf, highpass]
+}
+
+/// BS.1770 channel weights: surrounds +1.5 dB, LFE excluded.
+/// Layouts follow symphonia's channel order (FL FR FC LFE RL RR for 5.1,
+/// then SL SR for 7.1).
+fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        5 => vec![1.0, 1.0, 1.0, 1.41, 1.41],
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        8 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41, 1.41, 1.41],
+        n => vec![1.0; n],
+    }
+}
+
+/// Interpolation taps per phase for the 4x true-peak oversampler.
+const TRUE_PEAK_TAPS: usize = 12;
+const TRUE_PEAK_PHASES: usize = 4;
+
+/// 4x oversampled peak meter (Hann-windowed sinc interpolation).
+struct TruePeak {
+    coefs: [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES],
+    /// Last `TRUE_PEAK_TAPS` input samples per channel, oldest first.
+    history: Vec<[f64; TRUE_PEAK_TAPS]>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(channels: usize) -> Self {
+        let half = (TRUE_PEAK_TAPS / 2) as f64;
+        let mut coefs = [[0.0; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES];
+        for (phase, taps) in coefs.iter_mut().enumerate() {
+            // Output sits `half` samples behind the newest input, plus the phase offset
+            for (i, c) in taps.iter_mut().enumerate() {
+                let d = half - 1.0 + phase as f64 / TRUE_PEAK_PHASES as f64 - i as f64;
+                let sinc = if d == 0.0 { 1.0 } else { (PI * d).sin() / (PI * d) };
+                let window = if d.abs() < half { 0.5 * (1.0 + (PI * d / half).cos()) } else { 0.0 };
+                *c = sinc * window;
+            }
+            let sum: f64 = taps.iter().sum();
+            for c in taps.iter_mut() {
+                *c /= sum;
+            }
+        }
+        Self { coefs, history: vec![[0.0; TRUE_PEAK_TAPS]; channels], peak: 0.0 }
+    }
+
+    fn push(&mut self, channel: usize, x: f64) {
+        let hist = &mut self.history[channel];
+        hist.copy_within(1.., 0);
+        hist[TRUE_PEAK_TAPS - 1] = x;
+        for taps in &self.coefs {
+            let y: f64 = taps.iter().zip(hist.iter()).map(|(c, s)| c * s).sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+/// Streaming R128 meter over interleaved f32 samples.
+struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad64; 2]>,
+    true_peak: TruePeak,
+    sub_block_frames: usize,
+    sub_energy: f64,
+    sub_frames: usize,
+    /// Mean energies of the most recent 100 ms sub-blocks.
+    recent: VecDeque<f64>,
+    momentary: BlockHistogram,
+    short_term: BlockHistogram,
+}
+
+impl LoudnessMeter {
+    fn new(sample_rate: u32, channels: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        Self {
+            channels,
+            weights: channel_weights(channels),
+            filters: vec![k_weighting(sample_rate); channels],
+            true_peak: TruePeak::new(channels),
+            sub_block_frames: (sample_rate as usize / 10).max(1),
+            sub_energy: 0.0,
+            sub_frames: 0,
+            recent: VecDeque::with_capacity(SHORT_TERM_SUB_BLOCKS),
+            momentary: BlockHistogram::new(),
+            short_term: BlockHistogram::new(),
+        }
+    }
+
+    fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            let mut energy = 0.0;
+            for (c, &s) in frame.iter().enumerate() {
+                let x = s as f64;
+                self.true_peak.push(c, x);
+                let [shelf, highpass] = &mut self.filters[c];
+                let y = highpass.process(shelf.process(x));
+                energy += self.weights[c] * y * y;
+            }
+            self.sub_energy += energy;
+            self.sub_frames += 1;
+            if self.sub_frames == self.sub_block_frames {
+                self.finish_sub_block();
+            }
+        }
+    }
+
+    fn finish_sub_block(&mut self) {
+        let mean = self.sub_energy / self.sub_frames as f64;
+        self.sub_energy = 0.0;
+        self.sub_frames = 0;
+        if self.recent.len() == SHORT_TERM_SUB_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(mean);
+
+        let n = self.recent.len();
+        if n >= MOMENTARY_SUB_BLOCKS {
+            let sum: f64 = self.recent.iter().skip(n - MOMENTARY_SUB_BLOCKS).sum();
+            self.momentary.add(sum / MOMENTARY_SUB_BLOCKS as f64);
+        }
+        if n == SHORT_TERM_SUB_BLOCKS {
+            let sum: f64 = self.recent.iter().sum();
+            self.short_term.add(sum / SHORT_TERM_SUB_BLOCKS as f64);
+        }
+    }
+
+    /// Track-level result fields (partial trailing sub-block is discarded).
+    fn finish(&self) -> Value {
+        let peak = self.true_peak.peak;
+        serde_json::json!({
+            "integrated_lufs": self.momentary.integrated().map(round2),
+            "loudness_range_lu": round2(self.short_term.loudness_range()),
+            "true_peak": peak,
+            "true_peak_dbtp": (peak > 0.0).then(|| round2(20.0 * peak.log10())),
+            "blocks": {
+                "momentary": self.momentary.to_value(),
+                "short_term": self.short_term.to_value(),
+            },
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Jobs
+// ---------------------------------------------------------------------------
+
+/// Decode and measure one file. Returns None if `shutdown` was raised.
+fn analyze_file(file_path: &str, shutdown: &AtomicBool) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let mut track = TrackDecoder::open(file_path)?;
+    let mut meter = LoudnessMeter::new(track.sample_rate, track.channels);
+    let mut samples = Vec::new();
+    let mut frames: u64 = 0;
+
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        samples.clear();
+        match track.decode_next(&mut samples)? {
+            Some(n) => {
+                meter.process(&samples);
+                frames += n as u64;
+            }
+            None => break,
+        }
+    }
+
+    let mut result = meter.finish();
+    result["sample_rate"] = track.sample_rate.into();
+    result["channels"] = track.channels.into();
+    result["duration_ms"] = (frames * 1000 / track.sample_rate.max(1) as u64).into();
+    Ok(Some(result))
+}
+
+/// Run an analysis request until done, superseded or shut down.
+///
+/// Request: `{"ids": [...]}` limits the job to those items (default: the
+/// whole library); `"force": true` re-analyses items that already have a
+/// result; `"include_tagged": true` also measures items with ReplayGain tags.
+///
+/// `request_version` is the request scroll version being served — a newer
+/// request stops this one. `started_ms` identifies the job: results written
+/// at or after it count as done, which is how a resumed job skips its own
+/// finished work even with `force`. Results and status are stamped from
+/// `time`. Returns the number of tracks analysed.
+pub fn run_analysis(
+    shell: &Shell,
+    request: &Value,
+    request_version: u64,
+    started_ms: u64,
+    time: &dyn TimeSource,
+    shutdown: &AtomicBool,
+) -> usize {
+    let force = request["force"].as_bool().unwrap_or(false);
+    let include_tagged = request["include_tagged"].as_bool().unwrap_or(false);
+    let items = job_items(shell, request);
+    let total = items.len();
+
+    let mut status = serde_json::json!({
+        "running": true,
+        "request": request,
+        "started_ms": started_ms,
+        "total": total,
+        "done": 0,
+        "analyzed": 0,
+        "skipped": 0,
+        "failed": 0,
+    });
+    let _ = shell.put(paths::ANALYSIS_STATUS, status.clone());
+
+    let (mut analyzed, mut skipped, mut failed) = (0usize, 0usize, 0usize);
+    let (mut last_put, mut unreported) = (time.now_ms(), 0usize);
+    for (done, (id, item)) in items.iter().enumerate() {
+        let superseded = shell
+            .get(paths::ANALYSIS_REQUEST)
+            .ok()
+            .flatten()
+            .is_some_and(|s| s.metadata.version != request_version);
+        if shutdown.load(Ordering::SeqCst) || superseded {
+            return analyzed; // Status stays "running" — resumed on next start
+        }
+
+        let existing = shell.get(&paths::analysis_path(id)).ok().flatten().map(|s| s.data);
+        let finished_this_job = existing
+            .as_ref()
+            .and_then(|a| a["analyzed_ms"].as_u64())
+            .is_some_and(|t| t >= started_ms);
+
+        if finished_this_job {
+            analyzed += 1;
+        } else if (existing.is_some() && !force) || (!include_tagged && !item["replay_gain"].is_null()) {
+            skipped += 1;
+        } else {
+            status["current_id"] = id.as_str().into();
+            let _ = shell.put(paths::ANALYSIS_STATUS, status.clone());
+            (last_put, unreported) = (time.now_ms(), 0);
+
+            let path = item["path"].as_str().unwrap_or_default();
+            match analyze_file(path, shutdown) {
+                Ok(Some(mut result)) => {
+                    result["id"] = id.as_str().into();
+                    result["album"] = item["album"].clone();
+                    result["analyzed_ms"] = time.now_ms().max(0).into();
+                    if shell.put(&paths::analysis_path(id), result).is_ok() {
+                        analyzed += 1;
+                    } else {
+                        failed += 1;
+                    }
+                }
+                Ok(None) => return analyzed,
+                Err(e) => {
+                    log::warn!("amsal: loudness analysis failed for {}: {}", path, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        status["done"] = (done + 1).into();
+        status["analyzed"] = analyzed.into();
+        status["skipped"] = skipped.into();
+        status["failed"] = failed.into();
+
+        // Written before each analysis too; long runs of skipped or
+        // resumed items would otherwise leave the progress stale
+        unreported += 1;
+        if unreported >= STATUS_EVERY_ITEMS || time.now_ms() - last_put >= STATUS_EVERY_MS {
+            let _ = shell.put(paths::ANALYSIS_STATUS, status.clone());
+            (last_put, unreported) = (time.now_ms(), 0);
+        }
+    }
+
+    let albums = update_albums(shell, &items);
+
+    status["running"] = false.into();
+    status["albums"] = albums.into();
+    status["finished_ms"] = time.now_ms().max(0).into();
+    if let Some(o) = status.as_object_mut() {
+        o.remove("current_id");
+    }
+    let _ = shell.put(paths::ANALYSIS_STATUS, status);
+    analyzed
+}
+
+/// Library audio items in scope for a request, as (id, item data).
+/// Streams (http URLs) are excluded — they have no end to measure.
+fn job_items(shell: &Shell, request: &Value) -> Vec<(String, Value)> {
+    let scroll_paths: Vec<String> = match request["ids"].as_array() {
+        Some(ids) => ids.iter().filter_map(|id| id.as_str()).map(paths::library_path).collect(),
+        None => shell.all(paths::LIBRARY_PREFIX).unwrap_or_default(),
+    };
+    scroll_paths
+        .into_iter()
+        .filter_map(|path| {
+            let scroll = shell.get(&path).ok()??;
+            if scroll.metadata.deleted == Some(true) || scroll.data["media_type"] != "Audio" {
+                return None;
+            }
+            let file = scroll.data["path"].as_str()?;
+            if file.starts_with("http://") || file.starts_with("https://") {
+                return None;
+            }
+            let id = scroll.data["id"].as_str().map(String::from)
+                .or_else(|| path.rsplit('/').next().map(String::from))?;
+            Some((id, scroll.data))
+        })
+        .collect()
+}
+
+/// Pool the stored block histograms of each album in `items` and write
+/// album loudness/range/peak into every member's result. Returns albums updated.
+fn update_albums(shell: &Shell, items: &[(String, Value)]) -> usize {
+    let mut albums: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (id, item) in items {
+        if let Some(album) = item["album"].as_str().filter(|a| !a.is_empty()) {
+            albums.entry(album).or_default().push(id);
+        }
+    }
+
+    let mut updated = 0;
+    for ids in albums.values() {
+        let results: Vec<(&str, Value)> = ids
+            .iter()
+            .filter_map(|id| Some((*id, shell.get(&paths::analysis_path(id)).ok()??.data)))
+            .collect();
+        if results.is_empty() {
+            continue;
+        }
+
+        let mut momentary = BlockHistogram::new();
+        let mut short_term = BlockHistogram::new();
+        let mut peak: f64 = 0.0;
+        for (_, r) in &results {
+            if let Some(h) = BlockHistogram::from_value(&r["blocks"]["momentary"]) {
+                momentary.merge(&h);
+            }
+            if let Some(h) = BlockHistogram::from_value(&r["blocks"]["short_term"]) {
+                short_term.merge(&h);
+            }
+            peak = peak.max(r["true_peak"].as_f64().unwrap_or(0.0));
+        }
+        let integrated = momentary.integrated().map(round2);
+        let range = round2(short_term.loudness_range());
+
+        for (id, mut r) in results {
+            r["album_integrated_lufs"] = integrated.into();
+            r["album_loudness_range_lu"] = range.into();
+            r["album_true_peak"] = peak.into();
+            let _ = shell.put(&paths::analysis_path(id), r);
+        }
+        updated += 1;
+    }
+    updated
+}
+
+/// ReplayGain-shaped gains (`replay_gain` on library items) from a result,
+/// relative to the -18 LUFS reference. None if the track was silent.
+pub fn replay_gain_from_analysis(analysis: &Value) -> Option<Value> {
+    let track = analysis["integrated_lufs"].as_f64()?;
+    let mut rg = serde_json::json!({"track_gain_db": round2(REFERENCE_LUFS - track)});
+    if let Some(peak) = analysis["true_peak"].as_f64() {
+        rg["track_peak"] = peak.into();
+    }
+    if let Some(album) = analysis["album_integrated_lufs"].as_f64() {
+        rg["album_gain_db"] = round2(REFERENCE_LUFS - album).into();
+    }
+    if let Some(peak) = analysis["album_true_peak"].as_f64() {
+        rg["album_peak"] = peak.into();
+    }
+    Some(rg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, amplitude: f64, rate: u32, channels: usize, secs: f64, phase: f64) -> Vec<f32> {
+        let frames = (rate as f64 * secs) as usize;
+        let mut out = Vec::with_capacity(frames * channels);
+        for n in 0..frames {
+            let s = amplitude * (2.0 * PI * freq * n as f64 / rate as f64 + phase).sin();
+            out.extend(std::iter::repeat_n(s as f32, channels));
+        }
+        out
+    }
+
+    #[test]
+    fn stereo_sine_at_minus_23_dbfs_reads_minus_23_lufs() {
+        let mut meter = LoudnessMeter::new(48000, 2);
+        meter.process(&sine(1000.0, 10f64.powf(-23.0 / 20.0), 48000, 2, 5.0, 0.0));
+        let lufs = meter.momentary.integrated().unwrap();
+        assert!((lufs - -23.0).abs() < 0.1, "got {lufs}");
+        // Steady tone has no loudness range
+        assert!(meter.short_term.loudness_range() < 0.2);
+    }
+
+    #[test]
+    fn surround_channels_weighted_and_lfe_ignored() {
+        // The same tone on one channel at a time, for 5.1 and 7.1
+        let solo = |channels: usize, channel: usize| {
+            let tone = sine(1000.0, 10f64.powf(-23.0 / 20.0), 48000, 1, 5.0, 0.0);
+            let mut samples = vec![0.0; tone.len() * channels];
+            for (frame, s) in samples.chunks_exact_mut(channels).zip(&tone) {
+                frame[channel] = *s;
+            }
+            let mut meter = LoudnessMeter::new(48000, channels as u32);
+            meter.process(&samples);
+            meter.momentary.integrated()
+        };
+        for channels in [6, 8] {
+            let front = solo(channels, 0).unwrap();
+            for surround in 4..channels {
+                let lufs = solo(channels, surround).unwrap();
+                assert!((lufs - front - 1.5).abs() < 0.15, "{} ch, channel {}: {} vs {}", channels, surround, lufs, front);
+            }
+            assert_eq!(solo(channels, 3), None);
+        }
+    }
+
+    #[test]
+    fn silence_has_no_integrated_loudness() {
+        let mut meter = LoudnessMeter::new(44100, 2);
+        meter.process(&vec![0.0; 44100 * 2]);
+        assert_eq!(meter.finish()["integrated_lufs"], Value::Null);
+    }
+
+    #[test]
+    fn true_peak_finds_inter_sample_peak() {
+        // fs/4 sine at 45°: every sample is ±0.707 but the waveform peaks at 1.0
+        let mut meter = LoudnessMeter::new(48000, 1);
+        meter.process(&sine(12000.0, 1.0, 48000, 1, 0.1, PI / 4.0));
+        assert!(meter.true_peak.peak > 0.95, "got {}", meter.true_peak.peak);
+    }
+
+    #[test]
+    fn loudness_range_spans_percentiles() {
+        let mut hist = BlockHistogram::new();
+        for _ in 0..100 {
+            hist.add(lufs_to_energy(-20.0));
+            hist.add(lufs_to_energy(-30.0));
+        }
+        assert!((hist.loudness_range() - 10.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn histogram_roundtrips_through_json() {
+        let mut hist = BlockHistogram::new();
+        hist.add(lufs_to_energy(-23.0));
+        hist.add(lufs_to_energy(-14.0));
+        let restored = BlockHistogram::from_value(&hist.to_value()).unwrap();
+        assert_eq!(restored, hist);
+    }
+
+    #[test]
+    fn gains_reference_minus_18_lufs() {
+        let rg = replay_gain_from_analysis(&serde_json::json!({
+            "integrated_lufs": -11.5, "true_peak": 1.02, "album_integrated_lufs": -12.0
+        }))
+        .unwrap();
+        assert_eq!(rg["track_gain_db"], -6.5);
+        assert_eq!(rg["album_gain_db"], -6.0);
+        assert_eq!(rg["track_peak"], 1.02);
+    }
+}
@@ -0,0 +1,151 @@
+//! Equalizer APO parametric presets (`ParametricEQ.txt`, as AutoEQ ships
+//! headphone corrections) to `/amsal/playback/eq` JSON.
+//!
+//! ```text
+//! Preamp: -6.2 dB
+//! Filter 1: ON PK Fc 105 Hz Gain 4.5 dB Q 0.70
+//! Filter 2: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.71
+//! ```
+//!
+//! The preamp becomes a leading `gain` filter; `OFF` filters are dropped.
+//! Anything the chain can't reproduce (other directives, unknown filter
+//! types) is an error naming the line, rather than a silently different EQ.
+
+use serde_json::{json, Value};
+
+/// Q of a Butterworth section, used where the file gives none.
+const DEFAULT_Q: f64 = 0.707;
+
+/// Parse a preset into EQ scroll JSON (`{"filters": [...]}`).
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut preamp_db = 0.0;
+    let mut filters = Vec::new();
+
+    for (n, raw) in text.lines().enumerate() {
+        let line = raw.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fail = |msg: String| format!("line {}: {}", n + 1, msg);
+        let (directive, rest) = line.split_once(':').ok_or_else(|| fail(format!("expected a directive: {}", line)))?;
+        let directive = directive.trim();
+
+        if directive.eq_ignore_ascii_case("preamp") {
+            let tokens: Vec<&str> = rest.split_whitespace().collect();
+            preamp_db += tokens
+                .first()
+                .and_then(|t| t.parse::<f64>().ok())
+                .ok_or_else(|| fail("preamp needs a value in dB".into()))?;
+        } else if directive.split_whitespace().next().is_some_and(|d| d.eq_ignore_ascii_case("filter")) {
+            if let Some(filter) = parse_filter(rest).map_err(fail)? {
+                filters.push(filter);
+            }
+        } else {
+            return Err(fail(format!("unsupported directive \"{}\"", directive)));
+        }
+    }
+
+    if preamp_db != 0.0 {
+        filters.insert(0, json!({"type": "gain", "db": preamp_db}));
+    }
+    Ok(json!({"filters": filters}))
+}
+
+/// One `Filter N:` body (`ON PK Fc 105 Hz Gain 4.5 dB Q 0.70`). None when `OFF`.
+fn parse_filter(body: &str) -> Result<Option<Value>, String> {
+    let tokens: Vec<&str> = body.split_whitespace().collect();
+    match tokens.first().map(|t| t.to_ascii_uppercase()).as_deref() {
+        Some("ON") => {}
+        Some("OFF") => return Ok(None),
+        _ => return Err("filter must start with ON or OFF".into()),
+    }
+    let mut kind = tokens.get(1).ok_or("missing filter type")?.to_ascii_uppercase();
+    let mut params = &tokens[2..];
+    // "LS 6dB" / "HS 12dB": the slope is part of the type
+    if let Some(slope) = params.first().filter(|t| t.eq_ignore_ascii_case("6dB") || t.eq_ignore_ascii_case("12dB")) {
+        kind = format!("{} {}", kind, slope.to_ascii_uppercase());
+        params = &params[1..];
+    }
+
+    let value = |name: &str| -> Result<Option<f64>, String> {
+        let Some(i) = params.iter().position(|t| t.eq_ignore_ascii_case(name)) else {
+            return Ok(None);
+        };
+        params
+            .get(i + 1)
+            .and_then(|t| t.parse::<f64>().ok())
+            .map(Some)
+            .ok_or_else(|| format!("{} needs a number", name))
+    };
+    let fc = value("Fc")?.ok_or("missing Fc")?;
+    let gain = value("Gain")?.unwrap_or(0.0);
+    // "BW Oct N": bandwidth in octaves instead of Q
+    let q = match (value("Q")?, value("Oct")?) {
+        (Some(q), _) => q,
+        (None, Some(bw)) => 2f64.powf(bw / 2.0) / (2f64.powf(bw) - 1.0),
+        (None, None) => DEFAULT_Q,
+    };
+
+    let (name, uses_q, uses_gain) = match kind.as_str() {
+        "PK" | "PEQ" => ("eq", true, true),
+        "LP" | "LPQ" => ("lowpass", true, false),
+        "HP" | "HPQ" => ("highpass", true, false),
+        "BP" => ("bandpass", true, false),
+        "NO" => ("notch", true, false),
+        "AP" => ("allpass", true, false),
+        "LS" | "LSC" | "LS 12DB" => ("lowshelf", true, true),
+        "HS" | "HSC" | "HS 12DB" => ("highshelf", true, true),
+        "LS 6DB" => ("lowshelf1", false, true),
+        "HS 6DB" => ("highshelf1", false, true),
+        other => return Err(format!("unsupported filter type \"{}\"", other)),
+    };
+    let mut filter = json!({"type": name, "freq_hz": fc});
+    if uses_q {
+        filter["q"] = q.into();
+    }
+    if uses_gain {
+        filter["gain_db"] = gain.into();
+    }
+    Ok(Some(filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::dsp;
+
+    #[test]
+    fn parses_autoeq_preset() {
+        let text = "Preamp: -6.2 dB\n\
+                    Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.71\n\
+                    Filter 2: ON PK Fc 2500 Hz Gain -3.1 dB Q 1.41\n\
+                    Filter 3: OFF PK Fc 4000 Hz Gain 2.0 dB Q 2.00\n\
+                    # tame the top\n\
+                    Filter 4: ON HS 6dB Fc 10000 Hz Gain -2.0 dB\n\
+                    Filter 5: ON HP Fc 20 Hz\n";
+        let eq = parse(text).unwrap();
+        assert_eq!(
+            eq["filters"],
+            json!([
+                {"type": "gain", "db": -6.2},
+                {"type": "lowshelf", "freq_hz": 105.0, "q": 0.71, "gain_db": 5.5},
+                {"type": "eq", "freq_hz": 2500.0, "q": 1.41, "gain_db": -3.1},
+                {"type": "highshelf1", "freq_hz": 10000.0, "gain_db": -2.0},
+                {"type": "highpass", "freq_hz": 20.0, "q": DEFAULT_Q},
+            ])
+        );
+        assert_eq!(dsp::parse_filters(&eq).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn bandwidth_and_errors() {
+        // One octave is Q √2
+        let eq = parse("Filter: ON PK Fc 1000 Hz Gain 3 dB BW Oct 1.0").unwrap();
+        assert!((eq["filters"][0]["q"].as_f64().unwrap() - 2f64.sqrt()).abs() < 1e-9);
+
+        assert_eq!(parse("Preamp: -3 dB\nChannel: L").unwrap_err(), "line 2: unsupported directive \"Channel\"");
+        assert_eq!(parse("Filter 1: ON XYZ Fc 100 Hz").unwrap_err(), "line 1: unsupported filter type \"XYZ\"");
+        assert_eq!(parse("Filter 1: ON PK Gain 3 dB").unwrap_err(), "line 1: missing Fc");
+        assert_eq!(parse("").unwrap(), json!({"filters": []}));
+    }
+}
@@ -2,27 +2,50 @@
 //!
 //! Rust-native audio pipeline:
 //! 1. symphonia decodes (MP3, FLAC, AAC, OGG, WAV, ALAC)
-//! 2. samples flow through a ring buffer
+//! 2. samples flow through a wait-free SPSC ring buffer
 //! 3. cpal outputs to hardware (CoreAudio / ALSA / WASAPI)
 //!
-//! Position is tracked via decoded sample count at the known sample rate.
+//! Position comes in two flavours. `decoded_position_ms` counts the frames
+//! the decoder has produced, up to the ring's length ahead. `position_ms` is
+//! what the listener hears: the output callback's read position in the ring,
+//! less the device latency, mapped back to the track.
 //! Seek is implemented by resetting the decoder to the requested timestamp.
+//!
+//! Gapless: when the decoder reaches the end of a track and `prepare_next`
+//! has staged a track with the same format, it opens that track and keeps
+//! feeding the same ring and cpal stream. Encoder delay/padding is trimmed
+//! so the join is sample-accurate. The output callback flags the handover
+//! once it has consumed the last sample of the old track.
+//!
+//! Crossfade: with `set_crossfade` > 0 the same handover starts early — the
+//! staged track is opened as a second decoder once the current track's tail
+//! fits in the fade, and its head is mixed over that tail in the decoder
+//! thread before the samples reach the ring.
+//!
+//! Real-time safety: the cpal callback never locks or allocates. It pulls
+//! from the ring, adapts channels through preallocated scratch, and owns
+//! the DSP chain (`DspHost`); new chains are handed over by the output thread.
 
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use parking_lot::Mutex;
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo};
+use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
-use symphonia::core::units::Time;
+
+use super::convert::{ChannelMatrix, ChannelMix};
+use super::decode::{finish_crossfade, Crossfade, TrackDecoder};
+use super::levels::{LevelMeter, LevelTap, Levels};
+use super::resample::{ResampleQuality, Resampler};
+use super::stretch::{TimeStretch, MAX_SPEED, MIN_SPEED};
+use super::MAX_TRACK_SETTINGS;
 
 /// Thread-safe audio effect handler.
 pub struct AudioEffect {
@@ -34,141 +57,333 @@ struct AudioState {
     paused: AtomicBool,
     /// Volume 0-100 mapped to 0.0-1.0.
     volume: AtomicU32,
-    /// Current position in milliseconds (updated by decoder).
+    /// Playback speed (`f32` bits), applied by the decoder's time stretch.
+    speed: AtomicU32,
+    /// Position the decoder has reached, in ms. Runs ahead of what is
+    /// audible by whatever the ring holds.
     position_ms: AtomicU64,
+    /// Track positions of the blocks pushed to the ring, so the output's
+    /// read position maps back to the track (see `audible_position_ms`).
+    position_marks: Mutex<VecDeque<PositionMark>>,
+    /// Frames between the newest sample pulled and what the device plays,
+    /// measured by the output callback.
+    output_delay_frames: AtomicU64,
     /// Total duration in milliseconds (set when track is probed).
     duration_ms: AtomicU64,
     /// Sample rate of current track.
     sample_rate: AtomicU32,
     /// Channel count of current track.
     channels: AtomicU32,
+    /// Speaker layout of current track (`Channels` bits, 0 = unknown).
+    channel_layout: AtomicU32,
+    /// `ChannelMix::to_bits` of the down/upmix policy.
+    channel_mix: AtomicU32,
     /// Channel count the output device is actually configured for.
     output_channels: AtomicU32,
-    /// Shared sample buffer: decoder writes, cpal reads.
-    samples: Mutex<SampleRing>,
+    /// Sample rate of the open output stream (0 = no stream).
+    output_rate: AtomicU32,
+    /// Shared sample buffer: decoder writes, cpal reads (wait-free SPSC).
+    samples: SampleRing,
     /// Signal decoder to stop current track.
     stop_signal: AtomicBool,
-    /// Seek target in ms (0 = no seek pending).
+    /// Target of the latest seek, in ms.
     seek_to_ms: AtomicU64,
+    /// Bumped by each `seek`. A seek is pending until the decoder has
+    /// carried it out and copied the generation to `seek_handled`.
+    seek_generation: AtomicU64,
+    seek_handled: AtomicU64,
+    /// Length of the ramps around pause/resume/seek/stop, in ms (0 = hard cuts).
+    transport_fade_ms: AtomicU64,
+    /// Hold the output at silence (set while a seek or stop waits for the
+    /// ramp down; the decoder clears it once the seek has flushed the ring).
+    muted: AtomicBool,
+    /// Length of a running `fade_out`, in ms (0 = none).
+    fade_out_ms: AtomicU64,
+    /// Set by the output callback while its ramp holds the output silent.
+    silent: AtomicBool,
     /// Track finished naturally (end of stream).
     finished: AtomicBool,
     /// Set when decoder or output thread exits with an error.
     error: AtomicBool,
     /// Pre-probed format for next track: (sample_rate, channels, file_path).
     next_probe: Mutex<Option<(u32, u32, String)>>,
+    /// Ring sample count at which the gapless next track starts
+    /// (`NO_BOUNDARY` = no handover pending).
+    track_boundary: AtomicU64,
+    /// Set by the output callback once playback crosses `track_boundary`.
+    track_advanced: AtomicBool,
+    /// Path of the staged track the decoder took, until `take_track_advance`
+    /// reports it. `prepare_next` is ignored meanwhile: the engine still sees
+    /// the outgoing track and would stage the track already decoding again.
+    advanced_path: Mutex<Option<String>>,
+    /// Crossfade length (ms, 0 = gapless) and curve for the next handover.
+    crossfade: Mutex<(u64, super::dsp::FadeCurve)>,
+    /// Normalization gain per file path (current + staged tracks).
+    replay_gain: Mutex<HashMap<String, f32>>,
+    /// Trim points (start_ms, end_ms) per file path, applied on open.
+    track_ranges: Mutex<HashMap<String, (u64, Option<u64>)>>,
+    /// Speed per file path, switched to when a handover moves into it.
+    track_speeds: Mutex<HashMap<String, f32>>,
+    /// A-B loop of the playing track in ms (None = no loop).
+    loop_region: Mutex<Option<(u64, u64)>>,
+    /// Resampler quality; a change swaps the resampler's kernel mid-stream.
+    resample_quality: Mutex<ResampleQuality>,
     /// Handles for decoder + output threads (joined on stop).
     threads: Mutex<Vec<thread::JoinHandle<()>>>,
-    /// DSP filter chain — applied in cpal callback after volume.
-    dsp_chain: parking_lot::RwLock<Option<super::dsp::DspChain>>,
+    /// DSP chain waiting to be handed to the output callback, which applies
+    /// it after volume. Parked here between streams (see `DspHost`).
+    dsp_chain: Mutex<Option<super::dsp::DspChain>>,
+    /// EQ scroll the chain is built from; rebuilt at each stream's format
+    /// (None = a fixed chain from `set_dsp`, or no DSP).
+    eq_spec: Mutex<Option<serde_json::Value>>,
+    /// Output callbacks that ran dry while the decoder was still running.
+    underruns: AtomicU64,
+    /// Levels and spectrum metered by the output callback.
+    levels: LevelTap,
+    /// Preferred output device name (None = host default).
+    output_device: Mutex<Option<String>>,
+    /// Name of the device the current stream plays on.
+    active_device: Mutex<Option<String>>,
+    /// TPDF dither for integer output formats.
+    dither: AtomicBool,
+    /// Set when the preferred device changes or the current one is lost;
+    /// the output thread reopens its stream.
+    reopen_output: AtomicBool,
 }
 
-/// Simple ring buffer for f32 samples.
-struct SampleRing {
-    buf: Vec<f32>,
-    read_pos: usize,
-    write_pos: usize,
-    len: usize,
-}
+/// Longest `seek` waits for the decoder. Slow (HTTP) seeks land later and
+/// show up in `position_ms` then.
+const SEEK_WAIT: std::time::Duration = std::time::Duration::from_secs(1);
 
-impl SampleRing {
-    fn new(capacity: usize) -> Self {
-        Self {
-            buf: vec![0.0; capacity],
-            read_pos: 0,
-            write_pos: 0,
-            len: 0,
+/// `track_boundary` value meaning "no gapless handover pending".
+const NO_BOUNDARY: u64 = u64::MAX;
+
+impl AudioState {
+    /// Refresh a track's normalization gain if one was set for its path.
+    fn refresh_gain(&self, track: &mut TrackDecoder) {
+        if let Some(&gain) = self.replay_gain.lock().get(&track.path) {
+            track.gain = gain;
         }
     }
 
-    fn push(&mut self, samples: &[f32]) {
-        for &s in samples {
-            if self.len < self.buf.len() {
-                self.buf[self.write_pos] = s;
-                self.write_pos = (self.write_pos + 1) % self.buf.len();
-                self.len += 1;
-            }
+    fn seek_pending(&self) -> bool {
+        self.seek_generation.load(Ordering::SeqCst) != self.seek_handled.load(Ordering::SeqCst)
+    }
+
+    /// The pending seek's generation and target; pass the generation to
+    /// `seek_done` once the seek has landed.
+    fn pending_seek(&self) -> Option<(u64, u64)> {
+        let generation = self.seek_generation.load(Ordering::SeqCst);
+        (generation != self.seek_handled.load(Ordering::SeqCst))
+            .then(|| (generation, self.seek_to_ms.load(Ordering::SeqCst)))
+    }
+
+    fn seek_done(&self, generation: u64) {
+        self.seek_handled.store(generation, Ordering::SeqCst);
+    }
+
+    /// Ring sample the listener hears now: the output's read position less
+    /// the device latency.
+    fn heard_sample(&self) -> u64 {
+        let ch = self.channels.load(Ordering::SeqCst).max(1) as u64;
+        self.samples.pulled().saturating_sub(self.output_delay_frames.load(Ordering::SeqCst) * ch)
+    }
+
+    /// Track position at `heard_sample`. Until a block of the current
+    /// track is buffered (just after play or a seek) it is where the
+    /// decoder is.
+    fn audible_position_ms(&self) -> u64 {
+        let heard = self.heard_sample();
+        let mut marks = self.position_marks.lock();
+        drop_heard_marks(&mut marks, heard);
+        match marks.front() {
+            Some(mark) => mark.position_at(heard),
+            None => self.position_ms.load(Ordering::SeqCst),
         }
     }
 
-    fn pull(&mut self, out: &mut [f32]) -> usize {
-        let n = out.len().min(self.len);
-        for sample in out.iter_mut().take(n) {
-            *sample = self.buf[self.read_pos];
-            self.read_pos = (self.read_pos + 1) % self.buf.len();
-            self.len -= 1;
+    fn mark_position(&self, mark: PositionMark) {
+        let heard = self.heard_sample();
+        let mut marks = self.position_marks.lock();
+        drop_heard_marks(&mut marks, heard);
+        marks.push_back(mark);
+    }
+
+    /// Apply the trim points set for a freshly opened track's path.
+    fn apply_range(&self, track: &mut TrackDecoder) {
+        if let Some(&(start_ms, end_ms)) = self.track_ranges.lock().get(&track.path) {
+            track.set_range(start_ms, end_ms);
         }
-        for sample in out.iter_mut().skip(n) {
-            *sample = 0.0;
+    }
+
+    /// Switch to the speed set for `path`, which a handover is moving
+    /// into, and return the speed now in effect.
+    fn handover_speed(&self, path: &str) -> f32 {
+        if let Some(&speed) = self.track_speeds.lock().get(path) {
+            self.speed.store(speed.to_bits(), Ordering::SeqCst);
         }
-        n
+        f32::from_bits(self.speed.load(Ordering::SeqCst))
     }
+}
 
-    fn clear(&mut self) {
-        self.read_pos = 0;
-        self.write_pos = 0;
-        self.len = 0;
+/// Ring samples `start..end` hold the track from `start_ms` to `end_ms`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PositionMark {
+    start: u64,
+    end: u64,
+    start_ms: u64,
+    end_ms: u64,
+}
+
+impl PositionMark {
+    /// Interpolated track position at ring sample `sample`, clamped to the block.
+    fn position_at(&self, sample: u64) -> u64 {
+        let into = sample.clamp(self.start, self.end) - self.start;
+        let span = (self.end - self.start).max(1);
+        self.start_ms + self.end_ms.saturating_sub(self.start_ms) * into / span
     }
 }
 
-/// Linear interpolation resampler — zero deps, sufficient for playback.
-struct LinearResampler {
-    ratio: f64,
-    phase: f64,
-    channels: usize,
+/// Drop marks that have been played out entirely, keeping the last one
+/// (it holds the position once the ring has run dry).
+fn drop_heard_marks(marks: &mut VecDeque<PositionMark>, heard: u64) {
+    while marks.len() > 1 && marks.front().is_some_and(|m| m.end <= heard) {
+        marks.pop_front();
+    }
 }
 
-impl LinearResampler {
-    fn new(src_rate: u32, dst_rate: u32, channels: u16) -> Self {
+/// Wait-free single-producer/single-consumer ring of f32 samples.
+///
+/// The decoder thread pushes and the output callback pulls; neither side
+/// locks or allocates. Samples are stored as `AtomicU32` bit patterns so the
+/// buffer can be shared without `unsafe`. `pushed`/`pulled` count samples
+/// over the ring's lifetime so gapless track boundaries can be expressed as
+/// absolute sample positions.
+struct SampleRing {
+    buf: Box<[AtomicU32]>,
+    pushed: AtomicU64,
+    pulled: AtomicU64,
+    /// Bumped by `clear()` so the consumer can tell a flush from an underrun.
+    clears: AtomicU64,
+}
+
+impl SampleRing {
+    fn new(capacity: usize) -> Self {
         Self {
-            ratio: dst_rate as f64 / src_rate as f64,
-            phase: 0.0,
-            channels: channels as usize,
+            buf: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            pushed: AtomicU64::new(0),
+            pulled: AtomicU64::new(0),
+            clears: AtomicU64::new(0),
         }
     }
 
-    fn is_needed(&self) -> bool {
-        (self.ratio - 1.0).abs() > 0.001
+    /// Samples buffered and not yet pulled.
+    fn len(&self) -> usize {
+        // `pulled` first: `pushed` only grows, so the difference can't underflow
+        let pulled = self.pulled.load(Ordering::Acquire);
+        (self.pushed.load(Ordering::Acquire) - pulled) as usize
+    }
+
+    fn pushed(&self) -> u64 {
+        self.pushed.load(Ordering::Acquire)
+    }
+
+    fn pulled(&self) -> u64 {
+        self.pulled.load(Ordering::Acquire)
+    }
+
+    fn clears(&self) -> u64 {
+        self.clears.load(Ordering::Acquire)
+    }
+
+    /// Producer side. Appends as many samples as fit and returns that count.
+    fn push(&self, samples: &[f32]) -> usize {
+        let cap = self.buf.len() as u64;
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        let free = cap - (pushed - self.pulled.load(Ordering::Acquire));
+        let n = samples.len().min(free as usize);
+        for (i, &s) in samples[..n].iter().enumerate() {
+            self.buf[((pushed + i as u64) % cap) as usize].store(s.to_bits(), Ordering::Relaxed);
+        }
+        self.pushed.store(pushed + n as u64, Ordering::Release);
+        n
     }
 
-    /// Resample interleaved samples. Returns resampled output.
-    fn process(&mut self, input: &[f32]) -> Vec<f32> {
-        if !self.is_needed() {
-            return input.to_vec();
+    /// Consumer side. Fills `out`, zero-padding past the buffered samples,
+    /// and returns how many real samples were delivered.
+    fn pull(&self, out: &mut [f32]) -> usize {
+        let cap = self.buf.len() as u64;
+        let pulled = self.pulled.load(Ordering::Acquire);
+        let available = self.pushed.load(Ordering::Acquire) - pulled;
+        let n = out.len().min(available as usize);
+        for (i, sample) in out[..n].iter_mut().enumerate() {
+            *sample = f32::from_bits(self.buf[((pulled + i as u64) % cap) as usize].load(Ordering::Relaxed));
         }
-        let ch = self.channels;
-        let in_frames = input.len() / ch;
-        if in_frames == 0 {
-            return Vec::new();
+        out[n..].fill(0.0);
+        // A concurrent clear() dropped what was just read — it may be stale
+        if self
+            .pulled
+            .compare_exchange(pulled, pulled + n as u64, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            out[..n].fill(0.0);
+            return 0;
         }
-        let out_frames = ((in_frames as f64) * self.ratio).ceil() as usize;
-        let mut output = Vec::with_capacity(out_frames * ch);
+        n
+    }
 
-        for _ in 0..out_frames {
-            let src_idx = self.phase as usize;
-            if src_idx >= in_frames {
-                break;
-            }
-            let frac = (self.phase - src_idx as f64) as f32;
+    /// Drop buffered samples. They count as consumed, so a pending
+    /// boundary inside the dropped region is considered crossed.
+    /// Safe to call from any thread.
+    fn clear(&self) {
+        self.pulled.fetch_max(self.pushed.load(Ordering::Acquire), Ordering::AcqRel);
+        self.clears.fetch_add(1, Ordering::AcqRel);
+    }
+}
 
-            for c in 0..ch {
-                let s0 = input[src_idx * ch + c];
-                let s1 = if src_idx + 1 < in_frames {
-                    input[(src_idx + 1) * ch + c]
-                } else {
-                    s0
-                };
-                output.push(s0 + (s1 - s0) * frac);
-            }
+/// Callback-side owner of the DSP chain.
+///
+/// New chains arrive over a bounded channel fed by the output thread and
+/// are crossfaded in (`DspSwitch`); replaced ones are sent back for that
+/// thread to drop, so the callback never locks or frees. While a handover
+/// runs the next chain waits in the channel. When the stream is torn down
+/// the active chain is parked in `AudioState::dsp_chain` again for the
+/// next stream.
+struct DspHost {
+    switch: super::dsp::DspSwitch,
+    incoming: mpsc::Receiver<super::dsp::DspChain>,
+    retired: mpsc::SyncSender<super::dsp::DspChain>,
+    state: Arc<AudioState>,
+}
 
-            self.phase += 1.0 / self.ratio;
+impl DspHost {
+    fn process(&mut self, samples: &mut [f32], channels: u16, sample_rate: u32) {
+        if self.switch.ready() {
+            if let Ok(chain) = self.incoming.try_recv() {
+                self.switch.switch(chain, sample_rate);
+            }
         }
-
-        self.phase -= in_frames as f64;
-        if self.phase < 0.0 {
-            self.phase = 0.0;
+        self.switch.process(samples, channels, sample_rate);
+        if let Some(old) = self.switch.take_retired() {
+            // Sized so this can't fill up; see output_from_ring
+            let _ = self.retired.try_send(old);
         }
+    }
+}
 
-        output
+impl Drop for DspHost {
+    fn drop(&mut self) {
+        let mut newest = self.switch.take_active();
+        // Forwarded but never picked up by the callback — still the newest
+        while let Ok(chain) = self.incoming.try_recv() {
+            newest = Some(chain);
+        }
+        if let Some(chain) = newest {
+            let mut parked = self.state.dsp_chain.lock();
+            if parked.is_none() {
+                *parked = Some(chain);
+            }
+        }
     }
 }
 
@@ -179,19 +394,47 @@ impl AudioEffect {
                 playing: AtomicBool::new(false),
                 paused: AtomicBool::new(false),
                 volume: AtomicU32::new(80),
+                speed: AtomicU32::new(1.0f32.to_bits()),
                 position_ms: AtomicU64::new(0),
+                position_marks: Mutex::new(VecDeque::new()),
+                output_delay_frames: AtomicU64::new(0),
                 duration_ms: AtomicU64::new(0),
                 sample_rate: AtomicU32::new(44100),
                 channels: AtomicU32::new(2),
+                channel_layout: AtomicU32::new(0),
                 output_channels: AtomicU32::new(2),
-                samples: Mutex::new(SampleRing::new(48000 * 2 * 4)), // ~4s stereo
+                output_rate: AtomicU32::new(0),
+                samples: SampleRing::new(48000 * 2 * 4), // ~4s stereo
                 stop_signal: AtomicBool::new(false),
                 seek_to_ms: AtomicU64::new(0),
+                seek_generation: AtomicU64::new(0),
+                seek_handled: AtomicU64::new(0),
+                transport_fade_ms: AtomicU64::new(super::dsp::TRANSPORT_FADE_MS),
+                muted: AtomicBool::new(false),
+                fade_out_ms: AtomicU64::new(0),
+                silent: AtomicBool::new(false),
                 finished: AtomicBool::new(false),
                 error: AtomicBool::new(false),
                 next_probe: Mutex::new(None),
+                track_boundary: AtomicU64::new(NO_BOUNDARY),
+                track_advanced: AtomicBool::new(false),
+                advanced_path: Mutex::new(None),
+                crossfade: Mutex::new((0, super::dsp::FadeCurve::default())),
+                replay_gain: Mutex::new(HashMap::new()),
+                track_ranges: Mutex::new(HashMap::new()),
+                track_speeds: Mutex::new(HashMap::new()),
+                loop_region: Mutex::new(None),
+                resample_quality: Mutex::new(ResampleQuality::default()),
+                channel_mix: AtomicU32::new(ChannelMix::default().to_bits()),
                 threads: Mutex::new(Vec::new()),
-                dsp_chain: parking_lot::RwLock::new(None),
+                dsp_chain: Mutex::new(None),
+                eq_spec: Mutex::new(None),
+                underruns: AtomicU64::new(0),
+                levels: LevelTap::new(),
+                output_device: Mutex::new(None),
+                active_device: Mutex::new(None),
+                dither: AtomicBool::new(true),
+                reopen_output: AtomicBool::new(false),
             }),
         }
     }
@@ -211,8 +454,15 @@ impl AudioEffect {
         self.state.error.store(false, Ordering::SeqCst);
         self.state.position_ms.store(0, Ordering::SeqCst);
         self.state.duration_ms.store(0, Ordering::SeqCst);
-        self.state.seek_to_ms.store(0, Ordering::SeqCst);
-        self.state.samples.lock().clear();
+        self.state.seek_handled.store(self.state.seek_generation.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.state.muted.store(false, Ordering::SeqCst);
+        self.state.fade_out_ms.store(0, Ordering::SeqCst);
+        *self.state.loop_region.lock() = None;
+        self.state.track_boundary.store(NO_BOUNDARY, Ordering::SeqCst);
+        self.state.track_advanced.store(false, Ordering::SeqCst);
+        *self.state.advanced_path.lock() = None;
+        self.state.samples.clear();
+        self.state.position_marks.lock().clear();
 
         // Use pre-probed format if available, otherwise probe synchronously
         let probe_result = {
@@ -226,6 +476,7 @@ impl AudioEffect {
         if let Some((rate, ch)) = probe_result {
             self.state.sample_rate.store(rate, Ordering::SeqCst);
             self.state.channels.store(ch, Ordering::SeqCst);
+            self.state.channel_layout.store(0, Ordering::SeqCst);
         }
 
         let path = file_path.to_string();
@@ -237,7 +488,10 @@ impl AudioEffect {
                 log::error!("amsal: decode error: {}", e);
                 decoder_state.error.store(true, Ordering::SeqCst);
             }
-            decoder_state.finished.store(true, Ordering::SeqCst);
+            // A stopped or faded-out track didn't end naturally
+            if !decoder_state.stop_signal.load(Ordering::SeqCst) {
+                decoder_state.finished.store(true, Ordering::SeqCst);
+            }
         }));
 
         let output_state = Arc::clone(&self.state);
@@ -258,22 +512,74 @@ impl AudioEffect {
         self.state.paused.store(false, Ordering::SeqCst);
     }
 
+    /// Stop playback, ramping the output down first when it is audible.
     pub fn stop(&self) {
+        self.ramp_to_silence();
         self.state.stop_signal.store(true, Ordering::SeqCst);
         self.state.playing.store(false, Ordering::SeqCst);
         self.state.paused.store(false, Ordering::SeqCst);
-        self.state.samples.lock().clear(); // Clear first so output thread exits fast
+        self.state.samples.clear(); // Clear first so output thread exits fast
 
         // Drain handles then join outside the lock
         let handles: Vec<_> = self.state.threads.lock().drain(..).collect();
         for handle in handles {
             let _ = handle.join();
         }
+        self.state.muted.store(false, Ordering::SeqCst);
     }
 
-    /// Seek to a position in milliseconds.
-    pub fn seek(&self, position_ms: u64) {
+    /// Seek to a position in milliseconds and return the position landed
+    /// on. The output ramps down before the buffered audio is flushed and
+    /// back up from the new position. Waits (bounded) for the decoder to
+    /// carry the seek out; a track that already ended stays where it is.
+    pub fn seek(&self, position_ms: u64) -> u64 {
+        if !self.is_playing() || self.is_finished() {
+            return self.position_ms();
+        }
+        // The decoder unmutes once it has flushed
+        self.ramp_to_silence();
         self.state.seek_to_ms.store(position_ms, Ordering::SeqCst);
+        self.state.seek_generation.fetch_add(1, Ordering::SeqCst);
+        let start = std::time::Instant::now();
+        while self.state.seek_pending() && !self.is_finished() && start.elapsed() < SEEK_WAIT {
+            thread::sleep(std::time::Duration::from_millis(2));
+        }
+        self.position_ms()
+    }
+
+    /// Fade to silence over `duration_ms`, then stop. The track doesn't
+    /// count as finished. Paused playback stops at once.
+    pub fn fade_out(&self, duration_ms: u64) {
+        if !self.is_playing() {
+            return;
+        }
+        if self.is_paused() {
+            self.stop();
+            return;
+        }
+        self.state.silent.store(false, Ordering::SeqCst);
+        self.state.fade_out_ms.store(duration_ms.max(1), Ordering::SeqCst);
+    }
+
+    /// Length of the ramps around pause, resume, seek, stop and track skips.
+    pub fn set_transport_fade(&self, duration_ms: u64) {
+        self.state.transport_fade_ms.store(duration_ms, Ordering::SeqCst);
+    }
+
+    /// Mute the output and wait (bounded) for the callback to ramp down.
+    /// Returns at once when nothing is audible.
+    fn ramp_to_silence(&self) {
+        let state = &self.state;
+        if !self.is_playing() || self.is_paused() || state.output_rate.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        state.silent.store(false, Ordering::SeqCst);
+        state.muted.store(true, Ordering::SeqCst);
+        let timeout = std::time::Duration::from_millis(state.transport_fade_ms.load(Ordering::SeqCst) + 100);
+        let start = std::time::Instant::now();
+        while !state.silent.load(Ordering::SeqCst) && start.elapsed() < timeout {
+            thread::sleep(std::time::Duration::from_millis(2));
+        }
     }
 
     pub fn set_volume(&self, volume: f32) {
@@ -281,6 +587,13 @@ impl AudioEffect {
         self.state.volume.store(v, Ordering::SeqCst);
     }
 
+    /// Playback speed, pitch preserved (clamped to 0.5-3.0). Applies from
+    /// the next decoded packet; audio already buffered keeps its speed.
+    pub fn set_speed(&self, speed: f32) {
+        let speed = if speed.is_finite() { speed.clamp(MIN_SPEED, MAX_SPEED) } else { 1.0 };
+        self.state.speed.store(speed.to_bits(), Ordering::SeqCst);
+    }
+
     pub fn is_playing(&self) -> bool {
         self.state.playing.load(Ordering::SeqCst)
     }
@@ -299,14 +612,103 @@ impl AudioEffect {
         self.state.error.load(Ordering::SeqCst)
     }
 
-    /// Pre-probe the next track's format for faster gapless transitions.
+    /// Stage the next track. If its format matches the current one, the
+    /// decoder continues into it without stopping (gapless); otherwise
+    /// `play()` uses the cached probe for a faster restart.
     pub fn prepare_next(&self, file_path: &str) {
+        {
+            let staged = self.state.next_probe.lock();
+            if staged.as_ref().is_some_and(|(_, _, p)| p == file_path) {
+                return; // Already staged — the heartbeat calls this every tick
+            }
+            // Checked under the probe lock, which the decoder holds while taking it
+            if self.state.advanced_path.lock().is_some() {
+                return;
+            }
+        }
         if let Some((rate, ch)) = probe_audio_format(file_path) {
             *self.state.next_probe.lock() = Some((rate, ch, file_path.to_string()));
         }
     }
 
+    /// Returns the path of the staged track once playback has crossed into
+    /// it gaplessly. Clears the flag, so each handover is reported once.
+    pub fn take_track_advance(&self) -> Option<String> {
+        if self.state.track_advanced.swap(false, Ordering::SeqCst) {
+            self.state.advanced_path.lock().take()
+        } else {
+            None
+        }
+    }
+
+    /// Overlap the next staged track with this one's last `duration_ms`.
+    /// Applies to handovers that start after the call; 0 keeps them gapless.
+    pub fn set_crossfade(&self, duration_ms: u64, curve: super::dsp::FadeCurve) {
+        *self.state.crossfade.lock() = (duration_ms, curve);
+    }
+
+    /// Set the normalization gain for `file_path`. Takes effect on the
+    /// track's next decoded packet (already-buffered audio keeps its gain).
+    pub fn set_replay_gain(&self, file_path: &str, gain: f32) {
+        let mut gains = self.state.replay_gain.lock();
+        if gains.len() >= MAX_TRACK_SETTINGS && !gains.contains_key(file_path) {
+            gains.clear();
+        }
+        gains.insert(file_path.to_string(), gain);
+    }
+
+    /// Speed for `file_path` (clamped like `set_speed`), switched to when
+    /// the decoder hands over into it.
+    pub fn set_track_speed(&self, file_path: &str, speed: f32) {
+        let speed = if speed.is_finite() { speed.clamp(MIN_SPEED, MAX_SPEED) } else { 1.0 };
+        let mut speeds = self.state.track_speeds.lock();
+        if speeds.len() >= MAX_TRACK_SETTINGS && !speeds.contains_key(file_path) {
+            speeds.clear();
+        }
+        speeds.insert(file_path.to_string(), speed);
+    }
+
+    /// Trim points for `file_path`, applied whenever it is opened.
+    pub fn set_track_range(&self, file_path: &str, start_ms: u64, end_ms: Option<u64>) {
+        let mut ranges = self.state.track_ranges.lock();
+        if ranges.len() >= MAX_TRACK_SETTINGS && !ranges.contains_key(file_path) {
+            ranges.clear();
+        }
+        ranges.insert(file_path.to_string(), (start_ms, end_ms));
+    }
+
+    /// Loop the playing track between `start_ms` and `end_ms`. The decoder
+    /// jumps back once it decodes past the end (at once when already past
+    /// it); audio already buffered plays out first.
+    pub fn set_loop(&self, region: Option<(u64, u64)>) {
+        *self.state.loop_region.lock() = region;
+    }
+
+    pub fn loop_region(&self) -> Option<(u64, u64)> {
+        *self.state.loop_region.lock()
+    }
+
+    /// Select the resampler used when the track rate differs from the
+    /// device rate. Applies from the next decoded packet.
+    pub fn set_resample_quality(&self, quality: ResampleQuality) {
+        *self.state.resample_quality.lock() = quality;
+    }
+
+    /// Down/upmix policy for tracks whose channels differ from the device's.
+    /// The output callback picks it up on its next buffer.
+    pub fn set_channel_mix(&self, mix: ChannelMix) {
+        self.state.channel_mix.store(mix.to_bits(), Ordering::SeqCst);
+    }
+
+    /// Position the listener hears: the output's read position in the
+    /// ring, less the device latency, mapped back to the track.
     pub fn position_ms(&self) -> u64 {
+        self.state.audible_position_ms()
+    }
+
+    /// Position the decoder has reached, up to the ring's length (~4s)
+    /// ahead of `position_ms`.
+    pub fn decoded_position_ms(&self) -> u64 {
         self.state.position_ms.load(Ordering::SeqCst)
     }
 
@@ -314,8 +716,62 @@ impl AudioEffect {
         self.state.duration_ms.load(Ordering::SeqCst)
     }
 
+    /// Output callbacks that ran out of audio mid-playback, since creation.
+    pub fn underruns(&self) -> u64 {
+        self.state.underruns.load(Ordering::SeqCst)
+    }
+
+    /// Latest output levels, metered after the DSP chain and transport ramps.
+    pub fn levels(&self) -> Option<Levels> {
+        self.state.levels.snapshot()
+    }
+
+    /// Prefer the output device with this name (None = host default).
+    /// A running stream moves over; a missing device falls back to default.
+    pub fn set_output_device(&self, name: Option<&str>) {
+        let mut preferred = self.state.output_device.lock();
+        if preferred.as_deref() != name {
+            *preferred = name.map(String::from);
+            self.state.reopen_output.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Dither integer output (16-bit devices especially). Takes effect immediately.
+    pub fn set_dither(&self, enabled: bool) {
+        self.state.dither.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Hosts and their output devices with supported configs, plus the
+    /// preferred and currently active device names.
+    pub fn output_devices(&self) -> serde_json::Value {
+        serde_json::json!({
+            "hosts": list_output_devices(),
+            "preferred": *self.state.output_device.lock(),
+            "active": *self.state.active_device.lock(),
+        })
+    }
+
+    /// (sample_rate, channels) the output stream is open at; None when idle.
+    pub fn output_format(&self) -> Option<(u32, u16)> {
+        let rate = self.state.output_rate.load(Ordering::SeqCst);
+        (rate > 0).then(|| (rate, self.state.output_channels.load(Ordering::SeqCst) as u16))
+    }
+
+    /// Replace the DSP chain. The output thread hands it to the callback
+    /// within one keep-alive tick; the old chain is dropped off the audio thread.
+    /// The chain is kept as built, whatever format later streams open at.
     pub fn set_dsp(&self, chain: super::dsp::DspChain) {
-        *self.state.dsp_chain.write() = Some(chain);
+        *self.state.eq_spec.lock() = None;
+        *self.state.dsp_chain.lock() = Some(chain);
+    }
+
+    /// Set the EQ from its scroll JSON. The chain is built at the running
+    /// stream's format now, and again whenever a stream opens.
+    pub fn set_eq(&self, spec: &serde_json::Value) {
+        *self.state.eq_spec.lock() = Some(spec.clone());
+        if let Some((rate, channels)) = self.output_format() {
+            *self.state.dsp_chain.lock() = Some(super::dsp::chain_from_value(spec, rate, channels));
+        }
     }
 }
 
@@ -330,251 +786,406 @@ impl super::AudioBackend for AudioEffect {
     fn pause(&self) { self.pause() }
     fn resume(&self) { self.resume() }
     fn stop(&self) { self.stop() }
-    fn seek(&self, position_ms: u64) { self.seek(position_ms) }
+    fn seek(&self, position_ms: u64) -> u64 { self.seek(position_ms) }
+    fn fade_out(&self, duration_ms: u64) { self.fade_out(duration_ms) }
+    fn set_transport_fade(&self, duration_ms: u64) { self.set_transport_fade(duration_ms) }
     fn set_volume(&self, volume: f32) { self.set_volume(volume) }
+    fn set_speed(&self, speed: f32) { self.set_speed(speed) }
+    fn set_track_speed(&self, file_path: &str, speed: f32) { self.set_track_speed(file_path, speed) }
     fn is_playing(&self) -> bool { self.is_playing() }
     fn is_paused(&self) -> bool { self.is_paused() }
     fn is_finished(&self) -> bool { self.is_finished() }
     fn is_error(&self) -> bool { self.is_error() }
     fn prepare_next(&self, file_path: &str) { self.prepare_next(file_path) }
+    fn take_track_advance(&self) -> Option<String> { self.take_track_advance() }
+    fn set_crossfade(&self, duration_ms: u64, curve: super::dsp::FadeCurve) { self.set_crossfade(duration_ms, curve) }
+    fn set_replay_gain(&self, file_path: &str, gain: f32) { self.set_replay_gain(file_path, gain) }
+    fn set_track_range(&self, file_path: &str, start_ms: u64, end_ms: Option<u64>) { self.set_track_range(file_path, start_ms, end_ms) }
+    fn set_loop(&self, region: Option<(u64, u64)>) { self.set_loop(region) }
+    fn loop_region(&self) -> Option<(u64, u64)> { self.loop_region() }
+    fn set_resample_quality(&self, quality: ResampleQuality) { self.set_resample_quality(quality) }
+    fn set_channel_mix(&self, mix: ChannelMix) { self.set_channel_mix(mix) }
     fn position_ms(&self) -> u64 { self.position_ms() }
+    fn decoded_position_ms(&self) -> u64 { self.decoded_position_ms() }
     fn duration_ms(&self) -> u64 { self.duration_ms() }
+    fn underruns(&self) -> u64 { self.underruns() }
+    fn levels(&self) -> Option<Levels> { self.levels() }
+    fn set_output_device(&self, name: Option<&str>) { self.set_output_device(name) }
+    fn set_dither(&self, enabled: bool) { self.set_dither(enabled) }
+    fn output_devices(&self) -> serde_json::Value { self.output_devices() }
+    fn output_format(&self) -> Option<(u32, u16)> { self.output_format() }
     fn set_dsp(&self, chain: super::dsp::DspChain) { self.set_dsp(chain) }
+    fn set_eq(&self, spec: &serde_json::Value) { self.set_eq(spec) }
 }
 
 /// Decode a file (or HTTP URL) using symphonia and push samples to the ring buffer.
+///
+/// At end of stream, continues into the track staged by `prepare_next` when
+/// its sample rate and channel count match (see `take_gapless_next`).
 fn decode_to_ring(file_path: &str, state: &AudioState) -> Result<(), Box<dyn std::error::Error>> {
-    let mut hint = Hint::new();
-
-    let mss = if file_path.starts_with("http://") || file_path.starts_with("https://") {
-        #[cfg(feature = "http")]
-        {
-            if let Some(ext) = super::http::extension_from_url(file_path) {
-                hint.with_extension(&ext);
-            }
-            super::http::open_url(file_path)?
-        }
-        #[cfg(not(feature = "http"))]
-        {
-            return Err("HTTP support requires the 'http' feature".into());
-        }
-    } else {
-        let path = Path::new(file_path);
-        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-            hint.with_extension(ext);
-        }
-        let file = File::open(path)?;
-        MediaSourceStream::new(Box::new(file), Default::default())
-    };
-
-    let probed = symphonia::default::get_probe().format(
-        &hint,
-        mss,
-        &FormatOptions::default(),
-        &MetadataOptions::default(),
-    )?;
-
-    let mut format = probed.format;
-    let track = format.default_track().ok_or("no default track")?;
-    let track_id = track.id;
-
-    // Extract sample rate and duration from codec params
-    let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
-    let channels = track.codec_params.channels.map(|c| c.count() as u32).unwrap_or(2);
-    state.sample_rate.store(sample_rate, Ordering::SeqCst);
-    state.channels.store(channels, Ordering::SeqCst);
-
-    // Compute duration from n_frames if available
-    if let Some(n_frames) = track.codec_params.n_frames {
-        let duration_ms = (n_frames * 1000) / sample_rate as u64;
-        state.duration_ms.store(duration_ms, Ordering::SeqCst);
-    }
-
-    let mut decoder = symphonia::default::get_codecs().make(
-        &track.codec_params,
-        &DecoderOptions::default(),
-    )?;
+    let mut track = TrackDecoder::open(file_path)?;
+    state.apply_range(&mut track);
+    state.sample_rate.store(track.sample_rate, Ordering::SeqCst);
+    state.channels.store(track.channels, Ordering::SeqCst);
+    state.channel_layout.store(track.layout, Ordering::SeqCst);
+    state.duration_ms.store(track.duration_ms, Ordering::SeqCst);
 
     // Determine device rate for potential resampling
-    let device_rate = probe_device_rate(sample_rate);
-    let mut resampler = if device_rate != sample_rate {
-        log::info!("amsal: resampling {}Hz -> {}Hz", sample_rate, device_rate);
-        Some(LinearResampler::new(sample_rate, device_rate, channels as u16))
+    let preferred = state.output_device.lock().clone();
+    let device_rate = probe_device_rate(preferred.as_deref(), track.sample_rate, track.channels.max(1) as u16);
+    let mut quality = *state.resample_quality.lock();
+    let mut resampler = if device_rate != track.sample_rate {
+        log::info!("amsal: resampling {}Hz -> {}Hz ({:?})", track.sample_rate, device_rate, quality);
+        Some(Resampler::new(track.sample_rate, device_rate, track.channels as u16, quality))
     } else {
         None
     };
-
-    let mut decoded_frames: u64 = 0;
+    let mut resampled: Vec<f32> = Vec::new();
+    // Gapless neighbours share rate and channels, so one stretcher serves them all
+    let mut stretch = TimeStretch::new(track.channels as u16, track.sample_rate);
+    let mut stretched: Vec<f32> = Vec::new();
+    // Stream ended; one more pass plays out what the stretcher holds
+    let mut ending = false;
+
+    let mut decoded_frames: u64 = track.start_frame;
+    // Outgoing track kept until its tail is audible, so a seek can return to it.
+    let mut previous: Option<(TrackDecoder, u64, String)> = None;
+    // Incoming track while its head overlaps the current track's tail.
+    let mut fade: Option<Crossfade> = None;
+    let mut raw: Vec<f32> = Vec::new();
 
     loop {
         if state.stop_signal.load(Ordering::SeqCst) {
             break;
         }
 
+        // Handover became audible — the outgoing track can no longer be sought
+        if previous.is_some() && state.track_boundary.load(Ordering::SeqCst) == NO_BOUNDARY {
+            previous = None;
+        }
+
         // Handle seek requests
-        let seek_ms = state.seek_to_ms.swap(0, Ordering::SeqCst);
-        if seek_ms > 0 {
-            let seek_time = Time::new(seek_ms / 1000, (seek_ms % 1000) as f64 / 1000.0);
-            if format
-                .seek(SeekMode::Accurate, SeekTo::Time { time: seek_time, track_id: Some(track_id) })
-                .is_ok()
-            {
-                decoder.reset();
-                state.samples.lock().clear();
-                decoded_frames = (seek_ms * sample_rate as u64) / 1000;
-                state.position_ms.store(seek_ms, Ordering::SeqCst);
+        if let Some((generation, seek_ms)) = state.pending_seek() {
+            // Mid-crossfade: before the fade is audible the seek targets the
+            // outgoing track (fade dropped, next re-staged); after, the incoming one.
+            if let Some(xf) = fade.take() {
+                if cancel_boundary(state) {
+                    *state.next_probe.lock() = Some((xf.next.sample_rate, xf.next.channels, xf.path));
+                } else {
+                    track = xf.next;
+                    decoded_frames = xf.mixed_frames;
+                }
+            }
+            // A seek before the handover is audible targets the outgoing track:
+            // cancel the boundary and re-stage the next track.
+            if let Some((prev, prev_frames, next_path)) = previous.take() {
+                if cancel_boundary(state) {
+                    *state.next_probe.lock() = Some((track.sample_rate, track.channels, next_path));
+                    track = prev;
+                    decoded_frames = prev_frames;
+                }
             }
+            if let Some(frame) = track.seek(seek_ms) {
+                state.samples.clear();
+                state.position_marks.lock().clear();
+                if let Some(rs) = resampler.as_mut() {
+                    rs.reset();
+                }
+                stretch.reset();
+                ending = false;
+                decoded_frames = frame;
+                state.position_ms.store(frame * 1000 / track.sample_rate as u64, Ordering::SeqCst);
+                state.duration_ms.store(track.duration_ms, Ordering::SeqCst);
+            }
+            // Old audio is gone (or the seek failed) — ramp back up
+            state.muted.store(false, Ordering::SeqCst);
+            state.seek_done(generation);
         }
 
-        // Wait while paused
+        // Wait while paused; a seek is still carried out so resume starts there
         while state.paused.load(Ordering::SeqCst) {
             if state.stop_signal.load(Ordering::SeqCst) {
                 return Ok(());
             }
+            if state.seek_pending() {
+                break;
+            }
             thread::sleep(std::time::Duration::from_millis(10));
         }
+        if state.seek_pending() {
+            continue;
+        }
 
-        let packet = match format.next_packet() {
-            Ok(packet) => packet,
-            Err(symphonia::core::errors::Error::IoError(ref e))
-                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
-            {
-                break; // End of stream
+        state.refresh_gain(&mut track);
+        if let Some(xf) = fade.as_mut() {
+            state.refresh_gain(&mut xf.next);
+        }
+
+        let looping = *state.loop_region.lock();
+
+        // Start a crossfade once the remaining tail fits in the fade length
+        // (never while looping: the tail isn't reached)
+        if fade.is_none() && previous.is_none() && looping.is_none() {
+            let (fade_ms, curve) = *state.crossfade.lock();
+            let fade_frames = fade_ms * track.sample_rate as u64 / 1000;
+            let remaining = track.total_frames.map(|t| t.saturating_sub(decoded_frames));
+            if let Some(len) = remaining.filter(|&r| r > 0 && r <= fade_frames) {
+                if let Some((next, next_path)) = take_gapless_next(state, &track) {
+                    let speed = state.handover_speed(&next_path);
+                    begin_handover(state, &next_path, held_samples(&stretch, speed, &track, device_rate));
+                    log::info!("amsal: crossfading into {} over {} frames", next_path, len);
+                    fade = Some(Crossfade::new(next, next_path, len, curve));
+                }
+            }
+        }
+
+        raw.clear();
+        let frames = match track.decode_next(&mut raw)? {
+            Some(frames) => frames,
+            // Outgoing tail ended mid-fade — the incoming track carries on
+            None if fade.is_some() => {
+                if let Some(xf) = fade.take() {
+                    previous = Some(finish_crossfade(xf, &mut track, &mut decoded_frames, &mut raw));
+                }
+                0
+            }
+            None => {
+                // A loop ending past the track's end wraps here instead
+                if let Some(frame) = looping.and_then(|region| track.wrap_loop(&mut raw, region, true)) {
+                    decoded_frames = frame;
+                    ending = false;
+                    continue;
+                }
+                // End of stream — continue gaplessly if a compatible track is staged
+                match take_gapless_next(state, &track) {
+                    Some((next, next_path)) => {
+                        let speed = state.handover_speed(&next_path);
+                        begin_handover(state, &next_path, held_samples(&stretch, speed, &track, device_rate));
+                        log::info!("amsal: gapless handover to {}", next_path);
+                        let outgoing = std::mem::replace(&mut track, next);
+                        previous = Some((outgoing, decoded_frames, next_path));
+                        decoded_frames = track.start_frame;
+                        ending = false;
+                        continue;
+                    }
+                    None if ending => break,
+                    None => {
+                        ending = true;
+                        0
+                    }
+                }
             }
-            Err(e) => return Err(e.into()),
         };
+        decoded_frames += frames as u64;
 
-        if packet.track_id() != track_id {
-            continue;
+        // A-B loop: cut the block at the loop end and jump back, keeping the
+        // stretcher and resampler running so the pipeline stays continuous
+        if fade.is_none() {
+            if let Some(frame) = looping.and_then(|region| track.wrap_loop(&mut raw, region, false)) {
+                decoded_frames = frame;
+            }
         }
 
-        let decoded = decoder.decode(&packet)?;
-        let spec = *decoded.spec();
-        let n_frames = decoded.frames();
+        if frames > 0 {
+            if let Some(xf) = fade.as_mut() {
+                xf.mix(&mut raw, track.channels.max(1) as usize);
+                if xf.is_complete() {
+                    if let Some(xf) = fade.take() {
+                        previous = Some(finish_crossfade(xf, &mut track, &mut decoded_frames, &mut raw));
+                    }
+                }
+            }
+        }
 
-        let mut sample_buf = SampleBuffer::<f32>::new(n_frames as u64, spec);
-        sample_buf.copy_interleaved_ref(decoded);
+        // Time stretch at the track's rate, before resampling for the device
+        stretch.set_speed(f32::from_bits(state.speed.load(Ordering::SeqCst)));
+        stretched.clear();
+        if ending {
+            stretch.flush(&mut stretched);
+        } else {
+            stretch.process(&raw, &mut stretched);
+        }
+        // The final pass still has the resampler's tail to play out
+        if stretched.is_empty() && !ending {
+            continue;
+        }
 
-        let raw_samples: Vec<f32> = sample_buf.samples().to_vec();
-        let samples = match resampler.as_mut() {
-            Some(rs) => rs.process(&raw_samples),
-            None => raw_samples,
+        // Update position; frames held in the stretcher haven't been pushed
+        // yet. While a handover is pending the outgoing track is still
+        // audible, so hold its duration until the boundary.
+        let (frames, current) = match &fade {
+            Some(xf) => (xf.mixed_frames, &xf.next),
+            None => (decoded_frames, &track),
         };
-
-        // Update position
-        decoded_frames += n_frames as u64;
-        let pos_ms = (decoded_frames * 1000) / sample_rate as u64;
+        let frames = frames.saturating_sub(stretch.pending_frames());
+        let pos_ms = (frames * 1000) / current.sample_rate as u64;
         state.position_ms.store(pos_ms, Ordering::SeqCst);
+        if state.track_boundary.load(Ordering::SeqCst) == NO_BOUNDARY {
+            state.duration_ms.store(current.duration_ms, Ordering::SeqCst);
+        }
 
-        // Push to ring, back-pressure if full
+        let wanted = *state.resample_quality.lock();
+        if wanted != quality {
+            quality = wanted;
+            if let Some(rs) = resampler.as_mut() {
+                rs.set_quality(quality);
+            }
+        }
+        let samples: &[f32] = match resampler.as_mut() {
+            Some(rs) => {
+                resampled.clear();
+                rs.process(&stretched, &mut resampled);
+                if ending {
+                    rs.flush(&mut resampled);
+                }
+                &resampled
+            }
+            None => &stretched,
+        };
+        if samples.is_empty() {
+            continue;
+        }
+
+        // Push to ring; when full, sleep about as long as the output
+        // needs to drain the remainder
+        let start = state.samples.pushed();
+        let mut rest = samples;
         loop {
-            let mut ring = state.samples.lock();
-            let available = ring.buf.len() - ring.len;
-            if available >= samples.len() {
-                ring.push(&samples);
+            rest = &rest[state.samples.push(rest)..];
+            if rest.is_empty() {
                 break;
             }
-            drop(ring);
-            thread::sleep(std::time::Duration::from_millis(5));
-
             if state.stop_signal.load(Ordering::SeqCst) {
                 return Ok(());
             }
+            // A muted output isn't draining; the seek flushes this anyway
+            if state.seek_pending() {
+                break;
+            }
+            let per_ms = (device_rate as u64 * track.channels.max(1) as u64 / 1000).max(1);
+            let wait_ms = (rest.len() as u64 / per_ms).clamp(1, 20);
+            thread::sleep(std::time::Duration::from_millis(wait_ms));
+        }
+        // The block plays for its length at the device rate, times the speed in track time
+        if rest.is_empty() {
+            let per_sec = device_rate as f64 * track.channels.max(1) as f64;
+            let block_ms = (samples.len() as f64 * 1000.0 * stretch.speed() as f64 / per_sec) as u64;
+            let end = state.samples.pushed();
+            state.mark_position(PositionMark { start, end, start_ms: pos_ms.saturating_sub(block_ms), end_ms: pos_ms });
         }
     }
 
     Ok(())
 }
 
+/// Mark the first ring sample of `next_path`: after what is pushed so far
+/// and the `held` samples of the outgoing track still in the stretcher.
+/// The output callback flags the advance once it plays past it.
+fn begin_handover(state: &AudioState, next_path: &str, held: u64) {
+    let boundary = state.samples.pushed() + held;
+    *state.advanced_path.lock() = Some(next_path.to_string());
+    state.track_boundary.store(boundary, Ordering::SeqCst);
+}
+
+/// Ring samples the frames held in `stretch` come out as, once stretched
+/// at `speed` and resampled from `track`'s rate to `device_rate`.
+fn held_samples(stretch: &TimeStretch, speed: f32, track: &TrackDecoder, device_rate: u32) -> u64 {
+    let frames = stretch.pending_frames() as f64 / speed as f64 * device_rate as f64 / track.sample_rate as f64;
+    frames.round() as u64 * track.channels.max(1) as u64
+}
+
+/// Withdraw a handover the output hasn't reached yet.
+/// Returns false when there was none or it has already been crossed.
+fn cancel_boundary(state: &AudioState) -> bool {
+    let boundary = state.track_boundary.load(Ordering::SeqCst);
+    let cancelled = boundary != NO_BOUNDARY
+        && state
+            .track_boundary
+            .compare_exchange(boundary, NO_BOUNDARY, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+    if cancelled {
+        *state.advanced_path.lock() = None;
+    }
+    cancelled
+}
+
+/// Take the staged next track if it can share the running output stream.
+///
+/// Same sample rate, channel count and speaker layout means the ring
+/// layout, channel mixing, resampler and cpal config all stay valid.
+/// Anything else falls back to stop + `play()`.
+fn take_gapless_next(state: &AudioState, current: &TrackDecoder) -> Option<(TrackDecoder, String)> {
+    let (rate, ch, path) = {
+        let mut staged = state.next_probe.lock();
+        match staged.take() {
+            // Leave it cached so play() can skip the probe
+            Some((rate, ch, path)) if rate != current.sample_rate || ch != current.channels => {
+                *staged = Some((rate, ch, path));
+                return None;
+            }
+            Some((rate, ch, path)) => {
+                // Taken: the engine mustn't stage it again while it still sees the outgoing track
+                *state.advanced_path.lock() = Some(path.clone());
+                (rate, ch, path)
+            }
+            None => return None,
+        }
+    };
+    match TrackDecoder::open(&path) {
+        Ok(mut next) if next.sample_rate == rate && next.channels == ch && next.layout == current.layout => {
+            state.refresh_gain(&mut next);
+            state.apply_range(&mut next);
+            Some((next, path))
+        }
+        Ok(_) => {
+            *state.advanced_path.lock() = None;
+            None
+        }
+        Err(e) => {
+            log::warn!("amsal: gapless open failed for {}: {}", path, e);
+            *state.advanced_path.lock() = None;
+            None
+        }
+    }
+}
+
 /// Pull samples from the ring buffer and send to cpal output.
 ///
 /// Takes Arc so the cpal callback closure can hold a safe reference
 /// without raw pointers. Output stream is configured at the track's
 /// sample rate (probed in play()) to avoid playback-speed drift.
+///
+/// When the preferred device changes or the current one disappears, the
+/// stream is reopened on the newly selected device at the same rate.
 fn output_from_ring(state: Arc<AudioState>) -> Result<(), Box<dyn std::error::Error>> {
-    let host = cpal::default_host();
-    let device = host.default_output_device().ok_or("no output device")?;
-
+    state.reopen_output.store(false, Ordering::SeqCst);
     let track_rate = state.sample_rate.load(Ordering::SeqCst);
     let track_channels = state.channels.load(Ordering::SeqCst).max(1) as u16;
+    let mut output = open_output(&state, track_rate, track_channels, false)?;
+    // The decoder resamples for this rate, so a reopened stream must match it
+    let rate = output.config.sample_rate.0;
 
-    // Check if device supports the track's rate + channels + f32 format
-    let device_supports_track = device
-        .supported_output_configs()
-        .map(|configs| {
-            configs.into_iter().any(|range| {
-                range.sample_format() == cpal::SampleFormat::F32
-                    && range.channels() >= track_channels
-                    && range.min_sample_rate().0 <= track_rate
-                    && range.max_sample_rate().0 >= track_rate
-            })
-        })
-        .unwrap_or(false);
+    // Keep stream alive while playing or draining
+    loop {
+        output.forward_dsp(&state);
 
-    let config: cpal::StreamConfig = if device_supports_track {
-        cpal::StreamConfig {
-            channels: track_channels,
-            sample_rate: cpal::SampleRate(track_rate),
-            buffer_size: cpal::BufferSize::Default,
+        if state.reopen_output.swap(false, Ordering::SeqCst) {
+            drop(output); // Parks the DSP chain for the new stream
+            output = open_output(&state, rate, track_channels, true)?;
         }
-    } else {
-        // Verify default config supports f32
-        let default_cfg = device.default_output_config()?;
-        if default_cfg.sample_format() != cpal::SampleFormat::F32 {
-            return Err(format!(
-                "device does not support f32 output (got {:?})",
-                default_cfg.sample_format()
-            ).into());
-        }
-        default_cfg.into()
-    };
 
-    let out_channels = config.channels;
-    state.output_channels.store(out_channels as u32, Ordering::SeqCst);
-
-    let cb_state = Arc::clone(&state);
-    let stream = device.build_output_stream(
-        &config,
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            if cb_state.paused.load(Ordering::SeqCst) {
-                data.fill(0.0);
-                return;
-            }
-            let ring_ch = cb_state.channels.load(Ordering::SeqCst) as u16;
-            if ring_ch == out_channels || out_channels == 0 {
-                // Channels match — pull directly
-                cb_state.samples.lock().pull(data);
-            } else {
-                // Channel mismatch — pull at ring's channel count, adapt
-                let frames = data.len() / out_channels as usize;
-                let ring_samples = frames * ring_ch as usize;
-                let mut tmp = vec![0.0f32; ring_samples];
-                cb_state.samples.lock().pull(&mut tmp);
-                adapt_channels(&tmp, ring_ch, data, out_channels);
-            }
-            let vol = cb_state.volume.load(Ordering::SeqCst) as f32 / 100.0;
-            for s in data.iter_mut() {
-                *s *= vol;
-            }
-            // DSP chain after volume
-            if let Some(chain) = &mut *cb_state.dsp_chain.write() {
-                let ch = cb_state.channels.load(Ordering::SeqCst) as u16;
-                let sr = cb_state.sample_rate.load(Ordering::SeqCst);
-                chain.process(data, ch, sr);
-            }
-        },
-        move |err| {
-            log::error!("amsal: cpal error: {}", err);
-        },
-        None,
-    )?;
-
-    stream.play()?;
+        // The decoder ended before it could take a seek: nothing will unmute
+        if state.finished.load(Ordering::SeqCst) && state.seek_pending() {
+            state.muted.store(false, Ordering::SeqCst);
+        }
+        // A fade-out reached silence: stop where the audio left off
+        if state.fade_out_ms.load(Ordering::SeqCst) > 0 && state.silent.load(Ordering::SeqCst) {
+            state.stop_signal.store(true, Ordering::SeqCst);
+            state.samples.clear();
+        }
 
-    // Keep stream alive while playing or draining
-    loop {
         let finished = state.finished.load(Ordering::SeqCst);
-        let buffered = state.samples.lock().len;
+        let buffered = state.samples.len();
         let stopped = state.stop_signal.load(Ordering::SeqCst);
 
         if stopped && buffered == 0 {
@@ -590,74 +1201,443 @@ fn output_from_ring(state: Arc<AudioState>) -> Result<(), Box<dyn std::error::Er
         thread::sleep(std::time::Duration::from_millis(25));
     }
 
+    drop(output);
+    state.output_rate.store(0, Ordering::SeqCst);
+    state.output_delay_frames.store(0, Ordering::SeqCst);
     state.playing.store(false, Ordering::SeqCst);
     Ok(())
 }
 
-/// Adapt interleaved samples between different channel counts.
-/// Handles mono→stereo, stereo→mono, and general up/down-mix.
-fn adapt_channels(src: &[f32], src_ch: u16, dst: &mut [f32], dst_ch: u16) {
-    let src_ch = src_ch as usize;
-    let dst_ch = dst_ch as usize;
-    let frames = dst.len() / dst_ch;
-
-    for f in 0..frames {
-        let src_off = f * src_ch;
-        let dst_off = f * dst_ch;
+/// A playing cpal stream and the channel ends feeding its `DspHost`.
+struct OutputStream {
+    _stream: cpal::Stream,
+    config: cpal::StreamConfig,
+    dsp_tx: mpsc::SyncSender<super::dsp::DspChain>,
+    retired_rx: mpsc::Receiver<super::dsp::DspChain>,
+}
 
-        if src_ch == 1 && dst_ch >= 2 {
-            // Mono → stereo+: duplicate to all channels
-            let s = if src_off < src.len() { src[src_off] } else { 0.0 };
-            for c in 0..dst_ch {
-                dst[dst_off + c] = s;
-            }
-        } else if src_ch >= 2 && dst_ch == 1 {
-            // Stereo+ → mono: average all source channels
-            let mut sum = 0.0f32;
-            let n = src_ch.min(src.len().saturating_sub(src_off));
-            for c in 0..n {
-                sum += src[src_off + c];
+impl OutputStream {
+    /// Drop replaced DSP chains here, then forward a newly set one.
+    fn forward_dsp(&self, state: &AudioState) {
+        for _ in self.retired_rx.try_iter() {}
+        let mut parked = state.dsp_chain.lock();
+        if let Some(chain) = parked.take() {
+            if let Err(mpsc::TrySendError::Full(chain) | mpsc::TrySendError::Disconnected(chain)) =
+                self.dsp_tx.try_send(chain)
+            {
+                *parked = Some(chain);
             }
-            dst[dst_off] = if n > 0 { sum / n as f32 } else { 0.0 };
+        }
+    }
+}
+
+/// Rebuild the EQ chain (if one is set from a scroll) for a stream opening
+/// at `rate`/`channels`, carrying filter state over from the parked chain.
+fn rebuild_eq(state: &AudioState, rate: u32, channels: u16) {
+    let Some(spec) = state.eq_spec.lock().clone() else {
+        return;
+    };
+    let mut chain = super::dsp::chain_from_value(&spec, rate, channels);
+    let mut parked = state.dsp_chain.lock();
+    if let Some(old) = parked.as_ref() {
+        chain.carry_state_from(old);
+    }
+    *parked = Some(chain);
+}
+
+/// Build and start a stream on the selected output device.
+fn open_output(
+    state: &Arc<AudioState>,
+    rate: u32,
+    channels: u16,
+    exact_rate: bool,
+) -> Result<OutputStream, Box<dyn std::error::Error>> {
+    let preferred = state.output_device.lock().clone();
+    let device = select_device(preferred.as_deref()).ok_or("no output device")?;
+    let (config, format) = stream_config(&device, rate, channels, exact_rate)?;
+    let device_name = device.name().unwrap_or_default();
+    log::info!(
+        "amsal: output on {:?} at {}Hz, {} channels, {}",
+        device_name, config.sample_rate.0, config.channels, format
+    );
+    *state.active_device.lock() = Some(device_name);
+    state.output_channels.store(config.channels as u32, Ordering::SeqCst);
+    state.output_rate.store(config.sample_rate.0, Ordering::SeqCst);
+    rebuild_eq(state, config.sample_rate.0, config.channels);
+
+    // Everything the callback touches is set up here: it must not lock or allocate
+    let (dsp_tx, dsp_rx) = mpsc::sync_channel(1);
+    // Each forwarded chain retires at most one, and forward_dsp drains
+    // retired chains before forwarding the next
+    let (retired_tx, retired_rx) = mpsc::sync_channel(2);
+    let mut callback = OutputCallback {
+        dsp: DspHost {
+            switch: super::dsp::DspSwitch::new(state.dsp_chain.lock().take()),
+            incoming: dsp_rx,
+            retired: retired_tx,
+            state: Arc::clone(state),
+        },
+        scratch: vec![0.0f32; CALLBACK_SCRATCH_FRAMES * channels as usize],
+        mixing: None,
+        ramp: super::dsp::GainRamp::new(0.0),
+        primed: false,
+        clears_seen: state.samples.clears(),
+        meter: LevelMeter::new(config.channels, config.sample_rate.0),
+        out_channels: config.channels,
+        out_rate: config.sample_rate.0,
+        state: Arc::clone(state),
+    };
+
+    let err_state = Arc::clone(state);
+    let on_error = move |err| {
+        log::error!("amsal: cpal error: {}", err);
+        if let cpal::StreamError::DeviceNotAvailable = err {
+            // Unplugged — the output thread moves to the next best device
+            err_state.reopen_output.store(true, Ordering::SeqCst);
+        }
+    };
+    let stream = match format {
+        cpal::SampleFormat::F32 => device.build_output_stream(
+            &config,
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                callback.note_delay(info, data.len());
+                callback.render(data);
+            },
+            on_error,
+            None,
+        )?,
+        cpal::SampleFormat::F64 => build_converted::<f64>(&device, &config, callback, on_error)?,
+        cpal::SampleFormat::I32 => build_converted::<i32>(&device, &config, callback, on_error)?,
+        cpal::SampleFormat::I16 => build_converted::<i16>(&device, &config, callback, on_error)?,
+        cpal::SampleFormat::U16 => build_converted::<u16>(&device, &config, callback, on_error)?,
+        other => return Err(format!("unsupported output sample format {}", other).into()),
+    };
+
+    stream.play()?;
+    Ok(OutputStream { _stream: stream, config, dsp_tx, retired_rx })
+}
+
+/// Everything the cpal callback owns. `render` produces one buffer of f32
+/// output; it never locks or allocates.
+struct OutputCallback {
+    state: Arc<AudioState>,
+    dsp: DspHost,
+    /// Ring-layout samples when ring and device channel counts differ.
+    scratch: Vec<f32>,
+    /// Matrix for the current (ring channels, layout, mix bits).
+    mixing: Option<((u16, u32, u32), ChannelMatrix)>,
+    /// Transport fade; a new stream ramps in from silence.
+    ramp: super::dsp::GainRamp,
+    /// Audio has been flowing since the last flush (for underrun counting).
+    primed: bool,
+    clears_seen: u64,
+    /// Tap at the very end of the chain, feeding `AudioState::levels`.
+    meter: LevelMeter,
+    out_channels: u16,
+    out_rate: u32,
+}
+
+impl OutputCallback {
+    /// Record how far the device runs behind this callback: the latency
+    /// cpal reports until the buffer starts playing, plus half the buffer,
+    /// which plays out over the following period.
+    fn note_delay(&self, info: &cpal::OutputCallbackInfo, samples: usize) {
+        let timestamp = info.timestamp();
+        let latency = timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default();
+        let latency_frames = latency.as_micros() as u64 * self.out_rate as u64 / 1_000_000;
+        let buffer_frames = (samples / self.out_channels.max(1) as usize) as u64;
+        self.state.output_delay_frames.store(latency_frames + buffer_frames / 2, Ordering::SeqCst);
+    }
+
+    fn render(&mut self, data: &mut [f32]) {
+        let state = &*self.state;
+        let fade_out_ms = state.fade_out_ms.load(Ordering::SeqCst);
+        let muted = state.paused.load(Ordering::SeqCst) || state.muted.load(Ordering::SeqCst);
+        let down = muted || fade_out_ms > 0;
+        if down && self.ramp.is_silent() {
+            // Held silent: nothing is pulled, so playback resumes where it stopped
+            data.fill(0.0);
+            state.silent.store(true, Ordering::SeqCst);
+            self.meter.process(data, &state.levels);
+            return;
+        }
+        let ring = &state.samples;
+        let out_channels = self.out_channels;
+        let ring_ch = state.channels.load(Ordering::SeqCst).max(1) as u16;
+        let full = if ring_ch == out_channels || out_channels == 0 {
+            // Channels match — pull directly
+            ring.pull(data) == data.len()
         } else {
-            // General: copy matching channels, zero-fill extra, drop excess
-            let copy_ch = src_ch.min(dst_ch);
-            for c in 0..copy_ch {
-                dst[dst_off + c] = if src_off + c < src.len() { src[src_off + c] } else { 0.0 };
-            }
-            for c in copy_ch..dst_ch {
-                dst[dst_off + c] = 0.0;
+            // Channel mismatch — pull at ring's channel count into scratch, mix
+            let key = (
+                ring_ch,
+                state.channel_layout.load(Ordering::SeqCst),
+                state.channel_mix.load(Ordering::SeqCst),
+            );
+            let matrix = match self.mixing {
+                Some((k, m)) if k == key => m,
+                _ => {
+                    let m = ChannelMatrix::new(ring_ch, key.1, out_channels, ChannelMix::from_bits(key.2));
+                    self.mixing = Some((key, m));
+                    m
+                }
+            };
+            let chunk_frames = (self.scratch.len() / ring_ch as usize).max(1);
+            let mut full = true;
+            for chunk in data.chunks_mut(chunk_frames * out_channels as usize) {
+                let frames = chunk.len() / out_channels as usize;
+                let src = &mut self.scratch[..frames * ring_ch as usize];
+                full &= ring.pull(src) == src.len();
+                matrix.apply(src, chunk);
             }
+            full
+        };
+
+        // Underrun: ran dry after audio was flowing, while more is coming.
+        // Flushes (seek/stop) restart the count from an empty ring.
+        let clears = ring.clears();
+        if clears != self.clears_seen {
+            self.clears_seen = clears;
+            self.primed = false;
+        }
+        if full {
+            self.primed = true;
+        } else if self.primed
+            && !state.finished.load(Ordering::SeqCst)
+            && !state.stop_signal.load(Ordering::SeqCst)
+        {
+            state.underruns.fetch_add(1, Ordering::SeqCst);
+            self.primed = false;
+        }
+
+        // Gapless: the last sample of the outgoing track has been played
+        let pulled = ring.pulled();
+        let boundary = state.track_boundary.load(Ordering::SeqCst);
+        if boundary != NO_BOUNDARY
+            && pulled >= boundary
+            && state
+                .track_boundary
+                .compare_exchange(boundary, NO_BOUNDARY, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            state.track_advanced.store(true, Ordering::SeqCst);
+        }
+        let vol = state.volume.load(Ordering::SeqCst) as f32 / 100.0;
+        for s in data.iter_mut() {
+            *s *= vol;
         }
+        // DSP chain after volume, at the device's format
+        self.dsp.process(data, out_channels, self.out_rate);
+
+        // Ramp last, so the DSP tail fades with everything else
+        let ramp_ms = if fade_out_ms > 0 && !muted { fade_out_ms } else { state.transport_fade_ms.load(Ordering::SeqCst) };
+        self.ramp.apply(data, out_channels, down, ramp_ms * self.out_rate as u64 / 1000);
+        state.silent.store(down && self.ramp.is_silent(), Ordering::SeqCst);
+        self.meter.process(data, &state.levels);
     }
 }
 
-/// Determine what sample rate the output device will use.
-/// If the device supports the track rate, use that. Otherwise fall back to default.
-fn probe_device_rate(track_rate: u32) -> u32 {
-    let host = cpal::default_host();
-    let Some(device) = host.default_output_device() else {
-        return track_rate;
-    };
+/// Device sample formats the f32 pipeline converts to.
+trait OutputSample: cpal::SizedSample + Send + 'static {
+    /// Integer formats take TPDF dither (when enabled) before rounding.
+    const INTEGER: bool;
+    fn from_f32(sample: f32, dither: f32) -> Self;
+}
+
+impl OutputSample for f64 {
+    const INTEGER: bool = false;
+    fn from_f32(sample: f32, _: f32) -> Self {
+        sample as f64
+    }
+}
 
-    let supports_track = device
+impl OutputSample for i32 {
+    const INTEGER: bool = true;
+    fn from_f32(sample: f32, dither: f32) -> Self {
+        super::convert::f32_to_i32(sample, dither)
+    }
+}
+
+impl OutputSample for i16 {
+    const INTEGER: bool = true;
+    fn from_f32(sample: f32, dither: f32) -> Self {
+        super::convert::f32_to_i16(sample, dither)
+    }
+}
+
+impl OutputSample for u16 {
+    const INTEGER: bool = true;
+    fn from_f32(sample: f32, dither: f32) -> Self {
+        super::convert::f32_to_u16(sample, dither)
+    }
+}
+
+/// Output stream in a non-f32 format: render f32 into a preallocated
+/// buffer, chunk by chunk, and convert each sample.
+fn build_converted<T: OutputSample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut callback: OutputCallback,
+    on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let mut buf = vec![0.0f32; CALLBACK_SCRATCH_FRAMES * config.channels.max(1) as usize];
+    let mut tpdf = super::convert::Tpdf::new(0x9e37_79b9);
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            callback.note_delay(info, data.len());
+            let dither = T::INTEGER && callback.state.dither.load(Ordering::Relaxed);
+            for chunk in data.chunks_mut(buf.len()) {
+                let rendered = &mut buf[..chunk.len()];
+                callback.render(rendered);
+                for (out, &s) in chunk.iter_mut().zip(rendered.iter()) {
+                    let d = if dither { tpdf.next_lsb() } else { 0.0 };
+                    *out = T::from_f32(s, d);
+                }
+            }
+        },
+        on_error,
+        None,
+    )
+}
+
+/// Supported output formats, best first: f32 needs no conversion, the
+/// rest by resolution.
+const FORMAT_PREFERENCE: [cpal::SampleFormat; 5] = [
+    cpal::SampleFormat::F32,
+    cpal::SampleFormat::F64,
+    cpal::SampleFormat::I32,
+    cpal::SampleFormat::I16,
+    cpal::SampleFormat::U16,
+];
+
+/// Stream config and sample format for `rate`/`channels`, in the best
+/// format the device supports there. Otherwise the device default rate and
+/// channels, or an error with `exact_rate` (the ring is already resampled
+/// for `rate`).
+fn stream_config(
+    device: &cpal::Device,
+    rate: u32,
+    channels: u16,
+    exact_rate: bool,
+) -> Result<(cpal::StreamConfig, cpal::SampleFormat), Box<dyn std::error::Error>> {
+    let ranges: Vec<cpal::SupportedStreamConfigRange> = device
         .supported_output_configs()
-        .map(|configs| {
-            configs.into_iter().any(|range| {
-                range.min_sample_rate().0 <= track_rate
-                    && range.max_sample_rate().0 >= track_rate
+        .map(|configs| configs.collect())
+        .unwrap_or_default();
+    let best_format = |rate: u32, channels: u16| {
+        FORMAT_PREFERENCE.into_iter().find(|format| {
+            ranges.iter().any(|range| {
+                range.sample_format() == *format
+                    && range.channels() >= channels
+                    && range.min_sample_rate().0 <= rate
+                    && range.max_sample_rate().0 >= rate
             })
         })
-        .unwrap_or(false);
+    };
+    let config = |rate: u32, channels: u16| cpal::StreamConfig {
+        channels,
+        sample_rate: cpal::SampleRate(rate),
+        buffer_size: cpal::BufferSize::Default,
+    };
 
-    if supports_track {
-        track_rate
-    } else {
-        device
-            .default_output_config()
-            .map(|c| c.sample_rate().0)
-            .unwrap_or(track_rate)
+    if let Some(format) = best_format(rate, channels) {
+        return Ok((config(rate, channels), format));
+    }
+    if exact_rate {
+        return Err(format!("output device cannot play at {}Hz", rate).into());
+    }
+    let default_cfg = device.default_output_config()?;
+    let (rate, channels) = (default_cfg.sample_rate().0, default_cfg.channels());
+    if FORMAT_PREFERENCE.contains(&default_cfg.sample_format()) {
+        return Ok((config(rate, channels), default_cfg.sample_format()));
+    }
+    best_format(rate, channels)
+        .map(|format| (config(rate, channels), format))
+        .ok_or_else(|| format!("device has no supported output format (default {})", default_cfg.sample_format()).into())
+}
+
+/// The output device named `preferred` (default host searched first), or
+/// the default device when there is no preference or it is not present.
+fn select_device(preferred: Option<&str>) -> Option<cpal::Device> {
+    let default_host = cpal::default_host();
+    if let Some(name) = preferred {
+        let other_hosts = cpal::available_hosts()
+            .into_iter()
+            .filter(|id| *id != default_host.id())
+            .filter_map(|id| cpal::host_from_id(id).ok());
+        for host in std::iter::once(cpal::default_host()).chain(other_hosts) {
+            let found = host
+                .output_devices()
+                .ok()
+                .and_then(|mut devices| devices.find(|d| d.name().ok().as_deref() == Some(name)));
+            if found.is_some() {
+                return found;
+            }
+        }
+        log::warn!("amsal: output device {:?} not found, using default", name);
     }
+    default_host.default_output_device()
+}
+
+/// Every host's output devices with their supported stream configs.
+fn list_output_devices() -> Vec<serde_json::Value> {
+    let default_host = cpal::default_host().id();
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|id| {
+            let host = cpal::host_from_id(id).ok()?;
+            let default_name = host.default_output_device().and_then(|d| d.name().ok());
+            let devices: Vec<serde_json::Value> = host
+                .output_devices()
+                .map(|devices| {
+                    devices
+                        .filter_map(|device| {
+                            let name = device.name().ok()?;
+                            let configs: Vec<serde_json::Value> = device
+                                .supported_output_configs()
+                                .map(|configs| {
+                                    configs
+                                        .map(|c| {
+                                            serde_json::json!({
+                                                "channels": c.channels(),
+                                                "min_rate": c.min_sample_rate().0,
+                                                "max_rate": c.max_sample_rate().0,
+                                                "format": c.sample_format().to_string(),
+                                            })
+                                        })
+                                        .collect()
+                                })
+                                .unwrap_or_default();
+                            Some(serde_json::json!({
+                                "default": default_name.as_deref() == Some(name.as_str()),
+                                "name": name,
+                                "configs": configs,
+                            }))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some(serde_json::json!({
+                "name": id.name(),
+                "default": id == default_host,
+                "devices": devices,
+            }))
+        })
+        .collect()
+}
+
+/// Frames per chunk when the callback adapts channel counts through its
+/// preallocated scratch buffer; larger device buffers are done in chunks.
+const CALLBACK_SCRATCH_FRAMES: usize = 4096;
+
+/// Determine what sample rate the output device will use — the rate the
+/// output thread's `stream_config` picks for this track.
+fn probe_device_rate(preferred: Option<&str>, track_rate: u32, channels: u16) -> u32 {
+    select_device(preferred)
+        .and_then(|device| stream_config(&device, track_rate, channels, false).ok())
+        .map(|(config, _)| config.sample_rate.0)
+        .unwrap_or(track_rate)
 }
 
 /// Probe a file's audio format without decoding. Returns (sample_rate, channels).
@@ -683,12 +1663,14 @@ fn probe_audio_format(file_path: &str) -> Option<(u32, u32)> {
 
 #[cfg(test)]
 mod tests {
-    use super::{LinearResampler, SampleRing};
+    use super::{begin_handover, held_samples, take_gapless_next, AudioEffect, PositionMark, SampleRing, TimeStretch, TrackDecoder};
+    use crate::effects::render::write_test_wav;
+    use std::sync::atomic::Ordering;
 
     #[test]
     fn push_pull_roundtrip() {
-        let mut ring = SampleRing::new(16);
-        ring.push(&[1.0, 2.0, 3.0, 4.0]);
+        let ring = SampleRing::new(16);
+        assert_eq!(ring.push(&[1.0, 2.0, 3.0, 4.0]), 4);
         let mut out = [0.0f32; 4];
         let n = ring.pull(&mut out);
         assert_eq!(n, 4);
@@ -696,11 +1678,11 @@ mod tests {
     }
 
     #[test]
-    fn overflow_drops_excess() {
-        let mut ring = SampleRing::new(4);
-        ring.push(&[1.0, 2.0, 3.0, 4.0]);
-        ring.push(&[5.0, 6.0]); // Full — silently dropped
-        assert_eq!(ring.len, 4);
+    fn overflow_pushes_what_fits() {
+        let ring = SampleRing::new(4);
+        ring.push(&[1.0, 2.0, 3.0]);
+        assert_eq!(ring.push(&[4.0, 5.0, 6.0]), 1); // Only one slot left
+        assert_eq!(ring.len(), 4);
         let mut out = [0.0f32; 6];
         let n = ring.pull(&mut out);
         assert_eq!(n, 4);
@@ -710,7 +1692,7 @@ mod tests {
 
     #[test]
     fn underflow_fills_zeros() {
-        let mut ring = SampleRing::new(8);
+        let ring = SampleRing::new(8);
         ring.push(&[1.0, 2.0]);
         let mut out = [0.0f32; 6];
         let n = ring.pull(&mut out);
@@ -721,10 +1703,11 @@ mod tests {
 
     #[test]
     fn clear_resets() {
-        let mut ring = SampleRing::new(8);
+        let ring = SampleRing::new(8);
         ring.push(&[1.0, 2.0, 3.0]);
         ring.clear();
-        assert_eq!(ring.len, 0);
+        assert_eq!(ring.len(), 0);
+        assert_eq!(ring.clears(), 1);
         let mut out = [0.0f32; 2];
         let n = ring.pull(&mut out);
         assert_eq!(n, 0);
@@ -732,7 +1715,7 @@ mod tests {
 
     #[test]
     fn interleaved_push_pull() {
-        let mut ring = SampleRing::new(8);
+        let ring = SampleRing::new(8);
         ring.push(&[1.0, 2.0, 3.0]);
         let mut out = [0.0f32; 2];
         ring.pull(&mut out);
@@ -746,11 +1729,11 @@ mod tests {
 
     #[test]
     fn wraparound_behavior() {
-        let mut ring = SampleRing::new(4);
+        let ring = SampleRing::new(4);
         ring.push(&[1.0, 2.0, 3.0]);
         let mut out = [0.0f32; 3];
         ring.pull(&mut out);
-        // read_pos=3, write_pos=3 — next push wraps around
+        // 3 pushed, 3 pulled — next push wraps around
         ring.push(&[7.0, 8.0, 9.0, 10.0]);
         let mut out2 = [0.0f32; 4];
         ring.pull(&mut out2);
@@ -758,53 +1741,130 @@ mod tests {
     }
 
     #[test]
-    fn resampler_same_rate_passthrough() {
-        let mut rs = LinearResampler::new(44100, 44100, 2);
-        assert!(!rs.is_needed());
-        let input = vec![1.0, 2.0, 3.0, 4.0];
-        let output = rs.process(&input);
-        assert_eq!(output, input);
-    }
-
-    #[test]
-    fn resampler_upsample_produces_more() {
-        let mut rs = LinearResampler::new(22050, 44100, 1);
-        assert!(rs.is_needed());
-        let input = vec![0.0, 1.0, 0.0, -1.0];
-        let output = rs.process(&input);
-        assert!(output.len() > input.len());
+    fn clear_counts_dropped_as_pulled() {
+        let ring = SampleRing::new(8);
+        ring.push(&[1.0, 2.0, 3.0, 4.0]);
+        let mut out = [0.0f32; 1];
+        ring.pull(&mut out);
+        assert_eq!((ring.pushed(), ring.pulled()), (4, 1));
+        ring.clear();
+        assert_eq!(ring.pulled(), ring.pushed());
     }
 
     #[test]
-    fn resampler_downsample_produces_fewer() {
-        let mut rs = LinearResampler::new(96000, 48000, 1);
-        assert!(rs.is_needed());
-        let input: Vec<f32> = (0..96).map(|i| i as f32 / 96.0).collect();
-        let output = rs.process(&input);
-        assert!(output.len() < input.len());
+    fn audible_position_follows_the_output_not_the_decoder() {
+        let effect = AudioEffect::new();
+        let state = &effect.state;
+        state.channels.store(2, Ordering::SeqCst);
+        // Two 100 ms blocks, 200 stereo samples each, buffered ahead of the output
+        state.samples.push(&[0.0; 400]);
+        state.mark_position(PositionMark { start: 0, end: 200, start_ms: 1000, end_ms: 1100 });
+        state.mark_position(PositionMark { start: 200, end: 400, start_ms: 1100, end_ms: 1200 });
+        state.position_ms.store(1200, Ordering::SeqCst);
+        assert_eq!(effect.position_ms(), 1000);
+        assert_eq!(effect.decoded_position_ms(), 1200);
+
+        state.samples.pull(&mut [0.0; 300]);
+        assert_eq!(effect.position_ms(), 1150);
+        // 20 frames pulled but still on their way through the device
+        state.output_delay_frames.store(20, Ordering::SeqCst);
+        assert_eq!(effect.position_ms(), 1130);
+        assert_eq!(state.position_marks.lock().len(), 1);
+
+        // Ran dry: holds at the end of what was played
+        state.output_delay_frames.store(0, Ordering::SeqCst);
+        state.samples.pull(&mut [0.0; 200]);
+        assert_eq!(effect.position_ms(), 1200);
+
+        // Flushed by a seek: where the decoder landed until new audio is buffered
+        state.samples.clear();
+        state.position_marks.lock().clear();
+        state.position_ms.store(5000, Ordering::SeqCst);
+        assert_eq!(effect.position_ms(), 5000);
     }
 
     #[test]
-    fn adapt_mono_to_stereo() {
-        let src = [1.0, 2.0, 3.0]; // 3 mono frames
-        let mut dst = [0.0f32; 6]; // 3 stereo frames
-        super::adapt_channels(&src, 1, &mut dst, 2);
-        assert_eq!(dst, [1.0, 1.0, 2.0, 2.0, 3.0, 3.0]);
+    fn track_taken_for_a_handover_is_not_staged_again() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a.wav"), dir.path().join("b.wav"));
+        write_test_wav(&a, 44100, 2, &[0.0; 4410 * 2]);
+        write_test_wav(&b, 44100, 2, &[0.0; 4410 * 2]);
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let effect = AudioEffect::new();
+        let state = &effect.state;
+        effect.prepare_next(b);
+        let current = TrackDecoder::open(a).unwrap();
+        let (_, path) = take_gapless_next(state, &current).expect("staged track taken");
+        begin_handover(state, &path, 0);
+
+        // Until the boundary is heard the engine still sees `a`, whose
+        // successor `b` is already decoding — staging it would replay it
+        effect.prepare_next(b);
+        assert!(state.next_probe.lock().is_none());
+
+        // Once the advance is reported, `b`'s own successor can be staged
+        state.track_advanced.store(true, Ordering::SeqCst);
+        assert_eq!(effect.take_track_advance().as_deref(), Some(b));
+        effect.prepare_next(a);
+        assert_eq!(state.next_probe.lock().as_ref().map(|(_, _, p)| p.as_str()), Some(a));
     }
 
     #[test]
-    fn adapt_stereo_to_mono() {
-        let src = [1.0, 3.0, 2.0, 4.0]; // 2 stereo frames
-        let mut dst = [0.0f32; 2]; // 2 mono frames
-        super::adapt_channels(&src, 2, &mut dst, 1);
-        assert_eq!(dst, [2.0, 3.0]); // averages
+    fn handover_switches_speed_and_waits_for_the_stretcher() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a.wav"), dir.path().join("b.wav"));
+        write_test_wav(&a, 44100, 2, &[0.0; 4410 * 2]);
+        write_test_wav(&b, 44100, 2, &[0.0; 4410 * 2]);
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let effect = AudioEffect::new();
+        let state = &effect.state;
+        effect.set_track_speed(b, 2.0);
+        effect.prepare_next(b);
+        let current = TrackDecoder::open(a).unwrap();
+        let (_, path) = take_gapless_next(state, &current).expect("staged track taken");
+        assert_eq!(state.handover_speed(&path), 2.0);
+
+        // The outgoing track's last frames are still in the stretcher
+        let mut stretch = TimeStretch::new(2, 44100);
+        stretch.set_speed(1.5);
+        let tone: Vec<f32> = (0..8820).map(|n| ((n / 2) as f32 * 0.05).sin() * 0.5).collect();
+        stretch.process(&tone, &mut Vec::new());
+        assert!(stretch.pending_frames() > 0);
+        // Played out at 2x and resampled 44.1 -> 88.2 kHz: one ring frame each
+        let held = held_samples(&stretch, 2.0, &current, 88200);
+        assert_eq!(held, stretch.pending_frames() * 2);
+
+        state.samples.push(&[0.0; 100]);
+        begin_handover(state, &path, held);
+        assert_eq!(state.track_boundary.load(Ordering::SeqCst), 100 + held);
     }
 
     #[test]
-    fn adapt_same_channels_passthrough() {
-        let src = [1.0, 2.0, 3.0, 4.0]; // 2 stereo frames
-        let mut dst = [0.0f32; 4];
-        super::adapt_channels(&src, 2, &mut dst, 2);
-        assert_eq!(dst, [1.0, 2.0, 3.0, 4.0]);
+    fn concurrent_producer_consumer_keeps_order() {
+        let ring = std::sync::Arc::new(SampleRing::new(64));
+        let total = 100_000usize;
+        let producer = {
+            let ring = std::sync::Arc::clone(&ring);
+            std::thread::spawn(move || {
+                let mut next = 0usize;
+                while next < total {
+                    let batch: Vec<f32> = (next..(next + 17).min(total)).map(|i| i as f32).collect();
+                    next += ring.push(&batch);
+                    std::thread::yield_now();
+                }
+            })
+        };
+        let mut expected = 0usize;
+        let mut out = [0.0f32; 13];
+        while expected < total {
+            let n = ring.pull(&mut out);
+            for &s in &out[..n] {
+                assert_eq!(s, expected as f32);
+                expected += 1;
+            }
+        }
+        producer.join().unwrap();
     }
 }
@@ -0,0 +1,459 @@
+//! Sample format conversion — internal f32 pipeline to integer PCM, and
+//! channel-count adaptation.
+//!
+//! Quantizing to integers adds distortion correlated with the signal (most
+//! audible on quiet fades at 16 bits). TPDF dither decorrelates it into a
+//! flat noise floor: the sum of two uniform randoms, ±1 LSB peak.
+//!
+//! Channel adaptation goes through a gain matrix built from the source's
+//! speaker layout (symphonia's `Channels` bits): speakers the output has
+//! are passed through, the rest folded down with ITU-R BS.775 gains
+//! (centre and surrounds at -3 dB into the front pair). Outputs are
+//! assumed to use the standard layout for their channel count.
+
+use symphonia::core::audio::Channels;
+
+/// Triangular-PDF dither source. xorshift32, no allocation — safe to run
+/// in the output callback.
+pub struct Tpdf {
+    state: u32,
+}
+
+impl Tpdf {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    /// Next dither value in LSBs, triangular on (-1, 1).
+    pub fn next_lsb(&mut self) -> f32 {
+        self.uniform() + self.uniform() - 1.0
+    }
+
+    /// Uniform on [0, 1).
+    fn uniform(&mut self) -> f32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        (x >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+/// f32 in [-1, 1] to i16, adding `dither` LSBs before rounding. Clamps.
+pub fn f32_to_i16(sample: f32, dither: f32) -> i16 {
+    (sample * i16::MAX as f32 + dither).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// f32 in [-1, 1] to offset-binary u16 (silence = 32768).
+pub fn f32_to_u16(sample: f32, dither: f32) -> u16 {
+    (f32_to_i16(sample, dither) as i32 + 32768) as u16
+}
+
+/// f32 in [-1, 1] to a 24-bit integer (in an i32), for 24-bit WAV.
+pub fn f32_to_i24(sample: f32, dither: f32) -> i32 {
+    (sample * 8_388_607.0 + dither).round().clamp(-8_388_608.0, 8_388_607.0) as i32
+}
+
+/// f32 in [-1, 1] to i32. Scaled in f64 so full scale stays exact.
+pub fn f32_to_i32(sample: f32, dither: f32) -> i32 {
+    (sample as f64 * i32::MAX as f64 + dither as f64).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32
+}
+
+/// Largest channel count the mixing matrix covers; further source
+/// channels are dropped and further output channels stay silent.
+pub const MAX_MIX_CHANNELS: usize = 8;
+
+/// -3 dB, the ITU fold-down gain for centre and surround channels.
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// How channels are mapped between layouts, from `"channel_mix"` in
+/// `/amsal/settings/audio`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelMix {
+    /// Scale a fold-down so no output channel can exceed full scale.
+    pub normalize: bool,
+    /// Fold the LFE channel into the mains (-3 dB) instead of dropping it.
+    pub lfe: bool,
+    pub upmix: Upmix,
+}
+
+impl Default for ChannelMix {
+    fn default() -> Self {
+        Self { normalize: true, lfe: false, upmix: Upmix::default() }
+    }
+}
+
+/// What an output with more speakers than the source plays on the extra ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Upmix {
+    /// Source channels on their own speakers only (mono on the front pair).
+    #[default]
+    Front,
+    /// Left/right copied to every speaker on that side, their average to
+    /// centre speakers (LFE stays silent).
+    Spread,
+}
+
+impl Upmix {
+    /// Parse a settings name ("front", "spread"). Unknown → None.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "front" => Some(Self::Front),
+            "spread" => Some(Self::Spread),
+            _ => None,
+        }
+    }
+}
+
+impl ChannelMix {
+    /// `{"normalize": true, "lfe": false, "upmix": "front"}`; missing or
+    /// invalid fields take the defaults.
+    pub fn from_value(v: &serde_json::Value) -> Self {
+        let default = Self::default();
+        Self {
+            normalize: v["normalize"].as_bool().unwrap_or(default.normalize),
+            lfe: v["lfe"].as_bool().unwrap_or(default.lfe),
+            upmix: v["upmix"].as_str().and_then(Upmix::from_name).unwrap_or_default(),
+        }
+    }
+
+    /// Packed form, for handing to the output callback through an atomic.
+    pub fn to_bits(self) -> u32 {
+        self.normalize as u32 | (self.lfe as u32) << 1 | (matches!(self.upmix, Upmix::Spread) as u32) << 2
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            normalize: bits & 1 != 0,
+            lfe: bits & 2 != 0,
+            upmix: if bits & 4 != 0 { Upmix::Spread } else { Upmix::Front },
+        }
+    }
+}
+
+/// Standard speaker layout for a channel count (WAVEFORMATEXTENSIBLE order).
+pub fn default_layout(channels: u16) -> u32 {
+    let fl = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+    let layout = match channels {
+        1 => Channels::FRONT_CENTRE,
+        2 => fl,
+        3 => fl | Channels::FRONT_CENTRE,
+        4 => fl | Channels::REAR_LEFT | Channels::REAR_RIGHT,
+        5 => fl | Channels::FRONT_CENTRE | Channels::REAR_LEFT | Channels::REAR_RIGHT,
+        6 => fl | Channels::FRONT_CENTRE | Channels::LFE1 | Channels::REAR_LEFT | Channels::REAR_RIGHT,
+        7 => fl | Channels::FRONT_CENTRE | Channels::LFE1 | Channels::REAR_CENTRE | Channels::SIDE_LEFT | Channels::SIDE_RIGHT,
+        _ => {
+            fl | Channels::FRONT_CENTRE
+                | Channels::LFE1
+                | Channels::REAR_LEFT
+                | Channels::REAR_RIGHT
+                | Channels::SIDE_LEFT
+                | Channels::SIDE_RIGHT
+        }
+    };
+    layout.bits()
+}
+
+/// Speakers left of centre, right of centre, and low-frequency.
+const LEFT: u32 = Channels::FRONT_LEFT.bits()
+    | Channels::REAR_LEFT.bits()
+    | Channels::FRONT_LEFT_CENTRE.bits()
+    | Channels::SIDE_LEFT.bits()
+    | Channels::TOP_FRONT_LEFT.bits()
+    | Channels::TOP_REAR_LEFT.bits()
+    | Channels::REAR_LEFT_CENTRE.bits()
+    | Channels::FRONT_LEFT_WIDE.bits()
+    | Channels::FRONT_LEFT_HIGH.bits();
+const RIGHT: u32 = Channels::FRONT_RIGHT.bits()
+    | Channels::REAR_RIGHT.bits()
+    | Channels::FRONT_RIGHT_CENTRE.bits()
+    | Channels::SIDE_RIGHT.bits()
+    | Channels::TOP_FRONT_RIGHT.bits()
+    | Channels::TOP_REAR_RIGHT.bits()
+    | Channels::REAR_RIGHT_CENTRE.bits()
+    | Channels::FRONT_RIGHT_WIDE.bits()
+    | Channels::FRONT_RIGHT_HIGH.bits();
+const LFE: u32 = Channels::LFE1.bits() | Channels::LFE2.bits();
+
+/// Gain matrix from one layout to another. Fixed size and built without
+/// allocating, so the output callback can rebuild it when the format changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelMatrix {
+    src: usize,
+    dst: usize,
+    /// `gains[out][in]`
+    gains: [[f32; MAX_MIX_CHANNELS]; MAX_MIX_CHANNELS],
+}
+
+impl ChannelMatrix {
+    /// `src_layout` is the source's `Channels` bits; 0 (unknown) or one
+    /// that doesn't match `src_ch` falls back to `default_layout`.
+    pub fn new(src_ch: u16, src_layout: u32, dst_ch: u16, mix: ChannelMix) -> Self {
+        let mut m = Self { src: src_ch.max(1) as usize, dst: dst_ch.max(1) as usize, gains: [[0.0; MAX_MIX_CHANNELS]; MAX_MIX_CHANNELS] };
+        let (src_n, dst_n) = (m.src.min(MAX_MIX_CHANNELS), m.dst.min(MAX_MIX_CHANNELS));
+
+        if src_n == 1 {
+            // Mono plays at full level on the front pair (or everywhere but LFE)
+            let dst_layout = default_layout(dst_n as u16);
+            for (out, speaker) in speakers(dst_layout).enumerate().take(dst_n) {
+                let on = match mix.upmix {
+                    _ if dst_n <= 2 => true,
+                    Upmix::Front => speaker & (Channels::FRONT_LEFT | Channels::FRONT_RIGHT).bits() != 0,
+                    Upmix::Spread => speaker & LFE == 0,
+                };
+                m.gains[out][0] = if on { 1.0 } else { 0.0 };
+            }
+            return m;
+        }
+
+        let src_layout = if src_layout.count_ones() as usize == m.src { src_layout } else { default_layout(m.src as u16) };
+        if dst_n == 1 {
+            // Mono output: the average of the stereo fold-down
+            let stereo = Self::new(src_ch, src_layout, 2, ChannelMix { normalize: false, ..mix });
+            for i in 0..src_n {
+                m.gains[0][i] = (stereo.gains[0][i] + stereo.gains[1][i]) * 0.5;
+            }
+        } else {
+            let dst_layout = default_layout(dst_n as u16);
+            for (i, speaker) in speakers(src_layout).enumerate().take(src_n) {
+                for (target, gain) in fold(speaker, dst_layout, mix.lfe).into_iter().flatten() {
+                    m.gains[position(dst_layout, target)][i] += gain;
+                }
+            }
+            if mix.upmix == Upmix::Spread {
+                m.spread(src_layout, dst_layout);
+            }
+        }
+
+        if mix.normalize {
+            let loudest = m.gains[..dst_n].iter().map(|row| row.iter().map(|g| g.abs()).sum::<f32>()).fold(0.0f32, f32::max);
+            if loudest > 1.0 {
+                for row in &mut m.gains {
+                    for g in row.iter_mut() {
+                        *g /= loudest;
+                    }
+                }
+            }
+        }
+        m
+    }
+
+    /// Fill output speakers the source doesn't have from its front pair.
+    fn spread(&mut self, src_layout: u32, dst_layout: u32) {
+        let front = (Channels::FRONT_LEFT | Channels::FRONT_RIGHT).bits();
+        if src_layout & front != front {
+            return;
+        }
+        let (l, r) = (position(src_layout, Channels::FRONT_LEFT.bits()), position(src_layout, Channels::FRONT_RIGHT.bits()));
+        for (out, speaker) in speakers(dst_layout).enumerate().take(self.dst.min(MAX_MIX_CHANNELS)) {
+            if src_layout & speaker != 0 || speaker & LFE != 0 {
+                continue;
+            }
+            let row = &mut self.gains[out];
+            if speaker & LEFT != 0 {
+                row[l] = 1.0;
+            } else if speaker & RIGHT != 0 {
+                row[r] = 1.0;
+            } else {
+                row[l] = 0.5;
+                row[r] = 0.5;
+            }
+        }
+    }
+
+    /// Mix interleaved `src` frames into `dst`, one output frame per
+    /// `dst` frame (missing source frames are silence).
+    pub fn apply(&self, src: &[f32], dst: &mut [f32]) {
+        let (src_n, dst_n) = (self.src.min(MAX_MIX_CHANNELS), self.dst.min(MAX_MIX_CHANNELS));
+        for (f, out) in dst.chunks_exact_mut(self.dst).enumerate() {
+            let frame = src.get(f * self.src..(f + 1) * self.src).unwrap_or(&[]);
+            for (o, sample) in out.iter_mut().enumerate() {
+                *sample = if o < dst_n {
+                    frame.iter().take(src_n).zip(&self.gains[o]).map(|(s, g)| s * g).sum()
+                } else {
+                    0.0
+                };
+            }
+        }
+    }
+}
+
+/// Single-speaker bits of a layout, in channel order.
+fn speakers(layout: u32) -> impl Iterator<Item = u32> {
+    (0..32).map(|bit| 1u32 << bit).filter(move |b| layout & b != 0)
+}
+
+/// Channel index of `speaker` within `layout`.
+fn position(layout: u32, speaker: u32) -> usize {
+    (layout & (speaker - 1)).count_ones() as usize
+}
+
+/// Where a source speaker goes on an output layout: itself if present,
+/// else ITU fold-down targets (at most two).
+fn fold(speaker: u32, dst: u32, lfe: bool) -> [Option<(u32, f32)>; 2] {
+    let fl = Channels::FRONT_LEFT.bits();
+    let fr = Channels::FRONT_RIGHT.bits();
+    let pair = |l: u32, r: u32, g: f32| [Some((l, g)), Some((r, g))];
+    let either = |alt: u32, main: u32| if dst & alt != 0 { [Some((alt, 1.0)), None] } else { [Some((main, MINUS_3DB)), None] };
+
+    if dst & speaker != 0 {
+        return [Some((speaker, 1.0)), None];
+    }
+    let rl = Channels::REAR_LEFT.bits();
+    let rr = Channels::REAR_RIGHT.bits();
+    let sl = Channels::SIDE_LEFT.bits();
+    let sr = Channels::SIDE_RIGHT.bits();
+    match Channels::from_bits_truncate(speaker) {
+        Channels::FRONT_CENTRE => pair(fl, fr, MINUS_3DB),
+        c if c.bits() & LFE != 0 => {
+            if lfe { pair(fl, fr, MINUS_3DB) } else { [None, None] }
+        }
+        Channels::SIDE_LEFT => either(rl, fl),
+        Channels::SIDE_RIGHT => either(rr, fr),
+        Channels::REAR_LEFT => either(sl, fl),
+        Channels::REAR_RIGHT => either(sr, fr),
+        Channels::REAR_CENTRE if dst & rl != 0 => pair(rl, rr, MINUS_3DB),
+        Channels::REAR_CENTRE if dst & sl != 0 => pair(sl, sr, MINUS_3DB),
+        Channels::FRONT_LEFT_CENTRE => [Some((fl, 1.0)), None],
+        Channels::FRONT_RIGHT_CENTRE => [Some((fr, 1.0)), None],
+        _ if speaker & LEFT != 0 => [Some((fl, MINUS_3DB)), None],
+        _ if speaker & RIGHT != 0 => [Some((fr, MINUS_3DB)), None],
+        // Rear and top centres
+        _ => pair(fl, fr, 0.5),
+    }
+}
+
+/// Adapt interleaved samples between different channel counts, assuming
+/// standard layouts and the default `ChannelMix`. Mono is duplicated to the
+/// front pair; more channels are folded down per ITU.
+pub fn adapt_channels(src: &[f32], src_ch: u16, dst: &mut [f32], dst_ch: u16) {
+    ChannelMatrix::new(src_ch, 0, dst_ch, ChannelMix::default()).apply(src, dst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_scale_and_clipping() {
+        assert_eq!(f32_to_i16(1.0, 0.0), i16::MAX);
+        assert_eq!(f32_to_i16(-1.5, 0.0), i16::MIN);
+        assert_eq!(f32_to_i16(0.0, 0.0), 0);
+        assert_eq!(f32_to_u16(0.0, 0.0), 32768);
+        assert_eq!(f32_to_u16(-1.0, 0.0), 1);
+        assert_eq!(f32_to_i32(1.0, 0.0), i32::MAX);
+        assert_eq!(f32_to_i32(2.0, 0.0), i32::MAX);
+        assert_eq!(f32_to_i24(1.0, 0.0), 8_388_607);
+        assert_eq!(f32_to_i24(-1.0, 0.0), -8_388_607);
+    }
+
+    #[test]
+    fn tpdf_is_bounded_and_centered() {
+        let mut tpdf = Tpdf::new(1);
+        let values: Vec<f32> = (0..100_000).map(|_| tpdf.next_lsb()).collect();
+        assert!(values.iter().all(|v| v.abs() < 1.0));
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!(mean.abs() < 0.01);
+        // Triangular on (-1, 1): variance 1/6
+        let var = values.iter().map(|v| v * v).sum::<f32>() / values.len() as f32;
+        assert!((var - 1.0 / 6.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn surround_folds_down_per_itu() {
+        // One 5.1 frame: FL FR FC LFE RL RR
+        let frame = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        let raw = ChannelMix { normalize: false, ..ChannelMix::default() };
+        let mut out = [0.0; 2];
+        ChannelMatrix::new(6, 0, 2, raw).apply(&frame, &mut out);
+        let c = MINUS_3DB;
+        assert!((out[0] - (0.1 + c * 0.3 + c * 0.5)).abs() < 1e-6);
+        assert!((out[1] - (0.2 + c * 0.3 + c * 0.6)).abs() < 1e-6);
+
+        // Normalized (the default) so a full-scale 5.1 frame can't clip
+        ChannelMatrix::new(6, 0, 2, ChannelMix::default()).apply(&[1.0; 6], &mut out);
+        assert!((out[0] - 1.0).abs() < 1e-6 && (out[1] - 1.0).abs() < 1e-6);
+
+        // LFE only when asked for
+        let lfe_only = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+        ChannelMatrix::new(6, 0, 2, raw).apply(&lfe_only, &mut out);
+        assert_eq!(out, [0.0, 0.0]);
+        ChannelMatrix::new(6, 0, 2, ChannelMix { lfe: true, ..raw }).apply(&lfe_only, &mut out);
+        assert!((out[0] - c).abs() < 1e-6);
+
+        // 7.1 side channels fold onto a 5.1 output's rears at full level
+        let mut side = [0.0; 8];
+        side[6] = 1.0; // SL
+        let mut out51 = [0.0; 6];
+        ChannelMatrix::new(8, 0, 6, raw).apply(&side, &mut out51);
+        assert_eq!(out51, [0.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+
+        // A reported layout wins over the default: 4 channels as FL FR FC LFE
+        let layout = (Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE | Channels::LFE1).bits();
+        ChannelMatrix::new(4, layout, 2, raw).apply(&[0.0, 0.0, 1.0, 0.0], &mut out);
+        assert!((out[0] - c).abs() < 1e-6 && (out[1] - c).abs() < 1e-6);
+
+        // Stereo to mono averages; mono to stereo duplicates
+        let mut mono = [0.0; 1];
+        adapt_channels(&[0.2, 0.6], 2, &mut mono, 1);
+        assert!((mono[0] - 0.4).abs() < 1e-6);
+        adapt_channels(&[0.5], 1, &mut out, 2);
+        assert_eq!(out, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn upmix_policies_and_settings() {
+        let mut out = [0.0; 6];
+        adapt_channels(&[0.2, 0.6], 2, &mut out, 6);
+        assert_eq!(out, [0.2, 0.6, 0.0, 0.0, 0.0, 0.0]);
+
+        let spread = ChannelMix::from_value(&serde_json::json!({"upmix": "spread"}));
+        assert_eq!(spread.upmix, Upmix::Spread);
+        ChannelMatrix::new(2, 0, 6, spread).apply(&[0.2, 0.6], &mut out);
+        assert_eq!(out, [0.2, 0.6, 0.4, 0.0, 0.2, 0.6]);
+        ChannelMatrix::new(1, 0, 6, spread).apply(&[0.5], &mut out);
+        assert_eq!(out, [0.5, 0.5, 0.5, 0.0, 0.5, 0.5]);
+
+        for mix in [ChannelMix::default(), spread, ChannelMix { normalize: false, lfe: true, upmix: Upmix::Front }] {
+            assert_eq!(ChannelMix::from_bits(mix.to_bits()), mix);
+        }
+        assert_eq!(ChannelMix::from_value(&serde_json::Value::Null), ChannelMix::default());
+    }
+
+    #[test]
+    fn dither_preserves_sub_lsb_level() {
+        // A DC level of 0.3 LSB truncates to silence without dither;
+        // dithered, the average output keeps it
+        let level = 0.3 / i16::MAX as f32;
+        assert_eq!(f32_to_i16(level, 0.0), 0);
+        let mut tpdf = Tpdf::new(7);
+        let n = 100_000;
+        let sum: i64 = (0..n).map(|_| f32_to_i16(level, tpdf.next_lsb()) as i64).sum();
+        assert!((sum as f64 / n as f64 - 0.3).abs() < 0.02);
+    }
+
+    #[test]
+    fn adapt_mono_to_stereo() {
+        let src = [1.0, 2.0, 3.0]; // 3 mono frames
+        let mut dst = [0.0f32; 6]; // 3 stereo frames
+        adapt_channels(&src, 1, &mut dst, 2);
+        assert_eq!(dst, [1.0, 1.0, 2.0, 2.0, 3.0, 3.0]);
+    }
+
+    #[test]
+    fn adapt_stereo_to_mono() {
+        let src = [1.0, 3.0, 2.0, 4.0]; // 2 stereo frames
+        let mut dst = [0.0f32; 2]; // 2 mono frames
+        adapt_channels(&src, 2, &mut dst, 1);
+        assert_eq!(dst, [2.0, 3.0]); // averages
+    }
+
+    #[test]
+    fn adapt_same_channels_passthrough() {
+        let src = [1.0, 2.0, 3.0, 4.0]; // 2 stereo frames
+        let mut dst = [0.0f32; 4];
+        adapt_channels(&src, 2, &mut dst, 2);
+        assert_eq!(dst, [1.0, 2.0, 3.0, 4.0]);
+    }
+}
@@ -0,0 +1,387 @@
+//! FIR convolution with a measured impulse response (room correction,
+//! headphone targets, cabinet/reverb IRs) for the EQ chain.
+//!
+//! Uniformly partitioned overlap-save: the IR is cut into `BLOCK`-frame
+//! partitions whose spectra are precomputed, each input block is
+//! transformed once and multiplied against every partition through a
+//! frequency-domain delay line. Latency is one block; the cost per block
+//! grows with IR length but not with the block count. Everything is sized
+//! at construction, so `process` never allocates.
+//!
+//! Decoded IRs are cached by path and output rate: every EQ edit rebuilds
+//! the chain, and re-decoding the file each time would make slider drags
+//! hit the disk.
+
+use std::any::Any;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+
+use super::decode::TrackDecoder;
+use super::dsp::AudioFilter;
+use super::fft::{Complex, Fft};
+use super::resample::{ResampleQuality, Resampler};
+
+/// Partition and processing block, in frames (also the added latency).
+pub const BLOCK: usize = 512;
+/// Longer impulse responses are truncated (with a warning).
+pub const MAX_IR_SECONDS: u32 = 4;
+
+/// Input frames of silence around an IR being resampled.
+const RESAMPLE_MARGIN: usize = 64;
+/// Half of `ResampleQuality::High`'s kernel, in input frames.
+const KERNEL_HALF: usize = 32;
+/// Smallest transform used for the drawn response (finer bins for short IRs).
+const RESPONSE_FFT_MIN: usize = 8192;
+/// Decoded IRs kept (least recently used dropped first).
+const IR_CACHE_ENTRIES: usize = 4;
+
+/// A decoded IR, valid while the file's modification time is unchanged.
+struct CachedIr {
+    path: String,
+    sample_rate: u32,
+    modified: Option<SystemTime>,
+    samples: Arc<Vec<f32>>,
+    channels: usize,
+}
+
+/// Most recently used last.
+static IR_CACHE: Mutex<Vec<CachedIr>> = Mutex::new(Vec::new());
+
+/// `load_impulse_response` through the IR cache. A file rewritten since it
+/// was cached is decoded again.
+pub fn cached_impulse_response(path: &str, sample_rate: u32) -> Result<(Arc<Vec<f32>>, usize), String> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let same = |c: &CachedIr| c.path == path && c.sample_rate == sample_rate;
+    {
+        let mut cache = IR_CACHE.lock();
+        if let Some(i) = cache.iter().position(|c| same(c) && c.modified == modified) {
+            let entry = cache.remove(i);
+            let hit = (Arc::clone(&entry.samples), entry.channels);
+            cache.push(entry);
+            return Ok(hit);
+        }
+    }
+    // Decoded unlocked: other chains build meanwhile
+    let (samples, channels) = load_impulse_response(path, sample_rate)?;
+    let samples = Arc::new(samples);
+    let mut cache = IR_CACHE.lock();
+    cache.retain(|c| !same(c));
+    if cache.len() >= IR_CACHE_ENTRIES {
+        cache.remove(0);
+    }
+    cache.push(CachedIr { path: path.to_string(), sample_rate, modified, samples: Arc::clone(&samples), channels });
+    Ok((samples, channels))
+}
+
+/// Decode an impulse response file at the output `sample_rate`.
+/// Returns interleaved samples and the file's channel count.
+pub fn load_impulse_response(path: &str, sample_rate: u32) -> Result<(Vec<f32>, usize), String> {
+    let mut track = TrackDecoder::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let channels = track.channels.max(1) as usize;
+    let max_frames = (MAX_IR_SECONDS * track.sample_rate) as usize;
+
+    let mut samples = Vec::new();
+    while samples.len() < max_frames * channels {
+        match track.decode_next(&mut samples).map_err(|e| format!("{}: {}", path, e))? {
+            Some(_) => {}
+            None => break,
+        }
+    }
+    if samples.len() > max_frames * channels {
+        log::warn!("amsal: impulse response {} truncated to {} s", path, MAX_IR_SECONDS);
+        samples.truncate(max_frames * channels);
+    }
+    if samples.is_empty() {
+        return Err(format!("{}: impulse response is empty", path));
+    }
+
+    if track.sample_rate != sample_rate && track.sample_rate > 0 {
+        samples = resample_ir(&samples, channels, track.sample_rate, sample_rate);
+    }
+    Ok((samples, channels))
+}
+
+/// Resample an IR, keeping the interpolation kernel's pre-ringing ahead
+/// of its first sample (the IR starts up to half a kernel later) and the
+/// ringing after its last.
+fn resample_ir(ir: &[f32], channels: usize, src_rate: u32, dst_rate: u32) -> Vec<f32> {
+    let frames = ir.len() / channels;
+    let mut padded = vec![0.0; (RESAMPLE_MARGIN + frames + RESAMPLE_MARGIN) * channels];
+    padded[RESAMPLE_MARGIN * channels..(RESAMPLE_MARGIN + frames) * channels].copy_from_slice(ir);
+
+    let mut rs = Resampler::new(src_rate, dst_rate, channels as u16, ResampleQuality::High);
+    let mut out = Vec::with_capacity(padded.len() * dst_rate as usize / src_rate as usize + channels);
+    rs.process(&padded, &mut out);
+    // Silence before the kernel's reach
+    let skip = (RESAMPLE_MARGIN - KERNEL_HALF) * dst_rate as usize / src_rate as usize;
+    // More taps per second at a higher rate: keep the response level
+    let scale = src_rate as f32 / dst_rate as f32;
+    out.iter().skip(skip * channels).map(|s| s * scale).collect()
+}
+
+/// Partitioned convolution of each channel with one IR channel
+/// (output channel `c` uses IR channel `c % ir_channels`), blended with
+/// the equally delayed dry signal by `mix`.
+pub struct Convolver {
+    channels: usize,
+    ir_channels: usize,
+    partitions: usize,
+    fft: Fft,
+    /// IR spectra, `[ir_channel][partition][bin]`, bins 0..=BLOCK.
+    ir: Vec<Complex>,
+    /// Spectra of recent input blocks, `[channel][partition][bin]`.
+    fdl: Vec<Complex>,
+    /// Partition slot the newest input block goes into.
+    fdl_pos: usize,
+    /// Last two input blocks per channel (`2 * BLOCK` each).
+    input: Vec<f32>,
+    /// Output of the last full block per channel, played during the next.
+    output: Vec<f32>,
+    /// Dry input one block back, to line up with the wet path.
+    dry: Vec<f32>,
+    /// Frames gathered into the current block.
+    fill: usize,
+    scratch: Vec<Complex>,
+    acc: Vec<Complex>,
+    wet: f32,
+    dry_gain: f32,
+    /// Half spectrum of IR channel 0 for `response`, and its size.
+    curve: Vec<Complex>,
+    curve_size: usize,
+    /// Whether a channel-count mismatch has been logged.
+    mismatch_logged: bool,
+}
+
+impl Convolver {
+    /// `ir` is interleaved with `ir_channels` channels, already at the
+    /// output rate. `mix` 0..1 blends dry to wet; `gain_db` scales the wet path.
+    pub fn new(ir: &[f32], ir_channels: usize, channels: u16, mix: f32, gain_db: f32) -> Self {
+        let ir_channels = ir_channels.max(1);
+        let channels = channels.max(1) as usize;
+        let ir_frames = (ir.len() / ir_channels).max(1);
+        let partitions = ir_frames.div_ceil(BLOCK);
+        let size = 2 * BLOCK;
+        let bins = BLOCK + 1;
+        let fft = Fft::new(size);
+
+        let mut spectra = vec![Complex::ZERO; ir_channels * partitions * bins];
+        let mut buf = vec![Complex::ZERO; size];
+        for c in 0..ir_channels {
+            for p in 0..partitions {
+                buf.fill(Complex::ZERO);
+                for (i, slot) in buf[..BLOCK].iter_mut().enumerate() {
+                    let frame = p * BLOCK + i;
+                    if frame < ir_frames {
+                        slot.re = ir.get(frame * ir_channels + c).copied().unwrap_or(0.0);
+                    }
+                }
+                fft.forward(&mut buf);
+                let at = (c * partitions + p) * bins;
+                spectra[at..at + bins].copy_from_slice(&buf[..bins]);
+            }
+        }
+
+        let curve_size = (2 * ir_frames).next_power_of_two().max(RESPONSE_FFT_MIN);
+        let mut curve: Vec<Complex> = (0..curve_size)
+            .map(|i| Complex::new(if i < ir_frames { ir.get(i * ir_channels).copied().unwrap_or(0.0) } else { 0.0 }, 0.0))
+            .collect();
+        Fft::new(curve_size).forward(&mut curve);
+        curve.truncate(curve_size / 2 + 1);
+
+        let mix = mix.clamp(0.0, 1.0);
+        Self {
+            channels,
+            ir_channels,
+            partitions,
+            fft,
+            ir: spectra,
+            fdl: vec![Complex::ZERO; channels * partitions * bins],
+            fdl_pos: 0,
+            input: vec![0.0; channels * size],
+            output: vec![0.0; channels * BLOCK],
+            dry: vec![0.0; channels * BLOCK],
+            fill: 0,
+            scratch: vec![Complex::ZERO; size],
+            acc: vec![Complex::ZERO; bins],
+            wet: mix * 10.0f32.powf(gain_db / 20.0),
+            dry_gain: 1.0 - mix,
+            curve,
+            curve_size,
+            mismatch_logged: false,
+        }
+    }
+
+    /// Convolve the block gathered in `input`, leaving it in `output`.
+    fn run_block(&mut self) {
+        let size = 2 * BLOCK;
+        let bins = BLOCK + 1;
+        let parts = self.partitions;
+        for c in 0..self.channels {
+            let window = &mut self.input[c * size..(c + 1) * size];
+            for (slot, &x) in self.scratch.iter_mut().zip(window.iter()) {
+                *slot = Complex::new(x, 0.0);
+            }
+            // Keep the newest block as the first half of the next window
+            window.copy_within(BLOCK.., 0);
+            self.fft.forward(&mut self.scratch);
+            let fdl = &mut self.fdl[c * parts * bins..(c + 1) * parts * bins];
+            fdl[self.fdl_pos * bins..(self.fdl_pos + 1) * bins].copy_from_slice(&self.scratch[..bins]);
+
+            let ir_c = c % self.ir_channels;
+            let ir = &self.ir[ir_c * parts * bins..(ir_c + 1) * parts * bins];
+            self.acc.fill(Complex::ZERO);
+            for p in 0..parts {
+                // Partition p meets the input block from p blocks ago
+                let slot = (self.fdl_pos + parts - p) % parts;
+                let x = &fdl[slot * bins..(slot + 1) * bins];
+                let h = &ir[p * bins..(p + 1) * bins];
+                for ((a, x), h) in self.acc.iter_mut().zip(x).zip(h) {
+                    *a = *a + *x * *h;
+                }
+            }
+
+            // Real signal: rebuild the upper half as the mirror image
+            self.scratch[..bins].copy_from_slice(&self.acc);
+            for k in 1..BLOCK {
+                self.scratch[size - k] = self.acc[k].conj();
+            }
+            self.fft.inverse(&mut self.scratch);
+            // Overlap-save: the first half is wrapped-around garbage
+            for (out, s) in self.output[c * BLOCK..(c + 1) * BLOCK].iter_mut().zip(&self.scratch[BLOCK..]) {
+                *out = s.re;
+            }
+        }
+        self.fdl_pos = (self.fdl_pos + 1) % parts;
+    }
+}
+
+impl AudioFilter for Convolver {
+    fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
+        // The delay lines exist for the channels the convolver was built
+        // with; a `set_dsp` chain keeps them across a format change. Those
+        // channels stay convolved, any others pass through unfiltered.
+        let ch = channels.max(1) as usize;
+        if ch != self.channels && !self.mismatch_logged {
+            log::warn!(
+                "amsal: convolver built for {} channels got {}; convolving the first {}",
+                self.channels,
+                ch,
+                ch.min(self.channels)
+            );
+            self.mismatch_logged = true;
+        }
+        let shared = ch.min(self.channels);
+        for frame in samples.chunks_exact_mut(ch) {
+            for (c, s) in frame[..shared].iter_mut().enumerate() {
+                let x = *s;
+                let dry = std::mem::replace(&mut self.dry[c * BLOCK + self.fill], x);
+                self.input[c * 2 * BLOCK + BLOCK + self.fill] = x;
+                *s = self.output[c * BLOCK + self.fill] * self.wet + dry * self.dry_gain;
+            }
+            self.fill += 1;
+            if self.fill == BLOCK {
+                self.fill = 0;
+                self.run_block();
+            }
+        }
+    }
+
+    fn carry_state(&mut self, previous: &dyn AudioFilter) {
+        let Some(prev) = previous.as_any().and_then(|p| p.downcast_ref::<Convolver>()) else {
+            return;
+        };
+        // Pending audio carries over; the old IR's tail doesn't
+        if prev.channels == self.channels {
+            self.input.copy_from_slice(&prev.input);
+            self.output.copy_from_slice(&prev.output);
+            self.dry.copy_from_slice(&prev.dry);
+            self.fill = prev.fill;
+        }
+    }
+
+    /// The IR's own response (channel 0) with mix and gain; the block
+    /// latency is left out.
+    fn response(&self, freq_hz: f64, sample_rate: u32) -> Option<(f64, f64)> {
+        let pos = (freq_hz / sample_rate.max(1) as f64 * self.curve_size as f64).max(0.0);
+        let i = (pos as usize).min(self.curve.len() - 1);
+        let j = (i + 1).min(self.curve.len() - 1);
+        let t = (pos - i as f64).min(1.0) as f32;
+        let (a, b) = (self.curve[i], self.curve[j]);
+        let h = Complex::new(a.re + (b.re - a.re) * t, a.im + (b.im - a.im) * t);
+        Some((
+            (h.re * self.wet + self.dry_gain) as f64,
+            (h.im * self.wet) as f64,
+        ))
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time-domain reference: y[n] = Σ h[k]·x[n-k].
+    fn direct(x: &[f32], h: &[f32]) -> Vec<f32> {
+        (0..x.len()).map(|n| h.iter().enumerate().take(n + 1).map(|(k, hk)| hk * x[n - k]).sum()).collect()
+    }
+
+    #[test]
+    fn matches_direct_convolution_after_one_block() {
+        // A few partitions' worth of decaying noise-like IR
+        let h: Vec<f32> = (0..1500).map(|n| (n as f32 * 1.7).sin() * (-(n as f32) / 400.0).exp()).collect();
+        let x: Vec<f32> = (0..6000).map(|n| ((n * 7919 % 1000) as f32 / 500.0 - 1.0) * 0.5).collect();
+        let want = direct(&x, &h);
+
+        let mut conv = Convolver::new(&h, 1, 1, 1.0, 0.0);
+        let mut got = x.clone();
+        // Odd chunk sizes: blocks don't line up with callbacks
+        for chunk in got.chunks_mut(333) {
+            conv.process(chunk, 1, 48000);
+        }
+        for n in BLOCK..x.len() {
+            assert!((got[n] - want[n - BLOCK]).abs() < 1e-3, "frame {}: {} vs {}", n, got[n], want[n - BLOCK]);
+        }
+    }
+
+    #[test]
+    fn stereo_mapping_mix_and_response() {
+        // Mono IR = 0.5 gain applies to both channels
+        let mut conv = Convolver::new(&[0.5], 1, 2, 1.0, 0.0);
+        let mut samples = vec![1.0; 4 * BLOCK];
+        conv.process(&mut samples, 2, 48000);
+        assert!(samples[..2 * BLOCK].iter().all(|s| *s == 0.0));
+        assert!(samples[2 * BLOCK..].iter().all(|s| (s - 0.5).abs() < 1e-5));
+
+        // Stereo IR: left passes, right is muted; half mix keeps half the dry
+        let mut conv = Convolver::new(&[1.0, 0.0], 2, 2, 0.5, 0.0);
+        let mut samples = vec![1.0; 4 * BLOCK];
+        conv.process(&mut samples, 2, 48000);
+        let last = &samples[samples.len() - 2..];
+        assert!((last[0] - 1.0).abs() < 1e-5 && (last[1] - 0.5).abs() < 1e-5, "{:?}", last);
+
+        // The drawn response of a 2-tap average: unity at DC, null at Nyquist
+        let conv = Convolver::new(&[0.5, 0.5], 1, 1, 1.0, 0.0);
+        let mag = |f: f64| {
+            let (re, im) = conv.response(f, 48000).unwrap();
+            (re * re + im * im).sqrt()
+        };
+        assert!((mag(0.0) - 1.0).abs() < 1e-4);
+        assert!((mag(12000.0) - 0.5f64.sqrt()).abs() < 1e-3);
+        assert!(mag(24000.0) < 1e-3);
+    }
+
+    #[test]
+    fn keeps_convolving_after_a_channel_change() {
+        // Built for mono, fed stereo: the first channel is still filtered
+        let mut conv = Convolver::new(&[0.5], 1, 1, 1.0, 0.0);
+        let mut samples = vec![1.0; 4 * BLOCK];
+        conv.process(&mut samples, 2, 48000);
+        let last = &samples[samples.len() - 2..];
+        assert!((last[0] - 0.5).abs() < 1e-5 && last[1] == 1.0, "{:?}", last);
+    }
+}
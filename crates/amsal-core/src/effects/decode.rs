@@ -0,0 +1,536 @@
+//! Track decoding shared by playback and analysis.
+//!
+//! Wraps symphonia's demuxer + decoder for one file (or HTTP URL) and
+//! trims encoder delay/padding so consecutive tracks join sample-accurately.
+//! Not tied to an output device — the cpal pipeline, the offline render
+//! backend and analysis jobs all pull interleaved f32 packets through
+//! `TrackDecoder::decode_next`, which also honours per-track trim points
+//! and A-B loops. `Crossfade` mixes a second decoder's head over the
+//! current track's tail for both playback backends.
+
+use std::fs::File;
+use std::path::Path;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision};
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use super::dsp::FadeCurve;
+
+/// Fade across an A-B loop's seam, so the jump back doesn't click.
+const LOOP_SEAM_MS: u64 = 5;
+
+/// Encoder delay/padding trim for formats the decoder doesn't trim itself
+/// (iTunSMPB in MP4/M4A). Counts are in frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct GaplessTrim {
+    /// Encoder delay: stream frames ahead of the first valid one.
+    delay: u64,
+    /// Frames still to drop from the start of the stream.
+    skip: u64,
+    /// Valid frames left before the end padding (None = unknown, no trim).
+    remaining: Option<u64>,
+    /// Total valid frames in the stream (for re-deriving `remaining` on seek).
+    total: Option<u64>,
+}
+
+impl GaplessTrim {
+    fn new(delay: u64, total: Option<u64>) -> Self {
+        Self { delay, skip: delay, remaining: total, total }
+    }
+
+    /// Restrict a decoded block of `frames` frames to its valid range.
+    /// Returns (first_frame, frame_count).
+    fn apply(&mut self, frames: usize) -> (usize, usize) {
+        let start = (self.skip.min(frames as u64)) as usize;
+        self.skip -= start as u64;
+        let mut count = frames - start;
+        if let Some(rem) = self.remaining.as_mut() {
+            count = count.min(*rem as usize);
+            *rem -= count as u64;
+        }
+        (start, count)
+    }
+
+    /// Stream frame holding valid frame `frame` (what the demuxer seeks to).
+    fn stream_frame(&self, frame: u64) -> u64 {
+        frame + self.delay
+    }
+
+    /// Re-arm after a seek to valid frame `frame`, which decoding reaches
+    /// after dropping `skip` frames (the demuxer lands at or before the target).
+    fn seek(&mut self, frame: u64, skip: u64) {
+        self.skip = skip;
+        self.remaining = self.total.map(|t| t.saturating_sub(frame));
+    }
+}
+
+/// Parse an iTunes `iTunSMPB` gapless tag.
+///
+/// Format: `" 00000000 00000840 000001CA 00000000003F31F6 ..."` — hex fields
+/// of which #2 is encoder delay, #3 end padding, #4 valid sample count.
+/// Returns (delay, padding, valid_frames).
+fn parse_itunsmpb(value: &str) -> Option<(u32, u32, u64)> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let delay = u32::from_str_radix(fields[1], 16).ok()?;
+    let padding = u32::from_str_radix(fields[2], 16).ok()?;
+    let frames = u64::from_str_radix(fields[3], 16).ok()?;
+    Some((delay, padding, frames))
+}
+
+/// Find an `iTunSMPB` tag in a metadata revision.
+fn itunsmpb_from_revision(rev: &MetadataRevision) -> Option<(u32, u32, u64)> {
+    rev.tags()
+        .iter()
+        .find(|t| t.key.to_ascii_lowercase().ends_with("itunsmpb"))
+        .and_then(|t| parse_itunsmpb(&t.value.to_string()))
+}
+
+/// An opened track: demuxer + decoder + gapless trim state.
+pub(crate) struct TrackDecoder {
+    pub(crate) path: String,
+    /// Linear normalization gain applied to decoded samples.
+    pub(crate) gain: f32,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    /// Unit of the track's timestamps (None = frames).
+    time_base: Option<TimeBase>,
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u32,
+    /// Speaker layout (symphonia `Channels` bits; 0 = not reported).
+    pub(crate) layout: u32,
+    pub(crate) duration_ms: u64,
+    /// Valid frames in the track (up to the end trim point), when known.
+    pub(crate) total_frames: Option<u64>,
+    /// Frame playback starts at (the start trim point, else 0).
+    pub(crate) start_frame: u64,
+    /// Frame playback ends at (the end trim point; None = end of stream).
+    end_frame: Option<u64>,
+    /// Position of the next frame decoded.
+    frame: u64,
+    /// Frames left of the fade-in after an A-B loop jumped back.
+    fade_in: u64,
+    trim: GaplessTrim,
+}
+
+impl TrackDecoder {
+    /// Open a file (or HTTP URL) and prepare its default track for decoding.
+    pub(crate) fn open(file_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut hint = Hint::new();
+
+        let mss = if file_path.starts_with("http://") || file_path.starts_with("https://") {
+            #[cfg(feature = "http")]
+            {
+                if let Some(ext) = super::http::extension_from_url(file_path) {
+                    hint.with_extension(&ext);
+                }
+                super::http::open_url(file_path)?
+            }
+            #[cfg(not(feature = "http"))]
+            {
+                return Err("HTTP support requires the 'http' feature".into());
+            }
+        } else {
+            let path = Path::new(file_path);
+            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                hint.with_extension(ext);
+            }
+            let file = File::open(path)?;
+            MediaSourceStream::new(Box::new(file), Default::default())
+        };
+
+        // enable_gapless: readers trim LAME delay/padding and Ogg pre-skip
+        let format_opts = FormatOptions { enable_gapless: true, ..Default::default() };
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            mss,
+            &format_opts,
+            &MetadataOptions::default(),
+        )?;
+
+        let smpb = probed
+            .metadata
+            .get()
+            .and_then(|m| m.current().and_then(itunsmpb_from_revision))
+            .or_else(|| probed.format.metadata().current().and_then(itunsmpb_from_revision));
+
+        let format = probed.format;
+        let track = format.default_track().ok_or("no default track")?;
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
+        let channels = track.codec_params.channels.map(|c| c.count() as u32).unwrap_or(2);
+        let layout = track.codec_params.channels.map(|c| c.bits()).unwrap_or(0);
+
+        let (trim, n_frames) = match smpb {
+            Some((delay, _padding, valid)) if valid > 0 => {
+                (GaplessTrim::new(delay as u64, Some(valid)), Some(valid))
+            }
+            _ => (GaplessTrim::default(), track.codec_params.n_frames),
+        };
+        let duration_ms = n_frames.map(|n| (n * 1000) / sample_rate as u64).unwrap_or(0);
+
+        let decoder = symphonia::default::get_codecs().make(
+            &track.codec_params,
+            &DecoderOptions::default(),
+        )?;
+
+        Ok(Self {
+            path: file_path.to_string(),
+            gain: 1.0,
+            format,
+            decoder,
+            track_id,
+            time_base,
+            sample_rate,
+            channels,
+            layout,
+            duration_ms,
+            total_frames: n_frames,
+            start_frame: 0,
+            end_frame: None,
+            frame: 0,
+            fade_in: 0,
+            trim,
+        })
+    }
+
+    /// Trim points: start playback at `start_ms` and end the track at
+    /// `end_ms` (None = end of stream). Set before the first decode.
+    pub(crate) fn set_range(&mut self, start_ms: u64, end_ms: Option<u64>) {
+        self.end_frame = end_ms.map(|ms| self.ms_to_frames(ms));
+        if let Some(end) = self.end_frame {
+            self.total_frames = Some(self.total_frames.map_or(end, |t| t.min(end)));
+        }
+        if start_ms > 0 {
+            if let Some(frame) = self.seek(start_ms) {
+                self.start_frame = frame;
+            }
+        }
+    }
+
+    /// Decode the next packet, appending its valid interleaved samples to
+    /// `out`. Returns the number of frames appended (0 for packets of other
+    /// tracks or fully trimmed ones), or None at end of stream.
+    pub(crate) fn decode_next(&mut self, out: &mut Vec<f32>) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        if self.end_frame.is_some_and(|end| self.frame >= end) {
+            return Ok(None);
+        }
+        let packet = match self.format.next_packet() {
+            Ok(packet) => packet,
+            Err(symphonia::core::errors::Error::IoError(ref e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if packet.track_id() != self.track_id {
+            return Ok(Some(0));
+        }
+
+        let decoded = self.decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let n_frames = decoded.frames();
+
+        let mut sample_buf = SampleBuffer::<f32>::new(n_frames as u64, spec);
+        sample_buf.copy_interleaved_ref(decoded);
+
+        // Trim encoder delay/padding. MP3 and Vorbis decoders trim packets
+        // themselves (their output is already `dur` frames); others return
+        // the full block and need the packet's trim applied here.
+        let ch = spec.channels.count().max(1);
+        let mut first = 0usize;
+        let mut count = n_frames;
+        let packet_trim = packet.trim_start() as usize + packet.trim_end() as usize;
+        if packet_trim > 0 && n_frames as u64 == packet.block_dur() {
+            first = (packet.trim_start() as usize).min(n_frames);
+            count = n_frames.saturating_sub(packet_trim);
+        }
+        let (skip, valid) = self.trim.apply(count);
+        first += skip;
+        count = valid;
+        if let Some(end) = self.end_frame {
+            count = count.min(end.saturating_sub(self.frame) as usize);
+        }
+        self.frame += count as u64;
+
+        let start = out.len();
+        out.extend_from_slice(&sample_buf.samples()[first * ch..(first + count) * ch]);
+        if self.gain != 1.0 {
+            for s in &mut out[start..] {
+                *s *= self.gain;
+            }
+        }
+        if self.fade_in > 0 {
+            let seam = self.ms_to_frames(LOOP_SEAM_MS).max(1);
+            for frame in out[start..].chunks_exact_mut(ch) {
+                if self.fade_in == 0 {
+                    break;
+                }
+                let gain = (seam - self.fade_in) as f32 / seam as f32;
+                frame.iter_mut().for_each(|s| *s *= gain);
+                self.fade_in -= 1;
+            }
+        }
+        Ok(Some(count))
+    }
+
+    /// A-B loop between `start_ms` and `end_ms`. Once the position reaches
+    /// `end_ms` (or the stream ended, `at_end`), cut `raw` — the block just
+    /// decoded — back to it, fade its last moments out and seek to
+    /// `start_ms`, which fades in. Returns the frame landed on.
+    pub(crate) fn wrap_loop(&mut self, raw: &mut Vec<f32>, (start_ms, end_ms): (u64, u64), at_end: bool) -> Option<u64> {
+        let end = self.ms_to_frames(end_ms);
+        if !at_end && self.frame < end {
+            return None;
+        }
+        // A stream that ended before the loop start can't loop
+        if at_end && self.frame <= self.ms_to_frames(start_ms) {
+            return None;
+        }
+        let ch = self.channels.max(1) as usize;
+        let over = self.frame.saturating_sub(end) as usize;
+        let keep = (raw.len() / ch).saturating_sub(over);
+        raw.truncate(keep * ch);
+        let seam = (self.ms_to_frames(LOOP_SEAM_MS) as usize).clamp(1, keep.max(1));
+        for (i, frame) in raw[keep.saturating_sub(seam) * ch..].chunks_exact_mut(ch).enumerate() {
+            let gain = 1.0 - (i + 1) as f32 / seam as f32;
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+        let frame = self.seek(start_ms)?;
+        self.fade_in = self.ms_to_frames(LOOP_SEAM_MS);
+        Some(frame)
+    }
+
+    fn ms_to_frames(&self, ms: u64) -> u64 {
+        ms * self.sample_rate as u64 / 1000
+    }
+
+    /// Seek to `position_ms`. Returns the frame position landed on. At or
+    /// past the end (trim point included) the track is left ended there.
+    pub(crate) fn seek(&mut self, position_ms: u64) -> Option<u64> {
+        if let Some(total) = self.total_frames.filter(|&t| self.ms_to_frames(position_ms) >= t) {
+            self.end_frame = Some(total);
+            self.frame = total;
+            self.fade_in = 0;
+            return Some(total);
+        }
+        let frame = self.ms_to_frames(position_ms);
+        // Past the encoder delay: valid frame 0 is stream frame `delay`
+        let target = self.trim.stream_frame(frame);
+        let rate = self.sample_rate.max(1) as u64;
+        let time = Time::new(target / rate, (target % rate) as f64 / rate as f64);
+        let seeked = self.format
+            .seek(SeekMode::Accurate, SeekTo::Time { time, track_id: Some(self.track_id) })
+            .ok()?;
+        self.decoder.reset();
+        // The demuxer may land early (on a packet boundary); decode up to the target
+        let early = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        let skip = match self.time_base {
+            Some(tb) => early * tb.numer as u64 * self.sample_rate as u64 / tb.denom.max(1) as u64,
+            None => early,
+        };
+        self.trim.seek(frame, skip);
+        self.frame = frame;
+        self.fade_in = 0;
+        Some(frame)
+    }
+}
+
+/// Incoming track of a running crossfade, mixed into the outgoing tail.
+pub(crate) struct Crossfade {
+    pub(crate) next: TrackDecoder,
+    pub(crate) path: String,
+    /// Incoming samples decoded but not yet mixed (interleaved).
+    pending: Vec<f32>,
+    /// Playback position of the incoming track (start frame plus frames mixed).
+    pub(crate) mixed_frames: u64,
+    /// Fade length and progress, in frames.
+    len: u64,
+    done: u64,
+    curve: FadeCurve,
+    eof: bool,
+}
+
+impl Crossfade {
+    pub(crate) fn new(next: TrackDecoder, path: String, len: u64, curve: FadeCurve) -> Self {
+        let mixed_frames = next.start_frame;
+        Self { next, path, pending: Vec::new(), mixed_frames, len: len.max(1), done: 0, curve, eof: false }
+    }
+
+    /// Mix the incoming head into a block of outgoing samples in place.
+    pub(crate) fn mix(&mut self, out: &mut [f32], channels: usize) {
+        while !self.eof && self.pending.len() < out.len() {
+            match self.next.decode_next(&mut self.pending) {
+                Ok(Some(_)) => {}
+                Ok(None) => self.eof = true,
+                Err(e) => {
+                    log::warn!("amsal: crossfade decode failed for {}: {}", self.path, e);
+                    self.eof = true;
+                }
+            }
+        }
+        let frames = out.len() / channels;
+        let available = (self.pending.len() / channels).min(frames);
+        for (f, frame) in out.chunks_exact_mut(channels).enumerate() {
+            let t = (self.done + f as u64) as f32 / self.len as f32;
+            let (gain_out, gain_in) = self.curve.gains(t);
+            for (c, s) in frame.iter_mut().enumerate() {
+                let incoming = if f < available { self.pending[f * channels + c] } else { 0.0 };
+                *s = *s * gain_out + incoming * gain_in;
+            }
+        }
+        self.pending.drain(..available * channels);
+        self.done += frames as u64;
+        self.mixed_frames += available as u64;
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.done >= self.len
+    }
+}
+
+/// End a crossfade: the incoming track becomes current and its decoded but
+/// unmixed head is appended to `raw`. Returns the outgoing track (with its
+/// frame position and the incoming path).
+pub(crate) fn finish_crossfade(
+    mut xf: Crossfade,
+    track: &mut TrackDecoder,
+    decoded_frames: &mut u64,
+    raw: &mut Vec<f32>,
+) -> (TrackDecoder, u64, String) {
+    let leftover = (xf.pending.len() / xf.next.channels.max(1) as usize) as u64;
+    raw.append(&mut xf.pending);
+    let outgoing = std::mem::replace(track, xf.next);
+    let outgoing_frames = std::mem::replace(decoded_frames, xf.mixed_frames + leftover);
+    (outgoing, outgoing_frames, xf.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_itunsmpb, GaplessTrim, TrackDecoder};
+    use crate::effects::render::write_test_wav;
+    use std::path::Path;
+
+    /// Mono WAV whose every sample holds its own frame index
+    /// (`n / frames`), so decoded positions can be read back.
+    fn write_ramp(path: &Path, rate: u32, frames: u32) {
+        let ramp: Vec<f32> = (0..frames).map(|n| n as f32 / frames as f32).collect();
+        write_test_wav(path, rate, 1, &ramp);
+    }
+
+    /// Decode to the end; returns (first frame index read back, frames decoded).
+    fn drain(track: &mut TrackDecoder, frames: u32) -> (u64, usize) {
+        let mut raw = Vec::new();
+        while track.decode_next(&mut raw).unwrap().is_some() {}
+        ((raw[0] * frames as f32).round() as u64, raw.len())
+    }
+
+    #[test]
+    fn parse_itunsmpb_fields() {
+        let tag = " 00000000 00000840 000001CA 00000000003F31F6 00000000 00000000";
+        assert_eq!(parse_itunsmpb(tag), Some((2112, 458, 0x3F31F6)));
+        assert_eq!(parse_itunsmpb("garbage"), None);
+    }
+
+    #[test]
+    fn gapless_trim_skips_delay_and_padding() {
+        let mut trim = GaplessTrim::new(1500, Some(2000));
+        assert_eq!(trim.apply(1024), (1024, 0)); // All delay
+        assert_eq!(trim.apply(1024), (476, 548));
+        assert_eq!(trim.apply(1024), (0, 1024));
+        assert_eq!(trim.apply(1024), (0, 428)); // Padding cut
+        assert_eq!(trim.apply(1024), (0, 0));
+    }
+
+    #[test]
+    fn gapless_trim_seek_rearms_remaining() {
+        let mut trim = GaplessTrim::new(1500, Some(2000));
+        trim.seek(1800, 0);
+        assert_eq!(trim.apply(1024), (0, 200));
+        assert_eq!(GaplessTrim::default().apply(64), (0, 64));
+    }
+
+    #[test]
+    fn loops_between_points_with_faded_seams() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("ramp.wav");
+        write_ramp(&path, 8000, 8000);
+
+        // A-B loop: cut at B with the seam faded, resumed from A fading in
+        let mut track = TrackDecoder::open(path.to_str().unwrap()).unwrap();
+        let mut played = Vec::new();
+        let mut wraps = Vec::new();
+        while wraps.len() < 2 {
+            let mut raw = Vec::new();
+            assert!(track.decode_next(&mut raw).unwrap().is_some());
+            if let Some(frame) = track.wrap_loop(&mut raw, (100, 200), false) {
+                wraps.push(played.len() + raw.len());
+                assert_eq!(frame, 800);
+            }
+            played.extend_from_slice(&raw);
+        }
+        assert_eq!(wraps[0], 1600);
+        assert_eq!(wraps[1] - wraps[0], 800);
+        assert_eq!(played[wraps[0] - 1], 0.0);
+        assert_eq!(played[wraps[0]], 0.0);
+        let resumed = played[wraps[0] + 100] * 8000.0;
+        assert!((resumed - 900.0).abs() < 1.0, "{}", resumed);
+    }
+
+    #[test]
+    fn seeks_land_on_the_frame_and_end_past_the_end() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("ramp.wav");
+        write_ramp(&path, 8000, 8000);
+
+        let mut track = TrackDecoder::open(path.to_str().unwrap()).unwrap();
+        let mut raw = Vec::new();
+        let first_sample_after = |track: &mut TrackDecoder, ms: u64| {
+            let frame = track.seek(ms).unwrap();
+            let mut raw = Vec::new();
+            while raw.is_empty() {
+                track.decode_next(&mut raw).unwrap().unwrap();
+            }
+            (frame, (raw[0] * 8000.0).round() as u64)
+        };
+        track.decode_next(&mut raw).unwrap();
+        assert_eq!(first_sample_after(&mut track, 0), (0, 0));
+        assert_eq!(first_sample_after(&mut track, 637), (5096, 5096));
+        assert_eq!(first_sample_after(&mut track, 125), (1000, 1000));
+
+        assert_eq!(track.seek(1000), Some(8000));
+        assert_eq!(track.decode_next(&mut raw).unwrap(), None);
+    }
+
+    #[test]
+    fn gapless_seek_skips_the_encoder_delay() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("ramp.wav");
+        write_ramp(&path, 8000, 8000);
+        // As an iTunSMPB tag would: 400 frames of delay, 600 of padding
+        let open = || {
+            let mut track = TrackDecoder::open(path.to_str().unwrap()).unwrap();
+            track.trim = GaplessTrim::new(400, Some(7000));
+            track.total_frames = Some(7000);
+            track
+        };
+        assert_eq!(drain(&mut open(), 8000), (400, 7000));
+
+        let mut track = open();
+        assert_eq!(track.seek(250), Some(2000));
+        // Valid frame 2000 is stream frame 2400; the padding is still cut
+        assert_eq!(drain(&mut track, 8000), (2400, 5000));
+    }
+}
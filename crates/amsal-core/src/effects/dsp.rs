@@ -1,19 +1,51 @@
 //! DSP filter chain — scroll-configured audio processing.
 //!
 //! Filters compose via the AudioFilter trait (same interface: &mut [f32], channels, rate).
-//! The chain is built from a JSON scroll at `/amsal/playback/eq` and hot-swapped
-//! via `Arc<RwLock<Option<DspChain>>>` in the cpal output callback.
+//! The chain is built from a JSON scroll at `/amsal/playback/eq` at the output
+//! stream's actual rate and channel count, rebuilt when that format changes
+//! (filter history carried over), and hot-swapped into the cpal output
+//! callback through a bounded channel (no locks there), where `DspSwitch`
+//! crossfades it in over the chain it replaces.
 
-use std::f32::consts::PI;
+use std::any::Any;
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+use super::convolution::{cached_impulse_response, Convolver};
+use super::dynamics::{Compressor, Limiter};
+use super::stereo::{crossfeed_preset, Balance, Crossfeed, Width};
 
 /// One audio filter operation. Process samples in-place.
+///
+/// `samples` is interleaved, whole frames of `channels` samples, so a filter
+/// may mix across channels (see `stereo`) as well as run per channel.
 pub trait AudioFilter: Send + Sync {
     fn process(&mut self, samples: &mut [f32], channels: u16, sample_rate: u32);
+
+    /// Per-channel delay lines, for filters that keep history.
+    fn history(&self) -> Option<&[[f32; 4]]> {
+        None
+    }
+
+    /// Continue from `previous`'s history (the filter this one replaces),
+    /// so a rebuilt chain doesn't restart from silence. Must not allocate.
+    fn carry_state(&mut self, _previous: &dyn AudioFilter) {}
+
+    /// Complex transfer function (re, im) at `freq_hz`, for filters with a
+    /// fixed linear response. None = not drawable (treated as unity).
+    fn response(&self, _freq_hz: f64, _sample_rate: u32) -> Option<(f64, f64)> {
+        None
+    }
+
+    /// For `carry_state` implementations that downcast `previous`.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
 }
 
 /// Biquad filter — the atom of audio DSP.
 ///
-/// Peaking EQ mode: boost/cut at a center frequency with configurable Q.
+/// Coefficients from the RBJ Audio EQ Cookbook (second order) and the
+/// bilinear transform of the analog prototypes (first order, b2 = a2 = 0).
 /// Direct Form I implementation with per-channel state.
 pub struct Biquad {
     b0: f32,
@@ -25,19 +57,109 @@ pub struct Biquad {
     state: Vec<[f32; 4]>,
 }
 
+/// Biquad response shapes selectable from the EQ scroll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BiquadKind {
+    Peaking,
+    LowPass,
+    HighPass,
+    /// Constant 0 dB peak gain.
+    BandPass,
+    Notch,
+    AllPass,
+    LowShelf,
+    HighShelf,
+    LowPass1,
+    HighPass1,
+    AllPass1,
+    LowShelf1,
+    HighShelf1,
+}
+
+impl BiquadKind {
+    /// Parse a scroll `type` ("eq"/"peaking", "lowpass", "lowshelf1", ...). Unknown → None.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "eq" | "peaking" => Some(Self::Peaking),
+            "lowpass" => Some(Self::LowPass),
+            "highpass" => Some(Self::HighPass),
+            "bandpass" => Some(Self::BandPass),
+            "notch" => Some(Self::Notch),
+            "allpass" => Some(Self::AllPass),
+            "lowshelf" => Some(Self::LowShelf),
+            "highshelf" => Some(Self::HighShelf),
+            "lowpass1" => Some(Self::LowPass1),
+            "highpass1" => Some(Self::HighPass1),
+            "allpass1" => Some(Self::AllPass1),
+            "lowshelf1" => Some(Self::LowShelf1),
+            "highshelf1" => Some(Self::HighShelf1),
+            _ => None,
+        }
+    }
+
+    /// Whether `gain_db` shapes the response (peaking and shelves).
+    pub fn uses_gain(self) -> bool {
+        matches!(self, Self::Peaking | Self::LowShelf | Self::HighShelf | Self::LowShelf1 | Self::HighShelf1)
+    }
+
+    /// Whether `q` shapes the response (second-order kinds).
+    pub fn uses_q(self) -> bool {
+        !matches!(self, Self::LowPass1 | Self::HighPass1 | Self::AllPass1 | Self::LowShelf1 | Self::HighShelf1)
+    }
+}
+
 impl Biquad {
-    /// Create a peaking EQ biquad.
-    pub fn peaking_eq(freq_hz: f32, gain_db: f32, q: f32, sample_rate: u32, channels: u16) -> Self {
+    /// Create a biquad of any kind. `freq_hz` is clamped below Nyquist, so a
+    /// spec written for 48k still builds at a lower output rate.
+    pub fn new(kind: BiquadKind, freq_hz: f32, q: f32, gain_db: f32, sample_rate: u32, channels: u16) -> Self {
+        let freq = freq_hz.min(sample_rate as f32 * 0.49);
+        let w0 = 2.0 * PI * freq / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
         let a = 10.0f32.powf(gain_db / 40.0);
-        let w0 = 2.0 * PI * freq_hz / sample_rate as f32;
-        let alpha = w0.sin() / (2.0 * q);
+        let sqrt_a2 = 2.0 * a.sqrt() * alpha;
+        // First order: bilinear transform with prewarped corner k
+        let k = (w0 / 2.0).tan();
+        let g = a * a;
+        let sg = a;
 
-        let b0 = 1.0 + alpha * a;
-        let b1 = -2.0 * w0.cos();
-        let b2 = 1.0 - alpha * a;
-        let a0 = 1.0 + alpha / a;
-        let a1 = -2.0 * w0.cos();
-        let a2 = 1.0 - alpha / a;
+        let [b0, b1, b2, a0, a1, a2] = match kind {
+            BiquadKind::Peaking => [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            BiquadKind::LowPass => {
+                let b = (1.0 - cos) / 2.0;
+                [b, 2.0 * b, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha]
+            }
+            BiquadKind::HighPass => {
+                let b = (1.0 + cos) / 2.0;
+                [b, -2.0 * b, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha]
+            }
+            BiquadKind::BandPass => [alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            BiquadKind::Notch => [1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            BiquadKind::AllPass => [1.0 - alpha, -2.0 * cos, 1.0 + alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            BiquadKind::LowShelf => [
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a2),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a2),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a2,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a2,
+            ],
+            BiquadKind::HighShelf => [
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a2),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a2),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a2,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a2,
+            ],
+            BiquadKind::LowPass1 => [k, k, 0.0, k + 1.0, k - 1.0, 0.0],
+            BiquadKind::HighPass1 => [1.0, -1.0, 0.0, k + 1.0, k - 1.0, 0.0],
+            BiquadKind::AllPass1 => [k - 1.0, k + 1.0, 0.0, k + 1.0, k - 1.0, 0.0],
+            // Shelf gain g, half of it (in dB) at the corner: (s + k√g) / (s + k/√g)
+            BiquadKind::LowShelf1 => [1.0 + k * sg, k * sg - 1.0, 0.0, 1.0 + k / sg, k / sg - 1.0, 0.0],
+            // g · (s + k/√g) / (s + k√g)
+            BiquadKind::HighShelf1 => [g * (1.0 + k / sg), g * (k / sg - 1.0), 0.0, 1.0 + k * sg, k * sg - 1.0, 0.0],
+        };
 
         // Normalize by a0
         Self {
@@ -49,9 +171,37 @@ impl Biquad {
             state: vec![[0.0; 4]; channels as usize],
         }
     }
+
+    /// Create a peaking EQ biquad.
+    pub fn peaking_eq(freq_hz: f32, gain_db: f32, q: f32, sample_rate: u32, channels: u16) -> Self {
+        Self::new(BiquadKind::Peaking, freq_hz, q, gain_db, sample_rate, channels)
+    }
 }
 
 impl AudioFilter for Biquad {
+    fn history(&self) -> Option<&[[f32; 4]]> {
+        Some(&self.state)
+    }
+
+    fn carry_state(&mut self, previous: &dyn AudioFilter) {
+        if let Some(history) = previous.history() {
+            for (mine, theirs) in self.state.iter_mut().zip(history) {
+                *mine = *theirs;
+            }
+        }
+    }
+
+    fn response(&self, freq_hz: f64, sample_rate: u32) -> Option<(f64, f64)> {
+        // H(e^jw) = (b0 + b1 e^-jw + b2 e^-2jw) / (1 + a1 e^-jw + a2 e^-2jw)
+        let w = 2.0 * std::f64::consts::PI * freq_hz / sample_rate as f64;
+        let (c1, s1, c2, s2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+        let poly = |k0: f32, k1: f32, k2: f32| {
+            let (k0, k1, k2) = (k0 as f64, k1 as f64, k2 as f64);
+            (k0 + k1 * c1 + k2 * c2, -k1 * s1 - k2 * s2)
+        };
+        Some(complex_div(poly(self.b0, self.b1, self.b2), poly(1.0, self.a1, self.a2)))
+    }
+
     fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
         let ch = channels as usize;
         if ch == 0 { return; }
@@ -92,6 +242,43 @@ impl AudioFilter for Gain {
             *s *= self.factor;
         }
     }
+
+    fn response(&self, _freq_hz: f64, _sample_rate: u32) -> Option<(f64, f64)> {
+        Some((self.factor as f64, 0.0))
+    }
+}
+
+/// Gain curve for crossfades: maps fade progress to (outgoing, incoming) gains.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FadeCurve {
+    /// cos/sin — constant summed power, no loudness dip mid-fade.
+    #[default]
+    EqualPower,
+    /// 1-t / t — constant summed amplitude.
+    Linear,
+}
+
+impl FadeCurve {
+    /// Parse a settings name ("equal_power", "linear"). Unknown → None.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "equal_power" => Some(Self::EqualPower),
+            "linear" => Some(Self::Linear),
+            _ => None,
+        }
+    }
+
+    /// Gains at progress `t` (0.0 = start of fade, 1.0 = end).
+    pub fn gains(self, t: f32) -> (f32, f32) {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Self::EqualPower => {
+                let angle = t * PI / 2.0;
+                (angle.cos(), angle.sin())
+            }
+            Self::Linear => (1.0 - t, t),
+        }
+    }
 }
 
 /// Ordered chain of filters. Applied in sequence.
@@ -113,6 +300,403 @@ impl DspChain {
     pub fn is_empty(&self) -> bool {
         self.filters.is_empty()
     }
+
+    /// Combined complex response (re, im) of every filter at `freq_hz`.
+    pub fn response(&self, freq_hz: f64, sample_rate: u32) -> (f64, f64) {
+        self.filters
+            .iter()
+            .filter_map(|f| f.response(freq_hz, sample_rate))
+            .fold((1.0, 0.0), complex_mul)
+    }
+
+    /// Take over filter state from the chain this one replaces. Only when
+    /// both have the same number of filters — otherwise positions don't
+    /// correspond and the new chain starts clean.
+    pub fn carry_state_from(&mut self, previous: &DspChain) {
+        if self.filters.len() != previous.filters.len() {
+            return;
+        }
+        for (new, old) in self.filters.iter_mut().zip(&previous.filters) {
+            new.carry_state(old.as_ref());
+        }
+    }
+}
+
+/// How long a replaced DSP chain takes to hand over to its successor.
+pub const CHAIN_FADE_MS: u32 = 20;
+
+/// Samples of scratch for running the outgoing chain during a handover.
+const SWITCH_SCRATCH_SAMPLES: usize = 8192;
+
+/// The active DSP chain, swapped without clicks.
+///
+/// A new chain (each step of an EQ slider drag is one) takes over from the
+/// old one across `CHAIN_FADE_MS`: both run on the same input and their
+/// outputs are crossfaded, so neither a coefficient jump nor reset filter
+/// history reaches the output. One handover runs at a time — hold further
+/// chains back until `ready`. Never allocates after construction.
+pub struct DspSwitch {
+    active: Option<DspChain>,
+    /// Chain being faded out (None while fading in from no chain at all).
+    outgoing: Option<DspChain>,
+    fading: bool,
+    fade_frames: usize,
+    faded: usize,
+    /// Faded-out chain, until `take_retired` collects it.
+    retired: Option<DspChain>,
+    /// Nothing processed yet: the first chain starts without a fade.
+    fresh: bool,
+    scratch: Vec<f32>,
+}
+
+impl DspSwitch {
+    pub fn new(active: Option<DspChain>) -> Self {
+        Self {
+            active,
+            outgoing: None,
+            fading: false,
+            fade_frames: 0,
+            faded: 0,
+            retired: None,
+            fresh: true,
+            scratch: vec![0.0; SWITCH_SCRATCH_SAMPLES],
+        }
+    }
+
+    /// Whether `switch` may be called (no handover running or uncollected).
+    pub fn ready(&self) -> bool {
+        !self.fading && self.retired.is_none()
+    }
+
+    /// Hand over to `chain`, carrying filter state from the current one.
+    /// Before any audio has been processed the swap is immediate.
+    pub fn switch(&mut self, mut chain: DspChain, sample_rate: u32) {
+        if let Some(old) = self.active.as_ref() {
+            chain.carry_state_from(old);
+        }
+        let old = self.active.replace(chain);
+        if self.fresh {
+            self.retired = old;
+            return;
+        }
+        self.outgoing = old;
+        self.fading = true;
+        self.fade_frames = (CHAIN_FADE_MS as u64 * sample_rate as u64 / 1000).max(1) as usize;
+        self.faded = 0;
+    }
+
+    /// The chain a finished handover replaced, to be dropped by the caller
+    /// (off the audio thread).
+    pub fn take_retired(&mut self) -> Option<DspChain> {
+        self.retired.take()
+    }
+
+    /// The newest chain, for parking when the stream goes away.
+    pub fn take_active(&mut self) -> Option<DspChain> {
+        self.active.take()
+    }
+
+    pub fn process(&mut self, samples: &mut [f32], channels: u16, sample_rate: u32) {
+        self.fresh = false;
+        let ch = channels.max(1) as usize;
+        let chunk_frames = (self.scratch.len() / ch).max(1);
+        for chunk in samples.chunks_mut(chunk_frames * ch) {
+            if !self.fading {
+                if let Some(chain) = &mut self.active {
+                    chain.process(chunk, channels, sample_rate);
+                }
+                continue;
+            }
+            let old = &mut self.scratch[..chunk.len()];
+            old.copy_from_slice(chunk);
+            if let Some(chain) = &mut self.outgoing {
+                chain.process(old, channels, sample_rate);
+            }
+            if let Some(chain) = &mut self.active {
+                chain.process(chunk, channels, sample_rate);
+            }
+            for (frame, old_frame) in chunk.chunks_exact_mut(ch).zip(old.chunks_exact(ch)) {
+                let t = self.faded as f32 / self.fade_frames as f32;
+                let (g_old, g_new) = FadeCurve::Linear.gains(t);
+                for (s, o) in frame.iter_mut().zip(old_frame) {
+                    *s = *s * g_new + o * g_old;
+                }
+                self.faded = (self.faded + 1).min(self.fade_frames);
+            }
+            if self.faded >= self.fade_frames {
+                self.fading = false;
+                self.retired = self.outgoing.take();
+            }
+        }
+    }
+}
+
+/// Default length of the ramps around pause, resume, seek, stop and skip.
+pub const TRANSPORT_FADE_MS: u64 = 30;
+
+/// Output gain that glides to silence and back instead of jumping, so
+/// transport changes don't click.
+///
+/// The level moves linearly; the applied gain is its square, which keeps
+/// long fade-outs sounding even rather than dropping off at the end.
+pub struct GainRamp {
+    level: f32,
+}
+
+impl GainRamp {
+    /// Start at `level` (0.0 = silent, 1.0 = unity).
+    pub fn new(level: f32) -> Self {
+        Self { level: level.clamp(0.0, 1.0) }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.level <= 0.0
+    }
+
+    /// Apply to interleaved `samples`, moving towards silence (`down`) or
+    /// unity by one `ramp_frames`-th of the way per frame. 0 frames jumps.
+    pub fn apply(&mut self, samples: &mut [f32], channels: u16, down: bool, ramp_frames: u64) {
+        let target = if down { 0.0 } else { 1.0 };
+        if self.level == target || ramp_frames == 0 {
+            self.level = target;
+            if down {
+                samples.fill(0.0);
+            }
+            return;
+        }
+        let step = 1.0 / ramp_frames as f32;
+        for frame in samples.chunks_mut(channels.max(1) as usize) {
+            self.level = if down { (self.level - step).max(0.0) } else { (self.level + step).min(1.0) };
+            let gain = self.level * self.level;
+            for s in frame.iter_mut() {
+                *s *= gain;
+            }
+        }
+    }
+}
+
+/// One filter entry of an EQ scroll, parsed and checked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterSpec {
+    Biquad { kind: BiquadKind, freq_hz: f32, q: f32, gain_db: f32 },
+    Gain { db: f32 },
+    Limiter { threshold_db: f32, lookahead_ms: f32, release_ms: f32 },
+    Compressor { threshold_db: f32, ratio: f32, attack_ms: f32, release_ms: f32, knee_db: f32, makeup_db: f32 },
+    Balance { balance: f32 },
+    Width { width: f32 },
+    Crossfeed { freq_hz: f32, feed_db: f32 },
+    Convolution { ir_path: String, mix: f32, gain_db: f32 },
+}
+
+impl FilterSpec {
+    /// Parse one `filters` entry. Missing fields take defaults (1000 Hz,
+    /// Q 0.707, 0 dB); present ones must be in range.
+    pub fn from_value(spec: &serde_json::Value) -> Result<Self, String> {
+        let name = match &spec["type"] {
+            serde_json::Value::String(name) => name.as_str(),
+            serde_json::Value::Null => return Err("missing type".into()),
+            _ => return Err("type must be a string".into()),
+        };
+        match name {
+            "gain" => return Ok(Self::Gain { db: number(spec, "db", 0.0, f32::is_finite)? }),
+            "limiter" => {
+                return Ok(Self::Limiter {
+                    threshold_db: number(spec, "threshold_db", -1.0, |t| t.is_finite() && t <= 0.0)?,
+                    lookahead_ms: number(spec, "lookahead_ms", 5.0, |ms| time_ms(ms) && ms <= 100.0)?,
+                    release_ms: number(spec, "release_ms", 50.0, time_ms)?,
+                })
+            }
+            "compressor" => {
+                return Ok(Self::Compressor {
+                    threshold_db: number(spec, "threshold_db", -18.0, |t| t.is_finite() && t <= 0.0)?,
+                    ratio: number(spec, "ratio", 4.0, |r| r.is_finite() && r >= 1.0)?,
+                    attack_ms: number(spec, "attack_ms", 10.0, time_ms)?,
+                    release_ms: number(spec, "release_ms", 100.0, time_ms)?,
+                    knee_db: number(spec, "knee_db", 6.0, |k| k.is_finite() && k >= 0.0)?,
+                    makeup_db: number(spec, "makeup_db", 0.0, f32::is_finite)?,
+                })
+            }
+            "balance" => {
+                return Ok(Self::Balance { balance: number(spec, "balance", 0.0, |b| (-1.0..=1.0).contains(&b))? })
+            }
+            "width" => return Ok(Self::Width { width: number(spec, "width", 1.0, |w| (0.0..=4.0).contains(&w))? }),
+            "crossfeed" => {
+                let (freq, feed) = match &spec["preset"] {
+                    serde_json::Value::Null => crossfeed_preset("default").unwrap_or((700.0, 4.5)),
+                    preset => preset
+                        .as_str()
+                        .and_then(crossfeed_preset)
+                        .ok_or_else(|| format!("unknown crossfeed preset {}", preset))?,
+                };
+                return Ok(Self::Crossfeed {
+                    freq_hz: number(spec, "freq_hz", freq, |f| (300.0..=2000.0).contains(&f))?,
+                    feed_db: number(spec, "feed_db", feed, |f| (1.0..=15.0).contains(&f))?,
+                });
+            }
+            "convolution" => {
+                let ir_path = spec["ir_path"].as_str().ok_or("ir_path must be a string")?;
+                if ir_path.is_empty() {
+                    return Err("ir_path must not be empty".into());
+                }
+                return Ok(Self::Convolution {
+                    ir_path: ir_path.to_string(),
+                    mix: number(spec, "mix", 1.0, |m| (0.0..=1.0).contains(&m))?,
+                    gain_db: number(spec, "gain_db", 0.0, f32::is_finite)?,
+                });
+            }
+            _ => {}
+        }
+        let kind = BiquadKind::from_name(name).ok_or_else(|| format!("unknown filter type \"{}\"", name))?;
+        Ok(Self::Biquad {
+            kind,
+            freq_hz: number(spec, "freq_hz", 1000.0, |f| f.is_finite() && f > 0.0)?,
+            q: number(spec, "q", 0.707, |q| q.is_finite() && q > 0.0)?,
+            gain_db: number(spec, "gain_db", 0.0, f32::is_finite)?,
+        })
+    }
+
+    /// Instantiate at the output format. Only fails when a convolution
+    /// filter's impulse response can't be decoded.
+    pub fn build(&self, sample_rate: u32, channels: u16) -> Result<Box<dyn AudioFilter>, String> {
+        Ok(match *self {
+            Self::Biquad { kind, freq_hz, q, gain_db } => {
+                Box::new(Biquad::new(kind, freq_hz, q, gain_db, sample_rate, channels))
+            }
+            Self::Gain { db } => Box::new(Gain::from_db(db)),
+            Self::Limiter { threshold_db, lookahead_ms, release_ms } => {
+                Box::new(Limiter::new(threshold_db, lookahead_ms, release_ms, sample_rate, channels))
+            }
+            Self::Compressor { threshold_db, ratio, attack_ms, release_ms, knee_db, makeup_db } => Box::new(
+                Compressor::new(threshold_db, ratio, attack_ms, release_ms, knee_db, makeup_db, sample_rate),
+            ),
+            Self::Balance { balance } => Box::new(Balance::new(balance)),
+            Self::Width { width } => Box::new(Width::new(width)),
+            Self::Crossfeed { freq_hz, feed_db } => Box::new(Crossfeed::new(freq_hz, feed_db, sample_rate)),
+            Self::Convolution { ref ir_path, mix, gain_db } => {
+                let (ir, ir_channels) = cached_impulse_response(ir_path, sample_rate)?;
+                Box::new(Convolver::new(&ir, ir_channels, channels, mix, gain_db))
+            }
+        })
+    }
+
+    /// Whether this entry can raise the level (and so earn the safety limiter).
+    pub fn boosts(&self) -> bool {
+        match *self {
+            Self::Biquad { kind: BiquadKind::LowPass | BiquadKind::HighPass, q, .. } => q > FRAC_1_SQRT_2,
+            Self::Biquad { kind, gain_db, .. } => kind.uses_gain() && gain_db > 0.0,
+            Self::Gain { db } => db > 0.0,
+            Self::Compressor { makeup_db, .. } => makeup_db > 0.0,
+            Self::Width { width } => width > 1.0,
+            // Depends on the IR; the response check decides
+            Self::Convolution { .. } => true,
+            Self::Limiter { .. } | Self::Balance { .. } | Self::Crossfeed { .. } => false,
+        }
+    }
+}
+
+/// Read an optional numeric field, rejecting non-numbers and values `valid` refuses.
+fn number(spec: &serde_json::Value, field: &str, default: f32, valid: fn(f32) -> bool) -> Result<f32, String> {
+    let value = match &spec[field] {
+        serde_json::Value::Null => return Ok(default),
+        v => v.as_f64().ok_or_else(|| format!("{} must be a number", field))? as f32,
+    };
+    if valid(value) {
+        Ok(value)
+    } else {
+        Err(format!("{} out of range: {}", field, value))
+    }
+}
+
+fn time_ms(ms: f32) -> bool {
+    ms.is_finite() && ms >= 0.0
+}
+
+/// Why one entry of an EQ scroll was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterError {
+    /// Position in `filters` (0 when the scroll itself is malformed).
+    pub index: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "filter {}: {}", self.index, self.message)
+    }
+}
+
+/// Parse every entry of an EQ scroll. Errors name each bad entry; an empty
+/// or absent `filters` list is a valid (flat) EQ.
+pub fn parse_filters(v: &serde_json::Value) -> Result<Vec<FilterSpec>, Vec<FilterError>> {
+    let entries = match &v["filters"] {
+        serde_json::Value::Null => return Ok(Vec::new()),
+        serde_json::Value::Array(entries) => entries,
+        _ => return Err(vec![FilterError { index: 0, message: "filters must be an array".into() }]),
+    };
+    let mut specs = Vec::with_capacity(entries.len());
+    let mut errors = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        match FilterSpec::from_value(entry) {
+            Ok(spec) => specs.push(spec),
+            Err(message) => errors.push(FilterError { index, message }),
+        }
+    }
+    if errors.is_empty() { Ok(specs) } else { Err(errors) }
+}
+
+/// Check that the files parsed specs refer to exist (convolution IRs).
+/// Kept out of `parse_filters` so parsing never touches the filesystem;
+/// callers that store or apply a spec run it after parsing.
+pub fn check_files(specs: &[FilterSpec]) -> Result<(), Vec<FilterError>> {
+    let errors: Vec<FilterError> = specs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, spec)| match spec {
+            FilterSpec::Convolution { ir_path, .. } if !std::path::Path::new(ir_path).is_file() => {
+                Some(FilterError { index, message: format!("impulse response not found: {}", ir_path) })
+            }
+            _ => None,
+        })
+        .collect();
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+fn complex_mul((a, b): (f64, f64), (c, d): (f64, f64)) -> (f64, f64) {
+    (a * c - b * d, a * d + b * c)
+}
+
+fn complex_div((a, b): (f64, f64), (c, d): (f64, f64)) -> (f64, f64) {
+    let norm = c * c + d * d;
+    ((a * c + b * d) / norm, (b * c - a * d) / norm)
+}
+
+/// Lowest frequency `frequency_response` plots.
+pub const RESPONSE_MIN_HZ: f64 = 20.0;
+/// Highest frequency plotted (or just below Nyquist at low rates).
+pub const RESPONSE_MAX_HZ: f64 = 20_000.0;
+
+/// Magnitude and phase of an EQ scroll's chain at `points` log-spaced
+/// frequencies from 20 Hz to 20 kHz, using the coefficients the chain is
+/// built with at `sample_rate`. For drawing the EQ curve:
+/// `{"sample_rate", "freq_hz": [..], "gain_db": [..], "phase_deg": [..]}`.
+pub fn frequency_response(v: &serde_json::Value, sample_rate: u32, points: usize) -> serde_json::Value {
+    let chain = chain_from_value(v, sample_rate, 1);
+    let points = points.max(2);
+    let top = RESPONSE_MAX_HZ.min(sample_rate as f64 * 0.499);
+    let ratio = (top / RESPONSE_MIN_HZ).max(1.0);
+
+    let mut freqs = Vec::with_capacity(points);
+    let mut gains = Vec::with_capacity(points);
+    let mut phases = Vec::with_capacity(points);
+    for i in 0..points {
+        let freq = RESPONSE_MIN_HZ * ratio.powf(i as f64 / (points - 1) as f64);
+        let (re, im) = chain.response(freq, sample_rate);
+        freqs.push(freq);
+        // Floor a perfect null (notch centre) at -200 dB rather than -inf
+        gains.push(20.0 * (re * re + im * im).sqrt().max(1e-10).log10());
+        phases.push(im.atan2(re).to_degrees());
+    }
+    serde_json::json!({"sample_rate": sample_rate, "freq_hz": freqs, "gain_db": gains, "phase_deg": phases})
 }
 
 /// Build a DspChain from scroll JSON.
@@ -121,33 +705,74 @@ impl DspChain {
 /// ```json
 /// {"filters": [
 ///   {"type": "eq", "freq_hz": 80, "gain_db": 3.0, "q": 0.7},
+///   {"type": "highpass", "freq_hz": 25, "q": 0.707},
+///   {"type": "compressor", "threshold_db": -18, "ratio": 3},
 ///   {"type": "gain", "db": -1.5}
 /// ]}
 /// ```
+/// Invalid entries are left out; check with `parse_filters` first to report them.
+/// A convolution filter whose impulse response fails to decode is skipped
+/// with a warning.
+///
+/// When any entry boosts, a safety limiter (`dynamics::SAFETY_CEILING_DB`)
+/// is appended unless the chain already ends in a limiter or the scroll
+/// sets `"safety_limiter": false`.
 pub fn chain_from_value(v: &serde_json::Value, sample_rate: u32, channels: u16) -> DspChain {
-    let mut filters: Vec<Box<dyn AudioFilter>> = Vec::new();
-
-    if let Some(arr) = v["filters"].as_array() {
-        for spec in arr {
-            match spec["type"].as_str() {
-                Some("eq") => {
-                    let freq = spec["freq_hz"].as_f64().unwrap_or(1000.0) as f32;
-                    let gain = spec["gain_db"].as_f64().unwrap_or(0.0) as f32;
-                    let q = spec["q"].as_f64().unwrap_or(0.707) as f32;
-                    if freq > 0.0 && q > 0.0 {
-                        filters.push(Box::new(Biquad::peaking_eq(freq, gain, q, sample_rate, channels)));
-                    }
-                }
-                Some("gain") => {
-                    let db = spec["db"].as_f64().unwrap_or(0.0) as f32;
-                    filters.push(Box::new(Gain::from_db(db)));
-                }
-                _ => {} // Unknown filter type — skip
+    let specs: Vec<FilterSpec> = match &v["filters"] {
+        serde_json::Value::Array(entries) => entries
+            .iter()
+            .filter_map(|entry| FilterSpec::from_value(entry).ok())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let filters: Vec<Box<dyn AudioFilter>> = specs
+        .iter()
+        .filter_map(|spec| match spec.build(sample_rate, channels) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                log::warn!("amsal: convolution filter skipped: {}", e);
+                None
             }
-        }
+        })
+        .collect();
+    let mut chain = DspChain::new(filters);
+    let ends_limited = matches!(specs.last(), Some(FilterSpec::Limiter { .. }));
+    if v["safety_limiter"].as_bool().unwrap_or(true) && !ends_limited && chain_boosts(&chain, &specs, sample_rate) {
+        chain.filters.push(Box::new(Limiter::safety(sample_rate, channels)));
     }
+    chain
+}
+
+/// Points checked for a net boost, besides each filter's own frequency.
+const BOOST_PROBE_POINTS: usize = 128;
 
-    DspChain::new(filters)
+/// Whether a chain can raise the level: compressor makeup, widening, or a
+/// magnitude response above 0 dB somewhere (so +6 dB then -6 dB doesn't count).
+fn chain_boosts(chain: &DspChain, specs: &[FilterSpec], sample_rate: u32) -> bool {
+    if !specs.iter().any(FilterSpec::boosts) {
+        return false;
+    }
+    // Boosts the (mono) response can't show
+    let hidden = |s: &FilterSpec| match *s {
+        FilterSpec::Compressor { makeup_db, .. } => makeup_db > 0.0,
+        FilterSpec::Width { width } => width > 1.0,
+        _ => false,
+    };
+    if specs.iter().any(hidden) {
+        return true;
+    }
+    let top = RESPONSE_MAX_HZ.min(sample_rate as f64 * 0.499);
+    let ratio = top / RESPONSE_MIN_HZ;
+    let probes = (0..BOOST_PROBE_POINTS).map(|i| RESPONSE_MIN_HZ * ratio.powf(i as f64 / (BOOST_PROBE_POINTS - 1) as f64));
+    let centres = specs.iter().filter_map(|s| match s {
+        FilterSpec::Biquad { freq_hz, .. } => Some((*freq_hz as f64).min(top)),
+        _ => None,
+    });
+    // 0.01 dB of slack for coefficient rounding
+    probes.chain(centres).chain([1.0]).any(|f| {
+        let (re, im) = chain.response(f, sample_rate);
+        re * re + im * im > 1.0023
+    })
 }
 
 #[cfg(test)]
@@ -200,6 +825,177 @@ mod tests {
         }
     }
 
+    /// Magnitude response in dB, from the coefficients.
+    fn response_db(b: &Biquad, freq_hz: f32, sample_rate: u32) -> f32 {
+        let (re, im) = b.response(freq_hz as f64, sample_rate).unwrap();
+        (20.0 * (re * re + im * im).sqrt().log10()) as f32
+    }
+
+    #[test]
+    fn rbj_family_responses() {
+        let sr = 48000;
+        let at = |kind, gain_db, f| response_db(&Biquad::new(kind, 1000.0, 0.707, gain_db, sr, 1), f, sr);
+        let near = |got: f32, want: f32| (got - want).abs() < 0.1;
+
+        assert!(near(at(BiquadKind::LowPass, 0.0, 10.0), 0.0));
+        assert!(near(at(BiquadKind::LowPass, 0.0, 1000.0), -3.0));
+        assert!(at(BiquadKind::LowPass, 0.0, 10_000.0) < -35.0);
+        assert!(near(at(BiquadKind::HighPass, 0.0, 20_000.0), 0.0));
+        assert!(at(BiquadKind::HighPass, 0.0, 100.0) < -35.0);
+        assert!(near(at(BiquadKind::BandPass, 0.0, 1000.0), 0.0));
+        assert!(at(BiquadKind::Notch, 0.0, 1000.0) < -60.0);
+        assert!(near(at(BiquadKind::Peaking, 6.0, 1000.0), 6.0));
+        assert!(near(at(BiquadKind::LowShelf, 6.0, 10.0), 6.0));
+        assert!(near(at(BiquadKind::LowShelf, 6.0, 20_000.0), 0.0));
+        assert!(near(at(BiquadKind::HighShelf, -6.0, 20_000.0), -6.0));
+        assert!(near(at(BiquadKind::HighShelf, -6.0, 10.0), 0.0));
+        for f in [50.0, 1000.0, 15_000.0] {
+            assert!(near(at(BiquadKind::AllPass, 0.0, f), 0.0));
+            assert!(near(at(BiquadKind::AllPass1, 0.0, f), 0.0));
+        }
+
+        assert!(near(at(BiquadKind::LowPass1, 0.0, 1000.0), -3.0));
+        assert!(near(at(BiquadKind::HighPass1, 0.0, 1000.0), -3.0));
+        assert!(near(at(BiquadKind::LowShelf1, 6.0, 5.0), 6.0));
+        assert!(near(at(BiquadKind::LowShelf1, 6.0, 1000.0), 3.0));
+        assert!(near(at(BiquadKind::HighShelf1, -6.0, 23_000.0), -6.0));
+        assert!(near(at(BiquadKind::HighShelf1, -6.0, 1000.0), -3.0));
+    }
+
+    #[test]
+    fn frequency_response_matches_processed_tone() {
+        let spec = serde_json::json!({"filters": [
+            {"type": "eq", "freq_hz": 1000, "gain_db": 6.0, "q": 1.0},
+            {"type": "highpass", "freq_hz": 100},
+            {"type": "gain", "db": -2.0}
+        ]});
+        let curve = frequency_response(&spec, 48000, 31);
+        let freqs = curve["freq_hz"].as_array().unwrap();
+        assert_eq!(freqs.len(), 31);
+        assert!((freqs[0].as_f64().unwrap() - 20.0).abs() < 1e-9);
+        assert!((freqs[30].as_f64().unwrap() - 20_000.0).abs() < 1e-6);
+        // 12 dB/oct highpass, two octaves plus below 100 Hz, and the -2 dB gain
+        assert!(curve["gain_db"][0].as_f64().unwrap() < -28.0);
+
+        // A steady 1 kHz tone through the real chain comes out at the plotted gain
+        let mut chain = chain_from_value(&spec, 48000, 1);
+        let (re, im) = chain.response(1000.0, 48000);
+        let predicted_db = 20.0 * (re * re + im * im).sqrt().log10();
+        // (quiet enough to stay under the safety limiter the boost brings)
+        let mut tone: Vec<f32> = (0..48000).map(|n| 0.5 * (2.0 * PI * 1000.0 * n as f32 / 48000.0).sin()).collect();
+        chain.process(&mut tone, 1, 48000);
+        let peak = tone[24000..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!((20.0 * (2.0 * peak).log10() as f64 - predicted_db).abs() < 0.05, "{} vs {}", peak, predicted_db);
+        assert!((predicted_db - 4.0).abs() < 0.1);
+
+        // At 22.05k the top point stays below Nyquist
+        let low = frequency_response(&spec, 22050, 8);
+        assert!(low["freq_hz"][7].as_f64().unwrap() < 11_025.0);
+    }
+
+    #[test]
+    fn dsp_switch_crossfades_chains() {
+        let gain = |db| DspChain::new(vec![Box::new(Gain::from_db(db)) as Box<dyn AudioFilter>]);
+        let mut switch = DspSwitch::new(None);
+        // Nothing played yet: the first chain applies at once
+        switch.switch(gain(-6.0), 48000);
+        assert!(switch.take_retired().is_none() && switch.ready());
+        let mut block = vec![1.0f32; 256];
+        switch.process(&mut block, 2, 48000);
+        assert!(block.iter().all(|s| (s - 0.501).abs() < 0.001));
+
+        // A new chain ramps in over CHAIN_FADE_MS (960 frames at 48k)
+        switch.switch(gain(0.0), 48000);
+        assert!(!switch.ready());
+        let mut block = vec![1.0f32; 2 * 1200];
+        switch.process(&mut block, 2, 48000);
+        let left: Vec<f32> = block.iter().step_by(2).copied().collect();
+        assert!((left[0] - 0.501).abs() < 0.001);
+        assert!(left.windows(2).all(|w| w[1] >= w[0] && w[1] - w[0] < 0.001));
+        assert!(left[960..].iter().all(|s| (s - 1.0).abs() < 1e-6));
+        assert!(switch.take_retired().is_some() && switch.ready());
+    }
+
+    #[test]
+    fn safety_limiter_follows_boosts() {
+        let count = |v: serde_json::Value| chain_from_value(&v, 48000, 2).filters.len();
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "eq", "gain_db": -3.0}]})), 1);
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "eq", "gain_db": 3.0}]})), 2);
+        // Boost and cut that cancel out need no limiter
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "gain", "db": 6.0}, {"type": "gain", "db": -6.0}]})), 2);
+        assert_eq!(count(serde_json::json!({"filters": [
+            {"type": "eq", "freq_hz": 3000, "gain_db": 2.0, "q": 8.0},
+            {"type": "gain", "db": -1.0}
+        ]})), 3);
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "gain", "db": 1.0}, {"type": "limiter"}]})), 2);
+        assert_eq!(count(serde_json::json!({"safety_limiter": false, "filters": [{"type": "gain", "db": 1.0}]})), 1);
+
+        // +12 dB on a full-scale tone stays under the ceiling
+        let v = serde_json::json!({"filters": [{"type": "gain", "db": 12.0}]});
+        let mut chain = chain_from_value(&v, 48000, 1);
+        let mut tone: Vec<f32> = (0..4800).map(|n| (2.0 * PI * 440.0 * n as f32 / 48000.0).sin()).collect();
+        chain.process(&mut tone, 1, 48000);
+        let ceiling = 10.0f32.powf(super::super::dynamics::SAFETY_CEILING_DB / 20.0);
+        assert!(tone.iter().all(|s| s.abs() <= ceiling));
+
+        // Widening can raise peaks too
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "width", "width": 1.5}]})), 2);
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "width", "width": 0.5}, {"type": "crossfeed"}]})), 2);
+    }
+
+    #[test]
+    fn resonant_pass_filters_get_the_safety_limiter() {
+        let count = |v: serde_json::Value| chain_from_value(&v, 48000, 2).filters.len();
+        // Q 5 peaks ~14 dB at the corner
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "lowpass", "freq_hz": 1000, "q": 5.0}]})), 2);
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "highpass", "freq_hz": 1000, "q": 5.0}]})), 2);
+        // Butterworth and below never rise above 0 dB
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "lowpass", "freq_hz": 1000, "q": 0.7}]})), 1);
+        assert_eq!(count(serde_json::json!({"filters": [{"type": "highpass", "freq_hz": 1000, "q": 0.5}]})), 1);
+    }
+
+    #[test]
+    fn dynamics_and_stereo_entries_report_bad_fields() {
+        let errors = parse_filters(&serde_json::json!({"filters": [
+            {"type": "compressor", "ratio": 0.5},
+            {"type": "limiter", "threshold_db": 3.0}
+        ]}))
+        .unwrap_err();
+        assert_eq!(errors[0].message, "ratio out of range: 0.5");
+        assert_eq!(errors[1].message, "threshold_db out of range: 3");
+        let errors = parse_filters(&serde_json::json!({"filters": [
+            {"type": "crossfeed", "preset": "loud"},
+            {"type": "balance", "balance": 2}
+        ]}))
+        .unwrap_err();
+        assert_eq!(errors[0].message, "unknown crossfeed preset \"loud\"");
+        assert_eq!(errors[1].message, "balance out of range: 2");
+    }
+
+    #[test]
+    fn parse_filters_reports_each_bad_entry() {
+        let v = serde_json::json!({"filters": [
+            {"type": "lowshelf", "freq_hz": 105, "gain_db": 4.5, "q": 0.7},
+            {"type": "bell"},
+            {"type": "highpass", "freq_hz": -20},
+            {"type": "eq", "q": "wide"},
+            {"db": 1.0}
+        ]});
+        let errors = parse_filters(&v).unwrap_err();
+        let indexes: Vec<usize> = errors.iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![1, 2, 3, 4]);
+        assert_eq!(errors[1].message, "freq_hz out of range: -20");
+        assert_eq!(errors[2].to_string(), "filter 3: q must be a number");
+
+        // The building path keeps only the good entry (and the safety
+        // limiter its boost brings)
+        assert_eq!(chain_from_value(&v, 48000, 2).filters.len(), 2);
+        assert!(parse_filters(&serde_json::json!({"filters": "eq"})).is_err());
+        assert_eq!(parse_filters(&serde_json::json!({})).unwrap(), vec![]);
+        let specs = parse_filters(&serde_json::json!({"filters": [{"type": "notch", "freq_hz": 50}]})).unwrap();
+        assert_eq!(specs, vec![FilterSpec::Biquad { kind: BiquadKind::Notch, freq_hz: 50.0, q: 0.707, gain_db: 0.0 }]);
+    }
+
     #[test]
     fn dsp_chain_applies_in_order() {
         let v = serde_json::json!({
@@ -216,4 +1012,63 @@ mod tests {
             assert!((s - 0.5).abs() < 0.01);
         }
     }
+
+    #[test]
+    fn rebuilt_chain_carries_biquad_history() {
+        let spec = serde_json::json!({"filters": [{"type": "eq", "freq_hz": 100, "gain_db": 6.0, "q": 0.7}]});
+        let tone: Vec<f32> = (0..512).map(|n| (2.0 * PI * 100.0 * n as f32 / 48000.0).sin()).collect();
+        let (head, tail) = tone.split_at(256);
+        let mut old = chain_from_value(&spec, 48000, 1);
+        old.process(&mut head.to_vec(), 1, 48000);
+
+        // A carried rebuild continues exactly where the old chain was;
+        // a fresh one restarts from silence
+        let mut carried = chain_from_value(&spec, 48000, 1);
+        carried.carry_state_from(&old);
+        let mut fresh = chain_from_value(&spec, 48000, 1);
+        let (mut expected, mut a, mut b) = (tail.to_vec(), tail.to_vec(), tail.to_vec());
+        old.process(&mut expected, 1, 48000);
+        carried.process(&mut a, 1, 48000);
+        fresh.process(&mut b, 1, 48000);
+        assert_eq!(a, expected);
+        assert!((b[0] - expected[0]).abs() > 0.01);
+    }
+
+    #[test]
+    fn fade_curves_endpoints_and_power() {
+        for curve in [FadeCurve::EqualPower, FadeCurve::Linear] {
+            assert_eq!(curve.gains(0.0), (1.0, 0.0));
+            let (out, inc) = curve.gains(1.0);
+            assert!(out.abs() < 1e-6 && (inc - 1.0).abs() < 1e-6);
+        }
+        // Equal power: out² + in² stays 1 across the fade
+        let (out, inc) = FadeCurve::EqualPower.gains(0.5);
+        assert!((out * out + inc * inc - 1.0).abs() < 1e-5);
+        assert_eq!(FadeCurve::from_name("linear"), Some(FadeCurve::Linear));
+        assert_eq!(FadeCurve::from_name("s-curve"), None);
+    }
+
+    #[test]
+    fn gain_ramp_glides_to_silence_and_back() {
+        let mut ramp = GainRamp::new(1.0);
+        let mut samples = vec![1.0f32; 8 * 2];
+        ramp.apply(&mut samples, 2, true, 4);
+        // Both channels of a frame share a gain; it falls monotonically to 0
+        assert_eq!(&samples[..4], &[0.5625, 0.5625, 0.25, 0.25]);
+        assert!(samples[6..].iter().all(|&s| s == 0.0));
+        assert!(ramp.is_silent());
+
+        // Held at silence, then back up to unity
+        let mut held = vec![1.0f32; 4];
+        ramp.apply(&mut held, 2, true, 4);
+        assert_eq!(held, vec![0.0; 4]);
+        let mut up = vec![1.0f32; 8 * 2];
+        ramp.apply(&mut up, 2, false, 4);
+        assert!(up.windows(2).all(|w| w[1] >= w[0]) && up[7] == 1.0);
+
+        // 0 frames switches at once
+        let mut cut = vec![1.0f32; 4];
+        ramp.apply(&mut cut, 2, true, 0);
+        assert_eq!(cut, vec![0.0; 4]);
+    }
 }
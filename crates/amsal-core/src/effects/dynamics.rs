@@ -0,0 +1,387 @@
+//! Dynamics processing — look-ahead brickwall limiter and feed-forward
+//! compressor, as `AudioFilter`s for the EQ chain.
+//!
+//! Both detect on the loudest channel and apply one gain to the whole frame
+//! (linked), so the stereo image doesn't shift under gain reduction. All
+//! buffers are sized at construction; `process` never allocates.
+
+use std::any::Any;
+
+use super::dsp::AudioFilter;
+
+/// Ceiling of the safety limiter `chain_from_value` appends after boosts.
+pub const SAFETY_CEILING_DB: f32 = -0.3;
+const SAFETY_LOOKAHEAD_MS: f32 = 1.5;
+const SAFETY_RELEASE_MS: f32 = 80.0;
+
+/// One-pole smoothing coefficient for a time constant (0 ms = instant).
+fn time_coef(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (ms * 0.001 * sample_rate as f32)).exp()
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+/// Look-ahead brickwall limiter. No sample leaves above the ceiling.
+///
+/// The gain each frame needs is min-filtered over the look-ahead window,
+/// released smoothly, then box-averaged over the same window; the signal
+/// is delayed by the window so the averaged gain has fully reached the
+/// needed value by the time a peak comes out (no overshoot, no hard
+/// corners in the gain curve).
+pub struct Limiter {
+    ceiling: f32,
+    channels: usize,
+    /// Window length in frames (look-ahead + 1).
+    window: usize,
+    release: f32,
+    /// Input frames waiting to come out (`window - 1` frames).
+    delay: Vec<f32>,
+    delay_pos: usize,
+    /// Monotonic queue of (frame, gain) for the sliding minimum; one slot
+    /// more than the window, since a rising gain fills it before eviction.
+    min_queue: Vec<(u64, f32)>,
+    min_head: usize,
+    min_len: usize,
+    frame: u64,
+    /// Released gain, before averaging.
+    env: f32,
+    average: Vec<f32>,
+    average_pos: usize,
+    average_sum: f64,
+    /// Whether the channel-count mismatch fallback has been logged.
+    mismatch_logged: bool,
+}
+
+impl Limiter {
+    pub fn new(threshold_db: f32, lookahead_ms: f32, release_ms: f32, sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        let window = (lookahead_ms.max(0.0) * 0.001 * sample_rate as f32).round() as usize + 1;
+        Self {
+            ceiling: db_to_linear(threshold_db),
+            channels,
+            window,
+            release: time_coef(release_ms, sample_rate),
+            delay: vec![0.0; (window - 1) * channels],
+            delay_pos: 0,
+            min_queue: vec![(0, 1.0); window + 1],
+            min_head: 0,
+            min_len: 0,
+            frame: 0,
+            env: 1.0,
+            average: vec![1.0; window],
+            average_pos: 0,
+            average_sum: window as f64,
+            mismatch_logged: false,
+        }
+    }
+
+    /// The limiter `chain_from_value` appends to chains that boost.
+    pub fn safety(sample_rate: u32, channels: u16) -> Self {
+        Self::new(SAFETY_CEILING_DB, SAFETY_LOOKAHEAD_MS, SAFETY_RELEASE_MS, sample_rate, channels)
+    }
+
+    /// Sliding minimum of the needed gain over the last `window` frames.
+    fn push_min(&mut self, gain: f32) -> f32 {
+        let cap = self.min_queue.len();
+        while self.min_len > 0 {
+            let back = (self.min_head + self.min_len - 1) % cap;
+            if self.min_queue[back].1 < gain {
+                break;
+            }
+            self.min_len -= 1;
+        }
+        self.min_queue[(self.min_head + self.min_len) % cap] = (self.frame, gain);
+        self.min_len += 1;
+        while self.min_queue[self.min_head].0 + self.window as u64 <= self.frame {
+            self.min_head = (self.min_head + 1) % cap;
+            self.min_len -= 1;
+        }
+        self.min_queue[self.min_head].1
+    }
+
+    /// Limit frames of a channel count the delay line wasn't sized for (a
+    /// `set_dsp` chain is kept across format changes): no look-ahead, so the
+    /// gain drops at the peak itself and the clamp catches what it misses.
+    fn process_unaligned(&mut self, samples: &mut [f32], channels: usize) {
+        if !self.mismatch_logged {
+            log::warn!(
+                "amsal: limiter built for {} channels got {}; limiting without look-ahead",
+                self.channels,
+                channels
+            );
+            self.mismatch_logged = true;
+        }
+        for frame in samples.chunks_exact_mut(channels) {
+            let peak = frame.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+            self.env = if needed < self.env { needed } else { needed + (self.env - needed) * self.release };
+            for s in frame.iter_mut() {
+                *s = (*s * self.env).clamp(-self.ceiling, self.ceiling);
+            }
+        }
+    }
+}
+
+impl AudioFilter for Limiter {
+    fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
+        if channels as usize != self.channels {
+            self.process_unaligned(samples, channels.max(1) as usize);
+            return;
+        }
+        let ch = self.channels;
+        for frame in samples.chunks_exact_mut(ch) {
+            let peak = frame.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+            let min = self.push_min(needed);
+            self.env = if min < self.env { min } else { min + (self.env - min) * self.release };
+            self.average_sum += (self.env - self.average[self.average_pos]) as f64;
+            self.average[self.average_pos] = self.env;
+            self.average_pos = (self.average_pos + 1) % self.window;
+            let gain = (self.average_sum / self.window as f64) as f32;
+            self.frame += 1;
+
+            if self.delay.is_empty() {
+                for s in frame.iter_mut() {
+                    *s = (*s * gain).clamp(-self.ceiling, self.ceiling);
+                }
+                continue;
+            }
+            let slot = &mut self.delay[self.delay_pos * ch..(self.delay_pos + 1) * ch];
+            for (s, delayed) in frame.iter_mut().zip(slot.iter_mut()) {
+                let out = *delayed * gain;
+                *delayed = *s;
+                // Rounding in the average can leave a hair over the ceiling
+                *s = out.clamp(-self.ceiling, self.ceiling);
+            }
+            self.delay_pos = (self.delay_pos + 1) % (self.window - 1);
+        }
+    }
+
+    fn carry_state(&mut self, previous: &dyn AudioFilter) {
+        let Some(prev) = previous.as_any().and_then(|p| p.downcast_ref::<Limiter>()) else {
+            return;
+        };
+        if prev.channels != self.channels || prev.window != self.window {
+            return;
+        }
+        self.delay.copy_from_slice(&prev.delay);
+        self.delay_pos = prev.delay_pos;
+        self.min_queue.copy_from_slice(&prev.min_queue);
+        self.min_head = prev.min_head;
+        self.min_len = prev.min_len;
+        self.frame = prev.frame;
+        self.env = prev.env;
+        self.average.copy_from_slice(&prev.average);
+        self.average_pos = prev.average_pos;
+        self.average_sum = prev.average_sum;
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// Feed-forward compressor with a soft knee.
+///
+/// Static curve (dB): below `threshold - knee/2` unchanged, above
+/// `threshold + knee/2` reduced by `ratio`, quadratic in between. The gain
+/// reduction is smoothed with separate attack and release time constants.
+pub struct Compressor {
+    threshold_db: f32,
+    ratio: f32,
+    knee_db: f32,
+    makeup: f32,
+    attack: f32,
+    release: f32,
+    /// Smoothed gain reduction in dB (≤ 0).
+    reduction_db: f32,
+}
+
+impl Compressor {
+    pub fn new(
+        threshold_db: f32,
+        ratio: f32,
+        attack_ms: f32,
+        release_ms: f32,
+        knee_db: f32,
+        makeup_db: f32,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            threshold_db,
+            ratio: ratio.max(1.0),
+            knee_db: knee_db.max(0.0),
+            makeup: db_to_linear(makeup_db),
+            attack: time_coef(attack_ms, sample_rate),
+            release: time_coef(release_ms, sample_rate),
+            reduction_db: 0.0,
+        }
+    }
+
+    /// Static gain reduction (dB, ≤ 0) for an input level in dB.
+    pub fn static_reduction(&self, level_db: f32) -> f32 {
+        let over = level_db - self.threshold_db;
+        let slope = 1.0 / self.ratio - 1.0;
+        if 2.0 * over <= -self.knee_db {
+            0.0
+        } else if 2.0 * over < self.knee_db {
+            let x = over + self.knee_db / 2.0;
+            slope * x * x / (2.0 * self.knee_db)
+        } else {
+            slope * over
+        }
+    }
+}
+
+impl AudioFilter for Compressor {
+    fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
+        let ch = channels as usize;
+        if ch == 0 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(ch) {
+            let peak = frame.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            let target = self.static_reduction(20.0 * peak.max(1e-9).log10());
+            // More reduction → attack; less → release
+            let coef = if target < self.reduction_db { self.attack } else { self.release };
+            self.reduction_db = target + (self.reduction_db - target) * coef;
+            let gain = db_to_linear(self.reduction_db) * self.makeup;
+            for s in frame.iter_mut() {
+                *s *= gain;
+            }
+        }
+    }
+
+    fn carry_state(&mut self, previous: &dyn AudioFilter) {
+        if let Some(prev) = previous.as_any().and_then(|p| p.downcast_ref::<Compressor>()) {
+            self.reduction_db = prev.reduction_db;
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn tone(freq: f32, amplitude: f32, frames: usize, sample_rate: u32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = amplitude * (2.0 * PI * freq * n as f32 / sample_rate as f32).sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling_and_delays_by_lookahead() {
+        let sr = 48000;
+        let mut limiter = Limiter::new(-6.0, 5.0, 50.0, sr, 2);
+        // Quiet lead-in, then a +6 dBFS burst
+        let mut samples = tone(440.0, 0.1, 4800, sr);
+        samples.extend(tone(440.0, 2.0, 4800, sr));
+        let input = samples.clone();
+        limiter.process(&mut samples, 2, sr);
+
+        let ceiling = db_to_linear(-6.0);
+        assert!(samples.iter().all(|s| s.abs() <= ceiling));
+        // The lead-in passes untouched, 240 frames (5 ms) late
+        assert_eq!(&samples[480..2000], &input[..2000 - 480]);
+        // Limited hard but not to silence
+        let burst_peak = samples[12_000..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(burst_peak > ceiling * 0.9);
+    }
+
+    #[test]
+    fn limiter_carries_its_delay_line() {
+        let sr = 48000;
+        let input = tone(100.0, 0.5, 1000, sr);
+        let (head, tail) = input.split_at(1000);
+        let mut old = Limiter::new(-1.0, 5.0, 50.0, sr, 2);
+        old.process(&mut head.to_vec(), 2, sr);
+        let mut carried = Limiter::new(-1.0, 5.0, 50.0, sr, 2);
+        carried.carry_state(&old);
+        let (mut expected, mut got) = (tail.to_vec(), tail.to_vec());
+        old.process(&mut expected, 2, sr);
+        carried.process(&mut got, 2, sr);
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling_under_a_decaying_bass_tone() {
+        // A rising needed gain over a whole window fills the sliding-minimum
+        // queue before anything is evicted
+        let sr = 48000;
+        let mut limiter = Limiter::new(-1.0, 1.5, 80.0, sr, 2);
+        let frames = sr as usize * 2;
+        let mut samples: Vec<f32> = (0..frames)
+            .flat_map(|n| {
+                let decay = 4.0 * (1.0 - n as f32 / frames as f32) + 1.0;
+                let s = decay * (2.0 * PI * 20.0 * n as f32 / sr as f32).sin();
+                [s, s]
+            })
+            .collect();
+        let ceiling = db_to_linear(-1.0);
+        for chunk in samples.chunks_mut(512) {
+            limiter.process(chunk, 2, sr);
+            assert!(limiter.min_len <= limiter.window, "{}", limiter.min_len);
+        }
+        assert!(samples.iter().all(|s| s.abs() <= ceiling));
+        // Still limiting, not ducking to silence
+        let tail_peak = samples[frames..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(tail_peak > ceiling * 0.9, "{}", tail_peak);
+    }
+
+    #[test]
+    fn limiter_still_limits_after_a_channel_change() {
+        let sr = 48000;
+        let mut limiter = Limiter::new(-1.0, 5.0, 50.0, sr, 2);
+        let mut samples: Vec<f32> = (0..4800).map(|n| 2.0 * (2.0 * PI * 440.0 * n as f32 / sr as f32).sin()).collect();
+        limiter.process(&mut samples, 1, sr);
+        let ceiling = db_to_linear(-1.0);
+        assert!(samples.iter().all(|s| s.abs() <= ceiling));
+        let peak = samples[480..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(peak > ceiling * 0.9, "{}", peak);
+    }
+
+    #[test]
+    fn compressor_static_curve_and_settling() {
+        let comp = Compressor::new(-20.0, 4.0, 5.0, 50.0, 0.0, 0.0, 48000);
+        assert_eq!(comp.static_reduction(-30.0), 0.0);
+        // 8 dB over at 4:1 → 2 dB over, 6 dB of reduction
+        assert!((comp.static_reduction(-12.0) + 6.0).abs() < 1e-5);
+        let soft = Compressor::new(-20.0, 4.0, 5.0, 50.0, 10.0, 0.0, 48000);
+        assert_eq!(soft.static_reduction(-25.0), 0.0);
+        assert!(soft.static_reduction(-20.0) < 0.0 && soft.static_reduction(-20.0) > -1.0);
+        assert!((soft.static_reduction(-10.0) + 7.5).abs() < 1e-5);
+
+        // A -6 dBFS tone peaks 10.5 dB down (14 over at 4:1), plus 3 dB
+        // makeup — with an instant attack the reduction is there at each peak
+        let sr = 48000;
+        let amplitude = db_to_linear(-6.0);
+        let peak_db = |attack_ms: f32| {
+            let mut comp = Compressor::new(-20.0, 4.0, attack_ms, 50.0, 0.0, 3.0, sr);
+            let mut samples = tone(1000.0, amplitude, sr as usize, sr);
+            comp.process(&mut samples, 2, sr);
+            let onset = samples[..96].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            let settled = samples[sr as usize..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            (20.0 * onset.log10(), 20.0 * settled.log10())
+        };
+        let (_, settled) = peak_db(0.0);
+        assert!((settled - (-6.0 - 10.5 + 3.0)).abs() < 0.1, "{}", settled);
+        // A slow attack lets the first peak through nearly untouched
+        let (onset, _) = peak_db(20.0);
+        assert!(onset > -6.0, "{}", onset);
+    }
+}
@@ -0,0 +1,155 @@
+//! Radix-2 complex FFT for the convolution filter and spectrum analysis.
+//!
+//! Plans (twiddles, bit-reversal table) are built once per size; the
+//! transforms themselves run in place and never allocate, so they can be
+//! called from the output callback.
+
+use std::f64::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub const ZERO: Complex = Complex { re: 0.0, im: 0.0 };
+
+    pub fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    pub fn conj(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    pub fn norm(self) -> f32 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, o: Complex) -> Complex {
+        Complex::new(self.re + o.re, self.im + o.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, o: Complex) -> Complex {
+        Complex::new(self.re - o.re, self.im - o.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, o: Complex) -> Complex {
+        Complex::new(self.re * o.re - self.im * o.im, self.re * o.im + self.im * o.re)
+    }
+}
+
+/// FFT plan for one power-of-two size.
+pub struct Fft {
+    n: usize,
+    /// e^(-2πik/n) for k < n/2
+    twiddles: Vec<Complex>,
+    /// Bit-reversed index of each position
+    reversed: Vec<usize>,
+}
+
+impl Fft {
+    /// Plan for size `n` (a power of two, at least 2).
+    pub fn new(n: usize) -> Self {
+        assert!(n >= 2 && n.is_power_of_two(), "FFT size must be a power of two");
+        let bits = n.trailing_zeros();
+        let twiddles = (0..n / 2)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f64 / n as f64;
+                Complex::new(angle.cos() as f32, angle.sin() as f32)
+            })
+            .collect();
+        let reversed = (0..n).map(|i| i.reverse_bits() >> (usize::BITS - bits)).collect();
+        Self { n, twiddles, reversed }
+    }
+
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// Forward transform in place (no scaling). `buf.len()` must equal the size.
+    pub fn forward(&self, buf: &mut [Complex]) {
+        self.transform(buf, false);
+    }
+
+    /// Inverse transform in place, scaled by 1/n so `inverse(forward(x)) == x`.
+    pub fn inverse(&self, buf: &mut [Complex]) {
+        self.transform(buf, true);
+        let scale = 1.0 / self.n as f32;
+        for c in buf.iter_mut() {
+            c.re *= scale;
+            c.im *= scale;
+        }
+    }
+
+    fn transform(&self, buf: &mut [Complex], inverse: bool) {
+        let n = self.n;
+        assert_eq!(buf.len(), n);
+        for i in 0..n {
+            let j = self.reversed[i];
+            if i < j {
+                buf.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let half = len / 2;
+            let stride = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..half {
+                    let w = self.twiddles[k * stride];
+                    let w = if inverse { w.conj() } else { w };
+                    let a = buf[start + k];
+                    let b = buf[start + k + half] * w;
+                    buf[start + k] = a + b;
+                    buf[start + k + half] = a - b;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_direct_dft_and_round_trips() {
+        let n = 64;
+        let input: Vec<Complex> = (0..n).map(|i| Complex::new((i as f32 * 0.37).sin(), (i as f32 * 0.11).cos())).collect();
+        let mut buf = input.clone();
+        let fft = Fft::new(n);
+        fft.forward(&mut buf);
+
+        for (k, got) in buf.iter().enumerate() {
+            let mut want = (0.0f64, 0.0f64);
+            for (t, x) in input.iter().enumerate() {
+                let angle = -2.0 * PI * (k * t) as f64 / n as f64;
+                want.0 += x.re as f64 * angle.cos() - x.im as f64 * angle.sin();
+                want.1 += x.re as f64 * angle.sin() + x.im as f64 * angle.cos();
+            }
+            assert!((got.re as f64 - want.0).abs() < 1e-3 && (got.im as f64 - want.1).abs() < 1e-3);
+        }
+
+        fft.inverse(&mut buf);
+        for (a, b) in buf.iter().zip(&input) {
+            assert!((a.re - b.re).abs() < 1e-5 && (a.im - b.im).abs() < 1e-5);
+        }
+    }
+}
@@ -7,6 +7,7 @@ use std::path::Path;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use lofty::prelude::*;
 use lofty::probe::Probe;
+use lofty::tag::Tag;
 use nine_s_shell::Shell;
 
 use crate::models::media::{Format, MediaType};
@@ -108,7 +109,7 @@ pub fn import_file(shell: &Shell, file_path: &str) -> bool {
 
     // Extract metadata for audio files
     if media_type == MediaType::Audio {
-        let (title, artist, album, genre, duration_ms) = extract_audio_metadata(path);
+        let (title, artist, album, genre, duration_ms, replay_gain) = extract_audio_metadata(path);
         data["title"] = title.into();
         if let Some(a) = artist {
             data["artist"] = a.into();
@@ -122,6 +123,9 @@ pub fn import_file(shell: &Shell, file_path: &str) -> bool {
         if let Some(d) = duration_ms {
             data["duration_ms"] = d.into();
         }
+        if let Some(rg) = replay_gain {
+            data["replay_gain"] = rg;
+        }
     } else {
         data["title"] = filename.into();
     }
@@ -218,15 +222,17 @@ fn parse_format(ext: &str) -> Format {
     }
 }
 
-fn extract_audio_metadata(
-    path: &Path,
-) -> (
+/// (title, artist, album, genre, duration_ms, replay_gain) read from tags.
+type AudioMetadata = (
     String,
     Option<String>,
     Option<String>,
     Option<String>,
     Option<u64>,
-) {
+    Option<serde_json::Value>,
+);
+
+fn extract_audio_metadata(path: &Path) -> AudioMetadata {
     let fallback_title = path
         .file_stem()
         .and_then(|s| s.to_str())
@@ -235,7 +241,7 @@ fn extract_audio_metadata(
 
     let tagged = match Probe::open(path).and_then(|p| p.read()) {
         Ok(t) => t,
-        Err(_) => return (fallback_title, None, None, None, None),
+        Err(_) => return (fallback_title, None, None, None, None, None),
     };
 
     let tag = tagged.primary_tag().or_else(|| tagged.first_tag());
@@ -249,8 +255,69 @@ fn extract_audio_metadata(
     let album = tag.and_then(|t| t.album().map(|s| s.to_string()));
     let genre = tag.and_then(|t| t.genre().map(|s| s.to_string()));
     let duration_ms = Some(props.duration().as_millis() as u64).filter(|&d| d > 0);
+    let replay_gain = tag.and_then(extract_replay_gain);
+
+    (title, artist, album, genre, duration_ms, replay_gain)
+}
+
+/// Read ReplayGain gain/peak tags, falling back to Opus R128 gains.
+///
+/// Returns `{"track_gain_db", "track_peak", "album_gain_db", "album_peak"}`
+/// with only the fields present, or None when the file has no gain tags.
+fn extract_replay_gain(tag: &Tag) -> Option<serde_json::Value> {
+    let read = |key: ItemKey| tag.get_string(&key);
+    let r128 = |key: &str| tag.get_string(&ItemKey::Unknown(key.to_string())).and_then(parse_r128_gain);
+
+    let track_gain = read(ItemKey::ReplayGainTrackGain)
+        .and_then(parse_gain_db)
+        .or_else(|| r128("R128_TRACK_GAIN"));
+    let album_gain = read(ItemKey::ReplayGainAlbumGain)
+        .and_then(parse_gain_db)
+        .or_else(|| r128("R128_ALBUM_GAIN"));
+    let track_peak = read(ItemKey::ReplayGainTrackPeak).and_then(parse_peak);
+    let album_peak = read(ItemKey::ReplayGainAlbumPeak).and_then(parse_peak);
+
+    let mut rg = serde_json::Map::new();
+    for (key, value) in [
+        ("track_gain_db", track_gain),
+        ("track_peak", track_peak),
+        ("album_gain_db", album_gain),
+        ("album_peak", album_peak),
+    ] {
+        if let Some(v) = value {
+            rg.insert(key.to_string(), v.into());
+        }
+    }
+    if rg.is_empty() {
+        None
+    } else {
+        Some(rg.into())
+    }
+}
 
-    (title, artist, album, genre, duration_ms)
+/// Parse a ReplayGain gain value: `"-6.53 dB"`, `"+2.1dB"`, `"-6.53"`.
+fn parse_gain_db(value: &str) -> Option<f64> {
+    let v = value.trim();
+    let v = v
+        .strip_suffix("dB")
+        .or_else(|| v.strip_suffix("db"))
+        .or_else(|| v.strip_suffix("DB"))
+        .unwrap_or(v);
+    v.trim().parse::<f64>().ok().filter(|g| g.is_finite())
+}
+
+/// Parse a ReplayGain peak (linear amplitude, 1.0 = full scale).
+fn parse_peak(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|p| p.is_finite() && *p > 0.0)
+}
+
+/// Convert an Opus `R128_*_GAIN` tag to a ReplayGain-equivalent dB value.
+///
+/// R128 gains are Q7.8 fixed-point dB relative to -23 LUFS; ReplayGain
+/// targets -18 LUFS, so the equivalent gain is 5 dB higher.
+fn parse_r128_gain(value: &str) -> Option<f64> {
+    let q78 = value.trim().parse::<i32>().ok()?;
+    Some(q78 as f64 / 256.0 + 5.0)
 }
 
 /// Extract embedded album art from an audio file.
@@ -291,7 +358,7 @@ fn sanitize_id(name: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::stable_id;
+    use super::{parse_gain_db, parse_peak, parse_r128_gain, stable_id};
 
     #[test]
     fn stable_id_deterministic() {
@@ -306,4 +373,22 @@ mod tests {
         let b = stable_id("/other/song.mp3", "song.mp3");
         assert_ne!(a, b);
     }
+
+    #[test]
+    fn parse_replay_gain_values() {
+        assert_eq!(parse_gain_db("-6.53 dB"), Some(-6.53));
+        assert_eq!(parse_gain_db("+2.10dB"), Some(2.1));
+        assert_eq!(parse_gain_db(" -1.5 "), Some(-1.5));
+        assert_eq!(parse_gain_db("loud"), None);
+        assert_eq!(parse_peak("0.988525"), Some(0.988525));
+        assert_eq!(parse_peak("0"), None);
+    }
+
+    #[test]
+    fn r128_gain_maps_to_replay_gain_reference() {
+        // -1792 / 256 = -7 dB at -23 LUFS → -2 dB at the -18 LUFS RG reference
+        assert_eq!(parse_r128_gain("-1792"), Some(-2.0));
+        assert_eq!(parse_r128_gain("0"), Some(5.0));
+        assert_eq!(parse_r128_gain("-7 dB"), None);
+    }
 }
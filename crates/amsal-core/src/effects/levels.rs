@@ -0,0 +1,279 @@
+//! Level and spectrum metering for visualisers.
+//!
+//! `LevelMeter` taps the output after the DSP chain (in the cpal callback,
+//! or the render loop). Every `HOP` frames it publishes each channel's RMS
+//! and peak over the hop, plus a Hann-windowed FFT of the mono mix binned
+//! into `BANDS` log-spaced bands from 20 Hz to 20 kHz. Results land in a
+//! `LevelTap`: a seqlock over atomics, so the meter never blocks or
+//! allocates and readers retry if they raced a write.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::fft::{Complex, Fft};
+
+/// Spectrum bands in a snapshot.
+pub const BANDS: usize = 32;
+/// Channels metered; further ones still count toward the spectrum.
+pub const MAX_CHANNELS: usize = 8;
+/// Reported for silence (and bands above the Nyquist frequency).
+pub const FLOOR_DB: f32 = -100.0;
+
+const FFT_SIZE: usize = 2048;
+/// Frames between snapshots (~43 per second at 44.1 kHz).
+const HOP: usize = FFT_SIZE / 2;
+const LOW_HZ: f32 = 20.0;
+const HIGH_HZ: f32 = 20_000.0;
+
+/// Edge `i` of the log-spaced bands (`i` in `0..=BANDS`).
+fn band_edge(i: usize) -> f32 {
+    LOW_HZ * (HIGH_HZ / LOW_HZ).powf(i as f32 / BANDS as f32)
+}
+
+/// Geometric centre of each spectrum band, in Hz.
+pub fn band_centres() -> [f32; BANDS] {
+    std::array::from_fn(|i| (band_edge(i) * band_edge(i + 1)).sqrt())
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    if amplitude > 0.0 {
+        (20.0 * amplitude.log10()).max(FLOOR_DB)
+    } else {
+        FLOOR_DB
+    }
+}
+
+/// One published measurement, in dBFS.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Levels {
+    pub rms_db: Vec<f32>,
+    pub peak_db: Vec<f32>,
+    pub spectrum_db: Vec<f32>,
+}
+
+impl Levels {
+    /// `{"rms_db": [..], "peak_db": [..], "spectrum_db": [..], "bands_hz": [..]}`,
+    /// rounded to 0.1 dB / 1 Hz.
+    pub fn to_value(&self) -> serde_json::Value {
+        let round = |v: &[f32]| v.iter().map(|x| (x * 10.0).round() / 10.0).collect::<Vec<_>>();
+        serde_json::json!({
+            "rms_db": round(&self.rms_db),
+            "peak_db": round(&self.peak_db),
+            "spectrum_db": round(&self.spectrum_db),
+            "bands_hz": band_centres().iter().map(|f| f.round()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Latest snapshot, shared between the metering thread and readers.
+pub struct LevelTap {
+    /// Odd while a write is in progress; 0 = nothing published yet.
+    seq: AtomicU64,
+    channels: AtomicU32,
+    rms: [AtomicU32; MAX_CHANNELS],
+    peak: [AtomicU32; MAX_CHANNELS],
+    spectrum: [AtomicU32; BANDS],
+}
+
+impl LevelTap {
+    pub fn new() -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            channels: AtomicU32::new(0),
+            rms: std::array::from_fn(|_| AtomicU32::new(0)),
+            peak: std::array::from_fn(|_| AtomicU32::new(0)),
+            spectrum: std::array::from_fn(|_| AtomicU32::new(0)),
+        }
+    }
+
+    /// Single writer only (one meter per tap at a time).
+    fn publish(&self, rms: &[f32], peak: &[f32], spectrum: &[f32]) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        self.channels.store(rms.len() as u32, Ordering::SeqCst);
+        for (slot, &v) in self.rms.iter().zip(rms) {
+            slot.store(v.to_bits(), Ordering::SeqCst);
+        }
+        for (slot, &v) in self.peak.iter().zip(peak) {
+            slot.store(v.to_bits(), Ordering::SeqCst);
+        }
+        for (slot, &v) in self.spectrum.iter().zip(spectrum) {
+            slot.store(v.to_bits(), Ordering::SeqCst);
+        }
+        self.seq.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The latest snapshot; None until a meter has published one.
+    pub fn snapshot(&self) -> Option<Levels> {
+        loop {
+            let before = self.seq.load(Ordering::SeqCst);
+            if before == 0 {
+                return None;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let channels = (self.channels.load(Ordering::SeqCst) as usize).min(MAX_CHANNELS);
+            let read = |slots: &[AtomicU32]| slots.iter().map(|s| f32::from_bits(s.load(Ordering::SeqCst))).collect();
+            let levels = Levels {
+                rms_db: read(&self.rms[..channels]),
+                peak_db: read(&self.peak[..channels]),
+                spectrum_db: read(&self.spectrum),
+            };
+            if self.seq.load(Ordering::SeqCst) == before {
+                return Some(levels);
+            }
+        }
+    }
+}
+
+impl Default for LevelTap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Meter for one interleaved stream format. All buffers are allocated up
+/// front; `process` is safe to call from the output callback.
+pub struct LevelMeter {
+    channels: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    /// Scales a bin magnitude so a full-scale sine reads 0 dB.
+    norm: f32,
+    /// Last `FFT_SIZE` mono samples, circular; `next` is the oldest.
+    history: Vec<f32>,
+    next: usize,
+    scratch: Vec<Complex>,
+    /// FFT bins `start..end` of each band (empty above Nyquist).
+    bins: [(usize, usize); BANDS],
+    /// Sum of squares and peak per channel over the current hop.
+    sum_sq: Vec<f32>,
+    peak: Vec<f32>,
+    frames: usize,
+    rms_db: Vec<f32>,
+    peak_db: Vec<f32>,
+    spectrum_db: Vec<f32>,
+}
+
+impl LevelMeter {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        let metered = channels.min(MAX_CHANNELS);
+        let window: Vec<f32> = (0..FFT_SIZE)
+            .map(|n| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        let norm = 2.0 / window.iter().sum::<f32>();
+        let hz_per_bin = sample_rate.max(1) as f32 / FFT_SIZE as f32;
+        let nyquist_bin = FFT_SIZE / 2;
+        let bins = std::array::from_fn(|i| {
+            let (lo, hi) = (band_edge(i) / hz_per_bin, band_edge(i + 1) / hz_per_bin);
+            let start = lo.ceil() as usize;
+            let end = hi.floor() as usize + 1;
+            // Narrower than a bin: take the bin the band's centre falls in
+            let (start, end) = if start < end { (start, end) } else {
+                let centre = (lo * hi).sqrt().round() as usize;
+                (centre, centre + 1)
+            };
+            (start.min(nyquist_bin), end.min(nyquist_bin))
+        });
+        Self {
+            channels,
+            fft: Fft::new(FFT_SIZE),
+            window,
+            norm,
+            history: vec![0.0; FFT_SIZE],
+            next: 0,
+            scratch: vec![Complex::ZERO; FFT_SIZE],
+            bins,
+            sum_sq: vec![0.0; metered],
+            peak: vec![0.0; metered],
+            frames: 0,
+            rms_db: vec![FLOOR_DB; metered],
+            peak_db: vec![FLOOR_DB; metered],
+            spectrum_db: vec![FLOOR_DB; BANDS],
+        }
+    }
+
+    /// Meter a block of interleaved output, publishing to `tap` at each hop.
+    pub fn process(&mut self, samples: &[f32], tap: &LevelTap) {
+        let metered = self.sum_sq.len();
+        for frame in samples.chunks_exact(self.channels) {
+            for (c, &s) in frame[..metered].iter().enumerate() {
+                self.sum_sq[c] += s * s;
+                self.peak[c] = self.peak[c].max(s.abs());
+            }
+            self.history[self.next] = frame.iter().sum::<f32>() / self.channels as f32;
+            self.next = (self.next + 1) % FFT_SIZE;
+            self.frames += 1;
+            if self.frames == HOP {
+                self.publish(tap);
+            }
+        }
+    }
+
+    fn publish(&mut self, tap: &LevelTap) {
+        for c in 0..self.sum_sq.len() {
+            self.rms_db[c] = to_db((self.sum_sq[c] / self.frames as f32).sqrt());
+            self.peak_db[c] = to_db(self.peak[c]);
+        }
+        self.sum_sq.fill(0.0);
+        self.peak.fill(0.0);
+        self.frames = 0;
+
+        for (i, slot) in self.scratch.iter_mut().enumerate() {
+            let s = self.history[(self.next + i) % FFT_SIZE];
+            *slot = Complex::new(s * self.window[i], 0.0);
+        }
+        self.fft.forward(&mut self.scratch);
+        for (band, &(start, end)) in self.spectrum_db.iter_mut().zip(&self.bins) {
+            let loudest = self.scratch[start..end].iter().map(|c| c.norm()).fold(0.0, f32::max);
+            *band = to_db(loudest * self.norm);
+        }
+        tap.publish(&self.rms_db, &self.peak_db, &self.spectrum_db);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, amplitude: f32, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = amplitude * (2.0 * std::f32::consts::PI * freq * n as f32 / rate as f32).sin();
+                [s, 0.0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_levels_and_finds_the_tone_band() {
+        let tap = LevelTap::new();
+        assert_eq!(tap.snapshot(), None);
+
+        let mut meter = LevelMeter::new(2, 48000);
+        meter.process(&sine(1000.0, 0.5, 48000, FFT_SIZE * 2), &tap);
+        let levels = tap.snapshot().expect("published");
+        // 0.5 peak sine: -6 dB peak, -9 dB RMS; the silent right channel sits at the floor
+        assert!((levels.peak_db[0] + 6.02).abs() < 0.1, "{:?}", levels.peak_db);
+        assert!((levels.rms_db[0] + 9.03).abs() < 0.1, "{:?}", levels.rms_db);
+        assert_eq!((levels.rms_db[1], levels.peak_db[1]), (FLOOR_DB, FLOOR_DB));
+
+        // Mono mix halves it again (-12 dB); every other band is well below
+        let centres = band_centres();
+        let band = (0..BANDS).find(|&i| band_edge(i) <= 1000.0 && 1000.0 < band_edge(i + 1)).unwrap();
+        assert!(centres[band] > 800.0 && centres[band] < 1200.0);
+        assert!((levels.spectrum_db[band] + 12.0).abs() < 1.5, "{:?}", levels.spectrum_db);
+        for (i, &db) in levels.spectrum_db.iter().enumerate() {
+            if i + 2 < band || i > band + 2 {
+                assert!(db < levels.spectrum_db[band] - 40.0, "band {} at {} dB", i, db);
+            }
+        }
+
+        // Silence decays to the floor at the next hop
+        meter.process(&vec![0.0; FFT_SIZE * 2 * 2], &tap);
+        let levels = tap.snapshot().unwrap();
+        assert!(levels.spectrum_db.iter().chain(&levels.rms_db).all(|&db| db == FLOOR_DB));
+        assert_eq!(levels.to_value()["bands_hz"].as_array().unwrap().len(), BANDS);
+    }
+}
@@ -1,7 +1,21 @@
+pub mod convert;
+pub mod convolution;
+pub(crate) mod decode;
 pub mod dsp;
+pub mod dynamics;
+pub mod fft;
+pub mod levels;
+pub mod resample;
+pub mod stereo;
+pub mod stretch;
 #[cfg(feature = "http")]
 pub mod http;
 
+/// Per-track gains, trim points and speeds a backend keeps before resetting
+/// each map. Only the playing and staged tracks matter; the engine re-sends
+/// both before they are opened.
+pub(crate) const MAX_TRACK_SETTINGS: usize = 8;
+
 /// Trait for audio output backends.
 ///
 /// The engine uses this to abstract over native (cpal) and headless/WASM backends.
@@ -11,16 +25,76 @@ pub trait AudioBackend: Send + Sync {
     fn pause(&self);
     fn resume(&self);
     fn stop(&self);
-    fn seek(&self, position_ms: u64);
+    /// Seek and return the position landed on (unchanged when the seek
+    /// failed or nothing is loaded). At or past the end, the track ends.
+    fn seek(&self, position_ms: u64) -> u64;
+    /// Fade to silence over `duration_ms`, then stop. Not a natural end:
+    /// `is_finished` stays false, so the engine doesn't advance the queue.
+    fn fade_out(&self, duration_ms: u64);
+    /// Length of the short ramps that keep pause, resume, seek, stop and
+    /// track skips from clicking (0 = hard cuts).
+    fn set_transport_fade(&self, duration_ms: u64);
     fn set_volume(&self, volume: f32);
+    /// Playback speed with pitch preserved (`stretch::MIN_SPEED..=MAX_SPEED`,
+    /// 1.0 = normal). Applies from the next decoded packet.
+    fn set_speed(&self, speed: f32);
+    /// Speed for `file_path`, switched to when a gapless or crossfaded
+    /// handover moves into it (`play` keeps `set_speed`'s).
+    fn set_track_speed(&self, file_path: &str, speed: f32);
     fn is_playing(&self) -> bool;
     fn is_paused(&self) -> bool;
     fn is_finished(&self) -> bool;
     fn is_error(&self) -> bool;
     fn prepare_next(&self, file_path: &str);
+    /// Path of the prepared track once playback moved into it gaplessly
+    /// (reported once). The engine then advances the queue without `play()`.
+    fn take_track_advance(&self) -> Option<String>;
+    /// Overlap the next prepared track with the current one's tail
+    /// (0 = gapless, no overlap). Read when the handover starts.
+    fn set_crossfade(&self, duration_ms: u64, curve: dsp::FadeCurve);
+    /// Linear gain for `file_path` (ReplayGain/R128 normalization, 1.0 = unity).
+    /// Applied to that track's samples from the next decoded packet on.
+    fn set_replay_gain(&self, file_path: &str, gain: f32);
+    /// Trim points for `file_path`: play it from `start_ms` and end it at
+    /// `end_ms` (None = end of stream). Read when the track is opened, by
+    /// `play` or a gapless/crossfaded handover.
+    fn set_track_range(&self, file_path: &str, start_ms: u64, end_ms: Option<u64>);
+    /// Loop the playing track between two positions in ms (None = no loop).
+    /// Reaching the end jumps back to the start without restarting the
+    /// pipeline; `play` clears the loop.
+    fn set_loop(&self, region: Option<(u64, u64)>);
+    fn loop_region(&self) -> Option<(u64, u64)>;
+    /// Resampler used when a track's rate differs from the device rate.
+    fn set_resample_quality(&self, quality: resample::ResampleQuality);
+    /// Down/upmix policy when a track's channels differ from the output's.
+    fn set_channel_mix(&self, mix: convert::ChannelMix);
+    /// Position the listener hears now: audio buffered for the output and
+    /// the device latency are behind the decoder, not counted as played.
     fn position_ms(&self) -> u64;
+    /// Position the decoder has reached (equal to `position_ms` for
+    /// backends that don't buffer ahead of the output).
+    fn decoded_position_ms(&self) -> u64;
     fn duration_ms(&self) -> u64;
+    /// Output callbacks that ran out of decoded audio mid-playback (cumulative).
+    fn underruns(&self) -> u64;
+    /// Latest RMS/peak and spectrum measured after the DSP chain (None
+    /// until audio has been output).
+    fn levels(&self) -> Option<levels::Levels>;
+    /// Prefer the named output device (None = host default). Falls back to
+    /// the default when it is missing or disappears during playback.
+    fn set_output_device(&self, name: Option<&str>);
+    /// TPDF dither when the device takes integer samples (default on).
+    fn set_dither(&self, enabled: bool);
+    /// `{"hosts": [{"name", "default", "devices": [{"name", "default", "configs"}]}],
+    /// "preferred", "active"}`.
+    fn output_devices(&self) -> serde_json::Value;
+    /// (sample_rate, channels) of the active output stream; None when idle.
+    fn output_format(&self) -> Option<(u32, u16)>;
+    /// Install a prebuilt DSP chain as is (not rebuilt on format changes).
     fn set_dsp(&self, chain: dsp::DspChain);
+    /// Build the DSP chain from an EQ scroll (`/amsal/playback/eq`) at the
+    /// output's actual format, rebuilding it whenever that format changes.
+    fn set_eq(&self, spec: &serde_json::Value);
 }
 
 /// No-op audio backend for headless/WASM use.
@@ -34,18 +108,44 @@ impl AudioBackend for NoopBackend {
     fn pause(&self) {}
     fn resume(&self) {}
     fn stop(&self) {}
-    fn seek(&self, _: u64) {}
+    fn seek(&self, _: u64) -> u64 { 0 }
+    fn fade_out(&self, _: u64) {}
+    fn set_transport_fade(&self, _: u64) {}
     fn set_volume(&self, _: f32) {}
+    fn set_speed(&self, _: f32) {}
+    fn set_track_speed(&self, _: &str, _: f32) {}
     fn is_playing(&self) -> bool { false }
     fn is_paused(&self) -> bool { false }
     fn is_finished(&self) -> bool { false }
     fn is_error(&self) -> bool { false }
     fn prepare_next(&self, _: &str) {}
+    fn take_track_advance(&self) -> Option<String> { None }
+    fn set_crossfade(&self, _: u64, _: dsp::FadeCurve) {}
+    fn set_replay_gain(&self, _: &str, _: f32) {}
+    fn set_track_range(&self, _: &str, _: u64, _: Option<u64>) {}
+    fn set_loop(&self, _: Option<(u64, u64)>) {}
+    fn loop_region(&self) -> Option<(u64, u64)> { None }
+    fn set_resample_quality(&self, _: resample::ResampleQuality) {}
+    fn set_channel_mix(&self, _: convert::ChannelMix) {}
     fn position_ms(&self) -> u64 { 0 }
+    fn decoded_position_ms(&self) -> u64 { 0 }
     fn duration_ms(&self) -> u64 { 0 }
+    fn underruns(&self) -> u64 { 0 }
+    fn levels(&self) -> Option<levels::Levels> { None }
+    fn set_output_device(&self, _: Option<&str>) {}
+    fn set_dither(&self, _: bool) {}
+    fn output_devices(&self) -> serde_json::Value {
+        serde_json::json!({"hosts": [], "preferred": null, "active": null})
+    }
+    fn output_format(&self) -> Option<(u32, u16)> { None }
     fn set_dsp(&self, _: dsp::DspChain) {}
+    fn set_eq(&self, _: &serde_json::Value) {}
 }
 
+pub mod analysis;
+pub mod apo;
 #[cfg(feature = "native")]
 pub mod audio;
 pub mod import;
+pub mod render;
+pub mod scripted;
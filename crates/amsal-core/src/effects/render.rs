@@ -0,0 +1,1066 @@
+//! Offline render backend — the playback pipeline into a WAV file or buffer.
+//!
+//! Runs the same stages as the cpal backend (decode with gapless trim and
+//! normalization gain, crossfade, resample, channel adaptation, volume,
+//! `DspChain`) on one thread, as fast as the decoder goes. Used to export
+//! EQ'd renders (`amsal render`) and to exercise the real pipeline in tests
+//! on machines without a sound card.
+//!
+//! The output format is fixed by `RenderOptions` or, where unset, by the
+//! first track played. Later tracks are resampled and channel-adapted to
+//! it, so a whole queue renders into one continuous file. With no device
+//! config to keep, any staged next track continues gaplessly; crossfades
+//! still need matching formats since they mix before resampling.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use parking_lot::Mutex;
+
+use super::convert::{f32_to_i16, f32_to_i24, ChannelMatrix, ChannelMix, Tpdf};
+use super::decode::{finish_crossfade, Crossfade, TrackDecoder};
+use super::dsp::{chain_from_value, DspChain, DspSwitch, FadeCurve, GainRamp};
+use super::levels::{LevelMeter, LevelTap, Levels};
+use super::resample::{ResampleQuality, Resampler};
+use super::stretch::{TimeStretch, MAX_SPEED, MIN_SPEED};
+use super::MAX_TRACK_SETTINGS;
+
+/// Sample encoding of rendered WAV files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WavFormat {
+    /// 16-bit PCM, TPDF-dithered unless dither is off.
+    #[default]
+    S16,
+    /// 24-bit PCM, dithered like S16.
+    S24,
+    /// 32-bit IEEE float — the pipeline's own samples, unquantized.
+    F32,
+}
+
+impl WavFormat {
+    /// Parse a format name ("s16", "s24", "f32"). Unknown → None.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "s16" => Some(Self::S16),
+            "s24" => Some(Self::S24),
+            "f32" => Some(Self::F32),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u16 {
+        match self {
+            Self::S16 => 16,
+            Self::S24 => 24,
+            Self::F32 => 32,
+        }
+    }
+}
+
+/// Output format of a render. Unset fields follow the first track played.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderOptions {
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub format: WavFormat,
+}
+
+/// Offline audio backend: renders instead of playing.
+pub struct RenderBackend {
+    state: Arc<RenderState>,
+}
+
+struct RenderState {
+    options: RenderOptions,
+    /// Output file (None = render into memory).
+    path: Option<PathBuf>,
+    /// Output (sample_rate, channels), fixed when the first track opens.
+    format: Mutex<Option<(u32, u16)>>,
+    sink: Mutex<Sink>,
+    playing: AtomicBool,
+    paused: AtomicBool,
+    finished: AtomicBool,
+    error: AtomicBool,
+    stop_signal: AtomicBool,
+    /// Volume 0-100 mapped to 0.0-1.0.
+    volume: AtomicU32,
+    /// Playback speed (`f32` bits).
+    speed: AtomicU32,
+    position_ms: AtomicU64,
+    duration_ms: AtomicU64,
+    /// Target of the latest seek, in ms; pending while `seek_generation`
+    /// is ahead of `seek_handled` (see the cpal backend).
+    seek_to_ms: AtomicU64,
+    seek_generation: AtomicU64,
+    seek_handled: AtomicU64,
+    /// Length of a requested `fade_out`, in ms (0 = none).
+    fade_out_ms: AtomicU64,
+    /// Track staged by `prepare_next`, already opened.
+    next: Mutex<Option<TrackDecoder>>,
+    /// Path the render moved into, until `take_track_advance` reports it.
+    advanced_path: Mutex<Option<String>>,
+    crossfade: Mutex<(u64, FadeCurve)>,
+    replay_gain: Mutex<HashMap<String, f32>>,
+    /// Trim points (start_ms, end_ms) per file path, applied on open.
+    track_ranges: Mutex<HashMap<String, (u64, Option<u64>)>>,
+    /// Speed per file path, switched to when the render moves into it.
+    track_speeds: Mutex<HashMap<String, f32>>,
+    /// A-B loop of the track being rendered, in ms.
+    loop_region: Mutex<Option<(u64, u64)>>,
+    resample_quality: Mutex<ResampleQuality>,
+    channel_mix: Mutex<ChannelMix>,
+    dither: AtomicBool,
+    /// Chain waiting to be picked up by the render thread, which owns the
+    /// active one while it runs and parks it here when it exits.
+    dsp_chain: Mutex<Option<DspChain>>,
+    /// EQ spec from `set_eq`, built into a chain once the format is known.
+    eq: Mutex<Option<serde_json::Value>>,
+    frames_rendered: AtomicU64,
+    /// Levels of the last rendered blocks, metered as they are written.
+    levels: LevelTap,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+enum Sink {
+    /// No file open yet (or `finish()` closed it).
+    Closed,
+    File(WavWriter<BufWriter<File>>),
+    Memory(Vec<f32>),
+}
+
+impl RenderState {
+    fn refresh_gain(&self, track: &mut TrackDecoder) {
+        if let Some(&gain) = self.replay_gain.lock().get(&track.path) {
+            track.gain = gain;
+        }
+    }
+
+    fn seek_pending(&self) -> bool {
+        self.seek_generation.load(Ordering::SeqCst) != self.seek_handled.load(Ordering::SeqCst)
+    }
+
+    fn apply_range(&self, track: &mut TrackDecoder) {
+        if let Some(&(start_ms, end_ms)) = self.track_ranges.lock().get(&track.path) {
+            track.set_range(start_ms, end_ms);
+        }
+    }
+
+    /// Fix the output format from the first track and open the file.
+    /// A zero rate or channel count (from `RenderOptions`) is rejected.
+    fn open_sink(&self, track: &TrackDecoder) -> io::Result<(u32, u16)> {
+        let mut format = self.format.lock();
+        let (rate, channels) = match *format {
+            Some(f) => f,
+            None => {
+                let f = (
+                    self.options.sample_rate.unwrap_or(track.sample_rate),
+                    self.options.channels.unwrap_or(track.channels.max(1) as u16),
+                );
+                if f.0 == 0 || f.1 == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid output format: {} Hz, {} channels", f.0, f.1),
+                    ));
+                }
+                if let Some(eq) = &*self.eq.lock() {
+                    *self.dsp_chain.lock() = Some(chain_from_value(eq, f.0, f.1));
+                }
+                *format = Some(f);
+                f
+            }
+        };
+        let mut sink = self.sink.lock();
+        if let (Sink::Closed, Some(path)) = (&*sink, &self.path) {
+            let file = BufWriter::new(File::create(path)?);
+            *sink = Sink::File(WavWriter::new(file, rate, channels, self.options.format)?);
+        }
+        Ok((rate, channels))
+    }
+
+    fn write(&self, samples: &[f32]) -> io::Result<()> {
+        match &mut *self.sink.lock() {
+            Sink::File(w) => w.write(samples, self.dither.load(Ordering::SeqCst)),
+            Sink::Memory(buf) => {
+                buf.extend_from_slice(samples);
+                Ok(())
+            }
+            Sink::Closed => Ok(()),
+        }
+    }
+
+    /// Patch the WAV header so the file is complete as it stands.
+    fn flush_sink(&self) -> io::Result<()> {
+        match &mut *self.sink.lock() {
+            Sink::File(w) => w.finalize(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Take the staged track. With `like`, only if it has the same format
+    /// (a crossfade mixes before resampling); otherwise it stays staged.
+    fn take_next(&self, like: Option<&TrackDecoder>) -> Option<TrackDecoder> {
+        let mut staged = self.next.lock();
+        if let (Some(next), Some(current)) = (staged.as_ref(), like) {
+            if next.sample_rate != current.sample_rate || next.channels != current.channels {
+                return None;
+            }
+        }
+        let mut next = staged.take()?;
+        // Set before the staging lock is released, see `prepare_next`
+        *self.advanced_path.lock() = Some(next.path.clone());
+        drop(staged);
+        if let Some(&speed) = self.track_speeds.lock().get(&next.path) {
+            self.speed.store(speed.to_bits(), Ordering::SeqCst);
+        }
+        self.refresh_gain(&mut next);
+        self.apply_range(&mut next);
+        Some(next)
+    }
+}
+
+impl RenderBackend {
+    /// Render into a WAV file at `path`, created when the first track plays.
+    pub fn to_file(path: impl AsRef<Path>, options: RenderOptions) -> Self {
+        Self::with_sink(Some(path.as_ref().to_path_buf()), Sink::Closed, options)
+    }
+
+    /// Render into memory; collect the samples with `take_rendered`.
+    pub fn in_memory(options: RenderOptions) -> Self {
+        Self::with_sink(None, Sink::Memory(Vec::new()), options)
+    }
+
+    fn with_sink(path: Option<PathBuf>, sink: Sink, options: RenderOptions) -> Self {
+        Self {
+            state: Arc::new(RenderState {
+                options,
+                path,
+                format: Mutex::new(None),
+                sink: Mutex::new(sink),
+                playing: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+                finished: AtomicBool::new(false),
+                error: AtomicBool::new(false),
+                stop_signal: AtomicBool::new(false),
+                volume: AtomicU32::new(100),
+                speed: AtomicU32::new(1.0f32.to_bits()),
+                position_ms: AtomicU64::new(0),
+                duration_ms: AtomicU64::new(0),
+                seek_to_ms: AtomicU64::new(0),
+                seek_generation: AtomicU64::new(0),
+                seek_handled: AtomicU64::new(0),
+                fade_out_ms: AtomicU64::new(0),
+                next: Mutex::new(None),
+                advanced_path: Mutex::new(None),
+                crossfade: Mutex::new((0, FadeCurve::default())),
+                replay_gain: Mutex::new(HashMap::new()),
+                track_ranges: Mutex::new(HashMap::new()),
+                track_speeds: Mutex::new(HashMap::new()),
+                loop_region: Mutex::new(None),
+                resample_quality: Mutex::new(ResampleQuality::default()),
+                channel_mix: Mutex::new(ChannelMix::default()),
+                dither: AtomicBool::new(true),
+                dsp_chain: Mutex::new(None),
+                eq: Mutex::new(None),
+                frames_rendered: AtomicU64::new(0),
+                levels: LevelTap::new(),
+                thread: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Render a file, appended to the output after anything rendered so far.
+    /// Stops a render in progress first.
+    pub fn play(&self, file_path: &str) {
+        self.stop();
+
+        self.state.stop_signal.store(false, Ordering::SeqCst);
+        self.state.playing.store(true, Ordering::SeqCst);
+        self.state.paused.store(false, Ordering::SeqCst);
+        self.state.finished.store(false, Ordering::SeqCst);
+        self.state.error.store(false, Ordering::SeqCst);
+        self.state.position_ms.store(0, Ordering::SeqCst);
+        self.state.duration_ms.store(0, Ordering::SeqCst);
+        self.state.seek_handled.store(self.state.seek_generation.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.state.fade_out_ms.store(0, Ordering::SeqCst);
+        *self.state.loop_region.lock() = None;
+        *self.state.advanced_path.lock() = None;
+
+        let path = file_path.to_string();
+        let state = Arc::clone(&self.state);
+        *self.state.thread.lock() = Some(thread::spawn(move || {
+            if let Err(e) = render_track(&path, &state) {
+                log::error!("amsal: render error: {}", e);
+                state.error.store(true, Ordering::SeqCst);
+            }
+            // A stopped or faded-out render didn't end naturally
+            if !state.stop_signal.load(Ordering::SeqCst) {
+                state.finished.store(true, Ordering::SeqCst);
+            }
+            state.playing.store(false, Ordering::SeqCst);
+        }));
+    }
+
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Stop rendering. What was rendered stays in the output.
+    pub fn stop(&self) {
+        self.state.stop_signal.store(true, Ordering::SeqCst);
+        self.state.playing.store(false, Ordering::SeqCst);
+        self.state.paused.store(false, Ordering::SeqCst);
+        let handle = self.state.thread.lock().take();
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+    }
+
+    /// Seek within the track being rendered and return the position landed
+    /// on, once the render thread has carried it out.
+    pub fn seek(&self, position_ms: u64) -> u64 {
+        if !self.is_playing() {
+            return self.position_ms();
+        }
+        self.state.seek_to_ms.store(position_ms, Ordering::SeqCst);
+        self.state.seek_generation.fetch_add(1, Ordering::SeqCst);
+        while self.state.seek_pending() && self.is_playing() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        self.position_ms()
+    }
+
+    /// Render the next `duration_ms` fading to silence, then stop. A paused
+    /// render stops at once.
+    pub fn fade_out(&self, duration_ms: u64) {
+        if self.is_paused() {
+            self.stop();
+        } else if self.is_playing() {
+            self.state.fade_out_ms.store(duration_ms.max(1), Ordering::SeqCst);
+        }
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        let v = (volume.clamp(0.0, 1.0) * 100.0) as u32;
+        self.state.volume.store(v, Ordering::SeqCst);
+    }
+
+    /// Render speed, pitch preserved (clamped to 0.5-3.0).
+    pub fn set_speed(&self, speed: f32) {
+        let speed = if speed.is_finite() { speed.clamp(MIN_SPEED, MAX_SPEED) } else { 1.0 };
+        self.state.speed.store(speed.to_bits(), Ordering::SeqCst);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.state.playing.load(Ordering::SeqCst)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::SeqCst)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.state.error.load(Ordering::SeqCst)
+    }
+
+    /// Stage the next track; the render continues into it at end of stream
+    /// whatever its format.
+    pub fn prepare_next(&self, file_path: &str) {
+        {
+            let staged = self.state.next.lock();
+            if staged.as_ref().is_some_and(|t| t.path == file_path) {
+                return; // Already staged — the heartbeat calls this every tick
+            }
+            // Moved into the staged track but not reported yet: the engine
+            // still sees the outgoing track and would stage this one again
+            if self.state.advanced_path.lock().is_some() {
+                return;
+            }
+        }
+        match TrackDecoder::open(file_path) {
+            Ok(track) => *self.state.next.lock() = Some(track),
+            Err(e) => log::warn!("amsal: render could not stage {}: {}", file_path, e),
+        }
+    }
+
+    /// Path of the staged track once the render moved into it (reported once).
+    pub fn take_track_advance(&self) -> Option<String> {
+        self.state.advanced_path.lock().take()
+    }
+
+    pub fn set_crossfade(&self, duration_ms: u64, curve: FadeCurve) {
+        *self.state.crossfade.lock() = (duration_ms, curve);
+    }
+
+    pub fn set_replay_gain(&self, file_path: &str, gain: f32) {
+        let mut gains = self.state.replay_gain.lock();
+        if gains.len() >= MAX_TRACK_SETTINGS && !gains.contains_key(file_path) {
+            gains.clear();
+        }
+        gains.insert(file_path.to_string(), gain);
+    }
+
+    pub fn set_track_speed(&self, file_path: &str, speed: f32) {
+        let speed = if speed.is_finite() { speed.clamp(MIN_SPEED, MAX_SPEED) } else { 1.0 };
+        let mut speeds = self.state.track_speeds.lock();
+        if speeds.len() >= MAX_TRACK_SETTINGS && !speeds.contains_key(file_path) {
+            speeds.clear();
+        }
+        speeds.insert(file_path.to_string(), speed);
+    }
+
+    pub fn set_track_range(&self, file_path: &str, start_ms: u64, end_ms: Option<u64>) {
+        let mut ranges = self.state.track_ranges.lock();
+        if ranges.len() >= MAX_TRACK_SETTINGS && !ranges.contains_key(file_path) {
+            ranges.clear();
+        }
+        ranges.insert(file_path.to_string(), (start_ms, end_ms));
+    }
+
+    /// Loop the track being rendered. A render that loops never finishes
+    /// on its own; clear the loop or stop it.
+    pub fn set_loop(&self, region: Option<(u64, u64)>) {
+        *self.state.loop_region.lock() = region;
+    }
+
+    pub fn loop_region(&self) -> Option<(u64, u64)> {
+        *self.state.loop_region.lock()
+    }
+
+    pub fn set_resample_quality(&self, quality: ResampleQuality) {
+        *self.state.resample_quality.lock() = quality;
+    }
+
+    pub fn set_channel_mix(&self, mix: ChannelMix) {
+        *self.state.channel_mix.lock() = mix;
+    }
+
+    /// Position rendered so far. Blocks are written as they are produced,
+    /// so this is the decoded position too.
+    pub fn position_ms(&self) -> u64 {
+        self.state.position_ms.load(Ordering::SeqCst)
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.state.duration_ms.load(Ordering::SeqCst)
+    }
+
+    /// TPDF dither for S16/S24 files (default on).
+    pub fn set_dither(&self, enabled: bool) {
+        self.state.dither.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Replace the DSP chain from the next rendered block on.
+    pub fn set_dsp(&self, chain: DspChain) {
+        *self.state.eq.lock() = None;
+        *self.state.dsp_chain.lock() = Some(chain);
+    }
+
+    /// Build the DSP chain from an EQ scroll (`/amsal/playback/eq`) at the
+    /// output format — now if it is known, else when the first track opens.
+    pub fn set_eq(&self, spec: &serde_json::Value) {
+        *self.state.eq.lock() = Some(spec.clone());
+        if let Some((rate, channels)) = *self.state.format.lock() {
+            *self.state.dsp_chain.lock() = Some(chain_from_value(spec, rate, channels));
+        }
+    }
+
+    /// Output (sample_rate, channels) once the first track has opened.
+    pub fn output_format(&self) -> Option<(u32, u16)> {
+        *self.state.format.lock()
+    }
+
+    /// Frames written to the output so far.
+    pub fn frames_rendered(&self) -> u64 {
+        self.state.frames_rendered.load(Ordering::SeqCst)
+    }
+
+    /// Levels of the most recently rendered audio.
+    pub fn levels(&self) -> Option<Levels> {
+        self.state.levels.snapshot()
+    }
+
+    /// Samples rendered into memory since the last call (interleaved at
+    /// `output_format`). Empty for file renders.
+    pub fn take_rendered(&self) -> Vec<f32> {
+        match &mut *self.state.sink.lock() {
+            Sink::Memory(buf) => std::mem::take(buf),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Stop and close the output file. A later `play()` starts a new file
+    /// (truncating the old one) with the format chosen afresh.
+    pub fn finish(&self) -> io::Result<()> {
+        self.stop();
+        let sink = match &mut *self.state.sink.lock() {
+            Sink::Memory(_) => return Ok(()),
+            sink => std::mem::replace(sink, Sink::Closed),
+        };
+        *self.state.format.lock() = None;
+        match sink {
+            Sink::File(mut w) => w.finalize(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for RenderBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::warn!("amsal: render output not finalized: {}", e);
+        }
+    }
+}
+
+impl super::AudioBackend for RenderBackend {
+    fn play(&self, file_path: &str) { self.play(file_path) }
+    fn pause(&self) { self.pause() }
+    fn resume(&self) { self.resume() }
+    fn stop(&self) { self.stop() }
+    fn seek(&self, position_ms: u64) -> u64 { self.seek(position_ms) }
+    fn fade_out(&self, duration_ms: u64) { self.fade_out(duration_ms) }
+    // Nothing is heard while rendering, so transport changes can't click
+    fn set_transport_fade(&self, _: u64) {}
+    fn set_volume(&self, volume: f32) { self.set_volume(volume) }
+    fn set_speed(&self, speed: f32) { self.set_speed(speed) }
+    fn set_track_speed(&self, file_path: &str, speed: f32) { self.set_track_speed(file_path, speed) }
+    fn is_playing(&self) -> bool { self.is_playing() }
+    fn is_paused(&self) -> bool { self.is_paused() }
+    fn is_finished(&self) -> bool { self.is_finished() }
+    fn is_error(&self) -> bool { self.is_error() }
+    fn prepare_next(&self, file_path: &str) { self.prepare_next(file_path) }
+    fn take_track_advance(&self) -> Option<String> { self.take_track_advance() }
+    fn set_crossfade(&self, duration_ms: u64, curve: FadeCurve) { self.set_crossfade(duration_ms, curve) }
+    fn set_replay_gain(&self, file_path: &str, gain: f32) { self.set_replay_gain(file_path, gain) }
+    fn set_track_range(&self, file_path: &str, start_ms: u64, end_ms: Option<u64>) { self.set_track_range(file_path, start_ms, end_ms) }
+    fn set_loop(&self, region: Option<(u64, u64)>) { self.set_loop(region) }
+    fn loop_region(&self) -> Option<(u64, u64)> { self.loop_region() }
+    fn set_resample_quality(&self, quality: ResampleQuality) { self.set_resample_quality(quality) }
+    fn set_channel_mix(&self, mix: ChannelMix) { self.set_channel_mix(mix) }
+    fn position_ms(&self) -> u64 { self.position_ms() }
+    fn decoded_position_ms(&self) -> u64 { self.position_ms() }
+    fn duration_ms(&self) -> u64 { self.duration_ms() }
+    fn underruns(&self) -> u64 { 0 }
+    fn levels(&self) -> Option<Levels> { self.levels() }
+    fn set_output_device(&self, _: Option<&str>) {}
+    fn set_dither(&self, enabled: bool) { self.set_dither(enabled) }
+    fn output_devices(&self) -> serde_json::Value {
+        serde_json::json!({"hosts": [], "preferred": null, "active": null})
+    }
+    fn output_format(&self) -> Option<(u32, u16)> { self.output_format() }
+    fn set_dsp(&self, chain: DspChain) { self.set_dsp(chain) }
+    fn set_eq(&self, spec: &serde_json::Value) { self.set_eq(spec) }
+}
+
+/// Render one track (and any staged successors) into the sink.
+///
+/// The DSP chain is owned here while rendering and parked back in the state
+/// afterwards; the WAV header is patched whenever the thread exits.
+fn render_track(file_path: &str, state: &RenderState) -> Result<(), Box<dyn std::error::Error>> {
+    let mut dsp = DspSwitch::new(None);
+    let result = render_loop(file_path, state, &mut dsp);
+    if let Some(chain) = dsp.take_active() {
+        state.dsp_chain.lock().get_or_insert(chain);
+    }
+    state.flush_sink()?;
+    result
+}
+
+fn render_loop(
+    file_path: &str,
+    state: &RenderState,
+    dsp: &mut DspSwitch,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut track = TrackDecoder::open(file_path)?;
+    state.refresh_gain(&mut track);
+    state.apply_range(&mut track);
+    state.duration_ms.store(track.duration_ms, Ordering::SeqCst);
+    let (rate, channels) = state.open_sink(&track)?;
+
+    let mut quality = *state.resample_quality.lock();
+    let resampler_for = |track: &TrackDecoder, quality: ResampleQuality| {
+        (track.sample_rate != rate)
+            .then(|| Resampler::new(track.sample_rate, rate, track.channels as u16, quality))
+    };
+    let mut resampler = resampler_for(&track, quality);
+
+    let mut decoded_frames: u64 = track.start_frame;
+    let mut fade: Option<Crossfade> = None;
+    // Staged track taken at the end of the current one, switched to once
+    // the old resampler's tail has gone through this track's channel path
+    let mut handover: Option<TrackDecoder> = None;
+    let mut raw: Vec<f32> = Vec::new();
+    let mut resampled: Vec<f32> = Vec::new();
+    let mut block: Vec<f32> = Vec::new();
+    // At the output format, which every track is converted to
+    let mut stretch = TimeStretch::new(channels, rate);
+    let mut stretched: Vec<f32> = Vec::new();
+    let mut ending = false;
+    let mut fading_out: Option<GainRamp> = None;
+    let mut meter = LevelMeter::new(channels, rate);
+
+    loop {
+        if state.stop_signal.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let generation = state.seek_generation.load(Ordering::SeqCst);
+        if generation != state.seek_handled.load(Ordering::SeqCst) {
+            let seek_ms = state.seek_to_ms.load(Ordering::SeqCst);
+            // The advance was reported when the fade began, so the seek
+            // targets the incoming track
+            if let Some(xf) = fade.take() {
+                track = xf.next;
+            }
+            if let Some(frame) = track.seek(seek_ms) {
+                if let Some(rs) = resampler.as_mut() {
+                    rs.reset();
+                }
+                stretch.reset();
+                ending = false;
+                decoded_frames = frame;
+                state.position_ms.store(frame * 1000 / track.sample_rate as u64, Ordering::SeqCst);
+                state.duration_ms.store(track.duration_ms, Ordering::SeqCst);
+            }
+            state.seek_handled.store(generation, Ordering::SeqCst);
+        }
+
+        // A seek while paused is still carried out
+        while state.paused.load(Ordering::SeqCst) {
+            if state.stop_signal.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if state.seek_pending() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        if state.seek_pending() {
+            continue;
+        }
+
+        state.refresh_gain(&mut track);
+        if let Some(xf) = fade.as_mut() {
+            state.refresh_gain(&mut xf.next);
+        }
+
+        let looping = *state.loop_region.lock();
+
+        // Start a crossfade once the remaining tail fits in the fade length
+        if fade.is_none() && looping.is_none() {
+            let (fade_ms, curve) = *state.crossfade.lock();
+            let fade_frames = fade_ms * track.sample_rate as u64 / 1000;
+            let remaining = track.total_frames.map(|t| t.saturating_sub(decoded_frames));
+            if let Some(len) = remaining.filter(|&r| r > 0 && r <= fade_frames) {
+                if let Some(next) = state.take_next(Some(&track)) {
+                    let path = next.path.clone();
+                    log::info!("amsal: render crossfading into {} over {} frames", path, len);
+                    fade = Some(Crossfade::new(next, path, len, curve));
+                }
+            }
+        }
+
+        raw.clear();
+        let frames = match track.decode_next(&mut raw)? {
+            Some(frames) => frames,
+            None if fade.is_some() => {
+                if let Some(xf) = fade.take() {
+                    finish_crossfade(xf, &mut track, &mut decoded_frames, &mut raw);
+                }
+                0
+            }
+            None => {
+                // A loop ending past the track's end wraps here instead
+                if let Some(frame) = looping.and_then(|region| track.wrap_loop(&mut raw, region, true)) {
+                    decoded_frames = frame;
+                    ending = false;
+                    continue;
+                }
+                match state.take_next(None) {
+                    Some(next) => {
+                        log::info!("amsal: render continuing into {}", next.path);
+                        handover = Some(next);
+                        0
+                    }
+                    None if ending => break,
+                    None => {
+                        ending = true;
+                        0
+                    }
+                }
+            }
+        };
+        decoded_frames += frames as u64;
+
+        // A-B loop: cut at the loop end and jump back to its start
+        if fade.is_none() && handover.is_none() {
+            if let Some(frame) = looping.and_then(|region| track.wrap_loop(&mut raw, region, false)) {
+                decoded_frames = frame;
+            }
+        }
+
+        if frames > 0 {
+            if let Some(xf) = fade.as_mut() {
+                xf.mix(&mut raw, track.channels.max(1) as usize);
+                if xf.is_complete() {
+                    if let Some(xf) = fade.take() {
+                        finish_crossfade(xf, &mut track, &mut decoded_frames, &mut raw);
+                    }
+                }
+            }
+        }
+        if raw.is_empty() && !ending && handover.is_none() {
+            continue;
+        }
+        let reformat = handover
+            .as_ref()
+            .is_some_and(|next| next.sample_rate != track.sample_rate || next.channels != track.channels);
+
+        let wanted = *state.resample_quality.lock();
+        if wanted != quality {
+            quality = wanted;
+            if let Some(rs) = resampler.as_mut() {
+                rs.set_quality(quality);
+            }
+        }
+        let samples: &[f32] = match resampler.as_mut() {
+            Some(rs) => {
+                resampled.clear();
+                rs.process(&raw, &mut resampled);
+                // Play out the filter delay before the stretcher flushes or
+                // the next track needs a different resampler
+                if ending || reformat {
+                    rs.flush(&mut resampled);
+                }
+                &resampled
+            }
+            None => &raw,
+        };
+
+        let track_channels = track.channels.max(1) as u16;
+        block.clear();
+        if track_channels == channels {
+            block.extend_from_slice(samples);
+        } else {
+            block.resize(samples.len() / track_channels as usize * channels as usize, 0.0);
+            let mix = *state.channel_mix.lock();
+            ChannelMatrix::new(track_channels, track.layout, channels, mix).apply(samples, &mut block);
+        }
+
+        if let Some(next) = handover.take() {
+            if reformat {
+                resampler = resampler_for(&next, quality);
+            }
+            track = next;
+            decoded_frames = track.start_frame;
+            ending = false;
+            state.duration_ms.store(track.duration_ms, Ordering::SeqCst);
+        }
+
+        stretch.set_speed(f32::from_bits(state.speed.load(Ordering::SeqCst)));
+        stretched.clear();
+        // On the final pass `block` holds the resampler's tail
+        stretch.process(&block, &mut stretched);
+        if ending {
+            stretch.flush(&mut stretched);
+        }
+        std::mem::swap(&mut block, &mut stretched);
+        if block.is_empty() {
+            continue;
+        }
+
+        // Frames held in the stretcher (output rate) haven't been rendered yet
+        let (pos_frames, current) = match &fade {
+            Some(xf) => (xf.mixed_frames, &xf.next),
+            None => (decoded_frames, &track),
+        };
+        let held = stretch.pending_frames() * current.sample_rate as u64 / rate as u64;
+        state.position_ms.store(pos_frames.saturating_sub(held) * 1000 / current.sample_rate as u64, Ordering::SeqCst);
+        state.duration_ms.store(current.duration_ms, Ordering::SeqCst);
+
+        let volume = state.volume.load(Ordering::SeqCst) as f32 / 100.0;
+        if volume != 1.0 {
+            for s in block.iter_mut() {
+                *s *= volume;
+            }
+        }
+        if dsp.ready() {
+            if let Some(chain) = state.dsp_chain.lock().take() {
+                dsp.switch(chain, rate);
+            }
+        }
+        dsp.process(&mut block, channels, rate);
+        dsp.take_retired();
+
+        let fade_ms = state.fade_out_ms.load(Ordering::SeqCst);
+        if fade_ms > 0 {
+            let ramp = fading_out.get_or_insert_with(|| GainRamp::new(1.0));
+            ramp.apply(&mut block, channels, true, fade_ms * rate as u64 / 1000);
+        }
+
+        meter.process(&block, &state.levels);
+        state.write(&block)?;
+        state.frames_rendered.fetch_add((block.len() / channels as usize) as u64, Ordering::SeqCst);
+        if fading_out.as_ref().is_some_and(GainRamp::is_silent) {
+            state.stop_signal.store(true, Ordering::SeqCst);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write interleaved samples as a float WAV file: the shared fixture writer
+/// for tests that decode real files.
+#[cfg(test)]
+pub(crate) fn write_test_wav(path: &Path, rate: u32, channels: u16, samples: &[f32]) {
+    let mut w = WavWriter::new(File::create(path).unwrap(), rate, channels, WavFormat::F32).unwrap();
+    w.write(samples, false).unwrap();
+    w.finalize().unwrap();
+}
+
+/// Streaming WAV writer. The RIFF and data sizes are patched by `finalize`,
+/// which may run repeatedly — the file is valid after each call.
+struct WavWriter<W: Write + Seek> {
+    inner: W,
+    format: WavFormat,
+    data_bytes: u64,
+    tpdf: Tpdf,
+    /// Encoded block, reused across writes.
+    bytes: Vec<u8>,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    fn new(mut inner: W, rate: u32, channels: u16, format: WavFormat) -> io::Result<Self> {
+        let block_align = channels * format.bits() / 8;
+        let tag: u16 = if format == WavFormat::F32 { 3 } else { 1 }; // IEEE float / PCM
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&tag.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&rate.to_le_bytes());
+        header.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&format.bits().to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        inner.write_all(&header)?;
+        Ok(Self { inner, format, data_bytes: 0, tpdf: Tpdf::new(0x9E37_79B9), bytes: Vec::new() })
+    }
+
+    fn write(&mut self, samples: &[f32], dither: bool) -> io::Result<()> {
+        self.bytes.clear();
+        for &s in samples {
+            let d = if dither && self.format != WavFormat::F32 { self.tpdf.next_lsb() } else { 0.0 };
+            match self.format {
+                WavFormat::S16 => self.bytes.extend_from_slice(&f32_to_i16(s, d).to_le_bytes()),
+                WavFormat::S24 => self.bytes.extend_from_slice(&f32_to_i24(s, d).to_le_bytes()[..3]),
+                WavFormat::F32 => self.bytes.extend_from_slice(&s.to_le_bytes()),
+            }
+        }
+        self.inner.write_all(&self.bytes)?;
+        self.data_bytes += self.bytes.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        // Sizes saturate: a >4 GiB render is truncated as far as readers know
+        let data = self.data_bytes.min((u32::MAX - 36) as u64) as u32;
+        self.inner.seek(SeekFrom::Start(4))?;
+        self.inner.write_all(&(36 + data).to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(40))?;
+        self.inner.write_all(&data.to_le_bytes())?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    fn tone(freq: f32, rate: u32, channels: u16, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = 0.5 * (2.0 * std::f32::consts::PI * freq * n as f32 / rate as f32).sin();
+                std::iter::repeat_n(s, channels as usize)
+            })
+            .collect()
+    }
+
+    fn wait_finished(backend: &RenderBackend) {
+        let start = Instant::now();
+        while !backend.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(10), "render timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!backend.is_error());
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn wav_header_sizes_are_patched() {
+        let mut w = WavWriter::new(Cursor::new(Vec::new()), 48000, 2, WavFormat::S24).unwrap();
+        w.write(&[0.0; 8], false).unwrap();
+        w.finalize().unwrap();
+        let bytes = w.inner.into_inner();
+        assert_eq!(bytes.len(), 44 + 8 * 3);
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 24);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 24);
+        assert_eq!(u16::from_le_bytes(bytes[34..36].try_into().unwrap()), 24);
+        assert_eq!(WavFormat::from_name("s24"), Some(WavFormat::S24));
+        assert_eq!(WavFormat::from_name("u8"), None);
+    }
+
+    #[test]
+    fn renders_track_unchanged_at_its_own_format() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("a.wav");
+        let input = tone(440.0, 44100, 2, 22050);
+        write_test_wav(&path, 44100, 2, &input);
+
+        let backend = RenderBackend::in_memory(RenderOptions::default());
+        backend.play(path.to_str().unwrap());
+        wait_finished(&backend);
+
+        assert_eq!(backend.output_format(), Some((44100, 2)));
+        let out = backend.take_rendered();
+        assert_eq!(out, input);
+        assert_eq!(backend.frames_rendered(), 22050);
+        assert_eq!(backend.position_ms(), 500);
+    }
+
+    #[test]
+    fn rejects_a_zero_output_format() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("a.wav");
+        write_test_wav(&path, 44100, 2, &tone(440.0, 44100, 2, 4410));
+
+        for options in [
+            RenderOptions { sample_rate: Some(0), ..Default::default() },
+            RenderOptions { channels: Some(0), ..Default::default() },
+        ] {
+            let backend = RenderBackend::in_memory(options);
+            backend.play(path.to_str().unwrap());
+            let start = Instant::now();
+            while !backend.is_finished() {
+                assert!(start.elapsed() < Duration::from_secs(10), "render timed out");
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert!(backend.is_error());
+            assert_eq!(backend.frames_rendered(), 0);
+        }
+    }
+
+    #[test]
+    fn resamples_downmixes_and_applies_eq() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("a.wav");
+        write_test_wav(&path, 44100, 2, &tone(1000.0, 44100, 2, 44100));
+
+        let options = RenderOptions { sample_rate: Some(48000), channels: Some(1), ..Default::default() };
+        let backend = RenderBackend::in_memory(options);
+        backend.set_eq(&serde_json::json!({"filters": [{"type": "gain", "db": -6.0206}]}));
+        backend.set_volume(0.5);
+        backend.play(path.to_str().unwrap());
+        wait_finished(&backend);
+
+        let out = backend.take_rendered();
+        // Every input frame, the resampler's tail included
+        assert!((48000..=48001).contains(&out.len()), "{}", out.len());
+        // 0.5 peak tone, halved by volume and again by the EQ chain
+        let expected = 0.5 * std::f32::consts::FRAC_1_SQRT_2 / 4.0;
+        assert!((rms(&out[1000..]) - expected).abs() < 0.002);
+        // Metered after the chain, at the output format
+        let levels = backend.levels().expect("metered");
+        assert_eq!(levels.rms_db.len(), 1);
+        assert!((levels.rms_db[0] - 20.0 * expected.log10()).abs() < 0.2, "{:?}", levels.rms_db);
+    }
+
+    #[test]
+    fn renders_at_speed_and_reports_track_position() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("a.wav");
+        write_test_wav(&path, 48000, 2, &tone(300.0, 48000, 2, 48000));
+
+        let backend = RenderBackend::in_memory(RenderOptions::default());
+        backend.set_speed(2.0);
+        backend.play(path.to_str().unwrap());
+        wait_finished(&backend);
+
+        // Half the frames, the whole second of track time
+        let frames = backend.frames_rendered();
+        assert!((23_500..=24_500).contains(&frames), "{}", frames);
+        assert_eq!(backend.position_ms(), 1000);
+        let out = backend.take_rendered();
+        assert!((rms(&out[4800..]) - 0.5 * std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+    }
+
+    #[test]
+    fn renders_between_trim_points() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("ramp.wav");
+        // Each sample holds its own frame index, so positions can be read back
+        let ramp: Vec<f32> = (0..8000).map(|n| n as f32 / 8000.0).collect();
+        write_test_wav(&path, 8000, 1, &ramp);
+        let path = path.to_str().unwrap();
+
+        let backend = RenderBackend::in_memory(RenderOptions::default());
+        backend.set_track_range(path, 250, Some(500));
+        backend.play(path);
+        wait_finished(&backend);
+        let out = backend.take_rendered();
+        assert_eq!(out.len(), 2000);
+        assert_eq!((out[0] * 8000.0).round(), 2000.0);
+        assert_eq!(backend.position_ms(), 500);
+    }
+
+    #[test]
+    fn continues_into_staged_track_and_writes_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let a = dir.path().join("a.wav");
+        let b = dir.path().join("b.wav");
+        write_test_wav(&a, 44100, 2, &tone(440.0, 44100, 2, 22050));
+        write_test_wav(&b, 48000, 1, &tone(440.0, 48000, 1, 24000));
+        let out = dir.path().join("out.wav");
+
+        let options = RenderOptions { sample_rate: Some(48000), format: WavFormat::S16, ..Default::default() };
+        let backend = RenderBackend::to_file(&out, options);
+        backend.prepare_next(b.to_str().unwrap());
+        backend.play(a.to_str().unwrap());
+        wait_finished(&backend);
+        assert_eq!(backend.take_track_advance().as_deref(), b.to_str());
+        backend.finish().unwrap();
+
+        // 0.5s of each track: the first resampled, its tail played out
+        // before the 48k mono one is spread to stereo without resampling
+        let mut rendered = TrackDecoder::open(out.to_str().unwrap()).unwrap();
+        assert_eq!((rendered.sample_rate, rendered.channels), (48000, 2));
+        let mut samples = Vec::new();
+        while rendered.decode_next(&mut samples).unwrap().is_some() {}
+        let frames = samples.len() / 2;
+        assert!((48000..=48001).contains(&frames), "{}", frames);
+        assert_eq!(backend.frames_rendered() as usize, frames);
+    }
+}
@@ -0,0 +1,329 @@
+//! Sample-rate conversion — streaming polyphase resampler.
+//!
+//! One interpolation engine with selectable kernels: linear ("low", for
+//! weak devices) or Kaiser-windowed sinc ("medium", "high") whose cutoff
+//! drops below the target Nyquist when downsampling, so 96k→48k doesn't
+//! fold ultrasonic content back into the audible band.
+//!
+//! Input history and the fractional read position carry across calls, so
+//! packet boundaries are seamless; `reset()` starts clean after a seek and
+//! `flush()` plays out the filter delay at the end of a stream. Switching
+//! quality keeps the history, so it doesn't click mid-stream.
+
+use std::f64::consts::PI;
+
+/// Resampler quality, from `"resampler"` in `/amsal/settings/audio`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Linear interpolation — cheapest, audible aliasing.
+    Low,
+    /// 32-tap windowed sinc.
+    #[default]
+    Medium,
+    /// 64-tap windowed sinc with a steeper transition band.
+    High,
+}
+
+impl ResampleQuality {
+    /// Parse a settings name ("low", "medium", "high"). Unknown → None.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "low" => Some(Self::Low),
+            "medium" => Some(Self::Medium),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    /// (taps, passband fraction of the lower Nyquist, Kaiser beta).
+    fn design(self) -> (usize, f64, f64) {
+        match self {
+            Self::Low => (2, 1.0, 0.0),
+            Self::Medium => (32, 0.90, 8.0),
+            Self::High => (64, 0.95, 10.0),
+        }
+    }
+}
+
+/// Kernel phases tabulated per input sample; adjacent phases are blended.
+const PHASES: usize = 256;
+
+/// Input frames kept before the read position: the most any quality's
+/// kernel reaches back (`taps / 2 - 1` at `High`).
+const HISTORY: usize = 31;
+
+/// Streaming resampler over interleaved f32 frames.
+pub struct Resampler {
+    channels: usize,
+    taps: usize,
+    /// Input frames advanced per output frame (src / dst).
+    step: f64,
+    /// `(PHASES + 1) * taps` kernel coefficients, one row per phase.
+    table: Vec<f32>,
+    /// Pending input (interleaved), starting `HISTORY` frames before `pos`.
+    history: Vec<f32>,
+    /// Read position in `history`, in frames.
+    pos: f64,
+}
+
+impl Resampler {
+    pub fn new(src_rate: u32, dst_rate: u32, channels: u16, quality: ResampleQuality) -> Self {
+        let step = src_rate as f64 / dst_rate as f64;
+        let (taps, table) = kernel(step, quality);
+        let mut rs = Self {
+            channels: channels.max(1) as usize,
+            taps,
+            step,
+            table,
+            history: Vec::new(),
+            pos: 0.0,
+        };
+        rs.reset();
+        rs
+    }
+
+    /// Switch kernels mid-stream. Buffered input and the read position are
+    /// kept, so the output carries on without a gap or a click.
+    pub fn set_quality(&mut self, quality: ResampleQuality) {
+        (self.taps, self.table) = kernel(self.step, quality);
+    }
+
+    /// Forget buffered input (after a seek). The next input frame is
+    /// treated as the start of a new stream.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.history.resize(HISTORY * self.channels, 0.0);
+        self.pos = HISTORY as f64;
+    }
+
+    /// End of stream: append the output still held back by the filter
+    /// delay, as if silence followed, then start clean like `reset`.
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        let end = (self.history.len() / self.channels) as f64;
+        self.history.resize(self.history.len() + self.taps * self.channels, 0.0);
+        self.run(out, end);
+        self.reset();
+    }
+
+    /// Resample `input`, appending output frames to `out`.
+    ///
+    /// Output lags input by `taps / 2` frames; that tail is emitted by the
+    /// next call (or `flush`) rather than padded, so consecutive packets
+    /// join exactly.
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.history.extend_from_slice(input);
+        self.run(out, f64::INFINITY);
+    }
+
+    /// Emit every output frame the history covers, up to read position `end`.
+    fn run(&mut self, out: &mut Vec<f32>, end: f64) {
+        let ch = self.channels;
+        let taps = self.taps;
+        let lead = taps / 2 - 1;
+        let frames = self.history.len() / ch;
+
+        while (self.pos as usize) + taps - lead <= frames && self.pos < end {
+            let base = self.pos as usize;
+            let frac = (self.pos - base as f64) * PHASES as f64;
+            let phase = frac as usize;
+            let blend = (frac - phase as f64) as f32;
+            let row_a = &self.table[phase * taps..(phase + 1) * taps];
+            let row_b = &self.table[(phase + 1) * taps..(phase + 2) * taps];
+            let first = base - lead;
+            for c in 0..ch {
+                let mut acc = 0.0f32;
+                for k in 0..taps {
+                    let coef = row_a[k] + (row_b[k] - row_a[k]) * blend;
+                    acc += coef * self.history[(first + k) * ch + c];
+                }
+                out.push(acc);
+            }
+            self.pos += self.step;
+        }
+
+        // Drop input no future output frame can reach
+        let keep_from = (self.pos as usize).saturating_sub(HISTORY).min(frames);
+        self.history.drain(..keep_from * ch);
+        self.pos -= keep_from as f64;
+    }
+}
+
+/// Tap count and phase table for resampling by `step` (src / dst).
+fn kernel(step: f64, quality: ResampleQuality) -> (usize, Vec<f32>) {
+    let (taps, passband, beta) = quality.design();
+    let cutoff = passband * (1.0 / step).min(1.0);
+    let half = (taps / 2) as f64;
+
+    let mut table = vec![0.0f32; (PHASES + 1) * taps];
+    for (phase, row) in table.chunks_exact_mut(taps).enumerate() {
+        let frac = phase as f64 / PHASES as f64;
+        for (k, c) in row.iter_mut().enumerate() {
+            // Distance from the output instant to input tap k
+            let x = k as f64 - (half - 1.0) - frac;
+            *c = match quality {
+                ResampleQuality::Low => (1.0 - x.abs()).max(0.0) as f32,
+                _ => (cutoff * sinc(cutoff * x) * kaiser(x / half, beta)) as f32,
+            };
+        }
+        // Unity DC gain for every phase
+        let sum: f32 = row.iter().sum();
+        if sum != 0.0 {
+            for c in row.iter_mut() {
+                *c /= sum;
+            }
+        }
+    }
+    (taps, table)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Kaiser window at normalized position `t`, zero at and beyond ±1 so
+/// adjacent kernel phases line up exactly when shifted by one tap.
+fn kaiser(t: f64, beta: f64) -> f64 {
+    if t.abs() >= 1.0 {
+        return 0.0;
+    }
+    bessel_i0(beta * (1.0 - t * t).sqrt()) / bessel_i0(beta)
+}
+
+/// Zeroth-order modified Bessel function of the first kind (power series).
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..32 {
+        term *= half / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|n| (2.0 * PI * freq * n as f64 / rate as f64).sin() as f32).collect()
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        (samples.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn output_length_follows_ratio() {
+        for quality in [ResampleQuality::Low, ResampleQuality::Medium, ResampleQuality::High] {
+            let mut rs = Resampler::new(44100, 48000, 2, quality);
+            let mut out = Vec::new();
+            rs.process(&vec![0.0; 44100 * 2], &mut out);
+            let frames = out.len() / 2;
+            // One second in, one second out minus the filter delay
+            assert!((47900..=48000).contains(&frames), "{quality:?}: {frames}");
+        }
+    }
+
+    #[test]
+    fn packet_split_matches_single_call() {
+        let input = tone(1000.0, 44100, 4410);
+        let mut whole = Vec::new();
+        Resampler::new(44100, 48000, 1, ResampleQuality::High).process(&input, &mut whole);
+
+        let mut split = Vec::new();
+        let mut rs = Resampler::new(44100, 48000, 1, ResampleQuality::High);
+        for packet in input.chunks(1152) {
+            rs.process(packet, &mut split);
+        }
+        assert_eq!(whole.len(), split.len());
+        for (a, b) in whole.iter().zip(&split) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn passband_tone_keeps_level() {
+        let mut rs = Resampler::new(44100, 48000, 1, ResampleQuality::Medium);
+        let mut out = Vec::new();
+        rs.process(&tone(1000.0, 44100, 44100), &mut out);
+        assert!((rms(&out[1000..]) - std::f64::consts::FRAC_1_SQRT_2).abs() < 0.01);
+    }
+
+    #[test]
+    fn downsampling_rejects_content_above_new_nyquist() {
+        // 30 kHz at 96k would alias to 18 kHz at 48k
+        let input = tone(30000.0, 96000, 96000);
+        let level = |quality| {
+            let mut out = Vec::new();
+            Resampler::new(96000, 48000, 1, quality).process(&input, &mut out);
+            rms(&out[1000..])
+        };
+        assert!(level(ResampleQuality::High) < 0.001);
+        assert!(level(ResampleQuality::Low) > 0.1);
+    }
+
+    #[test]
+    fn reset_restarts_stream() {
+        let input = tone(440.0, 48000, 2000);
+        let mut rs = Resampler::new(48000, 44100, 1, ResampleQuality::Medium);
+        let mut first = Vec::new();
+        rs.process(&input, &mut first);
+        rs.reset();
+        let mut second = Vec::new();
+        rs.process(&input, &mut second);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn flush_emits_the_held_back_tail() {
+        let input = tone(1000.0, 44100, 44100);
+        let mut rs = Resampler::new(44100, 48000, 1, ResampleQuality::High);
+        let mut out = Vec::new();
+        rs.process(&input, &mut out);
+        assert!(out.len() < 48000);
+        rs.flush(&mut out);
+        // Every input frame comes out: one second in, one second out
+        assert!((48000..=48001).contains(&out.len()), "{}", out.len());
+        // The tail is the tone, not silence
+        assert!(rms(&out[47900..47950]) > 0.5);
+
+        // Starts clean afterwards, like `reset`
+        let (mut again, mut fresh) = (Vec::new(), Vec::new());
+        rs.process(&input[..4410], &mut again);
+        Resampler::new(44100, 48000, 1, ResampleQuality::High).process(&input[..4410], &mut fresh);
+        assert_eq!(again, fresh);
+    }
+
+    #[test]
+    fn quality_switch_keeps_history() {
+        let input = tone(1000.0, 44100, 8820);
+        let mut high = Vec::new();
+        Resampler::new(44100, 48000, 1, ResampleQuality::High).process(&input, &mut high);
+
+        let mut switched = Vec::new();
+        let mut rs = Resampler::new(44100, 48000, 1, ResampleQuality::Medium);
+        rs.process(&input[..4410], &mut switched);
+        let at = switched.len();
+        rs.set_quality(ResampleQuality::High);
+        rs.process(&input[4410..], &mut switched);
+        // Continues at the same instant, as if it had been High all along
+        assert_eq!(switched.len(), high.len());
+        for (a, b) in switched[at..].iter().zip(&high[at..]) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn quality_names() {
+        assert_eq!(ResampleQuality::from_name("low"), Some(ResampleQuality::Low));
+        assert_eq!(ResampleQuality::from_name("ultra"), None);
+    }
+}
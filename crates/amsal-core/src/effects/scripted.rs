@@ -0,0 +1,474 @@
+//! Scriptable audio backend for engine tests.
+//!
+//! Plays nothing. Tracks advance in virtual time read from a `TimeSource`
+//! (normally the engine's `ManualClock`): position grows as the test
+//! advances the clock, and a track ends once its scripted duration has
+//! passed — continuing into a staged next track like the gapless pipeline,
+//! else reporting finished. Every call is recorded for assertions, and
+//! early ends, errors, underruns, output lag and meter levels can be
+//! injected.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::convert::ChannelMix;
+use super::dsp::{DspChain, FadeCurve};
+use super::levels::Levels;
+use super::resample::ResampleQuality;
+use crate::time::TimeSource;
+
+/// One call the engine made on the backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendCall {
+    Play(String),
+    Pause,
+    Resume,
+    Stop,
+    Seek(u64),
+    FadeOut(u64),
+    SetTransportFade(u64),
+    SetVolume(f32),
+    SetSpeed(f32),
+    SetTrackSpeed(String, f32),
+    PrepareNext(String),
+    SetCrossfade(u64, FadeCurve),
+    SetReplayGain(String, f32),
+    SetTrackRange(String, u64, Option<u64>),
+    SetLoop(Option<(u64, u64)>),
+    SetResampleQuality(ResampleQuality),
+    SetChannelMix(ChannelMix),
+    SetOutputDevice(Option<String>),
+    SetDither(bool),
+    SetDsp,
+    SetEq(serde_json::Value),
+}
+
+/// Track length for paths without a scripted duration.
+pub const DEFAULT_DURATION_MS: u64 = 180_000;
+
+/// Test double that "plays" in virtual time.
+pub struct ScriptedBackend {
+    time: Arc<dyn TimeSource>,
+    script: Mutex<Script>,
+}
+
+#[derive(Default)]
+struct Script {
+    calls: Vec<BackendCall>,
+    durations: HashMap<String, u64>,
+    /// Trim points per path, from `set_track_range`.
+    ranges: HashMap<String, (u64, Option<u64>)>,
+    /// Speeds per path, from `set_track_speed`.
+    speeds: HashMap<String, f32>,
+    loop_region: Option<(u64, u64)>,
+    current: Option<String>,
+    playing: bool,
+    paused: bool,
+    finished: bool,
+    error: bool,
+    position_ms: u64,
+    /// Track time played per unit of virtual time.
+    speed: f32,
+    /// `TimeSource::now_ms` the position was last brought up to.
+    synced_at: i64,
+    next: Option<String>,
+    advanced: Option<String>,
+    /// `TimeSource::now_ms` at which a running fade-out stops playback.
+    fade_ends_at: Option<i64>,
+    underruns: u64,
+    /// Reported by `output_format` while a track is loaded.
+    format: Option<(u32, u16)>,
+    /// Reported by `levels` while a track is loaded.
+    levels: Option<Levels>,
+    /// How far `position_ms` trails the decoded position (output buffering).
+    output_lag_ms: u64,
+}
+
+impl Script {
+    fn duration(&self, path: &str) -> u64 {
+        self.durations.get(path).copied().unwrap_or(DEFAULT_DURATION_MS)
+    }
+
+    /// Position a track starts playing at (its start trim point).
+    fn start_of(&self, path: &str) -> u64 {
+        self.ranges.get(path).map_or(0, |&(start, _)| start)
+    }
+
+    /// Position a track stops playing at (its end trim point, else its end).
+    fn end_of(&self, path: &str) -> u64 {
+        let duration = self.duration(path);
+        self.ranges.get(path).and_then(|&(_, end)| end).map_or(duration, |end| end.min(duration))
+    }
+
+    /// Play out the virtual time elapsed since the last sync.
+    fn sync(&mut self, now: i64) {
+        // A fade-out that ended in between stops playback right there
+        if let Some(end) = self.fade_ends_at.filter(|&end| end <= now) {
+            self.play_out(end);
+            self.fade_ends_at = None;
+            self.playing = false;
+            self.paused = false;
+        }
+        self.play_out(now);
+    }
+
+    fn play_out(&mut self, now: i64) {
+        let elapsed = (now - self.synced_at).max(0) as u64;
+        self.synced_at = now;
+        if !self.playing || self.paused {
+            return;
+        }
+        self.position_ms += (elapsed as f64 * self.speed as f64) as u64;
+        while let Some(current) = self.current.as_deref() {
+            let end = self.end_of(current);
+            if let Some((start, loop_end)) = self.loop_region {
+                let loop_end = loop_end.min(end);
+                if self.position_ms >= loop_end && loop_end > start {
+                    self.position_ms = start + (self.position_ms - loop_end) % (loop_end - start);
+                    break;
+                }
+            }
+            if self.position_ms < end {
+                break;
+            }
+            match self.next.take() {
+                Some(next) => {
+                    // Time played past the end runs at the next track's speed
+                    let mut over = self.position_ms - end;
+                    if let Some(&speed) = self.speeds.get(&next) {
+                        over = (over as f64 * speed as f64 / self.speed as f64) as u64;
+                        self.speed = speed;
+                    }
+                    self.position_ms = over + self.start_of(&next);
+                    self.advanced = Some(next.clone());
+                    self.current = Some(next);
+                }
+                None => {
+                    self.end_track(end);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn end_track(&mut self, position_ms: u64) {
+        self.position_ms = position_ms;
+        self.playing = false;
+        self.finished = true;
+    }
+}
+
+impl ScriptedBackend {
+    pub fn new(time: Arc<dyn TimeSource>) -> Self {
+        let synced_at = time.now_ms();
+        Self {
+            time,
+            script: Mutex::new(Script { synced_at, speed: 1.0, format: Some((44100, 2)), ..Default::default() }),
+        }
+    }
+
+    /// Length of the track at `path` (default `DEFAULT_DURATION_MS`).
+    pub fn set_duration(&self, path: &str, ms: u64) {
+        self.script.lock().durations.insert(path.to_string(), ms);
+    }
+
+    /// End the current track now, as if its last sample played. A staged
+    /// next track is not continued into.
+    pub fn finish(&self) {
+        let mut script = self.locked();
+        let end = script.current.as_deref().map(|p| script.end_of(p)).unwrap_or(0);
+        script.end_track(end);
+    }
+
+    /// Fail like a decoder or device error; `is_error` reports it until the next `play()`.
+    pub fn fail(&self) {
+        self.script.lock().error = true;
+    }
+
+    /// Output format reported while playing or paused (default 44.1k stereo).
+    pub fn set_output_format(&self, format: Option<(u32, u16)>) {
+        self.script.lock().format = format;
+    }
+
+    pub fn add_underruns(&self, count: u64) {
+        self.script.lock().underruns += count;
+    }
+
+    /// Make the audible `position_ms` trail `decoded_position_ms` by `ms`,
+    /// like audio buffered ahead of the output.
+    pub fn set_output_lag(&self, ms: u64) {
+        self.script.lock().output_lag_ms = ms;
+    }
+
+    /// Meter reading reported while playing or paused (default none).
+    pub fn set_levels(&self, levels: Option<Levels>) {
+        self.script.lock().levels = levels;
+    }
+
+    /// Path of the track currently "playing" (or last played).
+    pub fn current(&self) -> Option<String> {
+        self.locked().current.clone()
+    }
+
+    /// Track staged by `prepare_next` and not yet continued into.
+    pub fn staged(&self) -> Option<String> {
+        self.locked().next.clone()
+    }
+
+    /// Every call received so far, oldest first.
+    pub fn calls(&self) -> Vec<BackendCall> {
+        self.script.lock().calls.clone()
+    }
+
+    pub fn take_calls(&self) -> Vec<BackendCall> {
+        std::mem::take(&mut self.script.lock().calls)
+    }
+
+    /// Lock the script with its position brought up to the current time.
+    fn locked(&self) -> parking_lot::MutexGuard<'_, Script> {
+        let mut script = self.script.lock();
+        script.sync(self.time.now_ms());
+        script
+    }
+
+    /// Record a call, returning the script for the call's effect.
+    fn apply(&self, call: BackendCall) -> parking_lot::MutexGuard<'_, Script> {
+        let mut script = self.locked();
+        script.calls.push(call);
+        script
+    }
+
+    fn record(&self, call: BackendCall) {
+        self.locked().calls.push(call);
+    }
+}
+
+impl super::AudioBackend for ScriptedBackend {
+    fn play(&self, file_path: &str) {
+        let mut script = self.apply(BackendCall::Play(file_path.to_string()));
+        script.current = Some(file_path.to_string());
+        script.playing = true;
+        script.paused = false;
+        script.finished = false;
+        script.error = false;
+        script.position_ms = script.start_of(file_path);
+        script.advanced = None;
+        script.fade_ends_at = None;
+        script.loop_region = None;
+        if script.next.as_deref() == Some(file_path) {
+            script.next = None;
+        }
+    }
+
+    fn pause(&self) {
+        self.apply(BackendCall::Pause).paused = true;
+    }
+
+    fn resume(&self) {
+        self.apply(BackendCall::Resume).paused = false;
+    }
+
+    fn stop(&self) {
+        let mut script = self.apply(BackendCall::Stop);
+        script.playing = false;
+        script.paused = false;
+        script.fade_ends_at = None;
+    }
+
+    /// Lands exactly on the target; at or past the end the track ends there.
+    fn seek(&self, position_ms: u64) -> u64 {
+        let mut script = self.apply(BackendCall::Seek(position_ms));
+        if script.current.is_none() {
+            return 0;
+        }
+        script.position_ms = position_ms;
+        let now = script.synced_at;
+        script.play_out(now);
+        script.position_ms
+    }
+
+    /// Stops `duration_ms` of virtual time from now (at once when paused).
+    fn fade_out(&self, duration_ms: u64) {
+        let now = self.time.now_ms();
+        let mut script = self.apply(BackendCall::FadeOut(duration_ms));
+        if script.paused {
+            script.playing = false;
+            script.paused = false;
+        } else if script.playing {
+            script.fade_ends_at = Some(now + duration_ms as i64);
+        }
+    }
+
+    fn set_transport_fade(&self, duration_ms: u64) {
+        self.record(BackendCall::SetTransportFade(duration_ms));
+    }
+
+    fn set_volume(&self, volume: f32) {
+        self.record(BackendCall::SetVolume(volume));
+    }
+
+    fn set_speed(&self, speed: f32) {
+        self.apply(BackendCall::SetSpeed(speed)).speed = speed;
+    }
+
+    fn is_playing(&self) -> bool {
+        self.locked().playing
+    }
+
+    fn is_paused(&self) -> bool {
+        self.locked().paused
+    }
+
+    fn is_finished(&self) -> bool {
+        self.locked().finished
+    }
+
+    fn is_error(&self) -> bool {
+        self.locked().error
+    }
+
+    fn prepare_next(&self, file_path: &str) {
+        self.apply(BackendCall::PrepareNext(file_path.to_string())).next = Some(file_path.to_string());
+    }
+
+    fn take_track_advance(&self) -> Option<String> {
+        self.locked().advanced.take()
+    }
+
+    fn set_crossfade(&self, duration_ms: u64, curve: FadeCurve) {
+        self.record(BackendCall::SetCrossfade(duration_ms, curve));
+    }
+
+    fn set_replay_gain(&self, file_path: &str, gain: f32) {
+        self.record(BackendCall::SetReplayGain(file_path.to_string(), gain));
+    }
+
+    fn set_track_speed(&self, file_path: &str, speed: f32) {
+        let call = BackendCall::SetTrackSpeed(file_path.to_string(), speed);
+        self.apply(call).speeds.insert(file_path.to_string(), speed);
+    }
+
+    fn set_track_range(&self, file_path: &str, start_ms: u64, end_ms: Option<u64>) {
+        let call = BackendCall::SetTrackRange(file_path.to_string(), start_ms, end_ms);
+        self.apply(call).ranges.insert(file_path.to_string(), (start_ms, end_ms));
+    }
+
+    /// Wraps the position back to the loop start in virtual time.
+    fn set_loop(&self, region: Option<(u64, u64)>) {
+        self.apply(BackendCall::SetLoop(region)).loop_region = region;
+    }
+
+    fn loop_region(&self) -> Option<(u64, u64)> {
+        self.locked().loop_region
+    }
+
+    fn set_resample_quality(&self, quality: ResampleQuality) {
+        self.record(BackendCall::SetResampleQuality(quality));
+    }
+
+    fn set_channel_mix(&self, mix: ChannelMix) {
+        self.record(BackendCall::SetChannelMix(mix));
+    }
+
+    fn position_ms(&self) -> u64 {
+        let script = self.locked();
+        script.position_ms.saturating_sub(script.output_lag_ms)
+    }
+
+    fn decoded_position_ms(&self) -> u64 {
+        self.locked().position_ms
+    }
+
+    fn duration_ms(&self) -> u64 {
+        let script = self.locked();
+        script.current.as_deref().map(|p| script.duration(p)).unwrap_or(0)
+    }
+
+    fn underruns(&self) -> u64 {
+        self.locked().underruns
+    }
+
+    fn levels(&self) -> Option<Levels> {
+        let script = self.locked();
+        script.levels.clone().filter(|_| script.playing || script.paused)
+    }
+
+    fn set_output_device(&self, name: Option<&str>) {
+        self.record(BackendCall::SetOutputDevice(name.map(String::from)));
+    }
+
+    fn set_dither(&self, enabled: bool) {
+        self.record(BackendCall::SetDither(enabled));
+    }
+
+    fn output_devices(&self) -> serde_json::Value {
+        serde_json::json!({"hosts": [], "preferred": null, "active": null})
+    }
+
+    fn output_format(&self) -> Option<(u32, u16)> {
+        let script = self.locked();
+        script.format.filter(|_| script.playing || script.paused)
+    }
+
+    fn set_dsp(&self, _: DspChain) {
+        self.record(BackendCall::SetDsp);
+    }
+
+    fn set_eq(&self, spec: &serde_json::Value) {
+        self.record(BackendCall::SetEq(spec.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::AudioBackend;
+    use crate::time::ManualClock;
+
+    #[test]
+    fn plays_in_virtual_time_and_continues_into_staged_track() {
+        let clock = Arc::new(ManualClock::new(0));
+        let backend = ScriptedBackend::new(clock.clone());
+        backend.set_duration("a", 1000);
+        backend.set_duration("b", 2000);
+
+        backend.play("a");
+        clock.advance(400);
+        assert_eq!(backend.position_ms(), 400);
+        backend.pause();
+        clock.advance(1000);
+        assert_eq!(backend.position_ms(), 400);
+        backend.resume();
+
+        backend.prepare_next("b");
+        clock.advance(800);
+        assert_eq!(backend.take_track_advance().as_deref(), Some("b"));
+        assert_eq!(backend.current().as_deref(), Some("b"));
+        assert_eq!(backend.position_ms(), 200);
+        assert_eq!(backend.duration_ms(), 2000);
+
+        clock.advance(5000);
+        assert!(backend.is_finished() && !backend.is_playing());
+        assert_eq!(backend.position_ms(), 2000);
+        assert_eq!(backend.calls()[0], BackendCall::Play("a".into()));
+    }
+
+    #[test]
+    fn injected_end_and_error() {
+        let clock = Arc::new(ManualClock::new(0));
+        let backend = ScriptedBackend::new(clock);
+        backend.play("a");
+        backend.prepare_next("b");
+        backend.finish();
+        assert!(backend.is_finished());
+        assert_eq!(backend.take_track_advance(), None);
+        assert_eq!(backend.staged().as_deref(), Some("b"));
+
+        backend.fail();
+        assert!(backend.is_error());
+        backend.play("b");
+        assert!(!backend.is_error() && backend.staged().is_none());
+    }
+}
@@ -0,0 +1,220 @@
+//! Inter-channel filters — L/R balance, mid/side stereo width and
+//! headphone crossfeed.
+//!
+//! These work on the front pair (channels 0 and 1) of each interleaved
+//! frame; further channels pass through. A mono stream has no pair and is
+//! left alone. Mono sources adapted to stereo by `adapt_channels` arrive
+//! as identical L/R, which balance attenuates per side, width leaves
+//! centred, and crossfeed passes at unity level.
+
+use std::any::Any;
+use std::f32::consts::PI;
+
+use super::dsp::AudioFilter;
+
+/// L/R balance. -1.0 = left only, 0.0 = centre, 1.0 = right only; the
+/// side being moved away from is attenuated, the other stays at unity.
+pub struct Balance {
+    left: f32,
+    right: f32,
+}
+
+impl Balance {
+    pub fn new(balance: f32) -> Self {
+        let balance = balance.clamp(-1.0, 1.0);
+        Self { left: (1.0 - balance).min(1.0), right: (1.0 + balance).min(1.0) }
+    }
+}
+
+impl AudioFilter for Balance {
+    fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
+        if channels < 2 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(channels as usize) {
+            frame[0] *= self.left;
+            frame[1] *= self.right;
+        }
+    }
+}
+
+/// Mid/side stereo width. 0.0 = mono, 1.0 = unchanged, above 1.0 wider.
+pub struct Width {
+    width: f32,
+}
+
+impl Width {
+    pub fn new(width: f32) -> Self {
+        Self { width: width.max(0.0) }
+    }
+}
+
+impl AudioFilter for Width {
+    fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
+        if channels < 2 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(channels as usize) {
+            let mid = (frame[0] + frame[1]) * 0.5;
+            let side = (frame[0] - frame[1]) * 0.5 * self.width;
+            frame[0] = mid + side;
+            frame[1] = mid - side;
+        }
+    }
+}
+
+/// Named crossfeed settings: (cutoff Hz, feed level dB).
+pub fn crossfeed_preset(name: &str) -> Option<(f32, f32)> {
+    match name {
+        // bs2b's default
+        "default" => Some((700.0, 4.5)),
+        // Chu Moy's headphone amp crossfeed
+        "cmoy" => Some((700.0, 6.0)),
+        // Jan Meier's
+        "jmeier" => Some((650.0, 9.5)),
+        _ => None,
+    }
+}
+
+/// Bauer stereophonic-to-binaural crossfeed (the bs2b design).
+///
+/// Each ear gets the opposite channel low-passed at the cutoff and `feed`
+/// dB down, plus its own channel with a matching high shelf, so hard-panned
+/// bass reaches both ears the way speakers would. Normalized so signal
+/// common to both channels keeps its level.
+pub struct Crossfeed {
+    lo_a0: f32,
+    lo_b1: f32,
+    hi_a0: f32,
+    hi_a1: f32,
+    hi_b1: f32,
+    gain: f32,
+    /// [left, right] low-pass (cross) and high-shelf (direct) states,
+    /// and the previous input
+    lo: [f32; 2],
+    hi: [f32; 2],
+    last: [f32; 2],
+}
+
+impl Crossfeed {
+    pub fn new(freq_hz: f32, feed_db: f32, sample_rate: u32) -> Self {
+        let sr = sample_rate as f32;
+        let gb_lo = feed_db * -5.0 / 6.0 - 3.0;
+        let gb_hi = feed_db / 6.0 - 3.0;
+        let g_lo = 10.0f32.powf(gb_lo / 20.0);
+        let g_hi = 1.0 - 10.0f32.powf(gb_hi / 20.0);
+        let fc_hi = freq_hz * 2.0f32.powf((gb_lo - 20.0 * g_hi.log10()) / 12.0);
+
+        let x_lo = (-2.0 * PI * freq_hz / sr).exp();
+        let x_hi = (-2.0 * PI * fc_hi.min(sr * 0.49) / sr).exp();
+        Self {
+            lo_a0: g_lo * (1.0 - x_lo),
+            lo_b1: x_lo,
+            hi_a0: 1.0 - g_hi * (1.0 - x_hi),
+            hi_a1: -x_hi,
+            hi_b1: x_hi,
+            gain: 1.0 / (1.0 - g_hi + g_lo),
+            lo: [0.0; 2],
+            hi: [0.0; 2],
+            last: [0.0; 2],
+        }
+    }
+}
+
+impl AudioFilter for Crossfeed {
+    fn process(&mut self, samples: &mut [f32], channels: u16, _sample_rate: u32) {
+        if channels < 2 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(channels as usize) {
+            for (c, &x) in frame[..2].iter().enumerate() {
+                self.lo[c] = self.lo_a0 * x + self.lo_b1 * self.lo[c];
+                self.hi[c] = self.hi_a0 * x + self.hi_a1 * self.last[c] + self.hi_b1 * self.hi[c];
+                self.last[c] = x;
+            }
+            frame[0] = (self.hi[0] + self.lo[1]) * self.gain;
+            frame[1] = (self.hi[1] + self.lo[0]) * self.gain;
+        }
+    }
+
+    fn carry_state(&mut self, previous: &dyn AudioFilter) {
+        if let Some(prev) = previous.as_any().and_then(|p| p.downcast_ref::<Crossfeed>()) {
+            self.lo = prev.lo;
+            self.hi = prev.hi;
+            self.last = prev.last;
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::convert::adapt_channels;
+
+    fn stereo_tone(freq: f32, left: f32, right: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = (2.0 * PI * freq * n as f32 / 48000.0).sin();
+                [s * left, s * right]
+            })
+            .collect()
+    }
+
+    fn peaks(samples: &[f32]) -> (f32, f32) {
+        samples.chunks_exact(2).fold((0.0f32, 0.0f32), |(l, r), f| (l.max(f[0].abs()), r.max(f[1].abs())))
+    }
+
+    #[test]
+    fn balance_and_width() {
+        let mut samples = vec![0.5, 0.5, 1.0, -1.0];
+        Balance::new(0.5).process(&mut samples, 2, 48000);
+        assert_eq!(samples, vec![0.25, 0.5, 0.5, -1.0]);
+
+        // Width 0 folds to mono, 1 is transparent, 2 doubles the side
+        let mut samples = vec![1.0, 0.0];
+        Width::new(0.0).process(&mut samples, 2, 48000);
+        assert_eq!(samples, vec![0.5, 0.5]);
+        let mut samples = vec![1.0, 0.0];
+        Width::new(2.0).process(&mut samples, 2, 48000);
+        assert_eq!(samples, vec![1.5, -0.5]);
+
+        // A mono stream is left alone
+        let mut mono = vec![0.3, 0.6];
+        Balance::new(-1.0).process(&mut mono, 1, 48000);
+        Width::new(0.0).process(&mut mono, 1, 48000);
+        assert_eq!(mono, vec![0.3, 0.6]);
+    }
+
+    #[test]
+    fn crossfeed_bleeds_bass_across_and_keeps_mono_level() {
+        let (freq, feed) = crossfeed_preset("default").unwrap();
+
+        // Hard-left bass reaches the right ear, about `feed` + 3 dB down...
+        let mut cf = Crossfeed::new(freq, feed, 48000);
+        let mut bass = stereo_tone(50.0, 1.0, 0.0, 48000);
+        cf.process(&mut bass, 2, 48000);
+        let (l, r) = peaks(&bass[48000..]);
+        let bleed_db = 20.0 * (r / l).log10();
+        assert!(bleed_db > -12.0 && bleed_db < -3.0, "{}", bleed_db);
+
+        // ...hard-left treble barely does
+        let mut cf = Crossfeed::new(freq, feed, 48000);
+        let mut treble = stereo_tone(8000.0, 1.0, 0.0, 48000);
+        cf.process(&mut treble, 2, 48000);
+        let (l, r) = peaks(&treble[48000..]);
+        assert!(r / l < 0.05);
+
+        // A mono source adapted to stereo comes through at its own level
+        let mono: Vec<f32> = (0..48000).map(|n| 0.5 * (2.0 * PI * 100.0 * n as f32 / 48000.0).sin()).collect();
+        let mut adapted = vec![0.0; mono.len() * 2];
+        adapt_channels(&mono, 1, &mut adapted, 2);
+        let mut cf = Crossfeed::new(freq, feed, 48000);
+        cf.process(&mut adapted, 2, 48000);
+        let (l, r) = peaks(&adapted[48000..]);
+        assert!((l - 0.5).abs() < 0.01 && (r - l).abs() < 1e-6, "{} {}", l, r);
+    }
+}
@@ -0,0 +1,294 @@
+//! Pitch-preserving time stretch for playback speed (WSOLA).
+//!
+//! The input is cut into overlapping sequences whose start positions
+//! advance at `speed` times the output rate. Each sequence is shifted
+//! within a short search window to where its opening best matches (by
+//! normalized cross-correlation) the natural continuation of the previous
+//! one, then crossfaded onto it. Waveform periods line up across the
+//! joins, so pitch is kept and speech stays intelligible from 0.5x to 3x.
+//! At 1x the input passes through untouched.
+
+/// Slowest accepted playback speed.
+pub const MIN_SPEED: f32 = 0.5;
+/// Fastest accepted playback speed.
+pub const MAX_SPEED: f32 = 3.0;
+
+/// Sequence length: long enough to hold a few pitch periods of speech.
+const SEQUENCE_MS: u32 = 40;
+/// Crossfade between consecutive sequences.
+const OVERLAP_MS: u32 = 8;
+/// How far past its nominal start a sequence may be moved to match.
+const SEEK_MS: u32 = 15;
+
+/// WSOLA time stretcher for one interleaved stream.
+pub struct TimeStretch {
+    channels: usize,
+    speed: f32,
+    sequence: usize,
+    overlap: usize,
+    seek: usize,
+    /// Interleaved input not yet consumed.
+    input: Vec<f32>,
+    /// Where in `input` (frames) the next sequence nominally starts.
+    nominal: f64,
+    /// Natural continuation of the last sequence, crossfaded into the
+    /// next one. Empty while passing through at 1x.
+    tail: Vec<f32>,
+    /// Mono mix of `tail`, and of the search region, for correlation.
+    tail_mono: Vec<f32>,
+    search_mono: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let frames = |ms: u32| (sample_rate as u64 * ms as u64 / 1000).max(1) as usize;
+        Self {
+            channels: channels.max(1) as usize,
+            speed: 1.0,
+            sequence: frames(SEQUENCE_MS),
+            overlap: frames(OVERLAP_MS),
+            seek: frames(SEEK_MS),
+            input: Vec::new(),
+            nominal: 0.0,
+            tail: Vec::new(),
+            tail_mono: Vec::new(),
+            search_mono: Vec::new(),
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Clamped to `MIN_SPEED..=MAX_SPEED`; applies from the next `process`.
+    pub fn set_speed(&mut self, speed: f32) {
+        let speed = if speed.is_finite() { speed.clamp(MIN_SPEED, MAX_SPEED) } else { 1.0 };
+        self.speed = if (speed - 1.0).abs() < 1e-3 { 1.0 } else { speed };
+    }
+
+    /// Input frames taken in but not yet played out (position accounting).
+    pub fn pending_frames(&self) -> u64 {
+        (self.frames() as f64 - self.nominal).max(0.0) as u64
+    }
+
+    /// Drop buffered audio, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.tail.clear();
+        self.nominal = 0.0;
+    }
+
+    /// Stretch `input`, appending what is ready to `out`. Up to about one
+    /// sequence plus the search window is held back between calls.
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let ch = self.channels;
+        if self.speed == 1.0 && self.tail.is_empty() {
+            out.extend_from_slice(&self.input);
+            out.extend_from_slice(input);
+            self.reset();
+            return;
+        }
+        self.input.extend_from_slice(input);
+
+        if self.tail.is_empty() {
+            // Coming from pass-through: the next input is the natural continuation
+            if self.frames() < self.overlap {
+                return;
+            }
+            self.set_tail(0);
+            self.nominal = 0.0;
+        }
+
+        loop {
+            let start = self.nominal as usize;
+            if self.speed == 1.0 {
+                // Back to 1x: one last join, then pass the rest through
+                if self.frames() < start + self.seek + self.overlap {
+                    return;
+                }
+                let k = start + self.best_offset(start, self.seek);
+                self.crossfade_into(k, out);
+                out.extend_from_slice(&self.input[(k + self.overlap) * ch..]);
+                self.reset();
+                return;
+            }
+            if self.frames() < start + self.seek + self.sequence {
+                break;
+            }
+            self.next_sequence(start, self.seek, out);
+        }
+
+        // Input before the next nominal start can't be reached again
+        let done = (self.nominal as usize).min(self.frames());
+        self.input.drain(..done * ch);
+        self.nominal -= done as f64;
+    }
+
+    /// Emit everything still held (end of stream), searching only as far
+    /// as the input goes.
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        let ch = self.channels;
+        if self.tail.is_empty() {
+            out.extend_from_slice(&self.input);
+            self.reset();
+            return;
+        }
+        // Whole sequences while the input left is worth one at this speed
+        loop {
+            let start = self.nominal as usize;
+            let rest = self.frames().saturating_sub(start);
+            if rest < self.sequence || (rest as f32 / self.speed) < (self.sequence - self.overlap) as f32 {
+                break;
+            }
+            let seek = (rest - self.sequence).min(self.seek) + 1;
+            self.next_sequence(start, seek, out);
+        }
+        // Then join on what's left, cut to the length it plays for
+        let start = (self.nominal as usize).min(self.frames());
+        let rest = self.frames() - start;
+        if rest >= self.overlap {
+            self.crossfade_into(start, out);
+            let body = ((rest as f32 / self.speed) as usize).saturating_sub(self.overlap).min(rest - self.overlap);
+            let from = start + self.overlap;
+            out.extend_from_slice(&self.input[from * ch..(from + body) * ch]);
+        } else {
+            out.extend_from_slice(&self.tail);
+        }
+        self.reset();
+    }
+
+    /// Emit one sequence found within `seek` frames of `start`, keep its
+    /// tail and move the nominal position on by the speed.
+    fn next_sequence(&mut self, start: usize, seek: usize, out: &mut Vec<f32>) {
+        let ch = self.channels;
+        let k = start + self.best_offset(start, seek);
+        self.crossfade_into(k, out);
+        let body_end = k + self.sequence - self.overlap;
+        out.extend_from_slice(&self.input[(k + self.overlap) * ch..body_end * ch]);
+        self.set_tail(body_end);
+        self.nominal += self.speed as f64 * (self.sequence - self.overlap) as f64;
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        self.input[frame * self.channels..(frame + 1) * self.channels].iter().sum()
+    }
+
+    /// Keep `overlap` frames from `frame` on as the tail.
+    fn set_tail(&mut self, frame: usize) {
+        let ch = self.channels;
+        self.tail.clear();
+        self.tail.extend_from_slice(&self.input[frame * ch..(frame + self.overlap) * ch]);
+        self.tail_mono.clear();
+        for i in frame..frame + self.overlap {
+            let m = self.mono(i);
+            self.tail_mono.push(m);
+        }
+    }
+
+    /// Offset in `0..seek` from `start` whose opening best matches the tail.
+    fn best_offset(&mut self, start: usize, seek: usize) -> usize {
+        let len = seek + self.overlap;
+        self.search_mono.clear();
+        for i in start..start + len {
+            let m = self.mono(i);
+            self.search_mono.push(m);
+        }
+        let mut energy: f32 = self.search_mono[..self.overlap].iter().map(|x| x * x).sum();
+        let (mut best, mut best_score) = (0, f32::MIN);
+        for j in 0..seek {
+            if j > 0 {
+                let (old, new) = (self.search_mono[j - 1], self.search_mono[j + self.overlap - 1]);
+                energy = (energy - old * old + new * new).max(0.0);
+            }
+            let window = &self.search_mono[j..j + self.overlap];
+            let corr: f32 = self.tail_mono.iter().zip(window).map(|(a, b)| a * b).sum();
+            let score = corr / (energy + 1e-9).sqrt();
+            if score > best_score {
+                best = j;
+                best_score = score;
+            }
+        }
+        best
+    }
+
+    /// Crossfade the tail into the `overlap` frames starting at `frame`.
+    fn crossfade_into(&self, frame: usize, out: &mut Vec<f32>) {
+        let ch = self.channels;
+        let incoming = &self.input[frame * ch..(frame + self.overlap) * ch];
+        for (i, (old, new)) in self.tail.chunks_exact(ch).zip(incoming.chunks_exact(ch)).enumerate() {
+            let t = i as f32 / self.overlap as f32;
+            out.extend(old.iter().zip(new).map(|(o, n)| o * (1.0 - t) + n * t));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn tone(freq: f32, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = 0.5 * (2.0 * PI * freq * n as f32 / rate as f32).sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    /// Rising zero crossings of the left channel per second.
+    fn pitch(samples: &[f32], rate: u32) -> f32 {
+        let left: Vec<f32> = samples.chunks_exact(2).map(|f| f[0]).collect();
+        let rising = left.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        rising as f32 * rate as f32 / left.len() as f32
+    }
+
+    fn stretch_all(speed: f32, input: &[f32]) -> Vec<f32> {
+        let mut ts = TimeStretch::new(2, 48000);
+        ts.set_speed(speed);
+        let mut out = Vec::new();
+        for packet in input.chunks(1152 * 2) {
+            ts.process(packet, &mut out);
+        }
+        ts.flush(&mut out);
+        out
+    }
+
+    #[test]
+    fn changes_duration_but_keeps_pitch() {
+        let input = tone(220.0, 48000, 96000);
+        for speed in [0.5, 1.5, 2.0, 3.0] {
+            let out = stretch_all(speed, &input);
+            let ratio = input.len() as f32 / out.len() as f32;
+            assert!((ratio - speed).abs() < speed * 0.03, "speed {} gave {}", speed, ratio);
+            let p = pitch(&out, 48000);
+            assert!((p - 220.0).abs() < 3.0, "speed {} pitch {}", speed, p);
+        }
+
+        // 1x is a bit-exact pass-through
+        assert_eq!(stretch_all(1.0, &input), input);
+    }
+
+    #[test]
+    fn speed_changes_mid_stream_stay_continuous() {
+        let input = tone(220.0, 48000, 48000);
+        let mut ts = TimeStretch::new(2, 48000);
+        let mut out = Vec::new();
+        for (i, packet) in input.chunks(1152 * 2).enumerate() {
+            ts.set_speed(if (10..25).contains(&i) { 2.0 } else { 1.0 });
+            ts.process(packet, &mut out);
+            // Nothing read but not yet played is lost from the accounting
+            assert!(ts.pending_frames() < 48000 * (SEQUENCE_MS + SEEK_MS) as u64 / 1000 + 1152);
+        }
+        ts.flush(&mut out);
+        assert_eq!(ts.pending_frames(), 0);
+        // No step between neighbouring samples beyond what the tone itself has
+        let max_step = 0.5 * 2.0 * PI * 220.0 / 48000.0;
+        let worst = out.chunks_exact(2).map(|f| f[0]).collect::<Vec<_>>().windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+        assert!(worst < max_step * 1.5, "{} vs {}", worst, max_step);
+    }
+}
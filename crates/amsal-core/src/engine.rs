@@ -20,13 +20,20 @@ use beeclock_core::Clock;
 
 #[cfg(feature = "native")]
 use crate::effects::audio::AudioEffect;
+use crate::effects::convert::ChannelMix;
+use crate::effects::dsp::{FadeCurve, TRANSPORT_FADE_MS};
+use crate::effects::levels::Levels;
+use crate::effects::resample::ResampleQuality;
+use crate::effects::stretch::{MAX_SPEED, MIN_SPEED};
 use crate::effects::AudioBackend;
-use crate::effects::import;
+use crate::effects::{analysis, import};
 use crate::models::playback::PlaybackCommand;
 use crate::models::scroll_ext::{
-    default_playback_state, default_queue_state, queue_current_id, repeat_mode, ScrollExt,
+    default_playback_state, default_queue_state, queue_current_id, queue_id_at, queue_next_index,
+    repeat_mode, ScrollExt,
 };
 use crate::paths;
+use crate::time::{TimeSource, WallClock};
 
 use serde_json::Value;
 
@@ -50,8 +57,20 @@ pub struct Engine {
     /// Last-processed scroll versions (shared between in-process watcher + heartbeat).
     last_cmd_version: Arc<AtomicU64>,
     last_import_version: Arc<AtomicU64>,
+    /// Paces the heartbeat and analysis polling; stamps play history and
+    /// analysis jobs.
+    time: Arc<dyn TimeSource>,
 }
 
+/// Heartbeat period (4 Hz).
+const HEARTBEAT_PERIOD: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// How often the analysis loop checks for a new request.
+const ANALYSIS_POLL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Fastest `levels_hz` setting honoured for `/amsal/playback/levels`.
+const MAX_LEVELS_HZ: u64 = 60;
+
 impl Engine {
     /// Boot the engine with a 9S shell and the native (cpal) audio backend.
     #[cfg(feature = "native")]
@@ -78,9 +97,19 @@ impl Engine {
             handles: Mutex::new(Vec::new()),
             last_cmd_version: Arc::new(AtomicU64::new(0)),
             last_import_version: Arc::new(AtomicU64::new(0)),
+            time: Arc::new(WallClock),
         }
     }
 
+    /// Replace the heartbeat's time source (before `start()`).
+    ///
+    /// Tests pass a `ManualClock` so heartbeat ticks only happen when they
+    /// advance it.
+    pub fn with_time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.time = time;
+        self
+    }
+
     /// Start all effect loops. Idempotent — calling twice is a no-op.
     pub fn start(&self) {
         let mut handles = self.handles.lock();
@@ -89,6 +118,7 @@ impl Engine {
         }
         handles.push(self.start_playback_loop());
         handles.push(self.start_import_loop());
+        handles.push(self.start_analysis_loop());
         handles.push(self.start_heartbeat());
     }
 
@@ -187,12 +217,73 @@ impl Engine {
         })
     }
 
+    /// Loudness analysis jobs — long-running, so they get their own thread.
+    ///
+    /// Polls `/amsal/analysis/request` by version (in-process and
+    /// cross-process writers alike). A job left "running" in the status
+    /// scroll by a previous process is resumed first.
+    fn start_analysis_loop(&self) -> JoinHandle<()> {
+        let shell = Arc::clone(&self.shell);
+        let shutdown = Arc::clone(&self.shutdown);
+        let time = Arc::clone(&self.time);
+
+        thread::spawn(move || {
+            let request_version = |shell: &Shell| {
+                shell.get(paths::ANALYSIS_REQUEST).ok().flatten().map(|s| s.metadata.version).unwrap_or(0)
+            };
+            let mut last_version = request_version(&shell);
+
+            if let Ok(Some(status)) = shell.get(paths::ANALYSIS_STATUS) {
+                if status.data["running"].as_bool() == Some(true) {
+                    let started_ms = status.data["started_ms"].as_u64().unwrap_or(0);
+                    log::info!("amsal: resuming loudness analysis");
+                    analysis::run_analysis(
+                        &shell,
+                        &status.data["request"],
+                        last_version,
+                        started_ms,
+                        time.as_ref(),
+                        &shutdown,
+                    );
+                }
+            }
+
+            while !shutdown.load(Ordering::SeqCst) {
+                time.sleep(ANALYSIS_POLL, &shutdown);
+
+                let Ok(Some(scroll)) = shell.get(paths::ANALYSIS_REQUEST) else {
+                    continue;
+                };
+                if scroll.metadata.version == last_version {
+                    continue;
+                }
+                last_version = scroll.metadata.version;
+
+                if scroll.data["cancel"].as_bool() == Some(true) {
+                    if let Ok(Some(mut status)) = shell.get(paths::ANALYSIS_STATUS) {
+                        if status.data["running"].as_bool() == Some(true) {
+                            status.data["running"] = false.into();
+                            status.data["cancelled"] = true.into();
+                            log_err(shell.put(paths::ANALYSIS_STATUS, status.data), "analysis cancel");
+                        }
+                    }
+                    continue;
+                }
+                let started_ms = time.now_ms().max(0) as u64;
+                analysis::run_analysis(&shell, &scroll.data, last_version, started_ms, time.as_ref(), &shutdown);
+            }
+        })
+    }
+
     /// Clock-driven heartbeat — replaces ad-hoc position polling.
     ///
     /// A BeeClock with musical partitions (sub/beat/bar) ticks at 4 Hz.
     /// Each tick: sync audio position, check track end, write clock state
     /// to scrolls. Pulses fire at structural intervals — Flutter/web
     /// watches `/amsal/clock/**` to drive animations and game-like flows.
+    /// Ticks are paced by the engine's `TimeSource` (see `with_time_source`).
+    /// With `levels_hz` set, the loop also wakes between ticks to publish
+    /// the backend's meter reading to `/amsal/playback/levels`.
     fn start_heartbeat(&self) -> JoinHandle<()> {
         let shell = Arc::clone(&self.shell);
         let audio = Arc::clone(&self.audio);
@@ -201,18 +292,57 @@ impl Engine {
         let shutdown = Arc::clone(&self.shutdown);
         let last_cmd_version = Arc::clone(&self.last_cmd_version);
         let last_import_version = Arc::clone(&self.last_import_version);
+        let time = Arc::clone(&self.time);
 
         thread::spawn(move || {
             let mut clock = build_clock(&shell);
             let mut last_eq_version: u64 = 0;
+            let mut last_settings_version: u64 = 0;
+            let mut audio_settings = Value::Null;
+            log_err(shell.put(paths::DEVICES, audio.output_devices()), "devices");
+            let heartbeat_ms = HEARTBEAT_PERIOD.as_millis() as u64;
+            // Heartbeat time waited so far, and when the next tick is due;
+            // levels keep their own (interval, due) so neither skews the other
+            let (mut elapsed_ms, mut next_tick_ms) = (0, heartbeat_ms);
+            let mut levels_due: Option<(u64, u64)> = None;
+            let mut levels_live = false;
 
             while !shutdown.load(Ordering::SeqCst) {
-                thread::sleep(std::time::Duration::from_millis(250));
+                levels_due = match (levels_interval_ms(&audio_settings), levels_due) {
+                    (Some(interval_ms), Some((was, due_ms))) if was == interval_ms => Some((interval_ms, due_ms)),
+                    (Some(interval_ms), _) => Some((interval_ms, elapsed_ms + interval_ms)),
+                    (None, _) => None,
+                };
+                // Wake for whichever comes first: the next tick or levels snapshot
+                let wake_ms = levels_due.map_or(next_tick_ms, |(_, due_ms)| due_ms.min(next_tick_ms));
+                time.wait_tick(std::time::Duration::from_millis(wake_ms - elapsed_ms), &shutdown);
+                elapsed_ms = wake_ms;
 
                 if shutdown.load(Ordering::SeqCst) {
                     break;
                 }
 
+                // --- Levels/spectrum for visualisers, at their own rate ---
+                match levels_due {
+                    Some((interval_ms, due_ms)) if due_ms <= elapsed_ms => {
+                        levels_due = Some((interval_ms, due_ms + interval_ms));
+                        levels_live = publish_levels(&shell, &*audio, levels_live);
+                    }
+                    Some(_) => {}
+                    // Switched off: leave visualisers a silent reading
+                    None if levels_live => {
+                        clear_levels(&shell);
+                        levels_live = false;
+                    }
+                    None => {}
+                }
+
+                // Between ticks the loop only published levels
+                if elapsed_ms < next_tick_ms {
+                    continue;
+                }
+                next_tick_ms += heartbeat_ms;
+
                 let outcome = clock.tick();
 
                 // --- DSP chain hot-swap via scroll version ---
@@ -221,10 +351,42 @@ impl Engine {
                 if eq_version != last_eq_version && eq_version > 0 {
                     last_eq_version = eq_version;
                     if let Ok(Some(scroll)) = shell.get(paths::PLAYBACK_EQ) {
-                        let sr = 44100u32; // Default; actual rate is in audio state
-                        let ch = 2u16;
-                        let chain = crate::effects::dsp::chain_from_value(&scroll.data, sr, ch);
-                        audio.set_dsp(chain);
+                        // A bad spec is reported and the previous EQ keeps playing
+                        let errors = crate::effects::dsp::parse_filters(&scroll.data)
+                            .and_then(|specs| crate::effects::dsp::check_files(&specs))
+                            .err()
+                            .unwrap_or_default();
+                        if errors.is_empty() {
+                            // Built at the output's actual rate/channels by the backend
+                            audio.set_eq(&scroll.data);
+                        }
+                        let status = serde_json::json!({
+                            "version": eq_version,
+                            "applied": errors.is_empty(),
+                            "errors": errors.iter()
+                                .map(|e| serde_json::json!({"index": e.index, "error": e.message}))
+                                .collect::<Vec<_>>(),
+                        });
+                        log_err(shell.put(paths::PLAYBACK_EQ_STATUS, status), "eq status");
+                    }
+                }
+
+                // --- Audio settings via scroll version ---
+                if let Ok(Some(scroll)) = shell.get(paths::SETTINGS_AUDIO) {
+                    if scroll.metadata.version != last_settings_version {
+                        last_settings_version = scroll.metadata.version;
+                        audio_settings = scroll.data;
+                        apply_output_settings(&*audio, &audio_settings);
+                        // Re-apply normalization so a mode change is heard on the current track
+                        let current_id = state.lock()["current_id"].as_str().map(String::from);
+                        if let Some(id) = current_id {
+                            if let Ok(Some(item)) = shell.get(&paths::library_path(&id)) {
+                                if let Some(fp) = item.data["path"].as_str() {
+                                    let item = with_analysis_gain(&shell, &item.data);
+                                    audio.set_replay_gain(fp, replay_gain_for(&audio_settings, &item));
+                                }
+                            }
+                        }
                     }
                 }
 
@@ -270,6 +432,18 @@ impl Engine {
                     continue;
                 }
 
+                // --- Gapless handover: backend already crossed into the prepared track ---
+                if let Some(next_path) = audio.take_track_advance() {
+                    let (current_id, played_ms) = {
+                        let s = state.lock();
+                        (s["current_id"].as_str().map(String::from), s["duration_ms"].as_u64().unwrap_or(0))
+                    };
+                    if let Some(id) = current_id {
+                        record_play_event(&shell, &id, played_ms, time.now_ms());
+                    }
+                    follow_track_advance(&shell, &*audio, &state, &queue, &next_path);
+                }
+
                 // --- Audio state sync (same semantics as old position tracker) ---
                 if !audio.is_playing() && !audio.is_paused() {
                     if audio.is_finished() {
@@ -279,13 +453,18 @@ impl Engine {
                             s["current_id"].as_str().map(String::from)
                         };
                         if let Some(id) = current_id {
-                            record_play_event(&shell, &id, audio.position_ms());
+                            record_play_event(&shell, &id, audio.position_ms(), time.now_ms());
                         }
                         advance_queue(&shell, &*audio, &state, &queue);
+                    } else if state.lock()["fading_out"].as_bool() == Some(true) {
+                        // "Fade out and stop" completed: stopped, queue kept
+                        replace_state(&shell, &state, default_playback_state());
                     }
                 } else {
                     let pos = audio.position_ms();
                     let dur = audio.duration_ms();
+                    let format = audio.output_format();
+                    let active_loop = audio.loop_region();
 
                     update_state(&shell, &state, |s| {
                         s["position_ms"] = pos.into();
@@ -293,48 +472,31 @@ impl Engine {
                             s["duration_ms"] = dur.into();
                         }
                         s["playing"] = (audio.is_playing() && !audio.is_paused()).into();
+                        s["underruns"] = audio.underruns().into();
+                        s["output_format"] = match format {
+                            Some((rate, channels)) => serde_json::json!({"sample_rate": rate, "channels": channels}),
+                            None => Value::Null,
+                        };
+                        s["loop"] = loop_json(active_loop);
                     });
 
-                    // --- Pre-probe next track 3s before end for gapless ---
-                    if audio.is_playing() && !audio.is_paused() && dur > 3000 && pos > dur - 3000 {
-                        let next_path = {
-                            // Lock ordering: state before queue (matches advance_queue)
-                            let repeat = {
-                                let s = state.lock();
-                                repeat_mode(&s).to_string()
-                            };
-                            let q = queue.lock();
-                            if repeat == "one" {
-                                None // Will replay same track
-                            } else {
-                                let items = q["items"].as_array();
-                                let len = items.map(|a| a.len()).unwrap_or(0);
-                                let idx = q["index"].as_u64().unwrap_or(0) as usize;
-                                let next_idx = if idx + 1 < len {
-                                    Some(idx + 1)
-                                } else if repeat == "all" {
-                                    Some(0)
-                                } else {
-                                    None
-                                };
-                                next_idx.and_then(|i| {
-                                    if q["shuffle"].as_bool().unwrap_or(false) {
-                                        q["shuffle_order"]
-                                            .as_array()
-                                            .and_then(|order| order.get(i))
-                                            .and_then(|v| v.as_u64())
-                                            .and_then(|actual| items?.get(actual as usize))
-                                            .and_then(|v| v.as_str())
-                                            .map(String::from)
-                                    } else {
-                                        items?.get(i)?.as_str().map(String::from)
-                                    }
-                                })
-                            }
-                        };
-                        if let Some(next_id) = next_path {
+                    // --- Stage next track 3s before end (plus crossfade) for handover ---
+                    // Measured from the decoder, which runs ahead of what is audible
+                    let lead = 3000 + crossfade_settings(&audio_settings).0;
+                    let end = state.lock()["trim"]["end_ms"].as_u64().map_or(dur, |end| end.min(dur));
+                    let decoded = audio.decoded_position_ms();
+                    if audio.is_playing() && !audio.is_paused() && end > 0 && decoded + lead > end {
+                        if let Some(next_id) = peek_next_id(&state, &queue) {
                             if let Ok(Some(scroll)) = shell.get(&paths::library_path(&next_id)) {
                                 if let Some(fp) = scroll.data["path"].as_str() {
+                                    let album = state.lock()["album"].as_str().unwrap_or("").to_string();
+                                    let (fade_ms, curve) = crossfade_for(&audio_settings, &album, &scroll.data);
+                                    audio.set_crossfade(fade_ms, curve);
+                                    let item = with_analysis_gain(&shell, &scroll.data);
+                                    audio.set_replay_gain(fp, replay_gain_for(&audio_settings, &item));
+                                    apply_track_range(&*audio, fp, &scroll.data);
+                                    // Switched to by the decoder as it takes the track
+                                    audio.set_track_speed(fp, speed_for(&shell, &scroll.data));
                                     audio.prepare_next(fp);
                                 }
                             }
@@ -407,6 +569,30 @@ impl Engine {
         }
     }
 
+    /// Set a library item's trim points: every play starts at `start_ms`
+    /// and ends at `end_ms` (None = the track's end). `(0, None)` clears them.
+    pub fn set_track_range(&self, id: &str, start_ms: u64, end_ms: Option<u64>) -> NineSResult<Scroll> {
+        let path = paths::library_path(id);
+        match self.shell.get(&path)? {
+            Some(mut scroll) => {
+                if let Some(item) = scroll.data.as_object_mut() {
+                    item.remove("start_ms");
+                    item.remove("end_ms");
+                    if start_ms > 0 {
+                        item.insert("start_ms".into(), start_ms.into());
+                    }
+                    if let Some(end_ms) = end_ms {
+                        item.insert("end_ms".into(), end_ms.into());
+                    }
+                }
+                self.shell.put(&path, scroll.data)
+            }
+            None => Err(nine_s_core::errors::NineSError::Other(
+                format!("library item not found: {}", id),
+            )),
+        }
+    }
+
     /// Search library by case-insensitive substring across title, artist, album, genre.
     pub fn search_library(&self, query: &str) -> Vec<Value> {
         let q = query.to_lowercase();
@@ -515,6 +701,30 @@ impl Engine {
             .put(paths::IMPORT_REQUEST, serde_json::json!({"file": file}))
     }
 
+    /// Request loudness analysis of the whole library. Items with an
+    /// existing result (or ReplayGain tags) are skipped unless `force`.
+    pub fn analyze_library(&self, force: bool) -> NineSResult<Scroll> {
+        self.shell
+            .put(paths::ANALYSIS_REQUEST, serde_json::json!({"force": force}))
+    }
+
+    /// Read the loudness analysis result for a library item.
+    pub fn analysis(&self, id: &str) -> Option<Value> {
+        self.shell
+            .get(&paths::analysis_path(id))
+            .ok()
+            .flatten()
+            .map(|s| s.data)
+    }
+
+    /// Enumerate output hosts/devices and publish them to `/amsal/devices`.
+    /// Select one with `"output_device"` in `/amsal/settings/audio`.
+    pub fn list_devices(&self) -> Value {
+        let devices = self.audio.output_devices();
+        log_err(self.shell.put(paths::DEVICES, devices.clone()), "devices");
+        devices
+    }
+
     /// Read the latest clock tick state from scroll.
     pub fn clock_state(&self) -> Option<Value> {
         self.shell
@@ -651,7 +861,7 @@ impl Engine {
 
     /// Record a play event (writes history scroll + updates stats).
     pub fn record_play(&self, media_id: &str, duration_played_ms: u64) {
-        record_play_event(&self.shell, media_id, duration_played_ms);
+        record_play_event(&self.shell, media_id, duration_played_ms, self.time.now_ms());
     }
 
     /// Get recent play history, most recent first.
@@ -699,6 +909,85 @@ impl Engine {
         entries.into_iter().take(limit).collect()
     }
 
+    // -------------------------------------------------------------------
+    // EQ Presets
+    // -------------------------------------------------------------------
+
+    /// Store an EQ spec (`/amsal/playback/eq` schema) as a named preset.
+    /// Rejected if any filter is invalid.
+    pub fn save_eq_preset(&self, name: &str, spec: Value) -> NineSResult<Scroll> {
+        if name.is_empty() || name.contains('/') {
+            return Err(nine_s_core::errors::NineSError::Other(format!("invalid preset name: {:?}", name)));
+        }
+        let checked = crate::effects::dsp::parse_filters(&spec).and_then(|specs| crate::effects::dsp::check_files(&specs));
+        if let Err(errors) = checked {
+            let msgs: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            return Err(nine_s_core::errors::NineSError::Other(msgs.join("; ")));
+        }
+        self.shell.put(
+            &paths::eq_preset_path(name),
+            serde_json::json!({"name": name, "filters": spec["filters"].clone()}),
+        )
+    }
+
+    /// Parse an Equalizer APO / AutoEQ `ParametricEQ.txt` and store it as a preset.
+    pub fn import_eq_preset(&self, name: &str, text: &str) -> NineSResult<Scroll> {
+        let spec = crate::effects::apo::parse(text).map_err(nine_s_core::errors::NineSError::Other)?;
+        self.save_eq_preset(name, spec)
+    }
+
+    /// Read a stored EQ preset.
+    pub fn eq_preset(&self, name: &str) -> Option<Value> {
+        self.shell
+            .get(&paths::eq_preset_path(name))
+            .ok()
+            .flatten()
+            .filter(|s| s.metadata.deleted != Some(true))
+            .map(|s| s.data)
+    }
+
+    /// Names of all stored EQ presets.
+    pub fn list_eq_presets(&self) -> Vec<String> {
+        self.shell
+            .all(paths::EQ_PRESETS_PREFIX)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|path| self.shell.get(&path).ok().flatten())
+            .filter(|s| s.metadata.deleted != Some(true))
+            .filter_map(|s| s.data["name"].as_str().map(String::from))
+            .collect()
+    }
+
+    /// Make a stored preset the active EQ (written to `/amsal/playback/eq`,
+    /// applied on the next heartbeat).
+    pub fn activate_eq_preset(&self, name: &str) -> NineSResult<Scroll> {
+        match self.eq_preset(name) {
+            Some(preset) => self.shell.put(
+                paths::PLAYBACK_EQ,
+                serde_json::json!({"preset": name, "filters": preset["filters"].clone()}),
+            ),
+            None => Err(nine_s_core::errors::NineSError::Other(
+                format!("eq preset not found: {}", name),
+            )),
+        }
+    }
+
+    /// Magnitude/phase curve of an EQ spec, or of the active EQ when None,
+    /// at `points` log-spaced frequencies (see `dsp::frequency_response`).
+    /// `sample_rate` 0 = the output's current rate (48 kHz when idle).
+    pub fn eq_response(&self, spec: Option<&Value>, sample_rate: u32, points: usize) -> Value {
+        let rate = match sample_rate {
+            0 => self.audio.output_format().map(|(rate, _)| rate).unwrap_or(48_000),
+            rate => rate,
+        };
+        let active = match spec {
+            Some(_) => None,
+            None => self.shell.get(paths::PLAYBACK_EQ).ok().flatten().map(|s| s.data),
+        };
+        let spec = spec.or(active.as_ref()).unwrap_or(&Value::Null);
+        crate::effects::dsp::frequency_response(spec, rate, points)
+    }
+
     // -------------------------------------------------------------------
     // Clock Config
     // -------------------------------------------------------------------
@@ -808,13 +1097,8 @@ fn update_queue(shell: &Shell, queue: &Mutex<Value>, f: impl FnOnce(&mut Value))
     log_err(shell.put(paths::QUEUE_CURRENT, guard.clone()), "sync queue");
 }
 
-/// Record a play event: write history scroll + update stats.
-fn record_play_event(shell: &Shell, media_id: &str, duration_played_ms: u64) {
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_millis() as i64)
-        .unwrap_or(0);
-
+/// Record a play event at `now` (ms since epoch): write history scroll + update stats.
+fn record_play_event(shell: &Shell, media_id: &str, duration_played_ms: u64, now: i64) {
     log_err(
         shell.put(
             &paths::history_path(now),
@@ -862,34 +1146,15 @@ fn handle_playback(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>
             let path = paths::library_path(id);
             if let Ok(Some(scroll)) = shell.get(&path) {
                 if let Some(file_path) = scroll.data["path"].as_str() {
+                    let settings = shell.get(paths::SETTINGS_AUDIO).ok().flatten().map(|s| s.data).unwrap_or_default();
+                    let item = with_analysis_gain(shell, &scroll.data);
+                    audio.set_replay_gain(file_path, replay_gain_for(&settings, &item));
+                    apply_output_settings(audio, &settings);
+                    apply_track_range(audio, file_path, &scroll.data);
+                    let speed = speed_for(shell, &scroll.data);
+                    audio.set_speed(speed);
                     audio.play(file_path);
-                    let d = &scroll.data;
-                    let duration = d["duration_ms"].as_u64().unwrap_or(0);
-                    let title = d["title"].as_str().unwrap_or("Unknown");
-                    let artist = d["artist"].as_str().unwrap_or("Unknown");
-                    let album = d["album"].as_str().unwrap_or("");
-                    // Single snapshot — no interleaved mutations
-                    let guard = state.lock();
-                    let volume = guard["volume"].as_f64().unwrap_or(0.8);
-                    let shuffle = guard["shuffle"].as_bool().unwrap_or(false);
-                    let repeat = guard["repeat"].as_str().unwrap_or("off").to_string();
-                    drop(guard);
-                    replace_state(
-                        shell,
-                        state,
-                        serde_json::json!({
-                            "current_id": id,
-                            "title": title,
-                            "artist": artist,
-                            "album": album,
-                            "playing": true,
-                            "position_ms": 0,
-                            "duration_ms": duration,
-                            "volume": volume,
-                            "shuffle": shuffle,
-                            "repeat": repeat,
-                        }),
-                    );
+                    publish_now_playing(shell, state, id, &scroll.data, speed);
                 }
             }
         }
@@ -905,22 +1170,78 @@ fn handle_playback(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>
             audio.stop();
             replace_state(shell, state, default_playback_state());
         }
+        PlaybackCommand::FadeOut { duration_ms } => {
+            // The heartbeat resets state once the backend has stopped
+            if audio.is_playing() {
+                audio.fade_out(duration_ms.min(MAX_FADE_OUT_MS));
+                update_state(shell, state, |s| s["fading_out"] = true.into());
+            }
+        }
         PlaybackCommand::Seek { position_ms } => {
-            audio.seek(position_ms);
-            update_state(shell, state, |s| s["position_ms"] = position_ms.into());
+            let landed = audio.seek(position_ms);
+            update_state(shell, state, |s| s["position_ms"] = landed.into());
+        }
+        PlaybackCommand::SeekRelative { delta_ms } => {
+            // Past the end lands on it, which ends the track
+            let mut target = audio.position_ms().saturating_add_signed(delta_ms);
+            let duration = audio.duration_ms();
+            if duration > 0 {
+                target = target.min(duration);
+            }
+            let landed = audio.seek(target);
+            update_state(shell, state, |s| s["position_ms"] = landed.into());
         }
         PlaybackCommand::SetVolume { volume } => {
             audio.set_volume(volume);
             update_state(shell, state, |s| s["volume"] = volume.into());
         }
+        PlaybackCommand::SetSpeed { rate } => {
+            if rate.is_finite() {
+                let rate = rate.clamp(MIN_SPEED, MAX_SPEED);
+                audio.set_speed(rate);
+                update_state(shell, state, |s| s["speed"] = rate.into());
+                remember_speed(shell, state, rate);
+            }
+        }
+        PlaybackCommand::SetLoop { start_ms, end_ms } => {
+            // A rejected loop is reported in the state's `error`, like a
+            // failed track; play() clears the loop, so none is kept for later
+            let rejected = if end_ms <= start_ms {
+                Some("loop_invalid_region")
+            } else if !audio.is_playing() {
+                Some("loop_not_playing")
+            } else {
+                None
+            };
+            match rejected {
+                Some(error) => update_state(shell, state, |s| s["error"] = error.into()),
+                None => {
+                    audio.set_loop(Some((start_ms, end_ms)));
+                    update_state(shell, state, |s| {
+                        s["loop"] = loop_json(Some((start_ms, end_ms)));
+                        if s["error"].as_str().is_some_and(|e| e.starts_with("loop_")) {
+                            if let Some(o) = s.as_object_mut() {
+                                o.remove("error");
+                            }
+                        }
+                    });
+                }
+            }
+        }
+        PlaybackCommand::ClearLoop => {
+            audio.set_loop(None);
+            update_state(shell, state, |s| s["loop"] = Value::Null);
+        }
         PlaybackCommand::Next => {
             advance_queue(shell, audio, state, queue);
         }
         PlaybackCommand::Previous => {
             let pos = audio.position_ms();
-            if pos > 3000 {
-                audio.seek(0);
-                update_state(shell, state, |s| s["position_ms"] = 0.into());
+            let start = state.lock()["trim"]["start_ms"].as_u64().unwrap_or(0);
+            if pos > start + 3000 {
+                // Restart the track (from its start trim point)
+                let landed = audio.seek(start);
+                update_state(shell, state, |s| s["position_ms"] = landed.into());
             } else {
                 retreat_queue(shell, audio, state, queue);
             }
@@ -960,6 +1281,224 @@ fn handle_playback(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>
     }
 }
 
+/// Replace playback state with a fresh "now playing" snapshot for a library item.
+fn publish_now_playing(shell: &Shell, state: &Mutex<Value>, id: &str, item: &Value, speed: f32) {
+    let duration = item["duration_ms"].as_u64().unwrap_or(0);
+    let title = item["title"].as_str().unwrap_or("Unknown");
+    let artist = item["artist"].as_str().unwrap_or("Unknown");
+    let album = item["album"].as_str().unwrap_or("");
+    let (start, end) = track_range(item);
+    // Single snapshot — no interleaved mutations
+    let guard = state.lock();
+    let volume = guard["volume"].as_f64().unwrap_or(0.8);
+    let shuffle = guard["shuffle"].as_bool().unwrap_or(false);
+    let repeat = guard["repeat"].as_str().unwrap_or("off").to_string();
+    drop(guard);
+    let mut snapshot = serde_json::json!({
+        "current_id": id,
+        "title": title,
+        "artist": artist,
+        "album": album,
+        "playing": true,
+        "position_ms": start,
+        "duration_ms": duration,
+        "volume": volume,
+        "shuffle": shuffle,
+        "repeat": repeat,
+        "speed": speed,
+        "loop": null,
+    });
+    if start > 0 || end.is_some() {
+        snapshot["trim"] = serde_json::json!({"start_ms": start, "end_ms": end});
+    }
+    replace_state(shell, state, snapshot);
+}
+
+/// Playback speed remembered for an item's media type (1.0 when none).
+fn speed_for(shell: &Shell, item: &Value) -> f32 {
+    let Some(media_type) = item["media_type"].as_str() else {
+        return 1.0;
+    };
+    shell.get(paths::PLAYBACK_SPEED).ok().flatten()
+        .and_then(|s| s.data[media_type].as_f64())
+        .map(|v| (v as f32).clamp(MIN_SPEED, MAX_SPEED))
+        .unwrap_or(1.0)
+}
+
+/// Remember `speed` for the playing item's media type, so the next item of
+/// that type (the next podcast episode, say) starts at it.
+fn remember_speed(shell: &Shell, state: &Mutex<Value>, speed: f32) {
+    let Some(id) = state.lock()["current_id"].as_str().map(String::from) else {
+        return;
+    };
+    let Ok(Some(item)) = shell.get(&paths::library_path(&id)) else {
+        return;
+    };
+    let Some(media_type) = item.data["media_type"].as_str() else {
+        return;
+    };
+    let mut speeds = shell.get(paths::PLAYBACK_SPEED).ok().flatten()
+        .map(|s| s.data)
+        .filter(Value::is_object)
+        .unwrap_or_else(|| serde_json::json!({}));
+    speeds[media_type] = speed.into();
+    log_err(shell.put(paths::PLAYBACK_SPEED, speeds), "playback speed");
+}
+
+/// Trim points from a library item's optional `start_ms`/`end_ms` (an end
+/// not after the start is ignored).
+fn track_range(item: &Value) -> (u64, Option<u64>) {
+    let start_ms = item["start_ms"].as_u64().unwrap_or(0);
+    (start_ms, item["end_ms"].as_u64().filter(|&end| end > start_ms))
+}
+
+fn apply_track_range(audio: &dyn AudioBackend, file_path: &str, item: &Value) {
+    let (start_ms, end_ms) = track_range(item);
+    audio.set_track_range(file_path, start_ms, end_ms);
+}
+
+/// `{"start_ms", "end_ms"}` of an A-B loop, or null.
+fn loop_json(region: Option<(u64, u64)>) -> Value {
+    match region {
+        Some((start_ms, end_ms)) => serde_json::json!({"start_ms": start_ms, "end_ms": end_ms}),
+        None => Value::Null,
+    }
+}
+
+/// Longest accepted crossfade, so a typo can't fade whole tracks together.
+const MAX_CROSSFADE_MS: u64 = 12_000;
+
+/// Longest accepted "fade out and stop".
+const MAX_FADE_OUT_MS: u64 = 60_000;
+
+/// Longest accepted transport ramp; beyond this pause and seek feel sluggish.
+const MAX_TRANSPORT_FADE_MS: u64 = 500;
+
+/// Crossfade settings from `/amsal/settings/audio`:
+/// `{"crossfade": {"duration_ms": 4000, "curve": "equal_power", "skip_same_album": true}}`.
+/// Returns (duration_ms, curve, skip_same_album); missing → (0, equal power, true).
+fn crossfade_settings(settings: &Value) -> (u64, FadeCurve, bool) {
+    let xf = &settings["crossfade"];
+    let duration = xf["duration_ms"].as_u64().unwrap_or(0).min(MAX_CROSSFADE_MS);
+    let curve = xf["curve"].as_str().and_then(FadeCurve::from_name).unwrap_or_default();
+    let skip_same_album = xf["skip_same_album"].as_bool().unwrap_or(true);
+    (duration, curve, skip_same_album)
+}
+
+/// Crossfade for the transition from the current album into `next` item.
+/// Consecutive tracks of one album keep their gapless join when configured.
+pub(crate) fn crossfade_for(settings: &Value, current_album: &str, next: &Value) -> (u64, FadeCurve) {
+    let (duration, curve, skip_same_album) = crossfade_settings(settings);
+    let same_album = !current_album.is_empty() && next["album"].as_str() == Some(current_album);
+    if skip_same_album && same_album {
+        (0, curve)
+    } else {
+        (duration, curve)
+    }
+}
+
+/// Period of `/amsal/playback/levels` updates from the audio settings'
+/// `levels_hz` (0 or missing = off, capped at `MAX_LEVELS_HZ`).
+fn levels_interval_ms(settings: &Value) -> Option<u64> {
+    let hz = settings["levels_hz"].as_u64().unwrap_or(0).min(MAX_LEVELS_HZ);
+    (hz > 0).then(|| 1000 / hz)
+}
+
+/// Publish the backend's latest meter reading while a track is loaded.
+/// Once playback stops the scroll is cleared, once. Returns whether a
+/// reading was published.
+fn publish_levels(shell: &Shell, audio: &dyn AudioBackend, live: bool) -> bool {
+    match audio.levels().filter(|_| audio.is_playing() || audio.is_paused()) {
+        Some(levels) => {
+            log_err(shell.put(paths::PLAYBACK_LEVELS, levels.to_value()), "levels");
+            true
+        }
+        None => {
+            if live {
+                clear_levels(shell);
+            }
+            false
+        }
+    }
+}
+
+/// Empty readings: nothing is playing.
+fn clear_levels(shell: &Shell) {
+    log_err(shell.put(paths::PLAYBACK_LEVELS, Levels::default().to_value()), "clear levels");
+}
+
+/// Push output settings to the backend: resampler quality
+/// (`"low" | "medium" | "high"`, default medium), channel mixing, preferred
+/// device, dither.
+fn apply_output_settings(audio: &dyn AudioBackend, settings: &Value) {
+    let quality = settings["resampler"].as_str().and_then(ResampleQuality::from_name).unwrap_or_default();
+    audio.set_resample_quality(quality);
+    audio.set_channel_mix(ChannelMix::from_value(&settings["channel_mix"]));
+    audio.set_output_device(settings["output_device"].as_str());
+    audio.set_dither(settings["dither"].as_bool().unwrap_or(true));
+    let transport_fade = settings["transport_fade_ms"].as_u64().unwrap_or(TRANSPORT_FADE_MS);
+    audio.set_transport_fade(transport_fade.min(MAX_TRANSPORT_FADE_MS));
+}
+
+/// Linear loudness-normalization factor for a library item.
+///
+/// Settings: `{"replay_gain": {"mode": "track", "preamp_db": 0.0, "prevent_clipping": true}}`.
+/// `"track"` falls back to the album gain and `"album"` to the track gain;
+/// `"off"` (default) or an untagged item gives unity. With clipping
+/// prevention the factor is capped so the tagged peak stays at full scale.
+pub(crate) fn replay_gain_for(settings: &Value, item: &Value) -> f32 {
+    let cfg = &settings["replay_gain"];
+    let rg = &item["replay_gain"];
+    let (primary, fallback) = match cfg["mode"].as_str() {
+        Some("track") => (("track_gain_db", "track_peak"), ("album_gain_db", "album_peak")),
+        Some("album") => (("album_gain_db", "album_peak"), ("track_gain_db", "track_peak")),
+        _ => return 1.0,
+    };
+    let Some((gain_db, peak)) = [primary, fallback]
+        .iter()
+        .find_map(|(gain, peak)| rg[*gain].as_f64().map(|g| (g, rg[*peak].as_f64())))
+    else {
+        return 1.0;
+    };
+
+    let preamp_db = cfg["preamp_db"].as_f64().unwrap_or(0.0);
+    let mut gain = 10f64.powf((gain_db + preamp_db) / 20.0);
+    if cfg["prevent_clipping"].as_bool().unwrap_or(true) {
+        if let Some(peak) = peak.filter(|p| *p > 0.0) {
+            gain = gain.min(1.0 / peak);
+        }
+    }
+    gain as f32
+}
+
+/// Library item with gains from `/amsal/analysis/items/{id}` filled in when it
+/// carries no ReplayGain tags of its own.
+fn with_analysis_gain(shell: &Shell, item: &Value) -> Value {
+    let mut item = item.clone();
+    if item["replay_gain"].is_null() {
+        let analysed = item["id"]
+            .as_str()
+            .and_then(|id| shell.get(&paths::analysis_path(id)).ok().flatten())
+            .and_then(|scroll| analysis::replay_gain_from_analysis(&scroll.data));
+        if let Some(rg) = analysed {
+            item["replay_gain"] = rg;
+        }
+    }
+    item
+}
+
+/// ID of the item `advance_queue` would play next (for gapless staging).
+fn peek_next_id(state: &Mutex<Value>, queue: &Mutex<Value>) -> Option<String> {
+    // Lock ordering: state before queue (matches advance_queue)
+    let repeat = {
+        let s = state.lock();
+        repeat_mode(&s).to_string()
+    };
+    let q = queue.lock();
+    let next = queue_next_index(&q, &repeat)?;
+    queue_id_at(&q, next).map(String::from)
+}
+
 fn advance_queue(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>, queue: &Mutex<Value>) {
     // Read repeat mode from state (lock ordering: state before queue)
     let repeat = {
@@ -970,36 +1509,22 @@ fn advance_queue(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>,
     // Lock queue, compute next index, sync scroll, extract play ID
     let play_id = {
         let mut data = queue.lock();
-        let items = match data["items"].as_array() {
-            Some(a) if !a.is_empty() => a,
+        match data["items"].as_array() {
+            Some(a) if !a.is_empty() => {}
             _ => return,
-        };
-        let len = items.len();
+        }
 
-        if repeat == "one" {
-            let id = queue_current_id(&data).map(String::from);
+        let Some(index) = queue_next_index(&data, &repeat) else {
             drop(data);
-            if let Some(id) = id {
-                handle_playback(shell, audio, state, queue, PlaybackCommand::Play { id });
-            }
+            audio.stop();
+            replace_state(shell, state, default_playback_state());
             return;
-        }
-
-        let mut index = data["index"].as_u64().unwrap_or(0) as usize + 1;
+        };
 
-        if index >= len {
-            if repeat == "all" {
-                index = 0;
-            } else {
-                drop(data);
-                audio.stop();
-                replace_state(shell, state, default_playback_state());
-                return;
-            }
+        if repeat != "one" {
+            data["index"] = index.into();
+            log_err(shell.put(paths::QUEUE_CURRENT, data.clone()), "advance queue");
         }
-
-        data["index"] = index.into();
-        log_err(shell.put(paths::QUEUE_CURRENT, data.clone()), "advance queue");
         queue_current_id(&data).map(String::from)
     };
 
@@ -1008,6 +1533,44 @@ fn advance_queue(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>,
     }
 }
 
+/// Advance the queue after the backend moved into `next_path` gaplessly.
+///
+/// Same index rules as `advance_queue`, but audio is already playing the
+/// next item so only queue + state scrolls change. If the queue was edited
+/// since the track was staged, fall back to a regular `play()`.
+fn follow_track_advance(
+    shell: &Shell,
+    audio: &dyn AudioBackend,
+    state: &Mutex<Value>,
+    queue: &Mutex<Value>,
+    next_path: &str,
+) {
+    let Some(next_id) = peek_next_id(state, queue) else {
+        advance_queue(shell, audio, state, queue);
+        return;
+    };
+    let item = match shell.get(&paths::library_path(&next_id)) {
+        Ok(Some(scroll)) if scroll.data["path"].as_str() == Some(next_path) => scroll.data,
+        _ => {
+            advance_queue(shell, audio, state, queue);
+            return;
+        }
+    };
+
+    let repeat = {
+        let guard = state.lock();
+        repeat_mode(&guard).to_string()
+    };
+    update_queue(shell, queue, |data| {
+        if let Some(index) = queue_next_index(data, &repeat) {
+            data["index"] = index.into();
+        }
+    });
+    let speed = speed_for(shell, &item);
+    audio.set_speed(speed);
+    publish_now_playing(shell, state, &next_id, &item, speed);
+}
+
 fn retreat_queue(shell: &Shell, audio: &dyn AudioBackend, state: &Mutex<Value>, queue: &Mutex<Value>) {
     let play_id = {
         let mut data = queue.lock();
@@ -15,7 +15,9 @@ pub mod effects;
 pub mod engine;
 pub mod models;
 pub mod paths;
+pub mod time;
 
+pub use effects::render::{RenderBackend, RenderOptions, WavFormat};
 pub use effects::{AudioBackend, NoopBackend};
 pub use engine::Engine;
 pub use models::*;
@@ -23,6 +25,7 @@ pub use models::*;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::effects::render::write_test_wav;
     use nine_s_shell::Shell;
     use once_cell::sync::Lazy;
     use std::sync::Mutex;
@@ -606,6 +609,568 @@ mod tests {
         audio.prepare_next("/nonexistent/track.mp3");
     }
 
+    #[test]
+    fn list_devices_publishes_scroll() {
+        let (_dir, engine, _guard) = temp_engine("test_devices");
+        engine.audio().set_output_device(Some("USB DAC"));
+        let devices = engine.list_devices();
+        assert!(devices["hosts"].is_array());
+        #[cfg(feature = "native")]
+        assert_eq!(devices["preferred"], "USB DAC");
+        let scroll = engine.shell().get(paths::DEVICES).unwrap().unwrap();
+        assert_eq!(scroll.data, devices);
+    }
+
+    #[test]
+    fn crossfade_skips_same_album() {
+        use crate::effects::dsp::FadeCurve;
+        let settings = serde_json::json!({
+            "crossfade": {"duration_ms": 4000, "curve": "linear", "skip_same_album": true}
+        });
+        let next = serde_json::json!({"album": "Kind of Blue"});
+        assert_eq!(engine::crossfade_for(&settings, "Kind of Blue", &next), (0, FadeCurve::Linear));
+        assert_eq!(engine::crossfade_for(&settings, "Blue Train", &next), (4000, FadeCurve::Linear));
+        // No settings scroll — gapless
+        assert_eq!(engine::crossfade_for(&serde_json::Value::Null, "", &next).0, 0);
+    }
+
+    #[test]
+    fn replay_gain_modes_and_peak_protection() {
+        let item = serde_json::json!({"replay_gain": {
+            "track_gain_db": -6.0, "track_peak": 0.9,
+            "album_gain_db": 6.0, "album_peak": 0.8
+        }});
+        let mode = |m: &str| serde_json::json!({"replay_gain": {"mode": m}});
+        assert_eq!(engine::replay_gain_for(&mode("off"), &item), 1.0);
+        assert!((engine::replay_gain_for(&mode("track"), &item) - 0.501).abs() < 0.001);
+        // +6 dB album gain would clip a 0.8 peak — capped at 1/0.8
+        assert!((engine::replay_gain_for(&mode("album"), &item) - 1.25).abs() < 0.001);
+        // Album mode falls back to the track gain
+        let track_only = serde_json::json!({"replay_gain": {"track_gain_db": -6.0}});
+        assert!((engine::replay_gain_for(&mode("album"), &track_only) - 0.501).abs() < 0.001);
+        assert_eq!(engine::replay_gain_for(&mode("track"), &serde_json::json!({})), 1.0);
+    }
+
+    // -------------------------------------------------------------------
+    // Loudness analysis tests
+    // -------------------------------------------------------------------
+
+    #[test]
+    fn analysis_job_measures_tracks_and_albums() {
+        use std::sync::atomic::AtomicBool;
+        let (dir, engine, _guard) = temp_engine("test-analysis");
+
+        let amplitude = 10f32.powf(-23.0 / 20.0);
+        let tone: Vec<f32> = (0..48000 * 4)
+            .flat_map(|n| {
+                let s = amplitude * (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / 48000.0).sin();
+                [s, s]
+            })
+            .collect();
+        // "status" shares its name with the job's status scroll
+        for name in ["a", "status"] {
+            let path = dir.path().join(format!("{}.wav", name));
+            write_test_wav(&path, 48000, 2, &tone);
+            engine.add_to_library(name, serde_json::json!({
+                "id": name, "media_type": "Audio", "path": path.to_str().unwrap(), "album": "Tones",
+            })).unwrap();
+        }
+
+        let shutdown = AtomicBool::new(false);
+        let clock = crate::time::ManualClock::new(5_000);
+        let analyzed = effects::analysis::run_analysis(engine.shell(), &serde_json::json!({}), 0, 0, &clock, &shutdown);
+        assert_eq!(analyzed, 2);
+
+        let result = engine.analysis("a").expect("analysis scroll");
+        let lufs = result["integrated_lufs"].as_f64().unwrap();
+        assert!((lufs - -23.0).abs() < 0.2, "got {lufs}");
+        assert!((result["album_integrated_lufs"].as_f64().unwrap() - lufs).abs() < 0.1);
+        // Stamped from the engine's time source, not the system clock
+        assert_eq!(result["analyzed_ms"], 5_000);
+
+        let status = engine.shell().get(paths::ANALYSIS_STATUS).unwrap().unwrap().data;
+        assert_eq!(status["running"], false);
+        assert_eq!(status["albums"], 1);
+        assert_eq!(status["finished_ms"], 5_000);
+        assert!(engine.analysis("status").is_some_and(|r| r["integrated_lufs"].is_number()));
+
+        // A later job skips items that already have results
+        let later = u64::MAX;
+        assert_eq!(effects::analysis::run_analysis(engine.shell(), &serde_json::json!({}), 0, later, &clock, &shutdown), 0);
+    }
+
+    // -------------------------------------------------------------------
+    // Offline render tests
+    // -------------------------------------------------------------------
+
+    #[test]
+    fn render_backend_plays_queue_through_engine() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let dir = TempDir::new().expect("tempdir");
+        std::env::set_var("NINE_S_ROOT", dir.path());
+        let render = std::sync::Arc::new(RenderBackend::in_memory(RenderOptions::default()));
+        let engine = Engine::with_backend(Shell::open("test-render", &[]).expect("shell"), render.clone());
+        engine.start();
+
+        let tone = vec![0.25f32; 44100]; // 0.5s stereo
+        for name in ["a", "b"] {
+            let path = dir.path().join(format!("{}.wav", name));
+            write_test_wav(&path, 44100, 2, &tone);
+            engine.add_to_library(name, serde_json::json!({
+                "id": name, "media_type": "Audio", "path": path.to_str().unwrap(),
+            })).unwrap();
+        }
+        engine.set_queue(vec!["a".into(), "b".into()], 0).unwrap();
+        engine.command(PlaybackCommand::Play { id: "a".into() }).unwrap();
+
+        // Both tracks render back to back, then the queue ends
+        let start = std::time::Instant::now();
+        while render.frames_rendered() < 44100 || engine.playback_state()["playing"] != false {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10), "render timed out");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(render.frames_rendered(), 44100);
+        assert_eq!(engine.queue_state().unwrap()["index"], 1);
+        assert_eq!(render.take_rendered().len(), 44100 * 2);
+
+        engine.shutdown();
+    }
+
+    // -------------------------------------------------------------------
+    // Scripted backend tests (virtual time, no sleeps)
+    // -------------------------------------------------------------------
+
+    use crate::effects::scripted::{BackendCall, ScriptedBackend};
+    use crate::time::ManualClock;
+
+    const T0: i64 = 1_700_000_000_000;
+
+    /// Engine on a scripted backend whose heartbeat only ticks when the
+    /// returned clock is advanced. Library items `ids` play `/music/<id>.mp3`.
+    fn scripted_engine(
+        app: &str,
+        ids: &[&str],
+    ) -> (TempDir, Engine, std::sync::Arc<ScriptedBackend>, std::sync::Arc<ManualClock>, std::sync::MutexGuard<'static, ()>) {
+        let guard = ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let dir = TempDir::new().expect("tempdir");
+        std::env::set_var("NINE_S_ROOT", dir.path());
+        let clock = std::sync::Arc::new(ManualClock::new(T0));
+        let backend = std::sync::Arc::new(ScriptedBackend::new(clock.clone()));
+        let engine = Engine::with_backend(Shell::open(app, &[]).expect("shell"), backend.clone())
+            .with_time_source(clock.clone());
+        for id in ids {
+            engine.add_to_library(id, serde_json::json!({
+                "id": id, "media_type": "audio", "title": id, "path": format!("/music/{}.mp3", id),
+            })).unwrap();
+        }
+        engine.set_queue(ids.iter().map(|id| id.to_string()).collect(), 0).unwrap();
+        engine.start();
+        clock.wait_for_heartbeat();
+        (dir, engine, backend, clock, guard)
+    }
+
+    /// Play `id` and wait for the command watcher to publish it.
+    fn play_and_wait(engine: &Engine, id: &str) {
+        engine.command(PlaybackCommand::Play { id: id.into() }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["current_id"] != id {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "play {} not handled", id);
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn scripted_track_end_advances_queue_and_records_history() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-end", &["a", "b"]);
+        backend.set_duration("/music/a.mp3", 10_000);
+        play_and_wait(&engine, "a");
+
+        clock.advance(1000);
+        backend.add_underruns(2);
+        clock.advance(250);
+        let state = engine.playback_state();
+        assert_eq!(state["position_ms"], 1250);
+        assert_eq!(state["duration_ms"], 10_000);
+        assert_eq!(state["underruns"], 2);
+
+        // Too early for the next track to be staged — the end is a hard stop
+        backend.finish();
+        clock.advance(250);
+        assert_eq!(backend.current().as_deref(), Some("/music/b.mp3"));
+        assert!(backend.calls().contains(&BackendCall::Play("/music/b.mp3".into())));
+        assert_eq!(engine.queue_state().unwrap()["index"], 1);
+        assert_eq!(engine.playback_state()["current_id"], "b");
+
+        let history = engine.play_history(10);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0]["media_id"], "a");
+        assert_eq!(history[0]["played_at_ms"], T0 + 1500);
+        assert_eq!(history[0]["duration_played_ms"], 10_000);
+        assert_eq!(engine.media_stats("a").unwrap()["play_count"], 1);
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_gapless_prestage_follows_advance() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-gapless", &["a", "b"]);
+        backend.set_duration("/music/a.mp3", 2000);
+        play_and_wait(&engine, "a");
+
+        // Within 3s of the end: the next track is staged on the first tick
+        clock.advance(250);
+        assert_eq!(backend.staged().as_deref(), Some("/music/b.mp3"));
+
+        clock.advance(2000);
+        assert_eq!(backend.current().as_deref(), Some("/music/b.mp3"));
+        assert!(!backend.calls().contains(&BackendCall::Play("/music/b.mp3".into())));
+        assert_eq!(engine.queue_state().unwrap()["index"], 1);
+        let state = engine.playback_state();
+        assert_eq!(state["current_id"], "b");
+        assert_eq!(state["playing"], true);
+        assert_eq!(engine.play_history(10)[0]["media_id"], "a");
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_staging_follows_the_decoder_not_the_output() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-lag", &["a", "b"]);
+        backend.set_duration("/music/a.mp3", 10_000);
+        backend.set_output_lag(4000);
+        play_and_wait(&engine, "a");
+
+        // Decoded 7250 ms in, only 3250 ms audible: staged on the decoder's lead
+        clock.advance(7250);
+        assert_eq!(engine.playback_state()["position_ms"], 3250);
+        assert_eq!(backend.staged().as_deref(), Some("/music/b.mp3"));
+
+        clock.advance(3000);
+        assert_eq!(backend.current().as_deref(), Some("/music/b.mp3"));
+        assert!(!backend.calls().contains(&BackendCall::Play("/music/b.mp3".into())));
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_queue_end_stops_and_records_once() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-queue-end", &["a"]);
+        backend.set_duration("/music/a.mp3", 1000);
+        play_and_wait(&engine, "a");
+
+        clock.advance(2000);
+        let state = engine.playback_state();
+        assert_eq!(state["playing"], false);
+        assert!(state["current_id"].is_null());
+        assert_eq!(backend.calls().last(), Some(&BackendCall::Stop));
+        assert_eq!(engine.play_history(10).len(), 1);
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_error_stops_playback() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-error", &["a", "b"]);
+        play_and_wait(&engine, "a");
+
+        backend.fail();
+        clock.advance(250);
+        let state = engine.playback_state();
+        assert_eq!(state["playing"], false);
+        assert_eq!(state["error"], "audio_device_or_decode_error");
+        assert_eq!(backend.calls().last(), Some(&BackendCall::Stop));
+        // An error is not a natural end: the queue stays put
+        assert_eq!(engine.queue_state().unwrap()["index"], 0);
+        assert!(engine.play_history(10).is_empty());
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_fade_out_stops_without_advancing() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-fade-out", &["a", "b"]);
+        engine.shell().put(paths::SETTINGS_AUDIO, serde_json::json!({"transport_fade_ms": 900})).unwrap();
+        play_and_wait(&engine, "a");
+        assert!(backend.calls().contains(&BackendCall::SetTransportFade(500)));
+
+        engine.command(PlaybackCommand::FadeOut { duration_ms: 2000 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["fading_out"] != true {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "fadeout not handled");
+            std::thread::yield_now();
+        }
+        assert!(backend.calls().contains(&BackendCall::FadeOut(2000)));
+
+        // Still audible mid-fade
+        clock.advance(1000);
+        assert_eq!(engine.playback_state()["playing"], true);
+
+        clock.advance(1250);
+        let state = engine.playback_state();
+        assert_eq!(state["playing"], false);
+        assert!(state["current_id"].is_null() && state["fading_out"].is_null());
+        // Stopped, not finished: no advance and no play recorded
+        assert_eq!(engine.queue_state().unwrap()["index"], 0);
+        assert!(!backend.calls().contains(&BackendCall::Play("/music/b.mp3".into())));
+        assert!(engine.play_history(10).is_empty());
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_speed_is_remembered_per_media_type() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-speed", &["a"]);
+        for id in ["ep1", "ep2"] {
+            engine.add_to_library(id, serde_json::json!({
+                "id": id, "media_type": "Podcast", "title": id, "path": format!("/music/{}.mp3", id),
+            })).unwrap();
+        }
+        play_and_wait(&engine, "ep1");
+        assert_eq!(engine.playback_state()["speed"], 1.0);
+
+        engine.command(PlaybackCommand::SetSpeed { rate: 2.0 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["speed"] != 2.0 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "setspeed not handled");
+            std::thread::yield_now();
+        }
+        // Track time runs at twice the wall clock
+        clock.advance(1000);
+        assert_eq!(engine.playback_state()["position_ms"], 2000);
+        assert_eq!(engine.shell().get(paths::PLAYBACK_SPEED).unwrap().unwrap().data, serde_json::json!({"Podcast": 2.0}));
+
+        // Music keeps its own (default) speed; the next episode picks 2x back up
+        play_and_wait(&engine, "a");
+        assert_eq!(backend.calls().last(), Some(&BackendCall::Play("/music/a.mp3".into())));
+        assert_eq!(engine.playback_state()["speed"], 1.0);
+        play_and_wait(&engine, "ep2");
+        assert_eq!(engine.playback_state()["speed"], 2.0);
+        let calls = backend.calls();
+        assert_eq!(&calls[calls.len() - 2..], &[BackendCall::SetSpeed(2.0), BackendCall::Play("/music/ep2.mp3".into())]);
+
+        // Out-of-range requests are clamped
+        engine.command(PlaybackCommand::SetSpeed { rate: 8.0 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["speed"] != 3.0 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "clamped speed not published");
+            std::thread::yield_now();
+        }
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_staged_item_plays_at_its_speed_from_the_handover() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-speed-handover", &["a"]);
+        engine.add_to_library("ep1", serde_json::json!({
+            "id": "ep1", "media_type": "Podcast", "title": "ep1", "path": "/music/ep1.mp3",
+        })).unwrap();
+        engine.shell().put(paths::PLAYBACK_SPEED, serde_json::json!({"Podcast": 2.0})).unwrap();
+        engine.set_queue(vec!["a".into(), "ep1".into()], 0).unwrap();
+        backend.set_duration("/music/a.mp3", 10_100);
+        play_and_wait(&engine, "a");
+
+        clock.advance(7250);
+        assert_eq!(backend.staged().as_deref(), Some("/music/ep1.mp3"));
+        assert!(backend.calls().contains(&BackendCall::SetTrackSpeed("/music/ep1.mp3".into(), 2.0)));
+
+        // Handover at 10.1s; the 150 ms to the next tick already run at 2x
+        clock.advance(3000);
+        assert_eq!(engine.playback_state()["current_id"], "ep1");
+        assert_eq!(engine.playback_state()["position_ms"], 300);
+        assert_eq!(engine.playback_state()["speed"], 2.0);
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_seek_to_zero_and_relative_seeks() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-seek", &["a", "b"]);
+        backend.set_duration("/music/a.mp3", 30_000);
+        play_and_wait(&engine, "a");
+        clock.advance(5000);
+
+        let seek = |cmd: PlaybackCommand, expect: u64| {
+            engine.command(cmd).unwrap();
+            let start = std::time::Instant::now();
+            while engine.playback_state()["position_ms"] != expect {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5), "seek to {} not published", expect);
+                std::thread::yield_now();
+            }
+            assert_eq!(backend.position_ms(), expect);
+        };
+        // 0 is a real target, not "no seek"
+        seek(PlaybackCommand::Seek { position_ms: 0 }, 0);
+        assert_eq!(backend.calls().last(), Some(&BackendCall::Seek(0)));
+        seek(PlaybackCommand::SeekRelative { delta_ms: 10_000 }, 10_000);
+        seek(PlaybackCommand::SeekRelative { delta_ms: -4000 }, 6000);
+        seek(PlaybackCommand::SeekRelative { delta_ms: -60_000 }, 0);
+
+        // Past the end: clamped to it, and the track ends like it played out
+        engine.command(PlaybackCommand::SeekRelative { delta_ms: 60_000 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["current_id"] != "b" {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "seek past the end did not advance");
+            clock.advance(250);
+        }
+        assert!(backend.calls().contains(&BackendCall::Seek(30_000)));
+        assert_eq!(engine.play_history(10)[0]["media_id"], "a");
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_loop_and_trim_points() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-loop", &["a", "b"]);
+        backend.set_duration("/music/a.mp3", 60_000);
+        engine.set_track_range("a", 5000, Some(20_000)).unwrap();
+
+        // Nothing playing yet: the loop is refused, and says so
+        engine.command(PlaybackCommand::SetLoop { start_ms: 8000, end_ms: 10_000 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["error"] != "loop_not_playing" {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "setloop not rejected");
+            std::thread::yield_now();
+        }
+        assert!(engine.playback_state()["loop"].is_null());
+        assert!(!backend.calls().iter().any(|c| matches!(c, BackendCall::SetLoop(Some(_)))));
+
+        play_and_wait(&engine, "a");
+        let state = engine.playback_state();
+        assert_eq!(state["position_ms"], 5000);
+        assert_eq!(state["trim"], serde_json::json!({"start_ms": 5000, "end_ms": 20_000}));
+        assert!(backend.calls().contains(&BackendCall::SetTrackRange("/music/a.mp3".into(), 5000, Some(20_000))));
+
+        engine.command(PlaybackCommand::SetLoop { start_ms: 8000, end_ms: 10_000 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["loop"].is_null() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "setloop not handled");
+            std::thread::yield_now();
+        }
+        // 5s + 6s passes B once: back to A, 1s in
+        clock.advance(4000);
+        clock.advance(2000);
+        let state = engine.playback_state();
+        assert_eq!(state["position_ms"], 9000);
+        assert_eq!(state["loop"], serde_json::json!({"start_ms": 8000, "end_ms": 10_000}));
+
+        // A backwards loop is rejected; clearing lets the track run to its end trim point
+        engine.command(PlaybackCommand::SetLoop { start_ms: 9000, end_ms: 1000 }).unwrap();
+        let start = std::time::Instant::now();
+        while engine.playback_state()["error"] != "loop_invalid_region" {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "backwards loop not rejected");
+            std::thread::yield_now();
+        }
+        engine.command(PlaybackCommand::ClearLoop).unwrap();
+        let start = std::time::Instant::now();
+        while !engine.playback_state()["loop"].is_null() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "clearloop not handled");
+            std::thread::yield_now();
+        }
+        assert!(!backend.calls().contains(&BackendCall::SetLoop(Some((9000, 1000)))));
+        clock.advance(9000);
+        assert_eq!(backend.staged().as_deref(), Some("/music/b.mp3"));
+        clock.advance(3000);
+        let state = engine.playback_state();
+        assert_eq!(state["current_id"], "b");
+        assert_eq!(state["position_ms"], 1000);
+        assert!(state["trim"].is_null() && state["loop"].is_null());
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_eq_is_validated_and_output_format_is_published() {
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-eq", &["a"]);
+        backend.set_output_format(Some((96_000, 2)));
+        play_and_wait(&engine, "a");
+
+        let eq = serde_json::json!({"filters": [{"type": "eq", "freq_hz": 80, "gain_db": 3.0, "q": 0.7}]});
+        engine.shell().put(paths::PLAYBACK_EQ, eq.clone()).unwrap();
+        clock.advance(250);
+        assert!(backend.calls().contains(&BackendCall::SetEq(eq)));
+        assert_eq!(engine.playback_state()["output_format"], serde_json::json!({"sample_rate": 96_000, "channels": 2}));
+        assert_eq!(engine.shell().get(paths::PLAYBACK_EQ_STATUS).unwrap().unwrap().data["applied"], true);
+
+        // An invalid spec is reported and never reaches the backend
+        backend.take_calls();
+        let bad = serde_json::json!({"filters": [{"type": "lowpass", "freq_hz": 0}, {"type": "tilt"}]});
+        engine.shell().put(paths::PLAYBACK_EQ, bad).unwrap();
+        clock.advance(250);
+        assert!(!backend.calls().iter().any(|c| matches!(c, BackendCall::SetEq(_))));
+        let status = engine.shell().get(paths::PLAYBACK_EQ_STATUS).unwrap().unwrap().data;
+        assert_eq!(status["applied"], false);
+        assert_eq!(status["errors"][1], serde_json::json!({"index": 1, "error": "unknown filter type \"tilt\""}));
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_levels_are_published_at_their_own_rate() {
+        use crate::effects::levels::{Levels, BANDS};
+        let (_dir, engine, backend, clock, _guard) = scripted_engine("test-scripted-levels", &["a"]);
+        let levels = Levels { rms_db: vec![-12.0, -14.5], peak_db: vec![-3.0, -4.0], spectrum_db: vec![-40.0; BANDS] };
+        backend.set_levels(Some(levels.clone()));
+        play_and_wait(&engine, "a");
+        let read = || engine.shell().get(paths::PLAYBACK_LEVELS).unwrap();
+
+        // Off by default
+        clock.advance(250);
+        assert!(read().is_none());
+
+        engine.shell().put(paths::SETTINGS_AUDIO, serde_json::json!({"levels_hz": 20})).unwrap();
+        clock.advance(250);
+        // Every 50 ms from here, while the clock still ticks at 4 Hz
+        clock.advance(100);
+        let first = read().expect("levels published");
+        assert_eq!(first.data, levels.to_value());
+        clock.advance(50);
+        assert!(read().unwrap().metadata.version > first.metadata.version);
+        clock.advance(1000);
+        assert_eq!(engine.playback_state()["position_ms"], 1650);
+
+        // Stopping leaves an empty reading behind
+        engine.command(PlaybackCommand::Stop).unwrap();
+        let start = std::time::Instant::now();
+        while backend.is_playing() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "stop not handled");
+            std::thread::yield_now();
+        }
+        clock.advance(50);
+        assert_eq!(read().unwrap().data["rms_db"], serde_json::json!([]));
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn scripted_levels_rate_leaves_ticks_250ms_apart() {
+        let (_dir, engine, _backend, clock, _guard) = scripted_engine("test-scripted-levels-ticks", &["a"]);
+        let tick_version = || engine.shell().get(paths::CLOCK_TICK).unwrap().map_or(0, |s| s.metadata.version);
+        // 7 Hz snapshots (every 142 ms) don't divide the 250 ms tick
+        engine.shell().put(paths::SETTINGS_AUDIO, serde_json::json!({"levels_hz": 7})).unwrap();
+        clock.advance(250);
+        for _ in 0..8 {
+            let before = tick_version();
+            clock.advance(249);
+            assert_eq!(tick_version(), before, "ticked early");
+            clock.advance(1);
+            assert_eq!(tick_version(), before + 1, "tick late");
+        }
+        engine.shutdown();
+    }
+
+    #[test]
+    fn channel_mix_settings_reach_backend() {
+        use crate::effects::convert::{ChannelMix, Upmix};
+        let (_dir, engine, backend, _clock, _guard) = scripted_engine("test-channel-mix", &["a"]);
+        let settings = serde_json::json!({"channel_mix": {"lfe": true, "upmix": "spread"}});
+        engine.shell().put(paths::SETTINGS_AUDIO, settings).unwrap();
+        play_and_wait(&engine, "a");
+        let want = ChannelMix { normalize: true, lfe: true, upmix: Upmix::Spread };
+        assert!(backend.calls().contains(&BackendCall::SetChannelMix(want)));
+        engine.shutdown();
+    }
+
     // -------------------------------------------------------------------
     // History & stats tests
     // -------------------------------------------------------------------
@@ -819,4 +1384,76 @@ mod tests {
         let data = engine.playlist("pl-1").unwrap();
         assert_eq!(data["name"], "New Name");
     }
+
+    // -------------------------------------------------------------------
+    // EQ preset tests
+    // -------------------------------------------------------------------
+
+    #[test]
+    fn eq_preset_import_and_activate() {
+        let (_dir, engine, _guard) = temp_engine("test-eq-presets");
+        let text = "Preamp: -4.0 dB\nFilter 1: ON PK Fc 60 Hz Gain 4.0 dB Q 0.8\n";
+        engine.import_eq_preset("hd600", text).unwrap();
+        engine
+            .save_eq_preset("flat", serde_json::json!({"filters": []}))
+            .unwrap();
+        let mut names = engine.list_eq_presets();
+        names.sort();
+        assert_eq!(names, vec!["flat", "hd600"]);
+        assert_eq!(engine.eq_preset("hd600").unwrap()["filters"][1]["type"], "eq");
+
+        engine.activate_eq_preset("hd600").unwrap();
+        let eq = engine.shell().get(paths::PLAYBACK_EQ).unwrap().unwrap().data;
+        assert_eq!(eq["preset"], "hd600");
+        assert_eq!(eq["filters"][0], serde_json::json!({"type": "gain", "db": -4.0}));
+
+        assert!(engine.activate_eq_preset("missing").is_err());
+        assert!(engine.import_eq_preset("bad", "Filter 1: ON ZZ Fc 1 Hz").is_err());
+        assert!(engine
+            .save_eq_preset("bad", serde_json::json!({"filters": [{"type": "eq", "q": -1}]}))
+            .is_err());
+        assert!(engine.save_eq_preset("a/b", serde_json::json!({})).is_err());
+
+        // The active EQ (the preset: -4 dB preamp, +4 dB at 60 Hz) drawn at 48k
+        let curve = engine.eq_response(None, 0, 64);
+        assert_eq!(curve["sample_rate"], 48_000);
+        let gains = curve["gain_db"].as_array().unwrap();
+        assert_eq!(gains.len(), 64);
+        assert!((gains[63].as_f64().unwrap() + 4.0).abs() < 0.1);
+        let flat = engine.eq_response(Some(&serde_json::json!({"filters": []})), 44_100, 8);
+        assert!(flat["gain_db"].as_array().unwrap().iter().all(|g| g.as_f64() == Some(0.0)));
+    }
+
+    #[test]
+    fn convolution_filter_loads_impulse_response() {
+        let (dir, engine, _guard) = temp_engine("test-eq-convolution");
+        // A half-level impulse at 44.1k, used by a 48k chain
+        let ir_path = dir.path().join("ir.wav");
+        let mut ir = vec![0.0f32; 256];
+        ir[0] = 0.5;
+        write_test_wav(&ir_path, 44_100, 1, &ir);
+
+        let spec = serde_json::json!({"filters": [{"type": "convolution", "ir_path": ir_path.to_str().unwrap()}]});
+        engine.save_eq_preset("room", spec.clone()).unwrap();
+        let curve = engine.eq_response(Some(&spec), 48_000, 32);
+        let gains = curve["gain_db"].as_array().unwrap();
+        // Flat -6 dB across the band the resampler passes
+        for g in &gains[..28] {
+            assert!((g.as_f64().unwrap() + 6.02).abs() < 0.1, "{}", g);
+        }
+
+        // Decoded once per path and rate, then reused by every rebuild
+        let path = ir_path.to_str().unwrap();
+        let (first, _) = effects::convolution::cached_impulse_response(path, 48_000).unwrap();
+        let (again, _) = effects::convolution::cached_impulse_response(path, 48_000).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&first, &again));
+        let (other_rate, _) = effects::convolution::cached_impulse_response(path, 44_100).unwrap();
+        assert!(!std::sync::Arc::ptr_eq(&first, &other_rate));
+
+        // Parsing doesn't touch the filesystem; storing the preset checks the file
+        let missing = serde_json::json!({"filters": [{"type": "convolution", "ir_path": "/nonexistent/ir.wav"}]});
+        let specs = effects::dsp::parse_filters(&missing).unwrap();
+        assert_eq!(effects::dsp::check_files(&specs).unwrap_err()[0].message, "impulse response not found: /nonexistent/ir.wav");
+        assert!(engine.save_eq_preset("missing", missing).is_err());
+    }
 }
@@ -25,14 +25,31 @@ pub enum PlaybackCommand {
     Pause,
     Resume,
     Stop,
+    /// Fade to silence over `duration_ms` (default 3 s), then stop.
+    FadeOut {
+        #[serde(default = "default_fade_out_ms")]
+        duration_ms: u64,
+    },
     Seek { position_ms: u64 },
+    /// Seek by `delta_ms` from the current position (negative = back).
+    SeekRelative { delta_ms: i64 },
     Next,
     Previous,
     SetVolume { volume: f32 },
+    /// Playback speed, pitch preserved (0.5-3.0); remembered per media type.
+    SetSpeed { rate: f32 },
+    /// Loop the playing track from `start_ms` to `end_ms` (A-B repeat).
+    /// Refused, with the state's `error` set, when nothing is playing.
+    SetLoop { start_ms: u64, end_ms: u64 },
+    ClearLoop,
     SetShuffle { enabled: bool },
     SetRepeat { mode: RepeatMode },
 }
 
+fn default_fade_out_ms() -> u64 {
+    3000
+}
+
 impl PlaybackCommand {
     pub fn to_value(&self) -> Value {
         serde_json::to_value(self).unwrap_or(Value::Null)
@@ -68,11 +68,16 @@ pub fn default_queue_state() -> Value {
 
 /// Get the current item ID from a queue scroll's data.
 pub fn queue_current_id(data: &Value) -> Option<&str> {
+    let index = data["index"].as_u64().unwrap_or(0) as usize;
+    queue_id_at(data, index)
+}
+
+/// Get the item ID at a queue index (an index into shuffle_order when shuffling).
+pub fn queue_id_at(data: &Value, index: usize) -> Option<&str> {
     let items = data["items"].as_array()?;
     if items.is_empty() {
         return None;
     }
-    let index = data["index"].as_u64().unwrap_or(0) as usize;
 
     if data["shuffle"].as_bool().unwrap_or(false) {
         // Dereference through shuffle_order
@@ -84,6 +89,26 @@ pub fn queue_current_id(data: &Value) -> Option<&str> {
     }
 }
 
+/// Queue index that follows the current one under a repeat mode.
+///
+/// `"one"` stays put, `"all"` wraps to 0, `"off"` returns None at the end.
+pub fn queue_next_index(data: &Value, repeat: &str) -> Option<usize> {
+    let len = data["items"].as_array().map(|a| a.len()).unwrap_or(0);
+    if len == 0 {
+        return None;
+    }
+    let index = data["index"].as_u64().unwrap_or(0) as usize;
+    if repeat == "one" {
+        Some(index)
+    } else if index + 1 < len {
+        Some(index + 1)
+    } else if repeat == "all" {
+        Some(0)
+    } else {
+        None
+    }
+}
+
 /// Get repeat mode string, defaulting to "off".
 pub fn repeat_mode(data: &Value) -> &str {
     data["repeat"].as_str().unwrap_or("off")
@@ -20,6 +20,19 @@ pub const LIBRARY_PREFIX: &str = "/amsal/library";
 pub const PLAYBACK_STATE: &str = "/amsal/playback/state";
 pub const PLAYBACK_COMMAND: &str = "/amsal/playback/command";
 pub const PLAYBACK_EQ: &str = "/amsal/playback/eq";
+pub const PLAYBACK_EQ_STATUS: &str = "/amsal/playback/eq_status";
+pub const PLAYBACK_SPEED: &str = "/amsal/playback/speed";
+pub const PLAYBACK_LEVELS: &str = "/amsal/playback/levels";
+
+// ---------------------------------------------------------------------------
+// EQ presets
+// ---------------------------------------------------------------------------
+
+pub fn eq_preset_path(name: &str) -> String {
+    format!("/amsal/eq/presets/{}", name)
+}
+
+pub const EQ_PRESETS_PREFIX: &str = "/amsal/eq/presets";
 
 // ---------------------------------------------------------------------------
 // Queue
@@ -76,6 +89,21 @@ pub fn download_path(id: &str) -> String {
 
 pub const DOWNLOADS_PREFIX: &str = "/amsal/downloads";
 
+// ---------------------------------------------------------------------------
+// Loudness analysis
+// ---------------------------------------------------------------------------
+
+/// Per-item results live under their own prefix, so an item id can't
+/// collide with the request/status channels.
+pub fn analysis_path(id: &str) -> String {
+    format!("/amsal/analysis/items/{}", id)
+}
+
+pub const ANALYSIS_ITEMS_PREFIX: &str = "/amsal/analysis/items";
+
+pub const ANALYSIS_REQUEST: &str = "/amsal/analysis/request";
+pub const ANALYSIS_STATUS: &str = "/amsal/analysis/status";
+
 // ---------------------------------------------------------------------------
 // Settings
 // ---------------------------------------------------------------------------
@@ -83,6 +111,12 @@ pub const DOWNLOADS_PREFIX: &str = "/amsal/downloads";
 pub const SETTINGS_AUDIO: &str = "/amsal/settings/audio";
 pub const SETTINGS_STORAGE: &str = "/amsal/settings/storage";
 
+// ---------------------------------------------------------------------------
+// Output devices
+// ---------------------------------------------------------------------------
+
+pub const DEVICES: &str = "/amsal/devices";
+
 // ---------------------------------------------------------------------------
 // Clock
 // ---------------------------------------------------------------------------
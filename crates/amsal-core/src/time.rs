@@ -0,0 +1,201 @@
+//! Time sources for the engine heartbeat.
+//!
+//! The heartbeat paces its ticks and stamps play history through a
+//! `TimeSource`; the analysis poller sleeps and stamps jobs through the same
+//! one. `WallClock` sleeps for real. `ManualClock` only moves when
+//! a test advances it, and `advance` returns once the heartbeat has run every
+//! tick that came due — so the scrolls it writes can be asserted on without
+//! sleeping.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// Paces the heartbeat and tells the time.
+pub trait TimeSource: Send + Sync {
+    /// Block until the next tick is due, `period` after the previous one.
+    /// Returns early once `shutdown` is set.
+    fn wait_tick(&self, period: Duration, shutdown: &AtomicBool);
+    /// Block for `duration` without taking part in tick pacing (for pollers
+    /// other than the heartbeat). Returns early once `shutdown` is set.
+    fn sleep(&self, duration: Duration, shutdown: &AtomicBool);
+    /// Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> i64;
+}
+
+/// Real time: sleeps between ticks, reads the system clock.
+pub struct WallClock;
+
+impl TimeSource for WallClock {
+    fn wait_tick(&self, period: Duration, _shutdown: &AtomicBool) {
+        std::thread::sleep(period);
+    }
+
+    fn sleep(&self, duration: Duration, _shutdown: &AtomicBool) {
+        std::thread::sleep(duration);
+    }
+
+    fn now_ms(&self) -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0)
+    }
+}
+
+/// Virtual time for tests. Starts at a fixed epoch and only advances
+/// through `advance`.
+pub struct ManualClock {
+    ticks: Mutex<ManualTicks>,
+    changed: Condvar,
+}
+
+struct ManualTicks {
+    now_ms: i64,
+    /// Advanced time the heartbeat has not ticked through yet.
+    pending_ms: u64,
+    /// Period of the heartbeat while it waits for a tick (None = busy).
+    waiting: Option<u64>,
+    /// Set once a heartbeat has waited on this clock.
+    attached: bool,
+}
+
+/// How long `advance` waits for a busy heartbeat before giving up.
+const CATCH_UP_LIMIT: Duration = Duration::from_secs(5);
+
+impl ManualClock {
+    pub fn new(start_ms: i64) -> Self {
+        Self {
+            ticks: Mutex::new(ManualTicks { now_ms: start_ms, pending_ms: 0, waiting: None, attached: false }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Block until a heartbeat is waiting on this clock (call after
+    /// `Engine::start()`, so the first `advance` is caught up with).
+    pub fn wait_for_heartbeat(&self) {
+        let mut ticks = self.ticks.lock();
+        let deadline = Instant::now() + CATCH_UP_LIMIT;
+        while ticks.waiting.is_none() {
+            if self.changed.wait_until(&mut ticks, deadline).timed_out() {
+                log::warn!("amsal: no heartbeat attached to the manual clock");
+                return;
+            }
+        }
+    }
+
+    /// Move time forward by `ms`. With a heartbeat attached, blocks until it
+    /// has run every tick now due and is waiting for the next one.
+    pub fn advance(&self, ms: u64) {
+        let mut ticks = self.ticks.lock();
+        ticks.now_ms += ms as i64;
+        ticks.pending_ms += ms;
+        self.changed.notify_all();
+        if !ticks.attached {
+            return;
+        }
+        let deadline = Instant::now() + CATCH_UP_LIMIT;
+        while ticks.waiting.is_none_or(|period| ticks.pending_ms >= period) {
+            if self.changed.wait_until(&mut ticks, deadline).timed_out() {
+                log::warn!("amsal: heartbeat did not catch up with the manual clock");
+                return;
+            }
+        }
+    }
+}
+
+impl TimeSource for ManualClock {
+    fn wait_tick(&self, period: Duration, shutdown: &AtomicBool) {
+        let period_ms = (period.as_millis() as u64).max(1);
+        let mut ticks = self.ticks.lock();
+        ticks.attached = true;
+        ticks.waiting = Some(period_ms);
+        self.changed.notify_all();
+        while ticks.pending_ms < period_ms {
+            if shutdown.load(Ordering::SeqCst) {
+                ticks.waiting = None;
+                return;
+            }
+            // Shutdown is a plain flag, so re-check it now and then
+            self.changed.wait_for(&mut ticks, Duration::from_millis(10));
+        }
+        ticks.pending_ms -= period_ms;
+        ticks.waiting = None;
+    }
+
+    fn sleep(&self, duration: Duration, shutdown: &AtomicBool) {
+        let mut ticks = self.ticks.lock();
+        let until = ticks.now_ms + duration.as_millis() as i64;
+        while ticks.now_ms < until && !shutdown.load(Ordering::SeqCst) {
+            self.changed.wait_for(&mut ticks, Duration::from_millis(10));
+        }
+    }
+
+    fn now_ms(&self) -> i64 {
+        self.ticks.lock().now_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    #[test]
+    fn advance_runs_due_ticks_before_returning() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let ticks = Arc::new(AtomicU64::new(0));
+
+        let heartbeat = {
+            let (clock, shutdown, ticks) = (clock.clone(), shutdown.clone(), ticks.clone());
+            std::thread::spawn(move || {
+                while !shutdown.load(Ordering::SeqCst) {
+                    clock.wait_tick(Duration::from_millis(250), &shutdown);
+                    if !shutdown.load(Ordering::SeqCst) {
+                        ticks.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+        };
+        clock.wait_for_heartbeat();
+
+        clock.advance(1000);
+        assert_eq!(ticks.load(Ordering::SeqCst), 4);
+        clock.advance(100);
+        assert_eq!(ticks.load(Ordering::SeqCst), 4);
+        clock.advance(150);
+        assert_eq!(ticks.load(Ordering::SeqCst), 5);
+        assert_eq!(clock.now_ms(), 2_250);
+
+        shutdown.store(true, Ordering::SeqCst);
+        heartbeat.join().unwrap();
+    }
+
+    #[test]
+    fn sleep_waits_for_advanced_time_without_pacing_ticks() {
+        let clock = Arc::new(ManualClock::new(0));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let woke = Arc::new(AtomicBool::new(false));
+        let sleeper = {
+            let (clock, shutdown, woke) = (clock.clone(), shutdown.clone(), woke.clone());
+            std::thread::spawn(move || {
+                clock.sleep(Duration::from_millis(250), &shutdown);
+                woke.store(true, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!woke.load(Ordering::SeqCst));
+        // No heartbeat attached: advance returns at once, time still moves
+        let start = Instant::now();
+        while !woke.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < CATCH_UP_LIMIT, "sleep never returned");
+            clock.advance(50);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        sleeper.join().unwrap();
+        assert!(clock.now_ms() >= 250);
+    }
+}
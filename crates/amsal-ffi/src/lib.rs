@@ -324,6 +324,20 @@ pub extern "C" fn amsal_playback_state(handle: *mut EngineHandle) -> *mut c_char
     to_cstr(serde_json::to_string(&state).unwrap_or_default())
 }
 
+/// List output hosts and devices as JSON (caller frees). Also publishes
+/// them to `/amsal/devices`. Select one via `"output_device"` in
+/// `/amsal/settings/audio`.
+#[no_mangle]
+pub extern "C" fn amsal_list_devices(handle: *mut EngineHandle) -> *mut c_char {
+    clear_error();
+    let engine = match engine_ref(handle) {
+        Ok(e) => e,
+        Err(e) => return err_null(e),
+    };
+    let devices = engine.list_devices();
+    to_cstr(serde_json::to_string(&devices).unwrap_or_default())
+}
+
 // ---------------------------------------------------------------------------
 // Queue
 // ---------------------------------------------------------------------------
@@ -702,6 +716,84 @@ pub extern "C" fn amsal_rename_playlist(
     }
 }
 
+// ---------------------------------------------------------------------------
+// EQ Presets
+// ---------------------------------------------------------------------------
+
+/// Import an Equalizer APO / AutoEQ `ParametricEQ.txt` (its text, not a
+/// path) as a named EQ preset. Returns scroll JSON (caller frees).
+#[no_mangle]
+pub extern "C" fn amsal_import_eq_preset(
+    handle: *mut EngineHandle,
+    name: *const c_char,
+    text: *const c_char,
+) -> *mut c_char {
+    clear_error();
+    let engine = match engine_ref(handle) { Ok(e) => e, Err(e) => return err_null(e) };
+    let name_str = match read_cstr(name) { Ok(s) => s, Err(e) => return err_null(e) };
+    let text_str = match read_cstr(text) { Ok(s) => s, Err(e) => return err_null(e) };
+    match engine.import_eq_preset(&name_str, &text_str) {
+        Ok(scroll) => json_to_cstr(&scroll),
+        Err(e) => err_null(e.to_string()),
+    }
+}
+
+/// List stored EQ preset names as JSON array (caller frees).
+#[no_mangle]
+pub extern "C" fn amsal_list_eq_presets(handle: *mut EngineHandle) -> *mut c_char {
+    clear_error();
+    let engine = match engine_ref(handle) { Ok(e) => e, Err(e) => return err_null(e) };
+    let names = engine.list_eq_presets();
+    to_cstr(serde_json::to_string(&names).unwrap_or_default())
+}
+
+/// Make a stored EQ preset the active EQ. Returns 1 on success, 0 on error.
+#[no_mangle]
+pub extern "C" fn amsal_activate_eq_preset(
+    handle: *mut EngineHandle,
+    name: *const c_char,
+) -> i32 {
+    clear_error();
+    let engine = match engine_ref(handle) { Ok(e) => e, Err(e) => { set_error(e); return 0; } };
+    let name_str = match read_cstr(name) { Ok(s) => s, Err(e) => { set_error(e); return 0; } };
+    match engine.activate_eq_preset(&name_str) {
+        Ok(_) => 1,
+        Err(e) => { set_error(e.to_string()); 0 }
+    }
+}
+
+/// Most points `amsal_eq_response` draws; far more than any display needs.
+const MAX_EQ_RESPONSE_POINTS: u32 = 4096;
+
+/// EQ curve for drawing: magnitude (dB) and phase (degrees) at `points`
+/// log-spaced frequencies, as JSON (caller frees). `spec_json` NULL = the
+/// active EQ; `sample_rate` 0 = the output's current rate. Returns NULL on
+/// error, including `points` above 4096.
+#[no_mangle]
+pub extern "C" fn amsal_eq_response(
+    handle: *mut EngineHandle,
+    spec_json: *const c_char,
+    sample_rate: u32,
+    points: u32,
+) -> *mut c_char {
+    clear_error();
+    let engine = match engine_ref(handle) { Ok(e) => e, Err(e) => return err_null(e) };
+    if points > MAX_EQ_RESPONSE_POINTS {
+        return err_null(format!("points must be at most {}", MAX_EQ_RESPONSE_POINTS));
+    }
+    let spec = if spec_json.is_null() {
+        None
+    } else {
+        let json_str = match read_cstr(spec_json) { Ok(s) => s, Err(e) => return err_null(e) };
+        match serde_json::from_str::<serde_json::Value>(&json_str) {
+            Ok(v) => Some(v),
+            Err(e) => return err_null(e.to_string()),
+        }
+    };
+    let curve = engine.eq_response(spec.as_ref(), sample_rate, points as usize);
+    to_cstr(serde_json::to_string(&curve).unwrap_or_default())
+}
+
 // ---------------------------------------------------------------------------
 // Clock
 // ---------------------------------------------------------------------------
@@ -1149,6 +1241,62 @@ mod tests {
         amsal_close(handle);
     }
 
+    #[test]
+    fn ffi_list_devices() {
+        let (_dir, handle, _guard) = ffi_engine("ffi-devices");
+        let ptr = amsal_list_devices(handle);
+        assert!(!ptr.is_null());
+        let json = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { amsal_string_free(ptr) };
+        let devices: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(devices["hosts"].is_array());
+
+        amsal_close(handle);
+    }
+
+    #[test]
+    fn ffi_eq_presets_and_response() {
+        let (_dir, handle, _guard) = ffi_engine("ffi-eq-presets");
+        let name = c("hd650");
+        let text = c("Preamp: -2.5 dB\nFilter 1: ON LSC Fc 105 Hz Gain 2.5 dB Q 0.71\n");
+        let ptr = amsal_import_eq_preset(handle, name.as_ptr(), text.as_ptr());
+        let json = read_ffi_string(ptr);
+        let scroll: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(scroll["data"]["filters"][1]["type"], "lowshelf");
+
+        let ptr = amsal_list_eq_presets(handle);
+        let names: Vec<String> = serde_json::from_str(&read_ffi_string(ptr)).unwrap();
+        assert_eq!(names, vec!["hd650"]);
+
+        assert_eq!(amsal_activate_eq_preset(handle, name.as_ptr()), 1);
+        let missing = c("missing");
+        assert_eq!(amsal_activate_eq_preset(handle, missing.as_ptr()), 0);
+
+        let bad = c("Filter 1: ON PK Gain 3 dB");
+        assert!(amsal_import_eq_preset(handle, missing.as_ptr(), bad.as_ptr()).is_null());
+        let err = read_ffi_string(amsal_last_error());
+        assert!(err.contains("missing Fc"), "{}", err);
+
+        let spec = c(r#"{"filters": [{"type": "gain", "db": 3.0}]}"#);
+        let ptr = amsal_eq_response(handle, spec.as_ptr(), 48000, 16);
+        let curve: serde_json::Value = serde_json::from_str(&read_ffi_string(ptr)).unwrap();
+        assert_eq!(curve["freq_hz"].as_array().unwrap().len(), 16);
+        assert!((curve["gain_db"][5].as_f64().unwrap() - 3.0).abs() < 1e-3);
+        // NULL spec draws the active EQ (the hd650 preset)
+        let ptr = amsal_eq_response(handle, ptr::null(), 0, 16);
+        let curve: serde_json::Value = serde_json::from_str(&read_ffi_string(ptr)).unwrap();
+        assert!((curve["gain_db"][15].as_f64().unwrap() + 2.5).abs() < 0.1);
+        // Point counts are bounded, so a bad argument can't allocate without limit
+        assert!(amsal_eq_response(handle, ptr::null(), 0, u32::MAX).is_null());
+        let err = read_ffi_string(amsal_last_error());
+        assert!(err.contains("at most 4096"), "{}", err);
+        let ptr = amsal_eq_response(handle, ptr::null(), 0, 4096);
+        let curve: serde_json::Value = serde_json::from_str(&read_ffi_string(ptr)).unwrap();
+        assert_eq!(curve["freq_hz"].as_array().unwrap().len(), 4096);
+
+        amsal_close(handle);
+    }
+
     // -------------------------------------------------------------------
     // Command via FFI
     // -------------------------------------------------------------------